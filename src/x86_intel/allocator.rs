@@ -0,0 +1,303 @@
+//! Assign a set of events to the PMCs of one PerfCounterControler.
+//!
+//! schedule() honors the counter restrictions of every event (fixed-only events, the programmable
+//! counter mask, events taken alone), moves architectural events to fixed counters when possible,
+//! and skips counters that are already in use or reserved by another PerfCounter.
+//! Programmable counters are assigned with a bipartite matching, so a set is rejected only if no
+//! assignment exists at all.
+//!
+
+use x86::perfcnt::intel::{EventDescription, Counter};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler, PerfCounter, event_encoding, fixed_counter_index};
+
+///Upper bound of programmable counters considered (8 with Hyper-Threading disabled).
+pub const MAX_GENERAL_PMC: usize = 8;
+///Upper bound of fixed counters considered.
+pub const MAX_FIXED_PMC: usize = 8;
+
+///An event schedule() could not place, and why.
+#[derive(Debug)]
+pub struct UnschedulableEvent<'e> {
+    ///Position of the event in the requested set.
+    pub index: usize,
+    pub event_name: &'e str,
+    pub reason: ErrorMsg,
+}
+
+///Fixed counter that counts the same as a programmable architectural event without modifiers.
+fn architectural_fixed_counter(event:&EventDescription)->Option<u8>{
+    if event.counter_mask != 0 || event.invert || event.edge_detect {
+        return None;
+    }
+    //0x3C/0x01 counts a reference clock (REF_XCLK) whose rate differs from the TSC, so only
+    //the REF_TSC pseudo-encoding goes to fixed counter 2.
+    match event_encoding(event, 0) {
+        Ok((0xC0, 0x00)) => Some(0),
+        Ok((0x3C, 0x00)) => Some(1),
+        Ok((0x00, 0x03)) => Some(2),
+        _ => None,
+    }
+}
+
+///Counters `event` may use on a PMU with `general` programmable counters.
+///
+/// More than four programmable counters per logical processor means Hyper-Threading is disabled,
+/// where the event tables give a separate, usually wider, mask.
+fn programmable_counters(event:&EventDescription, general:usize)->Counter{
+    match event.counter_ht_off {
+        Some(counter) if general > 4 => counter,
+        _ => event.counter,
+    }
+}
+
+///Try to match event `e` to a programmable counter, moving previously matched events if needed.
+fn augment(e:usize, allowed:&[u8], owner:&mut [Option<usize>; MAX_GENERAL_PMC], visited:&mut u8)->bool{
+    for pmc in 0..MAX_GENERAL_PMC {
+        let bit = 1u8 << pmc;
+        if allowed[e] & bit == 0 || *visited & bit != 0 {
+            continue;
+        }
+        *visited |= bit;
+        let free = match owner[pmc] {
+            None => true,
+            Some(other) => augment(other, allowed, owner, visited),
+        };
+        if free {
+            owner[pmc] = Some(e);
+            return true;
+        }
+    }
+    false
+}
+
+///Assign every event in `events` to a counter.
+///
+/// On success `assignment[i]` holds the counter for `events[i]`, as Counter::Fixed(index) or
+/// Counter::Programmable(index). `assignment` must be at least as long as `events`; the first
+/// event without a slot is reported with CounterOutOfRange otherwise.
+pub fn schedule<'e, B: PmuBackend>(ctrler:&PerfCounterControler<B>, events:&[&'e EventDescription<'e>], assignment:&mut [Counter])->Result<(),UnschedulableEvent<'e>>{
+    let fail = |index:usize, reason:ErrorMsg| UnschedulableEvent{index:index, event_name:events[index].event_name, reason:reason};
+    if assignment.len() < events.len() {
+        return Err(fail(assignment.len(), ErrorMsg::CounterOutOfRange));
+    }
+    let capabilities = ctrler.get_capabilities();
+    let general = (ctrler.get_number_msr() as usize).min(MAX_GENERAL_PMC);
+    let mut free_general:u8 = 0;
+    for pmc in 0..general {
+        let c = Counter::Programmable(pmc as u8);
+        if !ctrler.is_reserved(c) && !ctrler.check_in_use(c) {
+            free_general |= 1 << pmc;
+        }
+    }
+    let mut free_fixed:u8 = 0;
+    for fixed in 0..(capabilities.fixed_counter_slots() as usize).min(MAX_FIXED_PMC) {
+        let c = Counter::Fixed(fixed as u8);
+        if capabilities.is_fixed_counter_supported(fixed as u8) && !ctrler.is_reserved(c) && !ctrler.check_in_use(c) {
+            free_fixed |= 1 << fixed;
+        }
+    }
+
+    //Fixed counters first: fixed-only events must get theirs, architectural events take one if free.
+    let mut on_fixed = [false; MAX_GENERAL_PMC + MAX_FIXED_PMC];
+    if events.len() > on_fixed.len() {
+        return Err(fail(on_fixed.len(), ErrorMsg::CounterOutOfRange));
+    }
+    for (i, event) in events.iter().enumerate() {
+        if let Counter::Fixed(_) = event.counter {
+            let fixed = match fixed_counter_index(event) {
+                Some(fixed) => fixed,
+                None => return Err(fail(i, ErrorMsg::UnsupportedEvent)),
+            };
+            if fixed as usize >= MAX_FIXED_PMC || free_fixed & (1 << fixed) == 0 {
+                return Err(fail(i, if capabilities.is_fixed_counter_supported(fixed) {ErrorMsg::CounterInUse} else {ErrorMsg::UnsupportedFixPMC}));
+            }
+            free_fixed &= !(1 << fixed);
+            assignment[i] = Counter::Fixed(fixed);
+            on_fixed[i] = true;
+        }
+    }
+    for (i, event) in events.iter().enumerate() {
+        if on_fixed[i] {
+            continue;
+        }
+        if let Some(fixed) = architectural_fixed_counter(event) {
+            if free_fixed & (1 << fixed) != 0 {
+                free_fixed &= !(1 << fixed);
+                assignment[i] = Counter::Fixed(fixed);
+                on_fixed[i] = true;
+            }
+        }
+    }
+
+    //Remaining events go to programmable counters.
+    let mut allowed = [0u8; MAX_GENERAL_PMC + MAX_FIXED_PMC];
+    let mut programmable = 0;
+    for (i, event) in events.iter().enumerate() {
+        if on_fixed[i] {
+            continue;
+        }
+        if let Counter::Programmable(mask) = programmable_counters(event, general) {
+            allowed[i] = mask & free_general;
+        }
+        programmable += 1;
+    }
+    for (i, event) in events.iter().enumerate() {
+        if !on_fixed[i] && event.taken_alone && programmable > 1 {
+            return Err(fail(i, ErrorMsg::CounterInUse));
+        }
+    }
+    let mut owner:[Option<usize>; MAX_GENERAL_PMC] = [None; MAX_GENERAL_PMC];
+    for i in 0..events.len() {
+        if on_fixed[i] {
+            continue;
+        }
+        let mut visited = 0u8;
+        if !augment(i, &allowed, &mut owner, &mut visited) {
+            return Err(fail(i, if allowed[i] == 0 {ErrorMsg::CounterOutOfRange} else {ErrorMsg::CounterInUse}));
+        }
+    }
+    for (pmc, e) in owner.iter().enumerate() {
+        if let Some(e) = e {
+            assignment[*e] = Counter::Programmable(pmc as u8);
+        }
+    }
+    Ok(())
+}
+
+///Build `counter` for `event` on the counter schedule() assigned to it.
+pub fn build_assigned<B: PmuBackend>(counter:&mut PerfCounter<B>, event:&EventDescription, assigned:Counter)->Result<(),ErrorMsg>{
+    match assigned {
+        Counter::Fixed(index) => counter.build_fixed(index, event.any_thread),
+        Counter::Programmable(index) => counter.build_from_intel_hw_event(event, index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::lookup::{CpuModel, EventTable};
+    use crate::x86_intel::capabilities::CpuSignature;
+    use crate::x86_intel::simulator::SimulatedPmu;
+
+    fn skylake() -> EventTable {
+        EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap()
+    }
+
+    fn controler() -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        ctrler
+    }
+
+    fn schedule_names(ctrler:&PerfCounterControler<SimulatedPmu>, table:&EventTable, names:&[&str], assignment:&mut [Counter]) -> Result<(), UnschedulableEvent<'static>> {
+        let mut events = [table.lookup(names[0]).unwrap(); 8];
+        for (event, name) in events.iter_mut().zip(names.iter()) {
+            *event = table.lookup(name).unwrap();
+        }
+        schedule(ctrler, &events[..names.len()], assignment)
+    }
+
+    #[test]
+    fn architectural_events_prefer_free_fixed_counters() {
+        let ctrler = controler();
+        let table = skylake();
+        let names = ["INST_RETIRED.ANY_P", "CPU_CLK_UNHALTED.THREAD_P", "CPU_CLK_UNHALTED.REF_TSC"];
+        let mut assignment = [Counter::Programmable(0); 3];
+        schedule_names(&ctrler, &table, &names, &mut assignment).unwrap();
+        assert_eq!(assignment, [Counter::Fixed(0), Counter::Fixed(1), Counter::Fixed(2)]);
+        //With fixed counter 0 taken, instructions are counted on a PMC instead.
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_fixed(0, false).unwrap();
+        schedule_names(&ctrler, &table, &names, &mut assignment).unwrap();
+        assert_eq!(assignment, [Counter::Programmable(0), Counter::Fixed(1), Counter::Fixed(2)]);
+    }
+
+    #[test]
+    fn fixed_only_events_need_their_counter() {
+        let ctrler = controler();
+        let table = skylake();
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_fixed(1, false).unwrap();
+        let mut assignment = [Counter::Programmable(0); 2];
+        let err = schedule_names(&ctrler, &table, &["INST_RETIRED.ANY", "CPU_CLK_UNHALTED.THREAD"], &mut assignment).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(err.event_name, "CPU_CLK_UNHALTED.THREAD");
+        assert!(matches!(err.reason, ErrorMsg::CounterInUse));
+        drop(other);
+        schedule_names(&ctrler, &table, &["INST_RETIRED.ANY", "CPU_CLK_UNHALTED.THREAD"], &mut assignment).unwrap();
+        assert_eq!(assignment, [Counter::Fixed(0), Counter::Fixed(1)]);
+    }
+
+    #[test]
+    fn taken_alone_events_get_the_pmcs_to_themselves() {
+        let ctrler = controler();
+        let table = skylake();
+        let mut assignment = [Counter::Programmable(0); 2];
+        let err = schedule_names(&ctrler, &table, &["BR_MISP_RETIRED.ALL_BRANCHES", "FRONTEND_RETIRED.DSB_MISS"], &mut assignment).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(err.event_name, "FRONTEND_RETIRED.DSB_MISS");
+        assert!(matches!(err.reason, ErrorMsg::CounterInUse));
+        //Events on fixed counters do not share its PMCs.
+        schedule_names(&ctrler, &table, &["INST_RETIRED.ANY", "FRONTEND_RETIRED.DSB_MISS"], &mut assignment).unwrap();
+        assert_eq!(assignment, [Counter::Fixed(0), Counter::Programmable(0)]);
+    }
+
+    #[test]
+    fn ref_xclk_stays_on_a_pmc() {
+        let ctrler = controler();
+        let table = skylake();
+        let mut assignment = [Counter::Programmable(0); 1];
+        schedule_names(&ctrler, &table, &["CPU_CLK_UNHALTED.REF_XCLK"], &mut assignment).unwrap();
+        assert_eq!(assignment, [Counter::Programmable(0)]);
+    }
+
+    #[test]
+    fn matching_moves_earlier_events() {
+        let ctrler = controler();
+        let table = skylake();
+        //Leave PMC0 and PMC1.
+        let mut pmc2 = PerfCounter::new(&ctrler);
+        pmc2.build_general_from_raw(0xC4, 0x00, true, true, 0, false, 2).unwrap();
+        let mut pmc3 = PerfCounter::new(&ctrler);
+        pmc3.build_general_from_raw(0xC4, 0x00, true, true, 0, false, 3).unwrap();
+        //BR_MISP_RETIRED.ALL_BRANCHES takes PMC0 first and moves to PMC1 for
+        //INST_RETIRED.TOTAL_CYCLES_PS, which may only use PMC0, 2 and 3.
+        let mut assignment = [Counter::Programmable(0); 3];
+        schedule_names(&ctrler, &table, &["BR_MISP_RETIRED.ALL_BRANCHES", "INST_RETIRED.TOTAL_CYCLES_PS"], &mut assignment).unwrap();
+        assert_eq!(assignment[..2], [Counter::Programmable(1), Counter::Programmable(0)]);
+        //INST_RETIRED.PREC_DIST needs PMC1 as well: no assignment exists.
+        let err = schedule_names(&ctrler, &table, &["BR_MISP_RETIRED.ALL_BRANCHES", "INST_RETIRED.TOTAL_CYCLES_PS", "INST_RETIRED.PREC_DIST"], &mut assignment).unwrap_err();
+        assert_eq!(err.index, 2);
+        assert!(matches!(err.reason, ErrorMsg::CounterInUse));
+        drop(pmc3);
+        schedule_names(&ctrler, &table, &["BR_MISP_RETIRED.ALL_BRANCHES", "INST_RETIRED.TOTAL_CYCLES_PS", "INST_RETIRED.PREC_DIST"], &mut assignment).unwrap();
+        assert_eq!(assignment, [Counter::Programmable(0), Counter::Programmable(3), Counter::Programmable(1)]);
+    }
+
+    #[test]
+    fn uses_the_ht_off_mask_with_eight_counters() {
+        let table = skylake();
+        let names = ["IDQ_UOPS_NOT_DELIVERED.CORE", "UOPS_ISSUED.ANY", "UOPS_RETIRED.RETIRE_SLOTS", "BR_MISP_RETIRED.ALL_BRANCHES", "MACHINE_CLEARS.COUNT"];
+        let mut events = [table.lookup(names[0]).unwrap(); 5];
+        for (event, name) in events.iter_mut().zip(names.iter()) {
+            *event = table.lookup(name).unwrap();
+        }
+        assert!(events.iter().all(|e| e.counter == Counter::Programmable(0xF)));
+        let mut assignment = [Counter::Programmable(0); 5];
+        let mut ht_on = PerfCounterControler::with_backend(SimulatedPmu::new(3 | 4<<8 | 48<<16, 0, 0, 3 | 48<<5));
+        ht_on.init();
+        assert!(matches!(schedule(&ht_on, &events, &mut assignment), Err(UnschedulableEvent{reason: ErrorMsg::CounterInUse, ..})));
+        let mut ht_off = PerfCounterControler::with_backend(SimulatedPmu::new(3 | 8<<8 | 48<<16, 0, 0, 3 | 48<<5));
+        ht_off.init();
+        schedule(&ht_off, &events, &mut assignment).unwrap();
+        let mut used = 0u8;
+        for counter in assignment.iter() {
+            match counter {
+                Counter::Programmable(index) => used |= 1 << index,
+                Counter::Fixed(_) => panic!("no architectural event in the set"),
+            }
+        }
+        assert_eq!(used.count_ones(), 5);
+    }
+}
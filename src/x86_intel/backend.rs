@@ -0,0 +1,131 @@
+//! Hardware access backends for PerfCounter and PerfCounterControler.
+//!
+//! Every MSR, CPUID, RDPMC and local APIC access done by this crate goes through a PmuBackend.
+//! NativePmu executes the real instructions and is the default backend.
+//! Other backends can be plugged in to run the counter logic outside ring 0.
+//!
+
+use x86::{cpuid::{native_cpuid, CpuIdResult}, msr::{rdmsr, wrmsr}, time::rdtsc};
+
+///Physical address of the LVT Performance Monitoring Counters register in the local APIC.
+pub const APIC_LVT_PERFMON: u64 = 0xFEE00340;
+
+pub trait PmuBackend {
+    ///Read a model specific register.
+    fn rdmsr(&self, msr:u32) -> u64;
+
+    ///Write a model specific register.
+    fn wrmsr(&self, msr:u32, value:u64);
+
+    ///Execute CPUID with EAX=leaf and ECX=subleaf.
+    fn cpuid(&self, leaf:u32, subleaf:u32) -> CpuIdResult;
+
+    ///Execute RDPMC with ECX=counter and return EDX:EAX.
+    /// Bit 30 of counter selects the fixed function counters.
+    fn rdpmc(&self, counter:u32) -> u64;
+
+    ///Read the time stamp counter.
+    fn rdtsc(&self) -> u64;
+
+    ///Read the LVT Performance Monitoring Counters register of the local APIC.
+    fn read_lvt_perfmon(&self) -> u32;
+
+    ///Write the LVT Performance Monitoring Counters register of the local APIC.
+    fn write_lvt_perfmon(&self, value:u32);
+}
+
+impl<B: PmuBackend + ?Sized> PmuBackend for &B {
+    fn rdmsr(&self, msr:u32) -> u64 {
+        (**self).rdmsr(msr)
+    }
+    fn wrmsr(&self, msr:u32, value:u64) {
+        (**self).wrmsr(msr, value)
+    }
+    fn cpuid(&self, leaf:u32, subleaf:u32) -> CpuIdResult {
+        (**self).cpuid(leaf, subleaf)
+    }
+    fn rdpmc(&self, counter:u32) -> u64 {
+        (**self).rdpmc(counter)
+    }
+    fn rdtsc(&self) -> u64 {
+        (**self).rdtsc()
+    }
+    fn read_lvt_perfmon(&self) -> u32 {
+        (**self).read_lvt_perfmon()
+    }
+    fn write_lvt_perfmon(&self, value:u32) {
+        (**self).write_lvt_perfmon(value)
+    }
+}
+
+///Backend executing the real instructions.
+///
+///Must run in ring 0 with the local APIC identity mapped at its default base.
+#[derive(Debug, Clone, Copy)]
+pub struct NativePmu {
+    _private: (),
+}
+
+impl NativePmu {
+    /// # Safety
+    ///
+    /// The caller must run in ring 0 with the local APIC identity mapped at APIC_LVT_PERFMON's page.
+    /// Any code holding the NativePmu, or a controler built on it, can read and write every MSR,
+    /// so it must not be handed to code that may not.
+    pub const unsafe fn new() -> NativePmu {
+        NativePmu{_private: ()}
+    }
+}
+
+impl PmuBackend for NativePmu {
+    fn rdmsr(&self, msr:u32) -> u64 {
+        unsafe{ rdmsr(msr) }
+    }
+
+    fn wrmsr(&self, msr:u32, value:u64) {
+        unsafe{ wrmsr(msr, value) }
+    }
+
+    fn cpuid(&self, leaf:u32, subleaf:u32) -> CpuIdResult {
+        native_cpuid::cpuid_count(leaf, subleaf)
+    }
+
+    fn rdpmc(&self, counter:u32) -> u64 {
+        let rcx:u64 = counter as u64;
+        let rax:u64;
+        let rdx:u64;
+        unsafe{
+            asm!(
+                "rdpmc",
+                in("rcx") rcx,
+                out("rax") rax,
+                out("rdx") rdx,
+            );
+        }
+        (rax<<32>>32) | rdx<<32
+    }
+
+    fn rdtsc(&self) -> u64 {
+        unsafe{ rdtsc() }
+    }
+
+    fn read_lvt_perfmon(&self) -> u32 {
+        let eax:u32;
+        unsafe{
+            asm!("MOV eax, [edx]",
+            in("rdx") APIC_LVT_PERFMON,
+            out("eax") eax,
+            );
+        }
+        eax
+    }
+
+    fn write_lvt_perfmon(&self, value:u32) {
+        unsafe{
+            asm!("MOV [edx],eax",
+            in("rdx") APIC_LVT_PERFMON,
+            in("eax") value,
+            );
+        }
+    }
+}
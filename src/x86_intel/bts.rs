@@ -0,0 +1,319 @@
+//! Branch Trace Store (BTS).
+//!
+//! With IA32_DEBUGCTL.TR and BTS set the processor writes a from/to record for every taken branch
+//! to the BTS buffer of the Debug Store. Without BTINT the buffer is circular and the oldest
+//! records are overwritten. The buffer is zeroed whenever its index is reset, so a record at the
+//! index tells records() that it wrapped. With BTINT tracing pauses at the interrupt threshold and a PMI is
+//! delivered through the LVT performance monitoring entry, i.e. on the vector
+//! register_overflow_interrput() installed. BTS does not set a bit in IA32_PERF_GLOBAL_STATUS, so
+//! handle_interrupt() recognizes its PMIs by the BTS index.
+//!
+//! BTS slows the traced code down considerably; it is meant for bring-up on parts without Intel PT.
+//!
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler};
+use super::debug_store::{self, DebugStore, IA32_MISC_ENABLE, MISC_ENABLE_BTS_UNAVAILABLE};
+use super::lbr::{IA32_DEBUGCTL, DEBUGCTL_TR, DEBUGCTL_BTS, DEBUGCTL_BTINT, DEBUGCTL_BTS_OFF_OS, DEBUGCTL_BTS_OFF_USR};
+
+///Size of a 64-bit BTS record.
+pub const BTS_RECORD_LEN: usize = 24;
+///Records kept free above the interrupt threshold.
+///
+/// The processor keeps writing records until the PMI is taken, and the branches on the way to the
+/// handler are traced too; with the threshold at the end of the buffer they would be lost.
+pub const BTS_THRESHOLD_HEADROOM: usize = 8;
+
+const BTS_FLAG_PREDICTED: u64 = 1<<4;
+const DEBUGCTL_BTS_MASK: u64 = DEBUGCTL_TR | DEBUGCTL_BTS | DEBUGCTL_BTINT | DEBUGCTL_BTS_OFF_OS | DEBUGCTL_BTS_OFF_USR;
+
+///One taken branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtsRecord {
+    pub from: u64,
+    pub to: u64,
+    ///The branch was predicted (bit 4 of the flags, not reported by every processor).
+    pub predicted: bool,
+}
+
+impl BtsRecord {
+    ///Decode a record from the first BTS_RECORD_LEN bytes of `bytes`.
+    pub fn decode(bytes:&[u8]) -> Option<BtsRecord> {
+        if bytes.len() < BTS_RECORD_LEN {
+            return None;
+        }
+        let u64_at = |at:usize| {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_le_bytes(b)
+        };
+        Some(BtsRecord{
+            from: u64_at(0),
+            to: u64_at(8),
+            predicted: u64_at(16) & BTS_FLAG_PREDICTED != 0,
+        })
+    }
+}
+
+///Branches to record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtsConfig {
+    ///Record branches in ring 0.
+    pub kernel: bool,
+    ///Record branches in ring 1 to 3.
+    pub user: bool,
+    ///Raise a PMI at the threshold instead of wrapping around.
+    pub interrupt: bool,
+}
+
+///BTS on one controler's processor, writing to a caller provided buffer.
+///
+/// The BTS half of the processor's DebugStore is claimed for the lifetime of the Bts; a Pebs can
+/// use the other half at the same time.
+pub struct Bts<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    ds: &'a DebugStore<'a, B>,
+    //Written by the processor, so only read through debug_store::read_buffer().
+    buffer: *mut u8,
+    len: usize,
+    _buffer: PhantomData<&'a mut [u8]>,
+    threshold: usize,
+    //Enabled without BtsConfig::interrupt, so the index wraps around to the base.
+    circular: Cell<bool>,
+}
+
+impl<'a, B: PmuBackend> Bts<'a, B> {
+    ///Check BTS is available, claim the BTS half of `ds` and lay it out for `buffer`.
+    ///
+    /// The threshold defaults to BTS_THRESHOLD_HEADROOM records below a full buffer; see set_threshold().
+    pub fn new(ds:&'a DebugStore<'a, B>, buffer:&'a mut [u8]) -> Result<Bts<'a, B>, ErrorMsg> {
+        let ctrler = ds.controler();
+        if ctrler.backend().rdmsr(IA32_MISC_ENABLE) & MISC_ENABLE_BTS_UNAVAILABLE != 0 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        ds.claim_bts()?;
+        let mut bts = Bts{
+            ctrler: ctrler,
+            ds: ds,
+            buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            _buffer: PhantomData,
+            threshold: usize::MAX,
+            circular: Cell::new(false),
+        };
+        bts.layout()?;
+        Ok(bts)
+    }
+
+    ///Records the buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.len / BTS_RECORD_LEN
+    }
+
+    ///With BtsConfig::interrupt, raise the PMI once `records` records are in the buffer.
+    /// The threshold is capped at BTS_THRESHOLD_HEADROOM records below the capacity.
+    pub fn set_threshold(&mut self, records:usize) -> Result<(), ErrorMsg> {
+        self.threshold = records.max(1);
+        self.layout()
+    }
+
+    fn layout(&mut self) -> Result<(), ErrorMsg> {
+        let records = self.capacity();
+        if records <= BTS_THRESHOLD_HEADROOM {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        let base = self.buffer as u64;
+        let threshold = self.threshold.min(records - BTS_THRESHOLD_HEADROOM);
+        unsafe { debug_store::clear_buffer(self.buffer, self.len); }
+        self.ds.set_bts_buffer(base, base + (records * BTS_RECORD_LEN) as u64, base + (threshold * BTS_RECORD_LEN) as u64);
+        Ok(())
+    }
+
+    ///Reset the buffer and point IA32_DS_AREA at the DebugStore, if it does not already.
+    pub fn arm(&mut self) -> Result<(), ErrorMsg> {
+        self.layout()?;
+        self.ds.install();
+        Ok(())
+    }
+
+    ///Start recording branches.
+    ///
+    /// Fails with UnsupportedFeature until arm() installed the DebugStore, since the processor
+    /// would write the records through whatever IA32_DS_AREA points at.
+    pub fn enable(&self, config:&BtsConfig) -> Result<(), ErrorMsg> {
+        if !self.ds.is_installed() {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        let mut debugctl = DEBUGCTL_TR | DEBUGCTL_BTS;
+        if config.interrupt {
+            debugctl |= DEBUGCTL_BTINT;
+        }
+        if !config.kernel {
+            debugctl |= DEBUGCTL_BTS_OFF_OS;
+        }
+        if !config.user {
+            debugctl |= DEBUGCTL_BTS_OFF_USR;
+        }
+        self.circular.set(!config.interrupt);
+        let backend = self.ctrler.backend();
+        backend.wrmsr(IA32_DEBUGCTL, (backend.rdmsr(IA32_DEBUGCTL) & !DEBUGCTL_BTS_MASK) | debugctl);
+        Ok(())
+    }
+
+    pub fn disable(&self) {
+        let backend = self.ctrler.backend();
+        backend.wrmsr(IA32_DEBUGCTL, backend.rdmsr(IA32_DEBUGCTL) & !DEBUGCTL_BTS_MASK);
+    }
+
+    ///Bytes of records between the start of the buffer and the BTS index.
+    /// In circular mode the buffer may have wrapped, see records().
+    pub fn pending_bytes(&self) -> usize {
+        (self.ds.bts_index().saturating_sub(self.buffer as u64) as usize).min(self.len)
+    }
+
+    ///Records written since the last drain, oldest first.
+    ///
+    /// Once a circular buffer wrapped, these are the capacity() most recent records, starting at the index.
+    pub fn records(&self) -> impl Iterator<Item = BtsRecord> + '_ {
+        let records = self.capacity();
+        let index = self.pending_bytes() / BTS_RECORD_LEN;
+        let wrapped = self.circular.get() && index < records && self.record_at(index).is_some_and(|r| r.from != 0 || r.to != 0);
+        let (start, count) = if wrapped {(index, records)} else {(0, index)};
+        (0..count).filter_map(move |i| self.record_at((start + i) % records))
+    }
+
+    fn record_at(&self, slot:usize) -> Option<BtsRecord> {
+        let mut bytes = [0u8; BTS_RECORD_LEN];
+        unsafe { debug_store::read_buffer(self.buffer.add(slot * BTS_RECORD_LEN), &mut bytes); }
+        BtsRecord::decode(&bytes)
+    }
+
+    ///Pass the records written since the last drain to `f` and empty the buffer.
+    /// Returns the number of records.
+    pub fn drain(&mut self, mut f:impl FnMut(&BtsRecord)) -> usize {
+        let mut count = 0;
+        for record in self.records() {
+            f(&record);
+            count += 1;
+        }
+        unsafe { debug_store::clear_buffer(self.buffer, self.len); }
+        self.ds.set_bts_index(self.buffer as u64);
+        count
+    }
+
+    ///PMI handler entry point for the BTS buffer threshold interrupt.
+    ///
+    /// If the index reached the threshold, drains the buffer into `f` and unmasks the LVT entry,
+    /// which lets tracing resume. Returns the number of records, 0 if the PMI was not raised by BTS.
+    pub fn handle_interrupt(&mut self, f:impl FnMut(&BtsRecord)) -> usize {
+        if self.ds.bts_index() < self.ds.bts_interrupt_threshold() {
+            return 0;
+        }
+        let count = self.drain(f);
+        self.ctrler.reset_overflow_interrput();
+        count
+    }
+}
+
+impl<'a, B: PmuBackend> Drop for Bts<'a, B> {
+    fn drop(&mut self) {
+        //The processor must not write to the buffer once it is given back.
+        self.disable();
+        self.ds.release_bts();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::debug_store::DebugStoreArea;
+    use crate::x86_intel::simulator::SimulatedPmu;
+    use x86::cpuid::CpuIdResult;
+
+    #[test]
+    fn threshold_leaves_headroom() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax:0, ebx:0, ecx:0, edx:1<<21}).unwrap();
+        let ctrler = PerfCounterControler::with_backend(pmu);
+        let mut area = DebugStoreArea::new();
+        let ds = DebugStore::new(&ctrler, &mut area).unwrap();
+        let mut small = [0u8; BTS_RECORD_LEN * BTS_THRESHOLD_HEADROOM];
+        assert!(matches!(Bts::new(&ds, &mut small), Err(ErrorMsg::BufferTooSmall)));
+        let mut buffer = [0u8; BTS_RECORD_LEN * 32];
+        let base = buffer.as_ptr() as u64;
+        let mut bts = Bts::new(&ds, &mut buffer).unwrap();
+        let max = base + (32 * BTS_RECORD_LEN) as u64;
+        assert_eq!(ds.bts_interrupt_threshold(), max - (BTS_THRESHOLD_HEADROOM * BTS_RECORD_LEN) as u64);
+        bts.set_threshold(4).unwrap();
+        assert_eq!(ds.bts_interrupt_threshold(), base + (4 * BTS_RECORD_LEN) as u64);
+        branch(&ds, base, 32, 0x1234, 0x5678, BTS_FLAG_PREDICTED);
+        let mut records = bts.records();
+        assert_eq!(records.next(), Some(BtsRecord{from: 0x1234, to: 0x5678, predicted: true}));
+        assert_eq!(records.next(), None);
+    }
+
+    #[test]
+    fn enable_needs_an_armed_bts() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax:0, ebx:0, ecx:0, edx:1<<21}).unwrap();
+        let ctrler = PerfCounterControler::with_backend(pmu);
+        let mut area = DebugStoreArea::new();
+        let ds = DebugStore::new(&ctrler, &mut area).unwrap();
+        let mut buffer = [0u8; BTS_RECORD_LEN * 16];
+        let mut bts = Bts::new(&ds, &mut buffer).unwrap();
+        let config = BtsConfig{kernel: false, user: true, interrupt: true};
+        assert!(matches!(bts.enable(&config), Err(ErrorMsg::UnsupportedFeature)));
+        assert_eq!(ctrler.backend().rdmsr(IA32_DEBUGCTL), 0);
+        bts.arm().unwrap();
+        bts.enable(&config).unwrap();
+        assert_eq!(ctrler.backend().rdmsr(IA32_DEBUGCTL), DEBUGCTL_TR | DEBUGCTL_BTS | DEBUGCTL_BTINT | DEBUGCTL_BTS_OFF_OS);
+        drop(bts);
+        assert_eq!(ctrler.backend().rdmsr(IA32_DEBUGCTL), 0);
+    }
+
+    ///Write a record at the BTS index like the processor does, wrapping around after `slots` records.
+    fn branch(ds:&DebugStore<'_, SimulatedPmu>, base:u64, slots:usize, from:u64, to:u64, flags:u64) {
+        let slot = (ds.bts_index() - base) as usize / BTS_RECORD_LEN;
+        let record = (base as *mut u8).wrapping_add(slot * BTS_RECORD_LEN) as *mut u64;
+        unsafe {
+            core::ptr::write_volatile(record, from);
+            core::ptr::write_volatile(record.add(1), to);
+            core::ptr::write_volatile(record.add(2), flags);
+        }
+        ds.set_bts_index(base + (((slot + 1) % slots) * BTS_RECORD_LEN) as u64);
+    }
+
+    #[test]
+    fn records_of_a_wrapped_circular_buffer() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax:0, ebx:0, ecx:0, edx:1<<21}).unwrap();
+        let ctrler = PerfCounterControler::with_backend(pmu);
+        let mut area = DebugStoreArea::new();
+        let ds = DebugStore::new(&ctrler, &mut area).unwrap();
+        let mut buffer = [0xFFu8; BTS_RECORD_LEN * 16];
+        let base = buffer.as_ptr() as u64;
+        let mut bts = Bts::new(&ds, &mut buffer).unwrap();
+        bts.arm().unwrap();
+        bts.enable(&BtsConfig{kernel: true, user: true, interrupt: false}).unwrap();
+        assert_eq!(bts.records().count(), 0);
+        for n in 0..5 {
+            branch(&ds, base, 16, 0x1000 + n, 0x2000 + n, 0);
+        }
+        assert!(bts.records().map(|r| r.from).eq(0x1000..0x1005));
+        for n in 5..21 {
+            branch(&ds, base, 16, 0x1000 + n, 0x2000 + n, 0);
+        }
+        //The 16 most recent branches, from the index on.
+        assert_eq!(bts.pending_bytes(), 5 * BTS_RECORD_LEN);
+        assert!(bts.records().map(|r| r.from).eq(0x1005..0x1015));
+        let mut last = 0;
+        assert_eq!(bts.drain(|r| last = r.to), 16);
+        assert_eq!(last, 0x2014);
+        //The drained records are gone, the index starts over at the base.
+        assert_eq!(bts.records().count(), 0);
+        branch(&ds, base, 16, 0x1015, 0x2015, 0);
+        assert!(bts.records().map(|r| r.from).eq(0x1015..0x1016));
+    }
+}
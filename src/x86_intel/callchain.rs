@@ -0,0 +1,391 @@
+//! Call chains of sampled contexts, walked through the frame pointers.
+//!
+//! walk_frame_pointers() follows the RBP chain of the interrupted context: every frame holds the
+//! caller's RBP at [RBP] and the return address at [RBP+8]. Since it runs in PMI context, no address
+//! is read unless it lies in the configured stack range, frames must move strictly up the stack and
+//! the walk stops after max_depth entries, so a corrupt RBP ends the chain instead of faulting.
+//!
+//! FoldedStacks counts identical call chains and prints them in the folded format of flamegraph.pl:
+//! one line per chain, frames from the root to the leaf separated by ';', then the count.
+//!
+
+use core::fmt;
+
+///Most entries of a Callchain, the sampled IP included.
+pub const MAX_CALLCHAIN_DEPTH: usize = 32;
+
+///Half-open range of virtual addresses [start, end).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AddrRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl AddrRange {
+    pub fn new(start:u64, end:u64) -> AddrRange {
+        AddrRange{start: start, end: end}
+    }
+
+    pub fn contains(&self, addr:u64) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+///Limits of a frame pointer walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallchainConfig {
+    ///Entries to record, the sampled IP included; at most MAX_CALLCHAIN_DEPTH.
+    pub max_depth: u8,
+    ///Memory the frames may be read from. It must be mapped and readable in PMI context.
+    pub stack: AddrRange,
+    ///If set, the walk stops at the first return address outside this range.
+    pub text: Option<AddrRange>,
+}
+
+///IP of a sample followed by the return addresses of its callers, leaf first.
+#[derive(Clone, Copy)]
+pub struct Callchain {
+    ips: [u64; MAX_CALLCHAIN_DEPTH],
+    len: u8,
+}
+
+impl Callchain {
+    pub const fn new() -> Callchain {
+        Callchain{ips: [0; MAX_CALLCHAIN_DEPTH], len: 0}
+    }
+
+    ///Append an entry. Returns false if the chain is full.
+    pub fn push(&mut self, ip:u64) -> bool {
+        if self.len as usize == MAX_CALLCHAIN_DEPTH {
+            return false;
+        }
+        self.ips[self.len as usize] = ip;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[u64] {
+        &self.ips[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Callchain {
+    fn default() -> Callchain {
+        Callchain::new()
+    }
+}
+
+impl PartialEq for Callchain {
+    fn eq(&self, other:&Callchain) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Callchain {}
+
+impl fmt::Debug for Callchain {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.as_slice().iter().map(|ip| Hex(*ip))).finish()
+    }
+}
+
+struct Hex(u64);
+
+impl fmt::Debug for Hex {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+///Walk the frame pointer chain of a context interrupted at `rip` with `rbp` and `rsp`.
+///
+/// # Safety
+/// Every address in `config.stack` must be mapped and readable.
+pub unsafe fn walk_frame_pointers(rip:u64, rbp:u64, rsp:u64, config:&CallchainConfig) -> Callchain {
+    let mut chain = Callchain::new();
+    let max_depth = (config.max_depth as usize).min(MAX_CALLCHAIN_DEPTH);
+    if max_depth == 0 {
+        return chain;
+    }
+    chain.push(rip);
+    let mut low = rsp.max(config.stack.start);
+    let mut fp = rbp;
+    while chain.len() < max_depth {
+        let in_stack = match fp.checked_add(16) {
+            Some(end) => fp & 7 == 0 && fp >= low && end <= config.stack.end,
+            None => false,
+        };
+        if !in_stack {
+            break;
+        }
+        let next = core::ptr::read_volatile(fp as *const u64);
+        let ret = core::ptr::read_volatile((fp + 8) as *const u64);
+        if ret == 0 || config.text.is_some_and(|text| !text.contains(ret)) {
+            break;
+        }
+        chain.push(ret);
+        if next <= fp {
+            break;
+        }
+        low = fp + 16;
+        fp = next;
+    }
+    chain
+}
+
+///Counts of distinct call chains, printed in the folded stack format.
+///
+/// Chains are looked up linearly; once N distinct chains are stored new ones are dropped.
+pub struct FoldedStacks<const N: usize> {
+    stacks: [(Callchain, u64); N],
+    len: usize,
+    dropped: u64,
+}
+
+impl<const N: usize> FoldedStacks<N> {
+    pub fn new() -> FoldedStacks<N> {
+        FoldedStacks{
+            stacks: [(Callchain::new(), 0); N],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    ///Count one occurrence of `chain`. Empty chains are ignored.
+    pub fn add(&mut self, chain:&Callchain) -> bool {
+        if chain.is_empty() {
+            return false;
+        }
+        if let Some(entry) = self.stacks[..self.len].iter_mut().find(|(c, _)| c == chain) {
+            entry.1 += 1;
+            return true;
+        }
+        if self.len == N {
+            self.dropped += 1;
+            return false;
+        }
+        self.stacks[self.len] = (*chain, 1);
+        self.len += 1;
+        true
+    }
+
+    ///Distinct chains stored.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    ///Occurrences of chains that did not fit.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Callchain, u64)> {
+        self.stacks[..self.len].iter().map(|(c, n)| (c, *n))
+    }
+
+    ///Print every chain with frames as hexadecimal addresses.
+    pub fn write(&self, out:&mut dyn fmt::Write) -> fmt::Result {
+        self.write_symbolized(out, &mut |out, ip| write!(out, "{:#x}", ip))
+    }
+
+    ///Print every chain, with `symbolize` printing the name of each frame.
+    pub fn write_symbolized(&self, out:&mut dyn fmt::Write, symbolize:&mut dyn FnMut(&mut dyn fmt::Write, u64) -> fmt::Result) -> fmt::Result {
+        for (chain, count) in self.iter() {
+            for (i, ip) in chain.as_slice().iter().rev().enumerate() {
+                if i != 0 {
+                    out.write_char(';')?;
+                }
+                symbolize(out, *ip)?;
+            }
+            writeln!(out, " {}", count)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for FoldedStacks<N> {
+    fn default() -> FoldedStacks<N> {
+        FoldedStacks::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIP: u64 = 0x40_0010;
+
+    ///Stack of 8-byte slots with frames [caller RBP, return address] at the given slots.
+    struct FakeStack {
+        slots: [u64; 80],
+    }
+
+    impl FakeStack {
+        fn new() -> FakeStack {
+            FakeStack{slots: [0; 80]}
+        }
+
+        fn addr(&self, slot:usize) -> u64 {
+            self.slots.as_ptr() as u64 + 8 * slot as u64
+        }
+
+        ///Frame at `slot` returning to `ret`, whose caller's frame is at `next`.
+        fn frame(&mut self, slot:usize, next:usize, ret:u64) {
+            self.slots[slot] = self.addr(next);
+            self.slots[slot + 1] = ret;
+        }
+
+        fn config(&self, max_depth:u8) -> CallchainConfig {
+            CallchainConfig{max_depth: max_depth, stack: AddrRange::new(self.addr(0), self.addr(80)), text: None}
+        }
+
+        fn walk(&self, rbp:u64, rsp_slot:usize, config:&CallchainConfig) -> Callchain {
+            unsafe { walk_frame_pointers(RIP, rbp, self.addr(rsp_slot), config) }
+        }
+    }
+
+    #[test]
+    fn walks_up_to_the_outermost_frame() {
+        let mut stack = FakeStack::new();
+        stack.frame(2, 6, 0x40_1000);
+        stack.frame(6, 10, 0x40_2000);
+        //The outermost frame has a null RBP.
+        stack.slots[10] = 0;
+        stack.slots[11] = 0x40_3000;
+        let chain = stack.walk(stack.addr(2), 0, &stack.config(32));
+        assert_eq!(chain.as_slice(), [RIP, 0x40_1000, 0x40_2000, 0x40_3000]);
+    }
+
+    #[test]
+    fn bad_frame_pointers_end_the_chain() {
+        let mut stack = FakeStack::new();
+        stack.frame(2, 6, 0x40_1000);
+        stack.frame(6, 10, 0x40_2000);
+        let config = stack.config(32);
+        //Outside the stack, or with [RBP+8] past its end.
+        assert_eq!(stack.walk(stack.addr(80), 0, &config).as_slice(), [RIP]);
+        assert_eq!(stack.walk(stack.addr(79), 0, &config).as_slice(), [RIP]);
+        assert_eq!(stack.walk(stack.addr(0) - 16, 0, &config).as_slice(), [RIP]);
+        assert_eq!(stack.walk(u64::MAX - 7, 0, &config).as_slice(), [RIP]);
+        //Below the interrupted RSP.
+        assert_eq!(stack.walk(stack.addr(2), 4, &config).as_slice(), [RIP]);
+        //Misaligned.
+        assert_eq!(stack.walk(stack.addr(2) + 4, 0, &config).as_slice(), [RIP]);
+        stack.slots[6] = stack.addr(10) + 4;
+        assert_eq!(stack.walk(stack.addr(2), 0, &config).as_slice(), [RIP, 0x40_1000, 0x40_2000]);
+    }
+
+    #[test]
+    fn frame_pointers_must_move_up_the_stack() {
+        let mut stack = FakeStack::new();
+        let config = stack.config(32);
+        //A frame pointing to itself.
+        stack.frame(2, 2, 0x40_1000);
+        assert_eq!(stack.walk(stack.addr(2), 0, &config).as_slice(), [RIP, 0x40_1000]);
+        //A loop through two frames.
+        stack.frame(2, 6, 0x40_1000);
+        stack.frame(6, 2, 0x40_2000);
+        assert_eq!(stack.walk(stack.addr(2), 0, &config).as_slice(), [RIP, 0x40_1000, 0x40_2000]);
+        //A next frame overlapping the current one.
+        stack.frame(6, 7, 0x40_2000);
+        assert_eq!(stack.walk(stack.addr(2), 0, &config).as_slice(), [RIP, 0x40_1000, 0x40_2000]);
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        let mut stack = FakeStack::new();
+        for slot in (0..76).step_by(2) {
+            stack.frame(slot, slot + 2, 0x40_0000 + slot as u64);
+        }
+        assert!(stack.walk(stack.addr(0), 0, &stack.config(0)).is_empty());
+        assert_eq!(stack.walk(stack.addr(0), 0, &stack.config(1)).as_slice(), [RIP]);
+        assert_eq!(stack.walk(stack.addr(0), 0, &stack.config(3)).as_slice(), [RIP, 0x40_0000, 0x40_0002]);
+        let chain = stack.walk(stack.addr(0), 0, &stack.config(u8::MAX));
+        assert_eq!(chain.len(), MAX_CALLCHAIN_DEPTH);
+        assert_eq!(chain.as_slice()[MAX_CALLCHAIN_DEPTH - 1], 0x40_0000 + 2 * (MAX_CALLCHAIN_DEPTH as u64 - 2));
+    }
+
+    #[test]
+    fn return_addresses_must_be_in_the_text() {
+        let mut stack = FakeStack::new();
+        stack.frame(2, 6, 0x40_1000);
+        stack.frame(6, 10, 0x7F00_0000);
+        stack.frame(10, 14, 0x40_3000);
+        let mut config = stack.config(32);
+        config.text = Some(AddrRange::new(0x40_0000, 0x50_0000));
+        assert_eq!(stack.walk(stack.addr(2), 0, &config).as_slice(), [RIP, 0x40_1000]);
+        stack.frame(6, 10, 0);
+        config.text = None;
+        assert_eq!(stack.walk(stack.addr(2), 0, &config).as_slice(), [RIP, 0x40_1000]);
+    }
+
+    struct Text {
+        buf: [u8; 128],
+        len: usize,
+    }
+
+    impl fmt::Write for Text {
+        fn write_str(&mut self, s:&str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.buf.len() {
+                return Err(fmt::Error);
+            }
+            self.buf[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    impl Text {
+        fn new() -> Text {
+            Text{buf: [0; 128], len: 0}
+        }
+
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(&self.buf[..self.len]).unwrap()
+        }
+    }
+
+    fn chain(ips:&[u64]) -> Callchain {
+        let mut chain = Callchain::new();
+        for ip in ips {
+            chain.push(*ip);
+        }
+        chain
+    }
+
+    #[test]
+    fn folded_stacks_count_distinct_chains() {
+        let mut folded = FoldedStacks::<2>::new();
+        assert!(folded.add(&chain(&[0x10, 0x20, 0x30])));
+        assert!(folded.add(&chain(&[0x11, 0x20])));
+        assert!(folded.add(&chain(&[0x10, 0x20, 0x30])));
+        assert!(!folded.add(&Callchain::new()));
+        assert!(!folded.add(&chain(&[0x12])));
+        assert!(!folded.add(&chain(&[0x12])));
+        assert!(folded.add(&chain(&[0x11, 0x20])));
+        assert_eq!((folded.len(), folded.dropped()), (2, 2));
+
+        let mut text = Text::new();
+        folded.write(&mut text).unwrap();
+        assert_eq!(text.as_str(), "0x30;0x20;0x10 2\n0x20;0x11 2\n");
+        let mut text = Text::new();
+        folded.write_symbolized(&mut text, &mut |out, ip| match ip {
+            0x20 => out.write_str("main"),
+            _ => write!(out, "f{:x}", ip),
+        }).unwrap();
+        assert_eq!(text.as_str(), "f30;main;f10 2\nmain;f11 2\n");
+    }
+}
@@ -0,0 +1,313 @@
+//! Typed decoding of CPUID leaf 0AH, IA32_PERF_CAPABILITIES and the processor signature.
+//!
+//! PmuCapabilities::decode() is a pure function of the raw CPUID.0AH registers,
+//! so it can be checked against values dumped from any processor.
+//!
+
+///Architectural performance events enumerated by CPUID.0AH:EBX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchEvent {
+    UnhaltedCoreCycles = 0,
+    InstructionsRetired = 1,
+    UnhaltedReferenceCycles = 2,
+    LlcReference = 3,
+    LlcMisses = 4,
+    BranchInstructionsRetired = 5,
+    BranchMissesRetired = 6,
+    TopdownSlots = 7,
+}
+
+///Fields of IA32_PERF_CAPABILITIES (available when CPUID.01H:ECX.PDCM[15] = 1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfCapabilities {
+    pub raw: u64,
+    ///Bits 5:0. Format of the LBR records.
+    pub lbr_format: u8,
+    ///Bit 6. PEBS records are generated after the instruction retired (trap-like).
+    pub pebs_trap: bool,
+    ///Bit 7. PEBS records contain the architectural registers.
+    pub pebs_save_arch_regs: bool,
+    ///Bits 11:8. PEBS record format.
+    pub pebs_record_format: u8,
+    ///Bit 12. IA32_DEBUGCTL.FREEZE_WHILE_SMM is supported.
+    pub freeze_while_smm: bool,
+    ///Bit 13. IA32_A_PMCx full-width counter writes are supported.
+    pub full_width_write: bool,
+    ///Bit 14. Adaptive PEBS (PEBS baseline) is supported.
+    pub pebs_baseline: bool,
+    ///Bit 15. IA32_PERF_METRICS is available.
+    pub perf_metrics: bool,
+    ///Bit 16. PEBS records can be written to the Intel PT output.
+    pub pebs_output_pt: bool,
+}
+
+impl PerfCapabilities {
+    pub const fn decode(raw:u64) -> PerfCapabilities {
+        PerfCapabilities{
+            raw: raw,
+            lbr_format: (raw & 0x3F) as u8,
+            pebs_trap: (raw>>6) & 1 == 1,
+            pebs_save_arch_regs: (raw>>7) & 1 == 1,
+            pebs_record_format: ((raw>>8) & 0xF) as u8,
+            freeze_while_smm: (raw>>12) & 1 == 1,
+            full_width_write: (raw>>13) & 1 == 1,
+            pebs_baseline: (raw>>14) & 1 == 1,
+            perf_metrics: (raw>>15) & 1 == 1,
+            pebs_output_pt: (raw>>16) & 1 == 1,
+        }
+    }
+}
+
+///Processor signature from CPUID.01H:EAX, with the extended family and model folded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSignature {
+    pub family: u16,
+    pub model: u8,
+    pub stepping: u8,
+}
+
+impl CpuSignature {
+    pub const fn decode(eax:u32) -> CpuSignature {
+        let family = ((eax >> 8) & 0xF) as u16;
+        let mut model = ((eax >> 4) & 0xF) as u8;
+        if family == 0x6 || family == 0xF {
+            model |= (((eax >> 16) & 0xF) as u8) << 4;
+        }
+        CpuSignature{
+            family: if family == 0xF { family + ((eax >> 20) & 0xFF) as u16 } else { family },
+            model: model,
+            stepping: (eax & 0xF) as u8,
+        }
+    }
+}
+
+///Performance monitoring capabilities of one logical processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmuCapabilities {
+    ///EAX[7:0]. Architectural performance monitoring version.
+    pub version: u8,
+    ///EAX[15:8]. Number of general-purpose counters per logical processor.
+    pub general_counters: u8,
+    ///EAX[23:16]. Bit width of the general-purpose counters.
+    pub general_width: u8,
+    ///EAX[31:24]. Number of valid bits in the EBX event vector.
+    pub ebx_length: u8,
+    ///EBX. A set bit means the architectural event is NOT available.
+    pub unavailable_events: u32,
+    ///ECX. Fixed counter support bitmap (version 5 and later).
+    pub fixed_counter_bitmap: u32,
+    ///EDX[4:0]. Number of contiguous fixed-function counters (version 2 and later).
+    pub fixed_counters: u8,
+    ///EDX[12:5]. Bit width of the fixed-function counters (version 2 and later).
+    pub fixed_width: u8,
+    ///EDX[15]. AnyThread is deprecated.
+    pub any_thread_deprecated: bool,
+    ///IA32_PERF_CAPABILITIES, if the processor has it.
+    pub perf_capabilities: Option<PerfCapabilities>,
+}
+
+impl PmuCapabilities {
+    ///Capabilities of a processor without architectural performance monitoring.
+    pub const fn empty() -> PmuCapabilities {
+        PmuCapabilities::decode(0, 0, 0, 0)
+    }
+
+    ///Decode the raw CPUID.0AH registers.
+    ///
+    /// Fields the reported version does not define are left zero.
+    pub const fn decode(eax:u32, ebx:u32, ecx:u32, edx:u32) -> PmuCapabilities {
+        let version = (eax & 0xFF) as u8;
+        let ebx_length = ((eax >> 24) & 0xFF) as u8;
+        let ebx_mask = if ebx_length >= 32 { !0 } else { (1u32 << ebx_length) - 1 };
+        PmuCapabilities{
+            version: version,
+            general_counters: ((eax >> 8) & 0xFF) as u8,
+            general_width: ((eax >> 16) & 0xFF) as u8,
+            ebx_length: ebx_length,
+            unavailable_events: ebx & ebx_mask,
+            fixed_counter_bitmap: if version >= 5 { ecx } else { 0 },
+            fixed_counters: if version >= 2 { (edx & 0x1F) as u8 } else { 0 },
+            fixed_width: if version >= 2 { ((edx >> 5) & 0xFF) as u8 } else { 0 },
+            any_thread_deprecated: version >= 5 && (edx >> 15) & 1 == 1,
+            perf_capabilities: None,
+        }
+    }
+
+    ///Attach the raw value of IA32_PERF_CAPABILITIES.
+    pub const fn with_perf_capabilities(mut self, raw:u64) -> PmuCapabilities {
+        self.perf_capabilities = Some(PerfCapabilities::decode(raw));
+        self
+    }
+
+    ///Check CPUID.0AH:EBX for the architectural event.
+    pub fn is_event_available(&self, event:ArchEvent) -> bool {
+        let bit = event as u8;
+        bit < self.ebx_length && (self.unavailable_events >> bit) & 1 == 0
+    }
+
+    ///Fixed counter `index` is supported if ECX[index] is set or EDX[4:0] > index.
+    pub fn is_fixed_counter_supported(&self, index:u8) -> bool {
+        if self.version < 2 {
+            return false;
+        }
+        (index < 32 && (self.fixed_counter_bitmap >> index) & 1 == 1) || self.fixed_counters > index
+    }
+
+    ///Number of fixed counters that may be addressed, counting the ones only reported in ECX.
+    pub fn fixed_counter_slots(&self) -> u8 {
+        let bitmap_slots = 32 - self.fixed_counter_bitmap.leading_zeros() as u8;
+        if self.version < 2 { 0 } else if bitmap_slots > self.fixed_counters { bitmap_slots } else { self.fixed_counters }
+    }
+
+    ///AnyThread (IA32_PERFEVTSELx[21] and IA32_FIXED_CTR_CTRL AnyThread bits) can be used.
+    pub fn any_thread_supported(&self) -> bool {
+        self.version >= 3 && !self.any_thread_deprecated
+    }
+
+    ///IA32_PERF_GLOBAL_CTRL, IA32_PERF_GLOBAL_STATUS and IA32_PERF_GLOBAL_OVF_CTRL exist.
+    pub fn has_global_ctrl(&self) -> bool {
+        self.version >= 2
+    }
+
+    ///IA32_PERF_GLOBAL_STATUS_RESET/SET and IA32_PERF_GLOBAL_INUSE exist.
+    pub fn has_global_status_set_reset(&self) -> bool {
+        self.version >= 4
+    }
+
+    ///Counters can be written with their full width through IA32_A_PMCx.
+    pub fn full_width_write(&self) -> bool {
+        match self.perf_capabilities {
+            Some(caps) => caps.full_width_write,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_core_duo() {
+        //CPUID.0AH of a Core Duo T2400: two 40 bit counters, no fixed counters.
+        let caps = PmuCapabilities::decode(0x0728_0201, 0, 0, 0);
+        assert_eq!(caps.version, 1);
+        assert_eq!(caps.general_counters, 2);
+        assert_eq!(caps.general_width, 40);
+        assert_eq!(caps.ebx_length, 7);
+        assert_eq!(caps.fixed_counters, 0);
+        assert_eq!(caps.fixed_width, 0);
+        assert!(!caps.is_fixed_counter_supported(0));
+        assert_eq!(caps.fixed_counter_slots(), 0);
+        assert!(!caps.has_global_ctrl());
+        assert!(!caps.any_thread_supported());
+        assert!(caps.is_event_available(ArchEvent::BranchMissesRetired));
+        assert!(!caps.is_event_available(ArchEvent::TopdownSlots));
+    }
+
+    #[test]
+    fn version_1_ignores_edx() {
+        let caps = PmuCapabilities::decode(0x0728_0201, 0, 0xFF, 0x503);
+        assert_eq!(caps.fixed_counters, 0);
+        assert_eq!(caps.fixed_counter_bitmap, 0);
+    }
+
+    #[test]
+    fn version_2_core2() {
+        //CPUID.0AH of a Core 2 Duo E6600.
+        let caps = PmuCapabilities::decode(0x0728_0202, 0, 0, 0x0503);
+        assert_eq!(caps.version, 2);
+        assert_eq!(caps.general_counters, 2);
+        assert_eq!(caps.general_width, 40);
+        assert_eq!(caps.fixed_counters, 3);
+        assert_eq!(caps.fixed_width, 40);
+        assert!(caps.is_fixed_counter_supported(2));
+        assert!(!caps.is_fixed_counter_supported(3));
+        assert!(caps.has_global_ctrl());
+        assert!(!caps.has_global_status_set_reset());
+        assert!(!caps.any_thread_supported());
+        assert!(!caps.full_width_write());
+    }
+
+    #[test]
+    fn unavailable_events() {
+        //LLC references and misses reported as unavailable.
+        let caps = PmuCapabilities::decode(0x0728_0203, 0x18, 0, 0x0503);
+        assert!(caps.is_event_available(ArchEvent::UnhaltedCoreCycles));
+        assert!(!caps.is_event_available(ArchEvent::LlcReference));
+        assert!(!caps.is_event_available(ArchEvent::LlcMisses));
+        assert!(caps.is_event_available(ArchEvent::BranchInstructionsRetired));
+        //Bits beyond EAX[31:24] are not part of the vector.
+        let caps = PmuCapabilities::decode(0x0428_0203, 0xF0, 0, 0x0503);
+        assert_eq!(caps.unavailable_events, 0);
+        assert!(!caps.is_event_available(ArchEvent::BranchInstructionsRetired));
+    }
+
+    #[test]
+    fn version_4_skylake() {
+        //Core i7-6700 with Hyper-Threading: CPUID.0AH and IA32_PERF_CAPABILITIES.
+        let caps = PmuCapabilities::decode(0x0730_0404, 0, 0, 0x0603).with_perf_capabilities(0x33C4);
+        assert_eq!(caps.version, 4);
+        assert_eq!(caps.general_counters, 4);
+        assert_eq!(caps.general_width, 48);
+        assert_eq!(caps.fixed_counters, 3);
+        assert_eq!(caps.fixed_width, 48);
+        assert!(caps.has_global_status_set_reset());
+        assert!(caps.any_thread_supported());
+        assert!(caps.full_width_write());
+        let perf = caps.perf_capabilities.unwrap();
+        assert_eq!(perf.lbr_format, 4);
+        assert!(perf.pebs_trap);
+        assert!(perf.pebs_save_arch_regs);
+        assert_eq!(perf.pebs_record_format, 3);
+        assert!(perf.freeze_while_smm);
+        assert!(perf.full_width_write);
+        assert!(!perf.pebs_baseline);
+        assert!(!perf.perf_metrics);
+        assert!(!perf.pebs_output_pt);
+    }
+
+    #[test]
+    fn version_5_ice_lake() {
+        //Core i7-1065G7: four fixed counters, AnyThread deprecated, adaptive PEBS and PERF_METRICS.
+        let caps = PmuCapabilities::decode(0x0830_0805, 0, 0xF, 0x8604).with_perf_capabilities(0x1_F4C5);
+        assert_eq!(caps.version, 5);
+        assert_eq!(caps.general_counters, 8);
+        assert_eq!(caps.ebx_length, 8);
+        assert!(caps.is_event_available(ArchEvent::TopdownSlots));
+        assert_eq!(caps.fixed_counters, 4);
+        assert_eq!(caps.fixed_width, 48);
+        assert_eq!(caps.fixed_counter_bitmap, 0xF);
+        assert_eq!(caps.fixed_counter_slots(), 4);
+        assert!(caps.any_thread_deprecated);
+        assert!(!caps.any_thread_supported());
+        let perf = caps.perf_capabilities.unwrap();
+        assert_eq!(perf.lbr_format, 5);
+        assert_eq!(perf.pebs_record_format, 4);
+        assert!(perf.pebs_baseline);
+        assert!(perf.perf_metrics);
+        assert!(perf.pebs_output_pt);
+    }
+
+    #[test]
+    fn version_5_sparse_fixed_bitmap() {
+        //Fixed counters 0, 1 and 5 in ECX, with only two contiguous ones in EDX.
+        let caps = PmuCapabilities::decode(0x0830_0805, 0, 0x23, 0x0602);
+        assert!(caps.is_fixed_counter_supported(0));
+        assert!(caps.is_fixed_counter_supported(1));
+        assert!(!caps.is_fixed_counter_supported(2));
+        assert!(caps.is_fixed_counter_supported(5));
+        assert_eq!(caps.fixed_counter_slots(), 6);
+        assert!(!caps.any_thread_deprecated);
+    }
+
+    #[test]
+    fn signatures() {
+        assert_eq!(CpuSignature::decode(0x0005_06E3), CpuSignature{family:6, model:0x5E, stepping:3});
+        assert_eq!(CpuSignature::decode(0x0000_06F6), CpuSignature{family:6, model:0x0F, stepping:6});
+        //Extended model only applies to families 6 and 0FH.
+        assert_eq!(CpuSignature::decode(0x0001_0521), CpuSignature{family:5, model:0x02, stepping:1});
+        assert_eq!(CpuSignature::decode(0x0000_0F29), CpuSignature{family:0xF, model:0x02, stepping:9});
+        assert_eq!(CpuSignature::decode(0x00A2_0F10), CpuSignature{family:0x19, model:0x21, stepping:0});
+    }
+}
@@ -0,0 +1,275 @@
+//! The Debug Store (DS) save area shared by PEBS and BTS.
+//!
+//! IA32_DS_AREA holds the linear address of a DebugStoreArea, which describes the PEBS and BTS
+//! buffers: where they start, where the processor writes the next record, where they end and when
+//! the buffer threshold interrupt is raised.
+//! The area and the buffers must stay mapped (and must not be paged out) while DS is in use.
+//!
+//! IA32_DS_AREA is one MSR per logical processor, so PEBS and BTS must share one area. A DebugStore
+//! owns the area of one controler's processor; Pebs and Bts borrow it and each fill in their half.
+//!
+
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::ptr::{self, addr_of_mut};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler};
+
+pub const IA32_DS_AREA: u32 = 0x600;
+pub const IA32_MISC_ENABLE: u32 = 0x1A0;
+///IA32_MISC_ENABLE: BTS is not available.
+pub const MISC_ENABLE_BTS_UNAVAILABLE: u64 = 1<<11;
+///IA32_MISC_ENABLE: PEBS is not available.
+pub const MISC_ENABLE_PEBS_UNAVAILABLE: u64 = 1<<12;
+
+///Programmable counters with a PEBS counter reset slot.
+pub const DS_GENERAL_RESETS: usize = 8;
+///Fixed counters with a PEBS counter reset slot (extended PEBS).
+pub const DS_FIXED_RESETS: usize = 4;
+
+///Layout of the 64-bit DS save area.
+#[repr(C, align(64))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DebugStoreArea {
+    pub bts_buffer_base: u64,
+    pub bts_index: u64,
+    pub bts_absolute_maximum: u64,
+    pub bts_interrupt_threshold: u64,
+    pub pebs_buffer_base: u64,
+    pub pebs_index: u64,
+    pub pebs_absolute_maximum: u64,
+    pub pebs_interrupt_threshold: u64,
+    ///Value a programmable counter is reloaded with after a PEBS record.
+    pub pebs_counter_reset: [u64; DS_GENERAL_RESETS],
+    ///Value a fixed counter is reloaded with after a PEBS record.
+    pub pebs_fixed_counter_reset: [u64; DS_FIXED_RESETS],
+}
+
+impl DebugStoreArea {
+    pub const fn new() -> DebugStoreArea {
+        DebugStoreArea{
+            bts_buffer_base: 0,
+            bts_index: 0,
+            bts_absolute_maximum: 0,
+            bts_interrupt_threshold: 0,
+            pebs_buffer_base: 0,
+            pebs_index: 0,
+            pebs_absolute_maximum: 0,
+            pebs_interrupt_threshold: 0,
+            pebs_counter_reset: [0; DS_GENERAL_RESETS],
+            pebs_fixed_counter_reset: [0; DS_FIXED_RESETS],
+        }
+    }
+}
+
+///Check the DS feature flag (CPUID.01H:EDX[21]).
+pub fn ds_supported<B: PmuBackend>(ctrler:&PerfCounterControler<B>) -> bool {
+    ctrler.backend().cpuid(0x01, 0).edx & (1<<21) != 0
+}
+
+///Copy `out.len()` bytes the processor wrote at `src` into `out`.
+///
+/// The bytes are read with volatile loads: the compiler knows nothing of the processor's writes.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `out.len()` bytes.
+pub(crate) unsafe fn read_buffer(src:*const u8, out:&mut [u8]) {
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = ptr::read_volatile(src.add(i));
+    }
+}
+
+///Zero `len` bytes of a buffer the processor writes to.
+pub(crate) unsafe fn clear_buffer(dst:*mut u8, len:usize) {
+    for i in 0..len {
+        ptr::write_volatile(dst.add(i), 0);
+    }
+}
+
+///Owner of the DS save area of one controler's processor, shared by its Pebs and Bts.
+///
+/// The area is installed in IA32_DS_AREA the first time one of them is armed, and the previous
+/// value of IA32_DS_AREA is put back when the DebugStore is dropped. Each half of the area can be
+/// claimed by one Pebs or one Bts at a time.
+pub struct DebugStore<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    //Written by the processor behind the compiler's back, so only accessed through volatile loads and stores.
+    area: *mut DebugStoreArea,
+    //IA32_DS_AREA before install().
+    saved: Cell<Option<u64>>,
+    bts_claimed: Cell<bool>,
+    pebs_claimed: Cell<bool>,
+    _area: PhantomData<&'a mut DebugStoreArea>,
+}
+
+impl<'a, B: PmuBackend> DebugStore<'a, B> {
+    ///Check the processor has a DS save area and take `area` for it.
+    pub fn new(ctrler:&'a PerfCounterControler<B>, area:&'a mut DebugStoreArea) -> Result<DebugStore<'a, B>, ErrorMsg> {
+        if !ds_supported(ctrler) {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        *area = DebugStoreArea::new();
+        Ok(DebugStore{
+            ctrler: ctrler,
+            area: area as *mut DebugStoreArea,
+            saved: Cell::new(None),
+            bts_claimed: Cell::new(false),
+            pebs_claimed: Cell::new(false),
+            _area: PhantomData,
+        })
+    }
+
+    pub fn controler(&self) -> &'a PerfCounterControler<B> {
+        self.ctrler
+    }
+
+    ///Point IA32_DS_AREA at the area. Does nothing if it already does.
+    pub fn install(&self) {
+        if self.saved.get().is_none() {
+            let backend = self.ctrler.backend();
+            self.saved.set(Some(backend.rdmsr(IA32_DS_AREA)));
+            backend.wrmsr(IA32_DS_AREA, self.area as u64);
+        }
+    }
+
+    pub fn is_installed(&self) -> bool {
+        self.saved.get().is_some()
+    }
+
+    pub(crate) fn claim_bts(&self) -> Result<(), ErrorMsg> {
+        Self::claim(&self.bts_claimed)
+    }
+
+    pub(crate) fn release_bts(&self) {
+        self.bts_claimed.set(false);
+    }
+
+    pub(crate) fn claim_pebs(&self) -> Result<(), ErrorMsg> {
+        Self::claim(&self.pebs_claimed)
+    }
+
+    pub(crate) fn release_pebs(&self) {
+        self.pebs_claimed.set(false);
+    }
+
+    fn claim(claimed:&Cell<bool>) -> Result<(), ErrorMsg> {
+        if claimed.replace(true) {
+            return Err(ErrorMsg::SharedMsrInUse);
+        }
+        Ok(())
+    }
+
+    fn load(&self, field:impl FnOnce(*mut DebugStoreArea) -> *mut u64) -> u64 {
+        unsafe { ptr::read_volatile(field(self.area)) }
+    }
+
+    fn store(&self, field:impl FnOnce(*mut DebugStoreArea) -> *mut u64, value:u64) {
+        unsafe { ptr::write_volatile(field(self.area), value) }
+    }
+
+    ///Describe the BTS buffer and reset its index to `base`.
+    pub(crate) fn set_bts_buffer(&self, base:u64, absolute_maximum:u64, interrupt_threshold:u64) {
+        self.store(|a| unsafe { addr_of_mut!((*a).bts_buffer_base) }, base);
+        self.store(|a| unsafe { addr_of_mut!((*a).bts_index) }, base);
+        self.store(|a| unsafe { addr_of_mut!((*a).bts_absolute_maximum) }, absolute_maximum);
+        self.store(|a| unsafe { addr_of_mut!((*a).bts_interrupt_threshold) }, interrupt_threshold);
+    }
+
+    pub(crate) fn bts_index(&self) -> u64 {
+        self.load(|a| unsafe { addr_of_mut!((*a).bts_index) })
+    }
+
+    pub(crate) fn set_bts_index(&self, index:u64) {
+        self.store(|a| unsafe { addr_of_mut!((*a).bts_index) }, index);
+    }
+
+    pub(crate) fn bts_interrupt_threshold(&self) -> u64 {
+        self.load(|a| unsafe { addr_of_mut!((*a).bts_interrupt_threshold) })
+    }
+
+    ///Describe the PEBS buffer and reset its index to `base`.
+    pub(crate) fn set_pebs_buffer(&self, base:u64, absolute_maximum:u64, interrupt_threshold:u64) {
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_buffer_base) }, base);
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_index) }, base);
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_absolute_maximum) }, absolute_maximum);
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_interrupt_threshold) }, interrupt_threshold);
+    }
+
+    pub(crate) fn pebs_index(&self) -> u64 {
+        self.load(|a| unsafe { addr_of_mut!((*a).pebs_index) })
+    }
+
+    pub(crate) fn set_pebs_index(&self, index:u64) {
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_index) }, index);
+    }
+
+    ///Reload value of programmable counter `index`, which must be below DS_GENERAL_RESETS.
+    pub(crate) fn set_pebs_counter_reset(&self, index:usize, value:u64) {
+        assert!(index < DS_GENERAL_RESETS);
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_counter_reset[index]) }, value);
+    }
+
+    ///Reload value of fixed counter `index`, which must be below DS_FIXED_RESETS.
+    pub(crate) fn set_pebs_fixed_counter_reset(&self, index:usize, value:u64) {
+        assert!(index < DS_FIXED_RESETS);
+        self.store(|a| unsafe { addr_of_mut!((*a).pebs_fixed_counter_reset[index]) }, value);
+    }
+}
+
+impl<'a, B: PmuBackend> Drop for DebugStore<'a, B> {
+    fn drop(&mut self) {
+        //Every Pebs and Bts borrowing the area is gone, and disabled itself when dropped.
+        if let Some(saved) = self.saved.get() {
+            self.ctrler.backend().wrmsr(IA32_DS_AREA, saved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::{bts::Bts, pebs::Pebs};
+    use crate::x86_intel::simulator::SimulatedPmu;
+    use x86::cpuid::CpuIdResult;
+
+    fn ds_controler() -> PerfCounterControler<SimulatedPmu> {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax:0, ebx:0, ecx:1<<15, edx:1<<21}).unwrap();
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        ctrler
+    }
+
+    #[test]
+    fn pebs_and_bts_share_one_area() {
+        let ctrler = ds_controler();
+        ctrler.backend().wrmsr(IA32_DS_AREA, 0x1000);
+        let mut area = DebugStoreArea::new();
+        let area_addr = &area as *const DebugStoreArea as u64;
+        let mut pebs_buffer = [0u8; 0x400];
+        let mut bts_buffer = [0u8; 0x400];
+        let mut spare = [0u8; 0x400];
+        {
+            let ds = DebugStore::new(&ctrler, &mut area).unwrap();
+            let mut pebs = Pebs::new(&ds, &mut pebs_buffer).unwrap();
+            let mut bts = Bts::new(&ds, &mut bts_buffer).unwrap();
+            assert!(matches!(Pebs::new(&ds, &mut spare), Err(ErrorMsg::SharedMsrInUse)));
+            pebs.arm().unwrap();
+            bts.arm().unwrap();
+            assert_eq!(ctrler.backend().rdmsr(IA32_DS_AREA), area_addr);
+            drop(pebs);
+            assert!(Pebs::new(&ds, &mut spare).is_ok());
+        }
+        assert_eq!(ctrler.backend().rdmsr(IA32_DS_AREA), 0x1000);
+        //The second Pebs laid the PEBS half out again, without touching the BTS half.
+        assert_eq!(area.pebs_buffer_base, spare.as_ptr() as u64);
+        assert_eq!(area.bts_buffer_base, bts_buffer.as_ptr() as u64);
+    }
+
+    #[test]
+    fn needs_the_ds_feature() {
+        let ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        assert!(matches!(DebugStore::new(&ctrler, &mut DebugStoreArea::new()), Err(ErrorMsg::UnsupportedFeature)));
+    }
+}
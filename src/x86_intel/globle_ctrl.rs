@@ -2,107 +2,109 @@
 //! 
 //! OS should obtain one instance of the controler before constrcuting any PerfCounter.
 //! 
-//! Must call init() before use.
+//! A controler built by hand must call init() before use.
+//! this_cpu_controler() instead returns the controler of the executing logical CPU,
+//! initialized the first time it is requested on that CPU; PerCpuControlers does the same for any backend.
 //! 
 
-use x86::{msr::{rdmsr, wrmsr}, perfcnt::intel::Counter};
+use core::cell::{Cell, UnsafeCell};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86::perfcnt::intel::Counter;
 use crate::ErrorMsg;
+use super::backend::{PmuBackend, NativePmu};
+use super::capabilities::PmuCapabilities;
 
-pub struct PerfCounterControler{
-    version_identifier:u8,
-    number_msr:u8,
-    bit_width:u8,
-    events_available:u8,
-    number_fixed_function_counter:u8,
-    bit_width_fixed_counter:u8,
-    unavailable_events_vec:u8,
-    perf_capability: bool,
+///Number of distinct auxiliary MSRs (MSR_OFFCORE_RSP_x, MSR_PEBS_LD_LAT_THRESHOLD, ...) that can be shared at once.
+pub const MAX_AUX_MSR: usize = 4;
+
+///Ownership of one auxiliary MSR shared by the counters of this PMU.
+#[derive(Debug, Clone, Copy)]
+struct AuxMsrSlot {
+    msr: u32,
+    value: u64,
+    //Value found in the MSR before the first user programmed it.
+    saved: u64,
+    users: u8,
+}
+
+const FREE_AUX_MSR: AuxMsrSlot = AuxMsrSlot{msr:0, value:0, saved:0, users:0};
+
+pub struct PerfCounterControler<B: PmuBackend = NativePmu>{
+    backend: B,
+    capabilities: PmuCapabilities,
+    aux_msrs: [Cell<AuxMsrSlot>; MAX_AUX_MSR],
+    //Counters owned by a PerfCounter, with the bit layout of IA32_PERF_GLOBAL_CTRL.
+    reserved: Cell<u64>,
 }
 
 impl  PerfCounterControler{
-    pub fn new() -> PerfCounterControler{
+    /// # Safety
+    ///
+    /// Same requirements as NativePmu::new().
+    pub unsafe fn new() -> PerfCounterControler{
+        PerfCounterControler::with_backend(NativePmu::new())
+    }
+}
+
+impl<B: PmuBackend> PerfCounterControler<B>{
+    pub fn with_backend(backend:B) -> PerfCounterControler<B>{
+        PerfCounterControler::from_capabilities(backend, PmuCapabilities::empty())
+    }
+
+    ///Build a controler from already decoded capabilities instead of calling init().
+    pub fn from_capabilities(backend:B, capabilities:PmuCapabilities) -> PerfCounterControler<B>{
         PerfCounterControler{
-            version_identifier:0,
-            number_msr:0,
-            bit_width:0,
-            events_available:0,
-            number_fixed_function_counter:0,
-            bit_width_fixed_counter:0,
-            unavailable_events_vec:0,
-            perf_capability:false,
+            backend: backend,
+            capabilities: capabilities,
+            aux_msrs: [const { Cell::new(FREE_AUX_MSR) }; MAX_AUX_MSR],
+            reserved: Cell::new(0),
         }
     }
 
+    pub fn backend(&self) -> &B{
+        &self.backend
+    }
+
     ///This must be called. 
+    /// Decodes CPUID.0AH and, if CPUID.01H:ECX.PDCM is set, IA32_PERF_CAPABILITIES.
     pub fn init(&mut self){
-        let mut rax :u64;
-        let mut rdx :u64;
-        let mut rbx :u64;
-        unsafe{
-        //get CPUID:0AH info
-            asm!(
-                "MOV EAX, 0AH",
-                "CPUID",
-                "MOV R8, RBX",
-                out("rax") rax,
-                out("rdx") rdx,
-               out("r8") rbx,
-            );
-        } 
-        let mask:u64 =  255;
-        self.version_identifier = (rax & mask) as u8;
-        self.number_msr = ((rax >> 8) & mask) as u8;
-        self.bit_width =  if rax & mask != 0 {((rax >> 16) & mask) as u8} else {40 as u8};
-        self.events_available = ((rax >> 24) & mask )as u8;
-        self.number_fixed_function_counter = (rdx & 31 )as u8;
-        self.bit_width_fixed_counter = (rdx>>5 & 127) as u8;
-        self.unavailable_events_vec = (rbx & mask) as u8;
-        unsafe{
-            let mut rcx :u64;
-            asm!(
-                "MOV EAX, 01H",
-                "CPUID",
-                "MOV R8, RBX",
-               out("rcx") rcx,
-            );
-            if (rcx >> 15)& 1 == 1{
-                self.perf_capability = (rdmsr(x86::msr::IA32_PERF_CAPABILITIES)>>13 & 0x1) == 1;
-            }
-            else{
-                self.perf_capability = false;
-            }
-
-            if ! self.perf_capability{
-                self.bit_width = 32;
-            }
-
-            }
+        let leaf = self.backend.cpuid(0x0A, 0);
+        let mut capabilities = PmuCapabilities::decode(leaf.eax, leaf.ebx, leaf.ecx, leaf.edx);
+        if (self.backend.cpuid(0x01, 0).ecx >> 15) & 1 == 1{
+            capabilities = capabilities.with_perf_capabilities(self.backend.rdmsr(x86::msr::IA32_PERF_CAPABILITIES));
+        }
+        self.capabilities = capabilities;
     }
 
+    pub fn get_capabilities(&self)-> &PmuCapabilities{
+        &self.capabilities
+    }
     pub fn get_version_identifier(&self)-> u8{
-        self.version_identifier
+        self.capabilities.version
     }
     pub fn get_number_msr(&self)-> u8{
-        self.number_msr
+        self.capabilities.general_counters
     }
     pub fn get_number_fixed_function_counter(&self)-> u8{
-        self.number_fixed_function_counter
+        self.capabilities.fixed_counters
     }
     pub fn get_bit_width(&self)-> u8{
-        self.bit_width
+        self.capabilities.general_width
     }
     pub fn get_events_available(&self)-> u8{
-        self.events_available
+        self.capabilities.ebx_length
     }
     pub fn get_bit_width_fixed_counter(&self)-> u8{
-        self.bit_width_fixed_counter
+        self.capabilities.fixed_width
     }
     pub fn get_unavailable_events_vec(&self)-> u8{
-        self.unavailable_events_vec
+        self.capabilities.unavailable_events as u8
     }
 
+    ///Full-width writes through IA32_A_PMCx are supported.
     pub fn get_perf_capability(&self)->bool{
-        self.perf_capability
+        self.capabilities.full_width_write()
     }
     
     ///Will clear overflow indicator for corresponding pmc in IA32_PERF_GLOBAL_STATUS
@@ -128,39 +130,19 @@ impl  PerfCounterControler{
     /// Should probably be called in interrput handler.
     pub fn reset_overflow_interrput(&self){
         let mask:u32 = !(1<<16);
-        let mut eax:u32;
-        let rdx:u64 = 0xFEE00340;
-        unsafe{
-            asm!("MOV eax, [edx]",
-            in("rdx") rdx,
-            out("eax") eax,
-            );
-        }
-        eax = eax & mask;
-        unsafe{
-            asm!("MOV [edx],eax",
-            in("rdx") rdx,
-            in("eax") eax,
-            );
-        }
+        let eax = self.backend.read_lvt_perfmon() & mask;
+        self.backend.write_lvt_perfmon(eax);
     }
 
     ///Start generating PMI on pmc overflow.
     /// Use get_overflow_counter() to find out which counter overflows.
     pub fn register_overflow_interrput(&self, interrput_vec:u8){
-        unsafe{
-            let edx:u32 = 0xFEE00340; //APIC PMC register 
-            let eax:u64 = interrput_vec as u64;
-            asm!("MOV [edx],eax",
-            in("edx") edx,
-            in("eax") eax,
-            );
-        }
+        self.backend.write_lvt_perfmon(interrput_vec as u32);
     }
 
     pub fn read_globle_ctrl_bits(&self)->Result<u64,ErrorMsg>{
         if self.get_version_identifier()>=2{
-            unsafe{Ok(rdmsr(0x38f))}
+            Ok(self.backend.rdmsr(0x38f))
         }
         else{
             Err(ErrorMsg::UnsupportedVersion)
@@ -170,17 +152,106 @@ impl  PerfCounterControler{
 
     pub fn set_globle_ctrl(&self,value:u64){
         if self.get_version_identifier()>=2{
-            let rcx:u64 = 0x38f;
-            let rax:u32 = value as u32;
-            let rdx:u32 = (value>>32) as u32;
-            unsafe {
-                asm!(
-                    //MSR[ECX] := EDX:EAX;
-                "wrmsr",
-                in("rcx") rcx,
-                in("rax") rax,
-                in("rdx") rdx,
-            )
+            self.backend.wrmsr(0x38f, value);
+        }
+    }
+
+    ///Bit of a counter in IA32_PERF_GLOBAL_CTRL and in the reservation bitmap.
+    pub fn counter_bit(c:Counter)->u64{
+        match c {
+            Counter::Fixed(index) => 1 << (index as u64 + 32),
+            Counter::Programmable(index) => 1 << index,
+        }
+    }
+
+    ///Claim a counter for one PerfCounter.
+    pub fn reserve_counter(&self, c:Counter)->Result<(),ErrorMsg>{
+        let bit = Self::counter_bit(c);
+        let reserved = self.reserved.get();
+        if reserved & bit != 0{
+            return Err(ErrorMsg::CounterInUse);
+        }
+        self.reserved.set(reserved | bit);
+        Ok(())
+    }
+
+    ///Give back a counter claimed with reserve_counter().
+    pub fn release_counter(&self, c:Counter){
+        self.reserved.set(self.reserved.get() & !Self::counter_bit(c));
+    }
+
+    ///Check if a PerfCounter owns the counter.
+    pub fn is_reserved(&self, c:Counter)->bool{
+        self.reserved.get() & Self::counter_bit(c) != 0
+    }
+
+    ///All reserved counters, with the bit layout of IA32_PERF_GLOBAL_CTRL.
+    pub fn get_reserved_counters(&self)->u64{
+        self.reserved.get()
+    }
+
+    ///Check if an auxiliary MSR can be claimed with `value`:
+    /// it is unused, or every current user programmed the same value.
+    pub fn aux_msr_available(&self, msr:u32, value:u64)->bool{
+        let mut has_free_slot = false;
+        for slot in self.aux_msrs.iter(){
+            let s = slot.get();
+            if s.users > 0 && s.msr == msr{
+                return s.value == value;
+            }
+            has_free_slot |= s.users == 0;
+        }
+        has_free_slot
+    }
+
+    ///Program an auxiliary MSR shared between counters.
+    /// The first user saves the previous content, later users must request the same value.
+    pub fn claim_aux_msr(&self, msr:u32, value:u64)->Result<(),ErrorMsg>{
+        for slot in self.aux_msrs.iter(){
+            let mut s = slot.get();
+            if s.users > 0 && s.msr == msr{
+                if s.value != value{
+                    return Err(ErrorMsg::SharedMsrInUse);
+                }
+                s.users += 1;
+                slot.set(s);
+                return Ok(());
+            }
+        }
+        for slot in self.aux_msrs.iter(){
+            if slot.get().users == 0{
+                let saved = self.backend.rdmsr(msr);
+                self.backend.wrmsr(msr, value);
+                slot.set(AuxMsrSlot{msr:msr, value:value, saved:saved, users:1});
+                return Ok(());
+            }
+        }
+        Err(ErrorMsg::SharedMsrInUse)
+    }
+
+    ///Drop one user of an auxiliary MSR. The last user restores its previous content.
+    pub fn release_aux_msr(&self, msr:u32){
+        for slot in self.aux_msrs.iter(){
+            let mut s = slot.get();
+            if s.users > 0 && s.msr == msr{
+                s.users -= 1;
+                if s.users == 0{
+                    self.backend.wrmsr(msr, s.saved);
+                }
+                slot.set(s);
+                return;
+            }
+        }
+    }
+
+    ///Drop one user of an auxiliary MSR without restoring it; the MSR keeps its current content.
+    pub fn abandon_aux_msr(&self, msr:u32){
+        for slot in self.aux_msrs.iter(){
+            let mut s = slot.get();
+            if s.users > 0 && s.msr == msr{
+                s.users -= 1;
+                slot.set(s);
+                return;
             }
         }
     }
@@ -222,27 +293,19 @@ impl  PerfCounterControler{
     }
 
     pub fn read_overflow_status(&self)->u64{
-        unsafe{
-            rdmsr(0x38E)
-        }
+        self.backend.rdmsr(0x38E)
     }
 
     pub fn set_overflow_status(&self, value:u64){
-        unsafe{
-            wrmsr(0x38E,value)
-        }
+        self.backend.wrmsr(0x38E,value)
     }
 
     pub fn read_overflow_ctrl(&self)->u64{
-        unsafe{
-            rdmsr(0x390)
-        }
+        self.backend.rdmsr(0x390)
     }
 
     pub fn set_overflow_ctrl(&self, value:u64){
-        unsafe{
-            wrmsr(0x390,value)
-        }
+        self.backend.wrmsr(0x390,value)
     }
 
     ///Get the overflowe counter during a PMI.
@@ -265,14 +328,12 @@ impl  PerfCounterControler{
 
     pub fn check_if_general_pmc_is_in_use(&self,index:u8)->bool{
         let mut ret:bool = true;
-        unsafe{
-            let mask = rdmsr(0x186+index as u32);
-            ret = ret & (mask>>22 & 1 > 0);
+        let mask = self.backend.rdmsr(0x186+index as u32);
+        ret = ret & (mask>>22 & 1 > 0);
 
-            if self.get_version_identifier()>=2{
-                let mask = rdmsr(0x38f);
-                ret = ret & (mask>>index & 1 > 0);
-            }
+        if self.get_version_identifier()>=2{
+            let mask = self.backend.rdmsr(0x38f);
+            ret = ret & (mask>>index & 1 > 0);
         }
         ret
     }
@@ -280,12 +341,12 @@ impl  PerfCounterControler{
 
     pub fn check_if_fixed_pmc_is_in_use(&self,index:u8)->bool{
         let mut ret:bool;
-        unsafe{
-            let mask = rdmsr(0x38D+index as u32);
-            ret = (mask>>(4*index)&3) > 0;
+        let mask = self.backend.rdmsr(0x38D);
+        ret = (mask>>(4*index)&3) > 0;
 
-            let mask = rdmsr(0x38f);
-            ret = ret & (mask>>(index + 32) > 0);
+        if self.get_version_identifier()>=2{
+            let mask = self.backend.rdmsr(0x38f);
+            ret = ret & (mask>>(index + 32) & 1 > 0);
         }
         ret
     }
@@ -300,13 +361,151 @@ impl  PerfCounterControler{
     }
 }
 
-pub static mut PERFCNT_GLOBAL_CTRLER:PerfCounterControler = PerfCounterControler{
-    version_identifier:0,
-    number_msr:0,
-    bit_width:0,
-    events_available:0,
-    number_fixed_function_counter:0,
-    bit_width_fixed_counter:0,
-    unavailable_events_vec:0,
-    perf_capability:false,
-};
\ No newline at end of file
+///Number of per-CPU controlers, indexed by APIC ID.
+pub const MAX_CPUS: usize = 256;
+
+const CPU_UNINIT: u8 = 0;
+const CPU_INITIALIZING: u8 = 1;
+const CPU_READY: u8 = 2;
+
+struct CpuControler<B: PmuBackend> {
+    state: AtomicU8,
+    ctrler: UnsafeCell<MaybeUninit<PerfCounterControler<B>>>,
+}
+
+///One lazily initialized controler per logical CPU, indexed by APIC ID.
+///
+/// The backend is only built, and init() only called, the first time a CPU asks for its controler.
+/// Can be placed in a static, for any backend.
+pub struct PerCpuControlers<B: PmuBackend, const CPUS: usize> {
+    slots: [CpuControler<B>; CPUS],
+}
+
+//A slot is only initialized and used on the logical CPU whose APIC ID indexes it,
+//so the controler itself is never shared between CPUs.
+unsafe impl<B: PmuBackend, const CPUS: usize> Sync for PerCpuControlers<B, CPUS> {}
+
+impl<B: PmuBackend, const CPUS: usize> PerCpuControlers<B, CPUS> {
+    pub const fn new() -> PerCpuControlers<B, CPUS> {
+        PerCpuControlers{
+            slots: [const { CpuControler{state: AtomicU8::new(CPU_UNINIT), ctrler: UnsafeCell::new(MaybeUninit::uninit())} }; CPUS],
+        }
+    }
+
+    ///Controler of logical CPU `cpu`, built with `backend()` and init() the first time it is requested.
+    ///
+    /// Fails with ControlerNotReady when called while init() is running on this CPU
+    /// (e.g. from an interrupt handler), and with CpuOutOfRange if `cpu` is not below CPUS.
+    ///
+    /// # Safety
+    ///
+    /// `cpu` must be the APIC ID of the executing logical CPU, and the reference must only be
+    /// used on that CPU: call this with preemption disabled, or from a thread pinned to one CPU.
+    pub unsafe fn get(&self, cpu:usize, backend:impl FnOnce() -> B) -> Result<&PerfCounterControler<B>, ErrorMsg>{
+        let slot = self.slots.get(cpu).ok_or(ErrorMsg::CpuOutOfRange)?;
+        match slot.state.compare_exchange(CPU_UNINIT, CPU_INITIALIZING, Ordering::Acquire, Ordering::Acquire){
+            Ok(_) => {
+                let ctrler = unsafe{ (*slot.ctrler.get()).write(PerfCounterControler::with_backend(backend())) };
+                ctrler.init();
+                slot.state.store(CPU_READY, Ordering::Release);
+            },
+            Err(CPU_READY) => {},
+            Err(_) => return Err(ErrorMsg::ControlerNotReady),
+        }
+        Ok(unsafe{ (*slot.ctrler.get()).assume_init_ref() })
+    }
+
+    ///Check if the controler of logical CPU `cpu` was initialized.
+    pub fn is_ready(&self, cpu:usize) -> bool{
+        self.slots.get(cpu).is_some_and(|slot| slot.state.load(Ordering::Acquire) == CPU_READY)
+    }
+}
+
+impl<B: PmuBackend, const CPUS: usize> Default for PerCpuControlers<B, CPUS> {
+    fn default() -> PerCpuControlers<B, CPUS> {
+        PerCpuControlers::new()
+    }
+}
+
+impl<B: PmuBackend, const CPUS: usize> Drop for PerCpuControlers<B, CPUS> {
+    fn drop(&mut self){
+        for slot in self.slots.iter_mut() {
+            if *slot.state.get_mut() == CPU_READY {
+                unsafe{ slot.ctrler.get_mut().assume_init_drop(); }
+            }
+        }
+    }
+}
+
+static CPU_CONTROLERS: PerCpuControlers<NativePmu, MAX_CPUS> = PerCpuControlers::new();
+
+///APIC ID of the executing logical CPU.
+///
+/// The x2APIC ID from CPUID.0BH when available, the initial APIC ID from CPUID.01H otherwise.
+/// This runs CPUID, so callers should look it up once per CPU and keep it.
+pub fn current_cpu_id<B: PmuBackend>(backend:&B) -> usize{
+    if backend.cpuid(0, 0).eax >= 0x0B{
+        let topology = backend.cpuid(0x0B, 0);
+        if topology.ebx != 0{
+            return topology.edx as usize;
+        }
+    }
+    (backend.cpuid(0x01, 0).ebx >> 24) as usize
+}
+
+///Controler of logical CPU `cpu` on NativePmu, calling init() the first time it is requested on this CPU.
+///
+/// Each logical CPU gets its own controler, since the PMU registers are per CPU. `cpu` is the
+/// APIC ID of the executing CPU, e.g. from current_cpu_id() cached in the OS's per-CPU data.
+/// Other backends can keep their own PerCpuControlers.
+///
+/// # Safety
+///
+/// Same requirements as NativePmu::new() and PerCpuControlers::get().
+pub unsafe fn this_cpu_controler(cpu:usize) -> Result<&'static PerfCounterControler, ErrorMsg>{
+    unsafe{ CPU_CONTROLERS.get(cpu, || NativePmu::new()) }
+}
+
+///Check if the controler of logical CPU `cpu` (an APIC ID) was initialized.
+pub fn is_cpu_controler_ready(cpu:usize) -> bool{
+    CPU_CONTROLERS.is_ready(cpu)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86::msr::{IA32_FIXED_CTR_CTRL, IA32_PERF_GLOBAL_CTRL};
+    use crate::x86_intel::simulator::SimulatedPmu;
+
+    #[test]
+    fn fixed_pmc_in_use_needs_both_enable_bits() {
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        let pmu = ctrler.backend();
+        //Fixed counter 1 counts in ring 0 and 3, fixed counter 2 in ring 3 only.
+        pmu.wrmsr(IA32_FIXED_CTR_CTRL, 3<<4 | 2<<8);
+        //Only fixed counter 2 is enabled globally; bit 33 stays clear below bit 34.
+        pmu.wrmsr(IA32_PERF_GLOBAL_CTRL, 1<<34);
+        assert!(!ctrler.check_if_fixed_pmc_is_in_use(0));
+        assert!(!ctrler.check_if_fixed_pmc_is_in_use(1));
+        assert!(ctrler.check_if_fixed_pmc_is_in_use(2));
+        pmu.wrmsr(IA32_PERF_GLOBAL_CTRL, 1<<33 | 1<<34);
+        assert!(ctrler.check_if_fixed_pmc_is_in_use(1));
+        pmu.wrmsr(IA32_FIXED_CTR_CTRL, 0);
+        assert!(!ctrler.check_if_fixed_pmc_is_in_use(1));
+    }
+
+    #[test]
+    fn per_cpu_controlers_init_once() {
+        let controlers: PerCpuControlers<SimulatedPmu, 2> = PerCpuControlers::new();
+        assert!(!controlers.is_ready(1));
+        let mut built = 0;
+        for _ in 0..2 {
+            let ctrler = unsafe{ controlers.get(1, || { built += 1; SimulatedPmu::default_v4() }) }.unwrap();
+            assert_eq!(ctrler.get_capabilities().version, 4);
+        }
+        assert_eq!(built, 1);
+        assert!(controlers.is_ready(1));
+        assert!(!controlers.is_ready(0));
+        assert!(matches!(unsafe{ controlers.get(2, SimulatedPmu::default_v4) }, Err(ErrorMsg::CpuOutOfRange)));
+    }
+}
@@ -0,0 +1,259 @@
+//! A group of PerfCounters that start, stop and are read together.
+//!
+//! start() first programs every member's IA32_PERFEVTSELx / IA32_FIXED_CTR_CTRL field and then enables
+//! all of them with a single IA32_PERF_GLOBAL_CTRL write, so all members measure the same window.
+//! stop() disables them with one write as well, and read() freezes the members while reading them.
+//!
+//! On version 1 PMUs there is no IA32_PERF_GLOBAL_CTRL and members are enabled one after the other.
+//!
+
+use crate::{AbstractPerfCounter, ErrorMsg};
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler, PerfCounter};
+
+pub const MAX_GROUP_MEMBERS: usize = 16;
+
+pub struct PerfCounterGroup<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    members: [Option<PerfCounter<'a, B>>; MAX_GROUP_MEMBERS],
+    len: usize,
+    running: bool,
+}
+
+impl<'a, B: PmuBackend> PerfCounterGroup<'a, B> {
+    pub fn new(ctrler:&'a PerfCounterControler<B>) -> PerfCounterGroup<'a, B> {
+        PerfCounterGroup{
+            ctrler: ctrler,
+            members: core::array::from_fn(|_| None),
+            len: 0,
+            running: false,
+        }
+    }
+
+    ///Add a built counter. Returns its position in the group.
+    ///
+    /// The counter must use the group's controler and a PMC no other member uses.
+    pub fn add(&mut self, counter:PerfCounter<'a, B>) -> Result<usize, ErrorMsg> {
+        if self.running {
+            return Err(ErrorMsg::CounterInUse);
+        }
+        if self.len == MAX_GROUP_MEMBERS || !core::ptr::eq(counter.global_ctrler, self.ctrler) {
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        if self.enable_mask() & counter.global_bit() != 0 {
+            return Err(ErrorMsg::CounterInUse);
+        }
+        self.members[self.len] = Some(counter);
+        self.len += 1;
+        Ok(self.len - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn member(&self, index:usize) -> Option<&PerfCounter<'a, B>> {
+        self.members.get(index)?.as_ref()
+    }
+
+    ///IA32_PERF_GLOBAL_CTRL bits of all members.
+    pub fn enable_mask(&self) -> u64 {
+        self.iter().fold(0, |mask, c| mask | c.global_bit())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &PerfCounter<'a, B>> {
+        self.members[..self.len].iter().filter_map(|c| c.as_ref())
+    }
+
+    ///Zero all member counters.
+    pub fn reset(&self) -> Result<(), ErrorMsg> {
+        for counter in self.iter() {
+            counter.reset()?;
+        }
+        Ok(())
+    }
+
+    ///Program all members, then enable them at once.
+    pub fn start(&mut self) -> Result<(), ErrorMsg> {
+        if self.running {
+            return Ok(());
+        }
+        //Members hold their auxiliary MSRs from build time on, unless one was released by hand.
+        for counter in self.iter() {
+            counter.claim_aux_msr()?;
+        }
+        let mask = self.enable_mask();
+        let has_global_ctrl = self.ctrler.get_capabilities().has_global_ctrl();
+        if has_global_ctrl {
+            self.ctrler.set_globle_ctrl(self.ctrler.read_globle_ctrl_bits()? & !mask);
+        }
+        for counter in self.iter() {
+            counter.program_ctrl();
+        }
+        if has_global_ctrl {
+            self.ctrler.set_globle_ctrl(self.ctrler.read_globle_ctrl_bits()? | mask);
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    ///Disable all members at once, then clear their control registers.
+    pub fn stop(&mut self) -> Result<(), ErrorMsg> {
+        if !self.running {
+            return Ok(());
+        }
+        if self.ctrler.get_capabilities().has_global_ctrl() {
+            self.ctrler.set_globle_ctrl(self.ctrler.read_globle_ctrl_bits()? & !self.enable_mask());
+        }
+        for counter in self.iter() {
+            counter.clear_ctrl();
+        }
+        self.running = false;
+        Ok(())
+    }
+
+    ///Give every member's reservation back without touching any MSR, see PerfCounter::abandon().
+    pub fn abandon(mut self) {
+        for counter in self.members[..self.len].iter_mut().filter_map(|c| c.as_mut()) {
+            counter.abandon();
+        }
+    }
+
+    ///Read every member into `values` (which must hold len() entries).
+    ///
+    /// A running group is frozen through IA32_PERF_GLOBAL_CTRL while it is read, so all values
+    /// cover the same window.
+    pub fn read(&self, values:&mut [u64]) -> Result<(), ErrorMsg> {
+        if values.len() < self.len {
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        let freeze = self.running && self.ctrler.get_capabilities().has_global_ctrl();
+        let mut saved = 0;
+        if freeze {
+            saved = self.ctrler.read_globle_ctrl_bits()?;
+            self.ctrler.set_globle_ctrl(saved & !self.enable_mask());
+        }
+        for (value, counter) in values.iter_mut().zip(self.iter()) {
+            *value = counter.read_ctr();
+        }
+        if freeze {
+            self.ctrler.set_globle_ctrl(saved);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86::msr::*;
+    use x86::perfcnt::intel::Counter;
+    use crate::x86_intel::lookup::{CpuModel, EventTable};
+    use crate::x86_intel::capabilities::CpuSignature;
+    use crate::x86_intel::simulator::{SimulatedPmu, SimRing, MAX_MSR_WRITE_LOG};
+
+    fn controler() -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        ctrler
+    }
+
+    fn general<'a>(ctrler:&'a PerfCounterControler<SimulatedPmu>, event:u8, index:u8) -> PerfCounter<'a, SimulatedPmu> {
+        let mut counter = PerfCounter::new(ctrler);
+        counter.build_general_from_raw(event as u32, 0, true, true, 0, false, index).unwrap();
+        counter.disable_interrupt();
+        counter
+    }
+
+    #[test]
+    fn start_enables_all_members_with_one_write() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let mut group = PerfCounterGroup::new(&ctrler);
+        group.add(general(&ctrler, 0xC4, 0)).unwrap();
+        group.add(general(&ctrler, 0xC5, 2)).unwrap();
+        let mut cycles = PerfCounter::new(&ctrler);
+        cycles.build_fixed(1, false).unwrap();
+        cycles.disable_interrupt();
+        group.add(cycles).unwrap();
+        assert!(matches!(group.add(general(&ctrler, 0xC3, 3)), Ok(3)));
+        let mask = 1 | 1 << 2 | 1 << 3 | 1 << 33;
+        assert_eq!(group.enable_mask(), mask);
+        let mut log = [(0, 0); MAX_MSR_WRITE_LOG];
+        pmu.take_msr_writes(&mut log);
+        group.start().unwrap();
+        let n = pmu.take_msr_writes(&mut log);
+        //The members are disabled first, since all PMCs are enabled in IA32_PERF_GLOBAL_CTRL on reset.
+        assert_eq!(log[0], (IA32_PERF_GLOBAL_CTRL, 0xF & !mask));
+        assert_eq!(log[n - 1], (IA32_PERF_GLOBAL_CTRL, 0xF | mask));
+        for (msr, value) in &log[1..n - 1] {
+            assert!(*msr != IA32_PERF_GLOBAL_CTRL, "IA32_PERF_GLOBAL_CTRL written while programming: {:#x}", value);
+        }
+        for index in [0, 2, 3] {
+            assert!(log[1..n - 1].iter().any(|(msr, value)| *msr == IA32_PERFEVTSEL0 + index && value & (1 << 22) != 0));
+        }
+        assert!(log[1..n - 1].contains(&(IA32_FIXED_CTR_CTRL, 0x3 << 4)));
+        assert!(group.is_running());
+        group.stop().unwrap();
+        let n = pmu.take_msr_writes(&mut log);
+        assert_eq!(log[0], (IA32_PERF_GLOBAL_CTRL, 0xF & !mask));
+        assert!(log[1..n].iter().all(|(msr, _)| *msr != IA32_PERF_GLOBAL_CTRL));
+        assert_eq!(pmu.rdmsr(IA32_FIXED_CTR_CTRL), 0);
+    }
+
+    #[test]
+    fn read_freezes_the_members() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let mut group = PerfCounterGroup::new(&ctrler);
+        group.add(general(&ctrler, 0xC4, 1)).unwrap();
+        group.add(general(&ctrler, 0xC5, 3)).unwrap();
+        group.reset().unwrap();
+        group.start().unwrap();
+        pmu.count_event(0xC4, 0, SimRing::User, 7);
+        pmu.count_event(0xC5, 0, SimRing::Kernel, 2);
+        let mut log = [(0, 0); MAX_MSR_WRITE_LOG];
+        pmu.take_msr_writes(&mut log);
+        let mut values = [0; 2];
+        group.read(&mut values).unwrap();
+        assert_eq!(values, [7, 2]);
+        let n = pmu.take_msr_writes(&mut log);
+        let running = pmu.rdmsr(IA32_PERF_GLOBAL_CTRL);
+        assert_eq!(log[..n], [(IA32_PERF_GLOBAL_CTRL, running & !0b1010), (IA32_PERF_GLOBAL_CTRL, running)]);
+        assert!(matches!(group.read(&mut values[..1]), Err(ErrorMsg::CounterOutOfRange)));
+    }
+
+    #[test]
+    fn start_fails_cleanly_on_an_aux_msr_conflict() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let table = EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap();
+        let mut offcore = PerfCounter::new(&ctrler);
+        offcore.build_from_intel_hw_event_alternative(table.lookup("OFFCORE_RESPONSE.OTHER.L3_MISS.ANY_SNOOP").unwrap(), 0, 0).unwrap();
+        //Give MSR_OFFCORE_RSP_0 up by hand and let another counter program another value.
+        offcore.release_aux_msr();
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_from_intel_hw_event_alternative(table.lookup("OFFCORE_RESPONSE.OTHER.L3_MISS.SNOOP_HITM").unwrap(), 1, 0).unwrap();
+        let mut group = PerfCounterGroup::new(&ctrler);
+        group.add(general(&ctrler, 0xC4, 2)).unwrap();
+        group.add(offcore).unwrap();
+        let mut log = [(0, 0); MAX_MSR_WRITE_LOG];
+        pmu.take_msr_writes(&mut log);
+        assert!(matches!(group.start(), Err(ErrorMsg::SharedMsrInUse)));
+        assert!(!group.is_running());
+        assert_eq!(pmu.take_msr_writes(&mut log), 0, "no register is touched");
+        group.stop().unwrap();
+        drop(other);
+        group.start().unwrap();
+        assert!(group.is_running());
+        assert_eq!(pmu.rdmsr(IA32_PERFEVTSEL0) & 0xFF, 0xB7);
+        assert!(ctrler.is_reserved(Counter::Programmable(2)));
+    }
+}
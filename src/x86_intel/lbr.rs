@@ -0,0 +1,525 @@
+//! Last Branch Records (LBR).
+//!
+//! With IA32_DEBUGCTL.LBR set the processor records the source and target of the most recent taken
+//! branches in a ring of MSR pairs (MSR_LASTBRANCH_x_FROM_IP/TO_IP), MSR_LBR_TOS pointing at the
+//! newest entry. Depth and MSR addresses are model specific and come from the table below; what an
+//! entry holds besides the addresses depends on the LBR format in IA32_PERF_CAPABILITIES:
+//! - 0: 32-bit addresses, both in the FROM MSR.
+//! - 1, 2: 64-bit linear or effective addresses.
+//! - 3: FROM bit 63 is the mispredict flag.
+//! - 4: FROM bits 63:61 are the mispredict, in-transaction and abort flags.
+//! - 5, 7: flags and the cycle count since the previous entry in MSR_LBR_INFO_x.
+//! - 6: FROM bit 63 is the mispredict flag, TO bits 63:48 the cycle count.
+//!
+//! MSR_LBR_SELECT (Nehalem and later) filters the recorded branches by privilege level and type.
+//! Architectural LBR (CPUID.(EAX=07H,ECX=0):EDX[19]) uses different MSRs and is not supported.
+//!
+//! With FREEZE_LBRS_ON_PMI the stack stops at the PMI, so a Sampler given the LbrLayout through
+//! Sampler::set_lbr() snapshots the branches leading to the sampled instruction and resumes them.
+//!
+
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, capabilities::CpuSignature, globle_ctrl::PerfCounterControler};
+
+pub const IA32_DEBUGCTL: u32 = 0x1D9;
+pub const DEBUGCTL_LBR: u64 = 1<<0;
+pub const DEBUGCTL_BTF: u64 = 1<<1;
+pub const DEBUGCTL_TR: u64 = 1<<6;
+pub const DEBUGCTL_BTS: u64 = 1<<7;
+pub const DEBUGCTL_BTINT: u64 = 1<<8;
+pub const DEBUGCTL_BTS_OFF_OS: u64 = 1<<9;
+pub const DEBUGCTL_BTS_OFF_USR: u64 = 1<<10;
+pub const DEBUGCTL_FREEZE_LBRS_ON_PMI: u64 = 1<<11;
+pub const DEBUGCTL_FREEZE_PERFMON_ON_PMI: u64 = 1<<12;
+
+pub const MSR_LBR_SELECT: u32 = 0x1C8;
+pub const MSR_LBR_TOS: u32 = 0x1C9;
+pub const MSR_LBR_INFO_0: u32 = 0xDC0;
+///IA32_PERF_GLOBAL_STATUS: the LBR stack is frozen after a PMI (version 4 and later).
+pub const GLOBAL_STATUS_LBR_FRZ: u64 = 1<<58;
+
+///Deepest LBR stack of the supported models.
+pub const MAX_LBR_ENTRIES: usize = 32;
+
+const LBR_INFO_MISPRED: u64 = 1<<63;
+const LBR_INFO_IN_TX: u64 = 1<<62;
+const LBR_INFO_ABORT: u64 = 1<<61;
+const LBR_INFO_CYCLES: u64 = 0xFFFF;
+
+struct LbrModel {
+    models: &'static [u8],
+    depth: u8,
+    from: u32,
+    to: u32,
+    select: bool,
+}
+
+//Family 6 models with a model specific LBR stack.
+const LBR_MODELS: [LbrModel; 6] = [
+    //Core 2
+    LbrModel{models: &[0x0F, 0x16, 0x17, 0x1D], depth: 4, from: 0x40, to: 0x60, select: false},
+    //Bonnell
+    LbrModel{models: &[0x1C, 0x26, 0x27, 0x35, 0x36], depth: 8, from: 0x40, to: 0x60, select: false},
+    //Nehalem to Broadwell
+    LbrModel{models: &[0x1A, 0x1E, 0x1F, 0x2E, 0x25, 0x2C, 0x2F, 0x2A, 0x2D, 0x3A, 0x3E, 0x3C, 0x3F, 0x45, 0x46, 0x3D, 0x47, 0x4F, 0x56],
+        depth: 16, from: 0x680, to: 0x6C0, select: true},
+    //Silvermont, Airmont, Knights Landing and Knights Mill
+    LbrModel{models: &[0x37, 0x4A, 0x4C, 0x4D, 0x5A, 0x5D, 0x57, 0x85], depth: 8, from: 0x680, to: 0x6C0, select: true},
+    //Goldmont, Goldmont Plus, Tremont
+    LbrModel{models: &[0x5C, 0x5F, 0x7A, 0x86, 0x96, 0x9C], depth: 32, from: 0x680, to: 0x6C0, select: true},
+    //Skylake to Rocket Lake
+    LbrModel{models: &[0x4E, 0x5E, 0x55, 0x8E, 0x9E, 0x66, 0x7D, 0x7E, 0x6A, 0x6C, 0xA5, 0xA6, 0x8C, 0x8D, 0xA7],
+        depth: 32, from: 0x680, to: 0x6C0, select: true},
+];
+
+///Where the LBR stack of a processor lives and how its entries are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbrLayout {
+    ///Number of entries, a power of two.
+    pub depth: u8,
+    ///MSR_LASTBRANCH_0_FROM_IP.
+    pub from_msr: u32,
+    ///MSR_LASTBRANCH_0_TO_IP.
+    pub to_msr: u32,
+    ///MSR_LBR_INFO_0, for the formats keeping flags and cycles there.
+    pub info_msr: Option<u32>,
+    ///LBR format from IA32_PERF_CAPABILITIES, 0 without it.
+    pub format: u8,
+    ///MSR_LBR_SELECT exists.
+    pub has_select: bool,
+}
+
+impl LbrLayout {
+    ///Look the LBR stack of the processor up by family and model.
+    pub fn detect<B: PmuBackend>(ctrler:&PerfCounterControler<B>) -> Result<LbrLayout, ErrorMsg> {
+        let backend = ctrler.backend();
+        //Architectural LBRs (CPUID.07H:EDX[19]) use another set of MSRs. Leaves above the
+        //maximum basic leaf return the data of the highest one, so check it first.
+        if backend.cpuid(0x00, 0).eax >= 0x07 && backend.cpuid(0x07, 0).edx & (1<<19) != 0 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        let signature = CpuSignature::decode(backend.cpuid(0x01, 0).eax);
+        let format = match ctrler.get_capabilities().perf_capabilities {
+            Some(caps) => caps.lbr_format,
+            None => 0,
+        };
+        if signature.family != 6 || format > 7 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        let model = match LBR_MODELS.iter().find(|m| m.models.contains(&signature.model)) {
+            Some(model) => model,
+            None => return Err(ErrorMsg::UnsupportedFeature),
+        };
+        Ok(LbrLayout{
+            depth: model.depth,
+            from_msr: model.from,
+            to_msr: model.to,
+            info_msr: if format == 5 || format == 7 {Some(MSR_LBR_INFO_0)} else {None},
+            format: format,
+            has_select: model.select,
+        })
+    }
+
+    ///Read the stack, newest entry first. Unused (all zero) entries are skipped.
+    pub fn read<B: PmuBackend>(&self, ctrler:&PerfCounterControler<B>) -> LbrStack {
+        let backend = ctrler.backend();
+        let mask = self.depth as u64 - 1;
+        let tos = backend.rdmsr(MSR_LBR_TOS) & mask;
+        let mut stack = LbrStack::new();
+        for i in 0..self.depth as u64 {
+            let index = ((tos + self.depth as u64 - i) & mask) as u32;
+            let from = backend.rdmsr(self.from_msr + index);
+            let to = if self.format == 0 {0} else {backend.rdmsr(self.to_msr + index)};
+            if from == 0 && to == 0 {
+                continue;
+            }
+            let info = match self.info_msr {
+                Some(msr) => backend.rdmsr(msr + index),
+                None => 0,
+            };
+            stack.push(decode_entry(self.format, from, to, info));
+        }
+        stack
+    }
+
+    ///Let a stack frozen by a PMI record again.
+    pub fn unfreeze<B: PmuBackend>(&self, ctrler:&PerfCounterControler<B>) {
+        if ctrler.get_version_identifier() >= 4 {
+            ctrler.set_overflow_ctrl(GLOBAL_STATUS_LBR_FRZ);
+        } else {
+            //Before version 4 the PMI clears IA32_DEBUGCTL.LBR instead.
+            let backend = ctrler.backend();
+            backend.wrmsr(IA32_DEBUGCTL, backend.rdmsr(IA32_DEBUGCTL) | DEBUGCTL_LBR);
+        }
+    }
+}
+
+///One branch of the LBR stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LbrEntry {
+    pub from: u64,
+    pub to: u64,
+    ///None if the format does not record prediction.
+    pub mispredicted: Option<bool>,
+    ///The branch was in a TSX transaction.
+    pub in_tx: bool,
+    ///The branch is a TSX abort.
+    pub abort: bool,
+    ///Core cycles since the previous entry, if the format records them.
+    pub cycles: Option<u16>,
+}
+
+const ENTRY_FLAG_PREDICTION: u64 = 1<<0;
+const ENTRY_FLAG_MISPRED: u64 = 1<<1;
+const ENTRY_FLAG_IN_TX: u64 = 1<<2;
+const ENTRY_FLAG_ABORT: u64 = 1<<3;
+const ENTRY_FLAG_CYCLES: u64 = 1<<4;
+
+impl LbrEntry {
+    ///Pack everything but the addresses into one word, with the cycles in bits 31:16.
+    pub fn flag_bits(&self) -> u64 {
+        let mut bits = 0;
+        if let Some(mispredicted) = self.mispredicted {
+            bits |= ENTRY_FLAG_PREDICTION;
+            if mispredicted {
+                bits |= ENTRY_FLAG_MISPRED;
+            }
+        }
+        if self.in_tx {
+            bits |= ENTRY_FLAG_IN_TX;
+        }
+        if self.abort {
+            bits |= ENTRY_FLAG_ABORT;
+        }
+        if let Some(cycles) = self.cycles {
+            bits |= ENTRY_FLAG_CYCLES | (cycles as u64) << 16;
+        }
+        bits
+    }
+
+    ///Inverse of flag_bits().
+    pub fn from_flag_bits(from:u64, to:u64, bits:u64) -> LbrEntry {
+        LbrEntry{
+            from: from,
+            to: to,
+            mispredicted: if bits & ENTRY_FLAG_PREDICTION != 0 {Some(bits & ENTRY_FLAG_MISPRED != 0)} else {None},
+            in_tx: bits & ENTRY_FLAG_IN_TX != 0,
+            abort: bits & ENTRY_FLAG_ABORT != 0,
+            cycles: if bits & ENTRY_FLAG_CYCLES != 0 {Some((bits >> 16) as u16)} else {None},
+        }
+    }
+}
+
+//Drop the `flags` top bits of an address and sign extend it again.
+fn strip_flags(addr:u64, flags:u32) -> u64 {
+    (((addr << flags) as i64) >> flags) as u64
+}
+
+///Decode the raw FROM, TO and INFO MSRs of one entry in LBR format `format`.
+pub fn decode_entry(format:u8, from:u64, to:u64, info:u64) -> LbrEntry {
+    let mut entry = LbrEntry{from: from, to: to, ..LbrEntry::default()};
+    match format {
+        0 => {
+            entry.from = from & 0xFFFF_FFFF;
+            entry.to = from >> 32;
+        },
+        3 => {
+            entry.mispredicted = Some(from & LBR_INFO_MISPRED != 0);
+            entry.from = strip_flags(from, 1);
+        },
+        4 => {
+            entry.mispredicted = Some(from & LBR_INFO_MISPRED != 0);
+            entry.in_tx = from & LBR_INFO_IN_TX != 0;
+            entry.abort = from & LBR_INFO_ABORT != 0;
+            entry.from = strip_flags(from, 3);
+        },
+        5 | 7 => {
+            entry.mispredicted = Some(info & LBR_INFO_MISPRED != 0);
+            entry.in_tx = info & LBR_INFO_IN_TX != 0;
+            entry.abort = info & LBR_INFO_ABORT != 0;
+            entry.cycles = Some((info & LBR_INFO_CYCLES) as u16);
+            entry.from = strip_flags(from, 3);
+        },
+        6 => {
+            entry.mispredicted = Some(from & LBR_INFO_MISPRED != 0);
+            entry.from = strip_flags(from, 1);
+            entry.cycles = Some((to >> 48) as u16);
+            entry.to = strip_flags(to, 16);
+        },
+        _ => {},
+    }
+    entry
+}
+
+///Snapshot of the LBR stack, newest branch first.
+#[derive(Clone, Copy)]
+pub struct LbrStack {
+    entries: [LbrEntry; MAX_LBR_ENTRIES],
+    len: u8,
+}
+
+impl LbrStack {
+    pub const fn new() -> LbrStack {
+        const EMPTY: LbrEntry = LbrEntry{from: 0, to: 0, mispredicted: None, in_tx: false, abort: false, cycles: None};
+        LbrStack{entries: [EMPTY; MAX_LBR_ENTRIES], len: 0}
+    }
+
+    ///Append an entry. Returns false if the stack is full.
+    pub fn push(&mut self, entry:LbrEntry) -> bool {
+        if self.len as usize == MAX_LBR_ENTRIES {
+            return false;
+        }
+        self.entries[self.len as usize] = entry;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[LbrEntry] {
+        &self.entries[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for LbrStack {
+    fn default() -> LbrStack {
+        LbrStack::new()
+    }
+}
+
+impl PartialEq for LbrStack {
+    fn eq(&self, other:&LbrStack) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for LbrStack {}
+
+impl core::fmt::Debug for LbrStack {
+    fn fmt(&self, f:&mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.as_slice().iter()).finish()
+    }
+}
+
+///Branches to record. MSR_LBR_SELECT takes the complement: a set bit filters the branch type out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LbrFilter {
+    ///Branches ending in ring 0.
+    pub kernel: bool,
+    ///Branches ending in ring 1 to 3.
+    pub user: bool,
+    pub conditional: bool,
+    pub near_rel_call: bool,
+    pub near_ind_call: bool,
+    pub near_ret: bool,
+    pub near_ind_jmp: bool,
+    pub near_rel_jmp: bool,
+    pub far_branch: bool,
+    ///Call stack mode: returns pop the matching call, leaving the current call chain (Haswell and later).
+    pub call_stack: bool,
+}
+
+impl LbrFilter {
+    ///Every branch at every privilege level.
+    pub const ALL: LbrFilter = LbrFilter{
+        kernel: true,
+        user: true,
+        conditional: true,
+        near_rel_call: true,
+        near_ind_call: true,
+        near_ret: true,
+        near_ind_jmp: true,
+        near_rel_jmp: true,
+        far_branch: true,
+        call_stack: false,
+    };
+
+    ///Calls and returns only, in call stack mode.
+    pub const CALL_STACK: LbrFilter = LbrFilter{
+        conditional: false,
+        near_ind_jmp: false,
+        near_rel_jmp: false,
+        far_branch: false,
+        call_stack: true,
+        ..LbrFilter::ALL
+    };
+
+    ///Value of MSR_LBR_SELECT.
+    pub fn select_bits(&self) -> u64 {
+        let filtered = [!self.kernel, !self.user, !self.conditional, !self.near_rel_call, !self.near_ind_call,
+            !self.near_ret, !self.near_ind_jmp, !self.near_rel_jmp, !self.far_branch, self.call_stack];
+        filtered.iter().enumerate().fold(0, |bits, (i, set)| if *set {bits | 1 << i} else {bits})
+    }
+}
+
+///LBR control of one controler's processor.
+pub struct Lbr<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    layout: LbrLayout,
+}
+
+impl<'a, B: PmuBackend> Lbr<'a, B> {
+    pub fn new(ctrler:&'a PerfCounterControler<B>) -> Result<Lbr<'a, B>, ErrorMsg> {
+        Ok(Lbr{
+            ctrler: ctrler,
+            layout: LbrLayout::detect(ctrler)?,
+        })
+    }
+
+    pub fn layout(&self) -> LbrLayout {
+        self.layout
+    }
+
+    ///Program MSR_LBR_SELECT. Without it only LbrFilter::ALL is accepted.
+    pub fn set_filter(&self, filter:&LbrFilter) -> Result<(), ErrorMsg> {
+        if !self.layout.has_select {
+            return if *filter == LbrFilter::ALL {Ok(())} else {Err(ErrorMsg::UnsupportedFeature)};
+        }
+        if filter.call_stack && self.layout.format < 4 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        self.ctrler.backend().wrmsr(MSR_LBR_SELECT, filter.select_bits());
+        Ok(())
+    }
+
+    ///Start recording. With `freeze_on_pmi` the stack stops at every PMI until unfreeze().
+    pub fn enable(&self, freeze_on_pmi:bool) {
+        let backend = self.ctrler.backend();
+        let mut debugctl = backend.rdmsr(IA32_DEBUGCTL) | DEBUGCTL_LBR;
+        if freeze_on_pmi {
+            debugctl |= DEBUGCTL_FREEZE_LBRS_ON_PMI;
+        } else {
+            debugctl &= !DEBUGCTL_FREEZE_LBRS_ON_PMI;
+        }
+        backend.wrmsr(IA32_DEBUGCTL, debugctl);
+    }
+
+    pub fn disable(&self) {
+        let backend = self.ctrler.backend();
+        backend.wrmsr(IA32_DEBUGCTL, backend.rdmsr(IA32_DEBUGCTL) & !(DEBUGCTL_LBR | DEBUGCTL_FREEZE_LBRS_ON_PMI));
+    }
+
+    ///Zero every entry, e.g. before a new measurement.
+    pub fn clear(&self) {
+        let backend = self.ctrler.backend();
+        for i in 0..self.layout.depth as u32 {
+            backend.wrmsr(self.layout.from_msr + i, 0);
+            if self.layout.format != 0 {
+                backend.wrmsr(self.layout.to_msr + i, 0);
+            }
+            if let Some(msr) = self.layout.info_msr {
+                backend.wrmsr(msr + i, 0);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> LbrStack {
+        self.layout.read(self.ctrler)
+    }
+
+    pub fn unfreeze(&self) {
+        self.layout.unfreeze(self.ctrler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86::cpuid::CpuIdResult;
+    use crate::x86_intel::simulator::SimulatedPmu;
+
+    const KERNEL: u64 = 0xFFFF_8000_0000_1000;
+    const USER: u64 = 0x40_1000;
+
+    fn entry(from:u64, to:u64, mispredicted:Option<bool>, in_tx:bool, abort:bool, cycles:Option<u16>) -> LbrEntry {
+        LbrEntry{from: from, to: to, mispredicted: mispredicted, in_tx: in_tx, abort: abort, cycles: cycles}
+    }
+
+    #[test]
+    fn decode_every_format() {
+        let table = [
+            (0, 0x0040_2000_0040_1000, 0, 0, entry(USER, 0x40_2000, None, false, false, None)),
+            (1, KERNEL, USER, 0, entry(KERNEL, USER, None, false, false, None)),
+            (2, USER, KERNEL, 0, entry(USER, KERNEL, None, false, false, None)),
+            //The flags replace the top bits, which are sign extended again.
+            (3, KERNEL, USER, 0, entry(KERNEL, USER, Some(true), false, false, None)),
+            (3, KERNEL & !(1 << 63), USER, 0, entry(KERNEL, USER, Some(false), false, false, None)),
+            (3, USER, USER, 0, entry(USER, USER, Some(false), false, false, None)),
+            (4, 0b101 << 61 | (KERNEL & !(0b111 << 61)), USER, 0, entry(KERNEL, USER, Some(true), false, true, None)),
+            (4, 0b010 << 61 | USER, USER, 0, entry(USER, USER, Some(false), true, false, None)),
+            (5, KERNEL & !(0b111 << 61), USER, LBR_INFO_MISPRED | LBR_INFO_ABORT | 0x1234, entry(KERNEL, USER, Some(true), false, true, Some(0x1234))),
+            (5, USER, KERNEL, LBR_INFO_IN_TX, entry(USER, KERNEL, Some(false), true, false, Some(0))),
+            (7, USER, USER, LBR_INFO_MISPRED | 0xFFFF, entry(USER, USER, Some(true), false, false, Some(0xFFFF))),
+            //Bits 63:48 of TO are the cycles.
+            (6, 1 << 63 | USER, 0x0042 << 48 | 0x7FFF_0000_2000, 0, entry(USER, 0x7FFF_0000_2000, Some(true), false, false, Some(0x42))),
+            (6, KERNEL & !(1 << 63), 0xFFFF << 48 | 0x8000_0000_2000, 0, entry(KERNEL, 0xFFFF_8000_0000_2000, Some(false), false, false, Some(0xFFFF))),
+        ];
+        for (format, from, to, info, expected) in table {
+            assert_eq!(decode_entry(format, from, to, info), expected, "format {}, from {:#x}", format, from);
+            assert_eq!(LbrEntry::from_flag_bits(expected.from, expected.to, expected.flag_bits()), expected);
+        }
+    }
+
+    #[test]
+    fn select_bits_are_the_complement() {
+        assert_eq!(LbrFilter::ALL.select_bits(), 0);
+        //Conditional, indirect and relative jumps and far branches filtered out, call stack mode on.
+        assert_eq!(LbrFilter::CALL_STACK.select_bits(), 1 << 2 | 1 << 6 | 1 << 7 | 1 << 8 | 1 << 9);
+        assert_eq!(LbrFilter{user: false, near_ret: false, ..LbrFilter::ALL}.select_bits(), 1 << 1 | 1 << 5);
+    }
+
+    fn controler(signature:u32, lbr_format:u64) -> PerfCounterControler<SimulatedPmu> {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax: signature, ebx: 0, ecx: 0, edx: 0}).unwrap();
+        pmu.set_perf_capabilities(1 << 13 | lbr_format).unwrap();
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        ctrler
+    }
+
+    #[test]
+    fn detect_by_model() {
+        let skylake = LbrLayout::detect(&controler(0x506E3, 5)).unwrap();
+        assert_eq!(skylake, LbrLayout{depth: 32, from_msr: 0x680, to_msr: 0x6C0, info_msr: Some(MSR_LBR_INFO_0), format: 5, has_select: true});
+        let core2 = LbrLayout::detect(&controler(0x10676, 1)).unwrap();
+        assert_eq!((core2.depth, core2.from_msr, core2.info_msr, core2.has_select), (4, 0x40, None, false));
+        assert!(matches!(LbrLayout::detect(&controler(0x506E3, 8)), Err(ErrorMsg::UnsupportedFeature)));
+        //Unknown model, and family 15.
+        assert!(matches!(LbrLayout::detect(&controler(0x906F0, 5)), Err(ErrorMsg::UnsupportedFeature)));
+        assert!(matches!(LbrLayout::detect(&controler(0xF4A, 0)), Err(ErrorMsg::UnsupportedFeature)));
+    }
+
+    #[test]
+    fn detect_rejects_architectural_lbrs() {
+        let ctrler = controler(0x806C1, 5);
+        //Below the maximum basic leaf, leaf 07H is not looked at.
+        ctrler.backend().set_cpuid(0x07, 0, CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 1 << 19}).unwrap();
+        assert!(LbrLayout::detect(&ctrler).is_ok());
+        ctrler.backend().set_cpuid(0x00, 0, CpuIdResult{eax: 0x1B, ebx: 0, ecx: 0, edx: 0}).unwrap();
+        assert!(matches!(LbrLayout::detect(&ctrler), Err(ErrorMsg::UnsupportedFeature)));
+    }
+
+    #[test]
+    fn read_starts_at_the_top_of_stack() {
+        let ctrler = controler(0x506E3, 5);
+        let pmu = ctrler.backend();
+        let layout = LbrLayout{depth: 4, ..LbrLayout::detect(&ctrler).unwrap()};
+        for index in [0, 1, 3] {
+            pmu.wrmsr(0x680 + index, USER + index as u64);
+            pmu.wrmsr(0x6C0 + index, 0x50_0000);
+            pmu.wrmsr(MSR_LBR_INFO_0 + index, index as u64);
+        }
+        //Unused bits of MSR_LBR_TOS are ignored.
+        pmu.wrmsr(MSR_LBR_TOS, 4 + 1);
+        let stack = layout.read(&ctrler);
+        let order:[(u64, Option<u16>); 3] = core::array::from_fn(|i| (stack.as_slice()[i].from, stack.as_slice()[i].cycles));
+        assert_eq!(stack.len(), 3);
+        assert_eq!(order, [(USER + 1, Some(1)), (USER, Some(0)), (USER + 3, Some(3))]);
+    }
+}
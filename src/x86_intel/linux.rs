@@ -0,0 +1,268 @@
+//! Linux user-space backends, available with the `std` feature.
+//!
+//! LinuxMsrPmu implements PmuBackend on top of /dev/cpu/N/msr and /dev/cpu/N/cpuid (needs the msr
+//! and cpuid modules and CAP_SYS_RAWIO), so a PerfCounterControler can read the global control
+//! registers of any CPU from a workstation.
+//!
+//! PerfEventCounter implements AbstractPerfCounter on top of perf_event_open(2).
+//! It is built from a PerfCounter so the same event encoding is measured on Linux and on bare metal.
+//!
+
+extern crate std;
+
+use std::{cell::Cell, fs::{File, OpenOptions}, os::unix::fs::FileExt, format, io};
+use x86::{cpuid::{native_cpuid, CpuIdResult}, msr::*, time::rdtsc};
+use crate::{AbstractPerfCounter, ErrorMsg};
+use super::{backend::PmuBackend, PerfCounter};
+
+const PERF_TYPE_RAW: u32 = 4;
+const PERF_ATTR_SIZE_VER5: u32 = 112;
+const PERF_EVENT_IOC_ENABLE: u64 = 0x2400;
+const PERF_EVENT_IOC_DISABLE: u64 = 0x2401;
+const PERF_EVENT_IOC_RESET: u64 = 0x2403;
+
+const ATTR_DISABLED: u64 = 1<<0;
+const ATTR_EXCLUDE_USER: u64 = 1<<4;
+const ATTR_EXCLUDE_KERNEL: u64 = 1<<5;
+const ATTR_EXCLUDE_HV: u64 = 1<<6;
+
+///Backend reading and writing MSRs through /dev/cpu/N/msr and running CPUID through /dev/cpu/N/cpuid.
+///
+///RDPMC is emulated by reading the counter MSRs and the local APIC is not accessible:
+/// LVT writes are dropped and reads return a masked entry.
+pub struct LinuxMsrPmu {
+    msr: File,
+    cpuid: Option<File>,
+    faults: Cell<u64>,
+}
+
+impl LinuxMsrPmu {
+    ///Open the MSR and CPUID devices of logical CPU `cpu`.
+    ///
+    /// Without the cpuid module, CPUID runs on whatever CPU the calling thread is on. On hybrid
+    /// processors the leaves then describe another core type unless the thread is pinned to `cpu`.
+    pub fn open(cpu:u32) -> io::Result<LinuxMsrPmu> {
+        let msr = OpenOptions::new().read(true).write(true).open(format!("/dev/cpu/{}/msr", cpu))?;
+        let cpuid = File::open(format!("/dev/cpu/{}/cpuid", cpu)).ok();
+        Ok(LinuxMsrPmu{
+            msr: msr,
+            cpuid: cpuid,
+            faults: Cell::new(0),
+        })
+    }
+
+    ///Number of MSR accesses the kernel rejected.
+    pub fn faults(&self) -> u64 {
+        self.faults.get()
+    }
+}
+
+impl PmuBackend for LinuxMsrPmu {
+    fn rdmsr(&self, msr:u32) -> u64 {
+        let mut buf = [0u8; 8];
+        match self.msr.read_exact_at(&mut buf, msr as u64) {
+            Ok(()) => u64::from_le_bytes(buf),
+            Err(_) => {
+                self.faults.set(self.faults.get() + 1);
+                0
+            }
+        }
+    }
+
+    fn wrmsr(&self, msr:u32, value:u64) {
+        if self.msr.write_all_at(&value.to_le_bytes(), msr as u64).is_err() {
+            self.faults.set(self.faults.get() + 1);
+        }
+    }
+
+    fn cpuid(&self, leaf:u32, subleaf:u32) -> CpuIdResult {
+        let device = match &self.cpuid {
+            Some(device) => device,
+            None => return native_cpuid::cpuid_count(leaf, subleaf),
+        };
+        //The file offset selects the leaf in bits 31:0 and the subleaf in bits 63:32.
+        let mut buf = [0u8; 16];
+        if device.read_exact_at(&mut buf, (subleaf as u64) << 32 | leaf as u64).is_err() {
+            self.faults.set(self.faults.get() + 1);
+            return CpuIdResult{eax: 0, ebx: 0, ecx: 0, edx: 0};
+        }
+        let reg = |i:usize| u32::from_le_bytes([buf[4*i], buf[4*i+1], buf[4*i+2], buf[4*i+3]]);
+        CpuIdResult{eax: reg(0), ebx: reg(1), ecx: reg(2), edx: reg(3)}
+    }
+
+    fn rdpmc(&self, counter:u32) -> u64 {
+        if counter & (1<<30) != 0 {
+            self.rdmsr(IA32_FIXED_CTR0 + (counter & 0x3FFF_FFFF))
+        } else {
+            self.rdmsr(IA32_PMC0 + counter)
+        }
+    }
+
+    fn rdtsc(&self) -> u64 {
+        unsafe{ rdtsc() }
+    }
+
+    fn read_lvt_perfmon(&self) -> u32 {
+        1<<16
+    }
+
+    fn write_lvt_perfmon(&self, _value:u32) {}
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    reserved: u16,
+}
+
+///A counter opened through perf_event_open(2).
+pub struct PerfEventCounter {
+    fd: i32,
+}
+
+impl PerfEventCounter {
+    ///Open a perf event counting the event `counter` was built for.
+    ///
+    /// `pid` and `cpu` follow perf_event_open(2): pid 0 and cpu -1 measure the calling thread on any CPU.
+    /// The counter is created disabled; call start() to begin counting.
+    /// Fails like PerfCounter::perf_raw_config() for events perf cannot express.
+    pub fn from_perf_counter<B: PmuBackend>(counter:&PerfCounter<B>, pid:i32, cpu:i32) -> Result<PerfEventCounter,ErrorMsg>{
+        let attr = Self::attr_of(counter)?;
+        let fd = unsafe {
+            libc::syscall(libc::SYS_perf_event_open, &attr as *const PerfEventAttr, pid, cpu, -1 as libc::c_int, 0 as libc::c_ulong)
+        };
+        if fd < 0 {
+            return Err(ErrorMsg::OsError(io::Error::last_os_error().raw_os_error().unwrap_or(0)));
+        }
+        Ok(PerfEventCounter{fd: fd as i32})
+    }
+
+    fn attr_of<B: PmuBackend>(counter:&PerfCounter<B>) -> Result<PerfEventAttr,ErrorMsg>{
+        let raw = counter.perf_raw_config()?;
+        let mut flags = ATTR_DISABLED | ATTR_EXCLUDE_HV;
+        if !raw.kernel {
+            flags |= ATTR_EXCLUDE_KERNEL;
+        }
+        if !raw.user {
+            flags |= ATTR_EXCLUDE_USER;
+        }
+        Ok(PerfEventAttr{
+            type_: PERF_TYPE_RAW,
+            size: PERF_ATTR_SIZE_VER5,
+            config: raw.config,
+            config1: raw.config1,
+            flags: flags,
+            ..Default::default()
+        })
+    }
+
+    fn ioctl(&self, request:u64) -> Result<(), ErrorMsg> {
+        if unsafe { libc::ioctl(self.fd, request as _, 0) } < 0 {
+            return Err(ErrorMsg::OsError(io::Error::last_os_error().raw_os_error().unwrap_or(0)));
+        }
+        Ok(())
+    }
+}
+
+impl AbstractPerfCounter for PerfEventCounter {
+    fn reset(&self) -> Result<(), ErrorMsg> {
+        self.ioctl(PERF_EVENT_IOC_RESET)
+    }
+
+    fn start(&self) -> Result<(), ErrorMsg> {
+        self.ioctl(PERF_EVENT_IOC_ENABLE)
+    }
+
+    fn stop(&self) -> Result<(), ErrorMsg> {
+        self.ioctl(PERF_EVENT_IOC_DISABLE)
+    }
+
+    fn read(&mut self) -> Result<u64, ErrorMsg> {
+        let mut value:u64 = 0;
+        let n = unsafe { libc::read(self.fd, &mut value as *mut u64 as *mut libc::c_void, 8) };
+        if n != 8 {
+            return Err(ErrorMsg::OsError(io::Error::last_os_error().raw_os_error().unwrap_or(0)));
+        }
+        Ok(value)
+    }
+}
+
+impl Drop for PerfEventCounter {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::globle_ctrl::PerfCounterControler;
+    use crate::x86_intel::lookup::{CpuModel, EventTable};
+    use crate::x86_intel::capabilities::CpuSignature;
+    use crate::x86_intel::simulator::SimulatedPmu;
+
+    fn controler() -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        ctrler
+    }
+
+    #[test]
+    fn attr_of_a_general_counter() {
+        let ctrler = controler();
+        let mut counter = PerfCounter::new(&ctrler);
+        counter.build_general_from_raw(0xA3, 0x04, true, true, 4, true, 1).unwrap();
+        counter.exnclude_os();
+        let attr = PerfEventCounter::attr_of(&counter).unwrap();
+        assert_eq!(attr.type_, PERF_TYPE_RAW);
+        assert_eq!(attr.size, PERF_ATTR_SIZE_VER5);
+        //The enable, interrupt and ring bits are left to perf.
+        assert_eq!(attr.config, 0x0404_04A3);
+        assert_eq!(attr.config1, 0);
+        assert_eq!(attr.flags, ATTR_DISABLED | ATTR_EXCLUDE_HV | ATTR_EXCLUDE_KERNEL);
+    }
+
+    #[test]
+    fn attr_of_a_fixed_counter() {
+        let ctrler = controler();
+        let mut counter = PerfCounter::new(&ctrler);
+        counter.build_fixed(2, false).unwrap();
+        counter.exclude_user();
+        let attr = PerfEventCounter::attr_of(&counter).unwrap();
+        assert_eq!(attr.config, 0x0300);
+        assert_eq!(attr.flags, ATTR_DISABLED | ATTR_EXCLUDE_HV | ATTR_EXCLUDE_USER);
+    }
+
+    #[test]
+    fn offcore_response_mask_goes_to_config1() {
+        let ctrler = controler();
+        let table = EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap();
+        let event = table.lookup("OFFCORE_RESPONSE.OTHER.L3_MISS.ANY_SNOOP").unwrap();
+        let mut counter = PerfCounter::new(&ctrler);
+        counter.build_from_intel_hw_event_alternative(event, 0, 1).unwrap();
+        let attr = PerfEventCounter::attr_of(&counter).unwrap();
+        assert_eq!(attr.config, 0x01BB);
+        assert_eq!(attr.config1, 0x3F_FC40_8000);
+        //perf has no config for other auxiliary MSRs.
+        counter.aux_msr = Some((0x1AD, 1));
+        assert!(matches!(PerfEventCounter::attr_of(&counter), Err(ErrorMsg::UnsupportedEvent)));
+    }
+}
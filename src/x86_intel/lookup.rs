@@ -0,0 +1,332 @@
+//! Find events of the running processor by name.
+//!
+//! CpuModel::detect() reads the vendor and the processor signature through CPUID, and EventTable
+//! selects the x86 crate's event table of that microarchitecture (keyed like "GenuineIntel-6-55").
+//! Names are matched ignoring ASCII case; a name that is not found comes back with the closest
+//! names of the table, ranked by edit distance, to catch typos and partial names.
+//!
+//! The descriptions are 'static, so a table can serve as the resolver of a metrics::MetricGroup:
+//! `MetricGroup::new(ctrler, &metrics, |name| table.lookup(name).ok())`.
+//!
+
+use core::fmt;
+use x86::perfcnt::intel::{events::COUNTER_MAP, EventDescription};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, capabilities::CpuSignature};
+
+///Most suggestions returned for an unknown name.
+pub const MAX_SUGGESTIONS: usize = 4;
+///Longer names are only compared on their first MAX_NAME_LEN bytes.
+pub const MAX_NAME_LEN: usize = 96;
+
+const INTEL_VENDOR: &[u8; 12] = b"GenuineIntel";
+const MODEL_KEY_LEN: usize = 24;
+
+///Vendor and signature of a processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuModel {
+    ///CPUID.00H vendor string, from EBX, EDX and ECX.
+    pub vendor: [u8; 12],
+    pub signature: CpuSignature,
+}
+
+impl CpuModel {
+    pub fn detect<B: PmuBackend>(backend:&B) -> CpuModel {
+        let leaf = backend.cpuid(0x00, 0);
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+        CpuModel{vendor:vendor, signature:CpuSignature::decode(backend.cpuid(0x01, 0).eax)}
+    }
+
+    pub fn vendor_str(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("")
+    }
+}
+
+//Key of the event table in COUNTER_MAP, e.g. "GenuineIntel-6-55".
+#[derive(Clone, Copy)]
+struct ModelKey {
+    buffer: [u8; MODEL_KEY_LEN],
+    len: usize,
+}
+
+impl ModelKey {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for ModelKey {
+    fn write_str(&mut self, s:&str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > MODEL_KEY_LEN {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ModelKey {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+///Closest names to an unknown one, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Suggestions {
+    names: [&'static str; MAX_SUGGESTIONS],
+    scores: [usize; MAX_SUGGESTIONS],
+    len: usize,
+}
+
+impl Suggestions {
+    const fn new() -> Suggestions {
+        Suggestions{names: [""; MAX_SUGGESTIONS], scores: [0; MAX_SUGGESTIONS], len: 0}
+    }
+
+    pub fn as_slice(&self) -> &[&'static str] {
+        &self.names[..self.len]
+    }
+
+    //Keep `name` if it scores better than the worst kept one. Ties keep the earlier name.
+    fn offer(&mut self, name:&'static str, score:usize) {
+        let mut at = self.len;
+        while at > 0 && self.scores[at - 1] > score {
+            at -= 1;
+        }
+        if at == MAX_SUGGESTIONS {
+            return;
+        }
+        let end = if self.len == MAX_SUGGESTIONS { MAX_SUGGESTIONS - 1 } else { self.len };
+        for i in (at..end).rev() {
+            self.names[i + 1] = self.names[i];
+            self.scores[i + 1] = self.scores[i];
+        }
+        self.names[at] = name;
+        self.scores[at] = score;
+        self.len = (self.len + 1).min(MAX_SUGGESTIONS);
+    }
+}
+
+///Why a name could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupError<'n> {
+    ///The x86 crate has no event table for this processor.
+    UnsupportedModel(CpuModel),
+    ///The table of the processor has no event with this name.
+    UnknownEvent{name:&'n str, suggestions:Suggestions},
+}
+
+impl<'n> fmt::Display for LookupError<'n> {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::UnsupportedModel(model) => write!(f, "no event table for {} family {:#X} model {:#X}",
+                model.vendor_str(), model.signature.family, model.signature.model),
+            LookupError::UnknownEvent{name, suggestions} => {
+                write!(f, "unknown event {}", name)?;
+                for (i, suggestion) in suggestions.as_slice().iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { ", did you mean " } else { ", " }, suggestion)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl<'n> From<LookupError<'n>> for ErrorMsg {
+    fn from(e:LookupError<'n>) -> ErrorMsg {
+        match e {
+            LookupError::UnsupportedModel(_) => ErrorMsg::UnsupportedFeature,
+            LookupError::UnknownEvent{..} => ErrorMsg::UnsupportedEvent,
+        }
+    }
+}
+
+//Levenshtein distance ignoring ASCII case, on the first MAX_NAME_LEN bytes of each name.
+fn edit_distance(a:&[u8], b:&[u8]) -> usize {
+    let a = &a[..a.len().min(MAX_NAME_LEN)];
+    let b = &b[..b.len().min(MAX_NAME_LEN)];
+    let mut prev = [0usize; MAX_NAME_LEN + 1];
+    let mut cur = [0usize; MAX_NAME_LEN + 1];
+    for (j, d) in prev.iter_mut().enumerate().take(b.len() + 1) {
+        *d = j;
+    }
+    for (i, ca) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca.eq_ignore_ascii_case(cb) { 0 } else { 1 };
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        core::mem::swap(&mut prev, &mut cur);
+    }
+    prev[b.len()]
+}
+
+//`needle` occurs in `haystack`, ignoring ASCII case.
+fn contains_ignore_case(haystack:&[u8], needle:&[u8]) -> bool {
+    needle.len() <= haystack.len() && haystack.windows(needle.len()).any(|w| w.eq_ignore_ascii_case(needle))
+}
+
+///Events of one microarchitecture.
+#[derive(Debug, Clone, Copy)]
+pub struct EventTable {
+    model: CpuModel,
+    key: ModelKey,
+}
+
+impl EventTable {
+    ///Table of the processor the backend runs on.
+    pub fn detect<B: PmuBackend>(backend:&B) -> Result<EventTable, LookupError<'static>> {
+        EventTable::for_model(CpuModel::detect(backend))
+    }
+
+    ///Table of `model`. Fails with UnsupportedModel for other vendors and models the x86 crate does not describe.
+    pub fn for_model(model:CpuModel) -> Result<EventTable, LookupError<'static>> {
+        let mut key = ModelKey{buffer: [0; MODEL_KEY_LEN], len: 0};
+        //The tables are keyed with the base family and the folded model.
+        let family = if model.signature.family > 0xF { 0xF } else { model.signature.family };
+        if &model.vendor != INTEL_VENDOR || fmt::write(&mut key, format_args!("GenuineIntel-{}-{:02X}", family, model.signature.model)).is_err() {
+            return Err(LookupError::UnsupportedModel(model));
+        }
+        if COUNTER_MAP.get(key.as_str()).is_none() {
+            return Err(LookupError::UnsupportedModel(model));
+        }
+        Ok(EventTable{model:model, key:key})
+    }
+
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
+    ///All events of the table, in no particular order.
+    pub fn events(&self) -> impl Iterator<Item = &'static EventDescription<'static>> {
+        COUNTER_MAP.get(self.key.as_str()).into_iter().flat_map(|table| table.values())
+    }
+
+    ///Event called `name`, ignoring ASCII case.
+    pub fn lookup<'n>(&self, name:&'n str) -> Result<&'static EventDescription<'static>, LookupError<'n>> {
+        if let Some(event) = COUNTER_MAP.get(self.key.as_str()).and_then(|table| table.get(name)) {
+            return Ok(event);
+        }
+        match self.events().find(|e| e.event_name.eq_ignore_ascii_case(name)) {
+            Some(event) => Ok(event),
+            None => Err(LookupError::UnknownEvent{name:name, suggestions:self.suggest(name)}),
+        }
+    }
+
+    ///Names of the table closest to `name`, best first.
+    ///
+    /// Names containing `name` rank first, then names within an edit distance of a third of
+    /// the length of `name` (at least 2).
+    pub fn suggest(&self, name:&str) -> Suggestions {
+        let query = name.as_bytes();
+        let limit = (query.len() / 3).max(2);
+        let mut suggestions = Suggestions::new();
+        for event in self.events() {
+            let candidate = event.event_name.as_bytes();
+            if !query.is_empty() && contains_ignore_case(candidate, query) {
+                //Rank by how much of the name is left over, ahead of every edit distance.
+                suggestions.offer(event.event_name, (candidate.len() - query.len()).min(MAX_NAME_LEN - 1));
+                continue;
+            }
+            let distance = edit_distance(query, candidate);
+            if distance <= limit {
+                suggestions.offer(event.event_name, MAX_NAME_LEN + distance);
+            }
+        }
+        suggestions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skylake() -> EventTable {
+        EventTable::for_model(CpuModel{vendor: *INTEL_VENDOR, signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap()
+    }
+
+    struct Text {
+        buf: [u8; 256],
+        len: usize,
+    }
+
+    impl fmt::Write for Text {
+        fn write_str(&mut self, s:&str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.buf.len() {
+                return Err(fmt::Error);
+            }
+            self.buf[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    impl Text {
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(&self.buf[..self.len]).unwrap()
+        }
+    }
+
+    fn display(e:&LookupError) -> Text {
+        let mut text = Text{buf: [0; 256], len: 0};
+        fmt::write(&mut text, format_args!("{}", e)).unwrap();
+        text
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        let table = skylake();
+        assert_eq!(table.lookup("inst_Retired.any").unwrap().event_name, "INST_RETIRED.ANY");
+        assert_eq!(table.lookup("INST_RETIRED.ANY").unwrap().event_name, "INST_RETIRED.ANY");
+    }
+
+    #[test]
+    fn unknown_names_come_with_suggestions() {
+        let table = skylake();
+        let typo = table.lookup("INST_RETIRD.ANY").unwrap_err();
+        assert_eq!(typo, LookupError::UnknownEvent{name: "INST_RETIRD.ANY", suggestions: table.suggest("INST_RETIRD.ANY")});
+        assert_eq!(table.suggest("INST_RETIRD.ANY").as_slice(), ["INST_RETIRED.ANY", "INST_RETIRED.ANY_P"]);
+        //Names containing a partial name, the shortest first.
+        assert_eq!(table.suggest("total_cycles").as_slice(), ["UOPS_RETIRED.TOTAL_CYCLES", "INST_RETIRED.TOTAL_CYCLES_PS"]);
+        assert_eq!(display(&typo).as_str(), "unknown event INST_RETIRD.ANY, did you mean INST_RETIRED.ANY, INST_RETIRED.ANY_P");
+        assert!(matches!(ErrorMsg::from(typo), ErrorMsg::UnsupportedEvent));
+    }
+
+    #[test]
+    fn offer_keeps_the_best_scores() {
+        let mut suggestions = Suggestions::new();
+        suggestions.offer("a", 5);
+        suggestions.offer("b", 3);
+        //Ties rank after the names already kept.
+        suggestions.offer("c", 5);
+        suggestions.offer("d", 1);
+        assert_eq!(suggestions.as_slice(), ["d", "b", "a", "c"]);
+        //The worst is evicted, the later of a tie first.
+        suggestions.offer("e", 4);
+        assert_eq!(suggestions.as_slice(), ["d", "b", "e", "a"]);
+        suggestions.offer("f", 5);
+        suggestions.offer("g", 9);
+        assert_eq!(suggestions.as_slice(), ["d", "b", "e", "a"]);
+        suggestions.offer("h", 0);
+        assert_eq!(suggestions.as_slice(), ["h", "d", "b", "e"]);
+    }
+
+    #[test]
+    fn unsupported_models() {
+        let amd = CpuModel{vendor: *b"AuthenticAMD", signature: CpuSignature{family: 0x19, model: 0x21, stepping: 0}};
+        let err = EventTable::for_model(amd).unwrap_err();
+        assert_eq!(err, LookupError::UnsupportedModel(amd));
+        assert_eq!(display(&err).as_str(), "no event table for AuthenticAMD family 0x19 model 0x21");
+        let unknown = CpuModel{vendor: *INTEL_VENDOR, signature: CpuSignature{family: 6, model: 0xFF, stepping: 0}};
+        let err = EventTable::for_model(unknown).unwrap_err();
+        assert_eq!(display(&err).as_str(), "no event table for GenuineIntel family 0x6 model 0xFF");
+        assert!(matches!(ErrorMsg::from(err), ErrorMsg::UnsupportedFeature));
+    }
+}
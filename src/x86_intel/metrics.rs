@@ -0,0 +1,451 @@
+//! Derived metrics over event counts.
+//!
+//! A Metric is a named expression over events, given by name, and constants. It is declared as a
+//! constant, e.g. IPC below is INST_RETIRED.ANY / CPU_CLK_UNHALTED.THREAD:
+//!
+//! ```ignore
+//! pub const IPC: Metric = Metric{name: "IPC", expr: Expr::Div(&Expr::Event(INST_RETIRED), &Expr::Event(CYCLES))};
+//! ```
+//!
+//! MetricGroup collects the events a set of metrics needs, resolves them to EventDescriptions,
+//! schedules them onto the PMCs and counts them as one PerfCounterGroup. evaluate() computes a metric
+//! from a MetricSnapshot with exact integer fractions and rounds once at the end to a MetricValue with
+//! six decimal places, so the result does not depend on the order of the operations.
+//!
+
+use core::fmt;
+use x86::perfcnt::intel::{EventDescription, Counter};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler, group::PerfCounterGroup, PerfCounter};
+use super::allocator::{self, UnschedulableEvent, MAX_GENERAL_PMC, MAX_FIXED_PMC};
+
+///Distinct events a MetricGroup or MetricSnapshot holds.
+pub const MAX_METRIC_EVENTS: usize = MAX_GENERAL_PMC + MAX_FIXED_PMC;
+///Distinct events one metric may use.
+pub const MAX_METRIC_INPUTS: usize = 8;
+
+///Expression over event counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expr {
+    ///Count of the event with this name.
+    Event(&'static str),
+    Const(u64),
+    Add(&'static Expr, &'static Expr),
+    ///May go below zero; MetricValue is signed.
+    Sub(&'static Expr, &'static Expr),
+    Mul(&'static Expr, &'static Expr),
+    ///Undefined if the divisor is zero.
+    Div(&'static Expr, &'static Expr),
+}
+
+impl Expr {
+    ///Call `f` with the name of every event in the expression, in order and with repetitions.
+    pub fn for_each_event(&self, f:&mut impl FnMut(&'static str)) {
+        match *self {
+            Expr::Event(name) => f(name),
+            Expr::Const(_) => {},
+            Expr::Add(a, b) | Expr::Sub(a, b) | Expr::Mul(a, b) | Expr::Div(a, b) => {
+                a.for_each_event(f);
+                b.for_each_event(f);
+            },
+        }
+    }
+
+    fn eval(&self, snapshot:&MetricSnapshot) -> Option<Ratio> {
+        match *self {
+            Expr::Event(name) => Some(Ratio::integer(snapshot.value(name)? as i128)),
+            Expr::Const(value) => Some(Ratio::integer(value as i128)),
+            Expr::Add(a, b) => a.eval(snapshot)?.add(b.eval(snapshot)?),
+            Expr::Sub(a, b) => a.eval(snapshot)?.add(b.eval(snapshot)?.neg()),
+            Expr::Mul(a, b) => a.eval(snapshot)?.mul(b.eval(snapshot)?),
+            Expr::Div(a, b) => a.eval(snapshot)?.div(b.eval(snapshot)?),
+        }
+    }
+}
+
+///A named derived metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metric {
+    pub name: &'static str,
+    pub expr: Expr,
+}
+
+const INST_RETIRED: &str = "INST_RETIRED.ANY";
+const CYCLES: &str = "CPU_CLK_UNHALTED.THREAD";
+const BRANCHES: &str = "BR_INST_RETIRED.ALL_BRANCHES";
+const BRANCH_MISSES: &str = "BR_MISP_RETIRED.ALL_BRANCHES";
+const L1D_MISSES: &str = "MEM_LOAD_RETIRED.L1_MISS";
+const LOADS: &str = "MEM_INST_RETIRED.ALL_LOADS";
+const LLC_MISSES: &str = "LONGEST_LAT_CACHE.MISS";
+const LLC_REFERENCES: &str = "LONGEST_LAT_CACHE.REFERENCE";
+
+///Instructions per cycle.
+pub const IPC: Metric = Metric{name: "IPC", expr: Expr::Div(&Expr::Event(INST_RETIRED), &Expr::Event(CYCLES))};
+///Cycles per instruction.
+pub const CPI: Metric = Metric{name: "CPI", expr: Expr::Div(&Expr::Event(CYCLES), &Expr::Event(INST_RETIRED))};
+///Mispredicted share of the retired branches.
+pub const BRANCH_MISS_RATIO: Metric = Metric{name: "branch miss ratio", expr: Expr::Div(&Expr::Event(BRANCH_MISSES), &Expr::Event(BRANCHES))};
+///Share of the retired loads that missed the L1 data cache (Skylake and later event names).
+pub const L1D_MISS_RATE: Metric = Metric{name: "L1D miss rate", expr: Expr::Div(&Expr::Event(L1D_MISSES), &Expr::Event(LOADS))};
+///Share of the last level cache references that missed, from the architectural events.
+pub const LLC_MISS_RATE: Metric = Metric{name: "LLC miss rate", expr: Expr::Div(&Expr::Event(LLC_MISSES), &Expr::Event(LLC_REFERENCES))};
+///Mispredicted branches per 1000 instructions.
+pub const BRANCH_MPKI: Metric = Metric{name: "branch MPKI", expr: Expr::Div(&Expr::Mul(&Expr::Const(1000), &Expr::Event(BRANCH_MISSES)), &Expr::Event(INST_RETIRED))};
+///L1 data cache load misses per 1000 instructions.
+pub const L1D_MPKI: Metric = Metric{name: "L1D MPKI", expr: Expr::Div(&Expr::Mul(&Expr::Const(1000), &Expr::Event(L1D_MISSES)), &Expr::Event(INST_RETIRED))};
+///Last level cache misses per 1000 instructions.
+pub const LLC_MPKI: Metric = Metric{name: "LLC MPKI", expr: Expr::Div(&Expr::Mul(&Expr::Const(1000), &Expr::Event(LLC_MISSES)), &Expr::Event(INST_RETIRED))};
+
+///All predefined metrics.
+pub const STANDARD_METRICS: [Metric; 8] = [IPC, CPI, BRANCH_MISS_RATIO, L1D_MISS_RATE, LLC_MISS_RATE, BRANCH_MPKI, L1D_MPKI, LLC_MPKI];
+
+///Exact fraction, den > 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ratio {
+    num: i128,
+    den: i128,
+}
+
+fn gcd(mut a:u128, mut b:u128) -> u128 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+impl Ratio {
+    fn integer(value:i128) -> Ratio {
+        Ratio{num:value, den:1}
+    }
+
+    fn reduced(num:i128, den:i128) -> Option<Ratio> {
+        if den == 0 {
+            return None;
+        }
+        let g = gcd(num.unsigned_abs(), den.unsigned_abs()).max(1) as i128;
+        let sign = if den < 0 { -1 } else { 1 };
+        Some(Ratio{num: num / g * sign, den: den / g * sign})
+    }
+
+    fn neg(self) -> Ratio {
+        Ratio{num: -self.num, den: self.den}
+    }
+
+    fn add(self, other:Ratio) -> Option<Ratio> {
+        let num = self.num.checked_mul(other.den)?.checked_add(other.num.checked_mul(self.den)?)?;
+        Ratio::reduced(num, self.den.checked_mul(other.den)?)
+    }
+
+    fn mul(self, other:Ratio) -> Option<Ratio> {
+        Ratio::reduced(self.num.checked_mul(other.num)?, self.den.checked_mul(other.den)?)
+    }
+
+    fn div(self, other:Ratio) -> Option<Ratio> {
+        Ratio::reduced(self.num.checked_mul(other.den)?, self.den.checked_mul(other.num)?)
+    }
+}
+
+///Fixed-point metric value with six decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MetricValue {
+    micros: i64,
+}
+
+impl MetricValue {
+    pub const SCALE: i64 = 1_000_000;
+
+    pub const fn from_micros(micros:i64) -> MetricValue {
+        MetricValue{micros:micros}
+    }
+
+    ///Value in millionths.
+    pub const fn micros(&self) -> i64 {
+        self.micros
+    }
+
+    ///Value rounded toward zero.
+    pub const fn trunc(&self) -> i64 {
+        self.micros / Self::SCALE
+    }
+
+    //Round to the nearest millionth, halves away from zero.
+    fn from_ratio(r:Ratio) -> Option<MetricValue> {
+        let scaled = r.num.checked_mul(Self::SCALE as i128)?;
+        //den > 0, so scaled / den is floor + rem / den with 0 <= rem < den.
+        let floor = scaled.div_euclid(r.den);
+        let rem = scaled.rem_euclid(r.den);
+        //2 * rem against den, without overflowing. A half goes up for a positive value and down for a negative one.
+        let up = rem > r.den - rem || (rem == r.den - rem && floor >= 0);
+        let rounded = if up { floor + 1 } else { floor };
+        Some(MetricValue{micros: i64::try_from(rounded).ok()?})
+    }
+}
+
+impl fmt::Display for MetricValue {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.micros < 0 { "-" } else { "" };
+        let abs = self.micros.unsigned_abs();
+        write!(f, "{}{}.{:06}", sign, abs / Self::SCALE as u64, abs % Self::SCALE as u64)
+    }
+}
+
+///Event counts by name, e.g. one read of a MetricGroup.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricSnapshot {
+    names: [&'static str; MAX_METRIC_EVENTS],
+    values: [u64; MAX_METRIC_EVENTS],
+    len: usize,
+}
+
+impl MetricSnapshot {
+    pub const fn new() -> MetricSnapshot {
+        MetricSnapshot{names: [""; MAX_METRIC_EVENTS], values: [0; MAX_METRIC_EVENTS], len: 0}
+    }
+
+    ///Set the count of event `name`. Names are compared exactly.
+    pub fn insert(&mut self, name:&'static str, value:u64) -> Result<(), ErrorMsg> {
+        if let Some(i) = self.position(name) {
+            self.values[i] = value;
+            return Ok(());
+        }
+        if self.len == MAX_METRIC_EVENTS {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        self.names[self.len] = name;
+        self.values[self.len] = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn value(&self, name:&str) -> Option<u64> {
+        self.position(name).map(|i| self.values[i])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    ///Event names and counts, in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.names[..self.len].iter().copied().zip(self.values[..self.len].iter().copied())
+    }
+
+    fn position(&self, name:&str) -> Option<usize> {
+        self.names[..self.len].iter().position(|n| *n == name)
+    }
+}
+
+impl Default for MetricSnapshot {
+    fn default() -> MetricSnapshot {
+        MetricSnapshot::new()
+    }
+}
+
+///Value of one metric together with the counts it was computed from.
+#[derive(Debug, Clone, Copy)]
+pub struct MetricReport {
+    pub name: &'static str,
+    ///None if a divisor is zero, an event is missing from the snapshot or the value does not fit.
+    pub value: Option<MetricValue>,
+    inputs: [(&'static str, Option<u64>); MAX_METRIC_INPUTS],
+    input_count: usize,
+}
+
+impl MetricReport {
+    ///Events of the metric and their counts, None for events missing from the snapshot.
+    /// Events beyond MAX_METRIC_INPUTS are left out.
+    pub fn inputs(&self) -> &[(&'static str, Option<u64>)] {
+        &self.inputs[..self.input_count]
+    }
+}
+
+///Compute `metric` from the counts in `snapshot`.
+pub fn evaluate(metric:&Metric, snapshot:&MetricSnapshot) -> MetricReport {
+    let mut report = MetricReport{
+        name: metric.name,
+        value: metric.expr.eval(snapshot).and_then(MetricValue::from_ratio),
+        inputs: [("", None); MAX_METRIC_INPUTS],
+        input_count: 0,
+    };
+    metric.expr.for_each_event(&mut |name| {
+        let known = report.inputs[..report.input_count].iter().any(|(n, _)| *n == name);
+        if !known && report.input_count < MAX_METRIC_INPUTS {
+            report.inputs[report.input_count] = (name, snapshot.value(name));
+            report.input_count += 1;
+        }
+    });
+    report
+}
+
+///The events a set of metrics needs, counted together on one PMU.
+pub struct MetricGroup<'a, B: PmuBackend> {
+    names: [&'static str; MAX_METRIC_EVENTS],
+    group: PerfCounterGroup<'a, B>,
+}
+
+impl<'a, B: PmuBackend> MetricGroup<'a, B> {
+    ///Schedule every event used by `metrics` once, resolving names with `resolve`.
+    ///
+    /// Fails with UnsupportedEvent for a name `resolve` does not know, with CounterOutOfRange if more
+    /// than MAX_METRIC_EVENTS events are needed, and like allocator::schedule() if they do not fit
+    /// on the PMCs together.
+    pub fn new<'e>(ctrler:&'a PerfCounterControler<B>, metrics:&[Metric], mut resolve:impl FnMut(&str) -> Option<&'e EventDescription<'e>>) -> Result<MetricGroup<'a, B>, UnschedulableEvent<'e>> {
+        let mut names = [""; MAX_METRIC_EVENTS];
+        let mut len = 0;
+        let mut overflow = None;
+        for metric in metrics {
+            metric.expr.for_each_event(&mut |name| {
+                if names[..len].contains(&name) {
+                    return;
+                }
+                if len == MAX_METRIC_EVENTS {
+                    overflow.get_or_insert(name);
+                    return;
+                }
+                names[len] = name;
+                len += 1;
+            });
+        }
+        if let Some(name) = overflow {
+            return Err(UnschedulableEvent{index:MAX_METRIC_EVENTS, event_name:name, reason:ErrorMsg::CounterOutOfRange});
+        }
+        if len == 0 {
+            return Ok(MetricGroup{names:names, group:PerfCounterGroup::new(ctrler)});
+        }
+        let mut resolve_name = |i:usize| match resolve(names[i]) {
+            Some(event) => Ok(event),
+            None => Err(UnschedulableEvent{index:i, event_name:names[i], reason:ErrorMsg::UnsupportedEvent}),
+        };
+        let mut events = [resolve_name(0)?; MAX_METRIC_EVENTS];
+        for (i, event) in events.iter_mut().enumerate().take(len).skip(1) {
+            *event = resolve_name(i)?;
+        }
+        let mut assignment = [Counter::Programmable(0); MAX_METRIC_EVENTS];
+        allocator::schedule(ctrler, &events[..len], &mut assignment)?;
+        let mut group = PerfCounterGroup::new(ctrler);
+        for i in 0..len {
+            let fail = |reason:ErrorMsg| UnschedulableEvent{index:i, event_name:names[i], reason:reason};
+            let mut counter = PerfCounter::new(ctrler);
+            allocator::build_assigned(&mut counter, events[i], assignment[i]).map_err(fail)?;
+            counter.disable_interrupt();
+            group.add(counter).map_err(fail)?;
+        }
+        Ok(MetricGroup{names:names, group:group})
+    }
+
+    ///Names of the scheduled events, in the order they are counted.
+    pub fn events(&self) -> &[&'static str] {
+        &self.names[..self.group.len()]
+    }
+
+    pub fn reset(&self) -> Result<(), ErrorMsg> {
+        self.group.reset()
+    }
+
+    pub fn start(&mut self) -> Result<(), ErrorMsg> {
+        self.group.start()
+    }
+
+    pub fn stop(&mut self) -> Result<(), ErrorMsg> {
+        self.group.stop()
+    }
+
+    ///Read all events at once.
+    pub fn snapshot(&self) -> Result<MetricSnapshot, ErrorMsg> {
+        let mut values = [0u64; MAX_METRIC_EVENTS];
+        self.group.read(&mut values)?;
+        let mut snapshot = MetricSnapshot::new();
+        for (name, value) in self.events().iter().zip(values.iter()) {
+            snapshot.insert(name, *value)?;
+        }
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::lookup::{CpuModel, EventTable};
+    use crate::x86_intel::capabilities::CpuSignature;
+    use crate::x86_intel::simulator::SimulatedPmu;
+
+    fn ratio(num:i128, den:i128) -> Ratio {
+        Ratio::reduced(num, den).unwrap()
+    }
+
+    fn snapshot(counts:&[(&'static str, u64)]) -> MetricSnapshot {
+        let mut snapshot = MetricSnapshot::new();
+        for (name, value) in counts {
+            snapshot.insert(name, *value).unwrap();
+        }
+        snapshot
+    }
+
+    #[test]
+    fn ratio_arithmetic() {
+        assert_eq!(ratio(6, -4), Ratio{num: -3, den: 2});
+        assert_eq!(ratio(0, 7), Ratio{num: 0, den: 1});
+        assert_eq!(ratio(1, 6).add(ratio(1, 3)), Some(ratio(1, 2)));
+        assert_eq!(ratio(1, 2).add(ratio(3, 4).neg()), Some(ratio(-1, 4)));
+        assert_eq!(ratio(2, 3).mul(ratio(9, 4)), Some(ratio(3, 2)));
+        assert_eq!(ratio(2, 3).div(ratio(-4, 9)), Some(ratio(-3, 2)));
+        assert_eq!(Ratio::integer(i128::MAX).mul(Ratio::integer(2)), None);
+    }
+
+    #[test]
+    fn rounds_halves_away_from_zero() {
+        //0.0000005 and 0.0000025 are halves of a millionth.
+        assert_eq!(MetricValue::from_ratio(ratio(1, 2_000_000)), Some(MetricValue::from_micros(1)));
+        assert_eq!(MetricValue::from_ratio(ratio(-1, 2_000_000)), Some(MetricValue::from_micros(-1)));
+        assert_eq!(MetricValue::from_ratio(ratio(5, 2_000_000)), Some(MetricValue::from_micros(3)));
+        assert_eq!(MetricValue::from_ratio(ratio(-5, 2_000_000)), Some(MetricValue::from_micros(-3)));
+        assert_eq!(MetricValue::from_ratio(ratio(1, 3)), Some(MetricValue::from_micros(333_333)));
+        assert_eq!(MetricValue::from_ratio(ratio(-2, 3)), Some(MetricValue::from_micros(-666_667)));
+        assert_eq!(MetricValue::from_micros(-1_500_000).trunc(), -1);
+        //Only results out of range have no value; large terms round like small ones.
+        assert_eq!(MetricValue::from_ratio(Ratio{num: i128::MAX, den: 1}), None);
+        assert_eq!(MetricValue::from_ratio(Ratio{num: i128::MAX / MetricValue::SCALE as i128, den: i128::MAX}), Some(MetricValue::from_micros(1)));
+        assert_eq!(MetricValue::from_ratio(Ratio{num: i128::MIN / MetricValue::SCALE as i128, den: i128::MAX}), Some(MetricValue::from_micros(-1)));
+        assert_eq!(MetricValue::from_ratio(Ratio{num: 1, den: i128::MAX}), Some(MetricValue::from_micros(0)));
+        assert_eq!(MetricValue::from_ratio(Ratio{num: -1, den: i128::MAX}), Some(MetricValue::from_micros(0)));
+        assert_eq!(MetricValue::from_ratio(Ratio{num: 10i128.pow(25), den: 2 * 10i128.pow(31)}), Some(MetricValue::from_micros(1)));
+        assert_eq!(MetricValue::from_ratio(Ratio{num: -10i128.pow(25), den: 2 * 10i128.pow(31)}), Some(MetricValue::from_micros(-1)));
+        assert_eq!(MetricValue::from_ratio(Ratio::integer(i64::MAX as i128 / MetricValue::SCALE as i128)), Some(MetricValue::from_micros(i64::MAX / MetricValue::SCALE * MetricValue::SCALE)));
+        assert_eq!(MetricValue::from_ratio(Ratio::integer(i64::MAX as i128)), None);
+    }
+
+    #[test]
+    fn zero_divisor_has_no_value() {
+        assert_eq!(ratio(1, 2).div(Ratio::integer(0)), None);
+        let report = evaluate(&IPC, &snapshot(&[(INST_RETIRED, 1000), (CYCLES, 0)]));
+        assert_eq!(report.value, None);
+        assert_eq!(report.inputs(), &[(INST_RETIRED, Some(1000)), (CYCLES, Some(0))]);
+        let report = evaluate(&IPC, &snapshot(&[(INST_RETIRED, 3000), (CYCLES, 2000)]));
+        assert_eq!(report.value, Some(MetricValue::from_micros(1_500_000)));
+        //A missing event has no value either.
+        let report = evaluate(&BRANCH_MPKI, &snapshot(&[(INST_RETIRED, 3000)]));
+        assert_eq!(report.value, None);
+        assert_eq!(report.inputs(), &[(BRANCH_MISSES, None), (INST_RETIRED, Some(3000))]);
+    }
+
+    #[test]
+    fn group_counts_each_event_once() {
+        let table = EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap();
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::new(3 | 4<<8 | 48<<16, 0, 0, 3 | 48<<5));
+        ctrler.init();
+        let group = MetricGroup::new(&ctrler, &[IPC, CPI, BRANCH_MPKI], |name| table.lookup(name).ok()).unwrap();
+        assert_eq!(group.events(), &[INST_RETIRED, CYCLES, BRANCH_MISSES]);
+        let counts = group.snapshot().unwrap();
+        assert_eq!(counts.len(), 3);
+        assert!(counts.iter().all(|(_, value)| value == 0));
+        drop(group);
+        let unknown = MetricGroup::new(&ctrler, &[IPC], |name| if name == CYCLES { None } else { table.lookup(name).ok() });
+        assert!(matches!(unknown, Err(UnschedulableEvent{index: 1, reason: ErrorMsg::UnsupportedEvent, ..})));
+    }
+}
@@ -7,12 +7,119 @@
 //! 2. Generate a Performance Monitoring Interrupt (PMI) when hitting a certain number of the hardware event through 
 //!     globle_ctrl.register_overflow_interrput(), overflow_after() globle_ctrl.get_overflow_counter(), reset() and globle_ctrl.reset_overflow_interrput().
 use crate::AbstractPerfCounter;
+pub mod allocator;
+pub mod backend;
+pub mod bts;
+pub mod callchain;
+pub mod capabilities;
+pub mod debug_store;
 pub mod globle_ctrl;
+pub mod group;
+pub mod lbr;
+pub mod lookup;
+pub mod metrics;
+pub mod multiplex;
+pub mod pebs;
+pub mod perf_data;
+pub mod pt;
+pub mod pt_decode;
+pub mod registers;
+pub mod ring_buffer;
+pub mod sampling;
+pub mod simulator;
+pub mod system_wide;
+pub mod topdown;
+pub mod virtual_counter;
+#[cfg(feature = "std")]
+pub mod linux;
+use backend::{PmuBackend, NativePmu};
 use globle_ctrl::PerfCounterControler;
-use x86::{msr::*, perfcnt::intel::{ EventDescription,Counter,Tuple}};
+use registers::{PerfEvtSel, FixedCtrField, FixedCtrCtrl};
+use core::cell::Cell;
+use x86::{msr::*, perfcnt::intel::{ EventDescription,Counter,Tuple,MSRIndex}};
 pub const ENABLE_GENERAL_PMC_MASK: u64 = 0x1<<22;
 
-use globle_ctrl::PERFCNT_GLOBAL_CTRLER;
+///IA32_PERFEVTSELx bits that perf accepts in a PERF_TYPE_RAW config on Intel:
+/// event select, umask, edge, any thread, invert and counter mask.
+const PERF_RAW_CONFIG_MASK: u64 = 0xFF | 0xFF<<8 | 1<<18 | 1<<21 | 1<<23 | 0xFF<<24;
+
+///Raw configs perf uses for the events hard-wired to fixed counters 0-3.
+const PERF_FIXED_RAW_CONFIG: [u64; 4] = [0x00C0, 0x003C, 0x0300, 0x0400];
+
+///Auxiliary MSRs perf programs from config1: MSR_OFFCORE_RSP_0/1, MSR_PEBS_LD_LAT_THRESHOLD and MSR_PEBS_FRONTEND.
+const PERF_CONFIG1_MSRS: [u32; 4] = [0x1A6, 0x1A7, 0x3F6, 0x3F7];
+
+///How perf_event_open(2) names the event of a PerfCounter, see PerfCounter::perf_raw_config().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfRawConfig {
+    ///PERF_TYPE_RAW config.
+    pub config: u64,
+    ///Value of the auxiliary MSR, 0 if the event has none.
+    pub config1: u64,
+    ///Counts in ring 0.
+    pub kernel: bool,
+    ///Counts in ring 1 to 3.
+    pub user: bool,
+}
+
+///Number of alternative encodings of an event.
+///
+/// Events such as OFFCORE_RESPONSE list two event codes (or two umasks), one for each of the
+/// MSR_OFFCORE_RSP_x MSRs. Each alternative counts the same thing, so the event can be
+/// programmed with either of them, or with both on two different counters.
+pub fn event_alternatives(event:&EventDescription)->u8{
+    match (&event.event_code, &event.umask) {
+        (Tuple::One(_), Tuple::One(_)) => 1,
+        _ => 2,
+    }
+}
+
+///Fixed counter an event listed with Counter::Fixed counts on.
+///
+/// The event tables number fixed counters inconsistently (some from 0, some from 1),
+/// so the architectural events are recognized by name and the mask is only a fallback.
+pub fn fixed_counter_index(event:&EventDescription)->Option<u8>{
+    let name = event.event_name;
+    if name.starts_with("INST_RETIRED.ANY") {
+        Some(0)
+    } else if name.starts_with("CPU_CLK_UNHALTED.REF") {
+        Some(2)
+    } else if name.starts_with("CPU_CLK_UNHALTED.") {
+        Some(1)
+    } else if name.starts_with("TOPDOWN.SLOTS") {
+        Some(3)
+    } else {
+        match event.counter {
+            Counter::Fixed(mask) if mask != 0 => Some(mask.trailing_zeros() as u8),
+            _ => None,
+        }
+    }
+}
+
+///Auxiliary MSR and value of one alternative encoding of an event, if it needs one.
+pub fn event_aux_msr(event:&EventDescription, alternative:u8)->Option<(u32,u64)>{
+    match event.msr_index {
+        MSRIndex::None => None,
+        MSRIndex::One(msr) => Some((msr as u32, event.msr_value)),
+        MSRIndex::Two(first, second) => Some((if alternative == 0 {first} else {second} as u32, event.msr_value)),
+    }
+}
+
+///Event select and umask of one alternative encoding of an event.
+pub fn event_encoding(event:&EventDescription, alternative:u8)->Result<(u8,u8),ErrorMsg>{
+    if alternative >= event_alternatives(event){
+        return Err(ErrorMsg::UnsupportedEventEncoding);
+    }
+    let code = match event.event_code {
+        Tuple::One(code) => code,
+        Tuple::Two(first, second) => if alternative == 0 {first} else {second},
+    };
+    let umask = match event.umask {
+        Tuple::One(umask) => umask,
+        Tuple::Two(first, second) => if alternative == 0 {first} else {second},
+    };
+    Ok((code, umask))
+}
 
 #[derive(Debug)]
 pub enum ErrorMsg {
@@ -21,59 +128,91 @@ pub enum ErrorMsg {
     CounterOutOfRange,
     UnsupportedFixPMC,
     UnsupportedVersion,
+    ///The event has no encoding for the requested alternative.
+    UnsupportedEventEncoding,
+    ///An auxiliary MSR such as MSR_OFFCORE_RSP_x is programmed with a different value by another counter.
+    SharedMsrInUse,
+    ///errno reported by the host OS backend.
+    OsError(i32),
+    ///The per-CPU controler is still being initialized on this CPU.
+    ControlerNotReady,
+    ///The APIC ID of the CPU is beyond the per-CPU controlers.
+    CpuOutOfRange,
+    ///The output does not have room for the data.
+    BufferTooSmall,
+    ///The input is not in the expected format.
+    InvalidFormat,
+    ///The processor does not implement the requested feature.
+    UnsupportedFeature,
 }
 
 
-pub struct PerfCounter{
-    pub global_ctrler: &'static PerfCounterControler,
+pub struct PerfCounter<'a, B: PmuBackend = NativePmu>{
+    pub global_ctrler: &'a PerfCounterControler<B>,
     pub counter_type:Counter,
     pub pmc_index:u8,  
     pub general_pmc_mask:u64,
     pub fixed_pmc_mask:u64,
+    pub event_alternative:u8,
+    ///Auxiliary MSR and value the event needs, e.g. MSR_OFFCORE_RSP_0 for OFFCORE_RESPONSE.
+    pub aux_msr:Option<(u32,u64)>,
+    aux_msr_claimed:Cell<bool>,
+    //Counter reserved in the controler for this PerfCounter, released on drop.
+    reservation:Option<Counter>,
 }
 
 
 
-impl  PerfCounter{
-    pub fn new_default() -> PerfCounter{
-        unsafe{
-            PerfCounter{
-                global_ctrler: &PERFCNT_GLOBAL_CTRLER,
-                pmc_index: 0,
-                counter_type: Counter::Programmable(0),
-                general_pmc_mask: 0,
-                fixed_pmc_mask: 0,
-            }
-        }
+impl  PerfCounter<'static>{
+    ///PerfCounter on the controler of the executing logical CPU, see globle_ctrl::this_cpu_controler().
+    ///
+    /// # Safety
+    ///
+    /// Same requirements as globle_ctrl::this_cpu_controler().
+    pub unsafe fn new_default(cpu:usize) -> Result<PerfCounter<'static>,ErrorMsg>{
+        Ok(PerfCounter::new(unsafe{ globle_ctrl::this_cpu_controler(cpu)? }))
     }
-    pub fn new(global_ctrler: &'static PerfCounterControler) -> PerfCounter{
+}
+
+impl<'a, B: PmuBackend> PerfCounter<'a, B>{
+    pub fn new(global_ctrler: &'a PerfCounterControler<B>) -> PerfCounter<'a, B>{
         PerfCounter{
             global_ctrler: global_ctrler,
             pmc_index: 0,
             counter_type: Counter::Programmable(0),
             general_pmc_mask: 0,
             fixed_pmc_mask: 0,
+            event_alternative: 0,
+            aux_msr: None,
+            aux_msr_claimed: Cell::new(false),
+            reservation: None,
         }
     }
 
     ///Build a PerfCounter for one pmc_msr (programmable or fixed) from x86::perfcnt::intel::description
     /// 
     ///Arg index indicates the index of programmable pmc_msr intended to use. It is not used when using fixed_pmc--can input any value.
+    /// Events with two encodings are programmed with the first one whose auxiliary MSR is available.
+    /// The auxiliary MSR is claimed and programmed here and held until the counter is released or dropped.
     pub fn build_from_intel_hw_event(&mut self,event:&EventDescription,index:u8,)->Result<(),ErrorMsg>{
+        for alternative in 0..event_alternatives(event){
+            match event_aux_msr(event, alternative){
+                Some((msr, value)) if !self.global_ctrler.aux_msr_available(msr, value) => continue,
+                _ => return self.build_from_intel_hw_event_alternative(event, index, alternative),
+            }
+        }
+        Err(ErrorMsg::SharedMsrInUse)
+    }
+
+    ///Same as build_from_intel_hw_event() but selects which encoding of a two-code event is used.
+    /// See event_alternatives(). Fails with SharedMsrInUse if its auxiliary MSR holds another value.
+    pub fn build_from_intel_hw_event_alternative(&mut self,event:&EventDescription,index:u8,alternative:u8)->Result<(),ErrorMsg>{
+        let (code, umask) = event_encoding(event, alternative)?;
         match event.counter{
 
-            Counter::Fixed(index)=> 
-            if self.global_ctrler.get_version_identifier()<2 {
-                return Err(ErrorMsg::UnsupportedFixPMC);
-            }else if index > self.global_ctrler.get_number_fixed_function_counter(){
-                    return Err(ErrorMsg::CounterOutOfRange);
-            }else{
-                self.counter_type = Counter::Fixed(index);
-                self.fixed_pmc_mask= 0xB;
-                self.pmc_index = index;
-                if event.any_thread && self.global_ctrler.get_version_identifier()>2{
-                    self.fixed_pmc_mask |= 4;
-                }
+            Counter::Fixed(_)=> match fixed_counter_index(event){
+                Some(index) => self.build_fixed(index, event.any_thread)?,
+                None => return Err(ErrorMsg::UnsupportedEvent),
             }
 
 
@@ -81,90 +220,144 @@ impl  PerfCounter{
             if index >= self.global_ctrler.get_number_msr(){
                 return Err(ErrorMsg::CounterOutOfRange);
             }else{
+                self.claim_counter(Counter::Programmable(index))?;
                 self.pmc_index = index;
                 self.counter_type = Counter::Programmable(index);
-                let mut config: u64 = 0;
-
-                match event.event_code {
-                Tuple::One(code) => config |= (code as u64) << 0,
-                Tuple::Two(_, _) => unreachable!(), // NYI
-                };
-                match event.umask {
-                Tuple::One(code) => config |= (code as u64) << 8,
-                Tuple::Two(_, _) => unreachable!(), // NYI
-            };
-            config |= (event.counter_mask as u64) << 24;
-
-            if event.edge_detect {
-                config |= 1 << 18;
-            }
-            if event.any_thread {
-                config |= 1 << 21;
-            }
-            if event.invert {
-                config |= 1 << 23;
-            }
-            config |= 1<<17;
-            config |= 1<<16;
-            config |= 1<<20;
-            self.general_pmc_mask = config | ENABLE_GENERAL_PMC_MASK;
-            
+                self.event_alternative = alternative;
+                self.aux_msr = event_aux_msr(event, alternative);
+                if let Err(e) = self.claim_aux_msr(){
+                    self.aux_msr = None;
+                    self.release_counter();
+                    return Err(e);
+                }
+                let mut config = PerfEvtSel::default();
+                config.set_event_select(code)
+                    .set_umask(umask)
+                    .set_cmask(event.counter_mask)
+                    .set_edge(event.edge_detect)
+                    .set_any_thread(event.any_thread && self.global_ctrler.get_capabilities().any_thread_supported())
+                    .set_inv(event.invert)
+                    .set_os(true)
+                    .set_usr(true)
+                    .set_int(true)
+                    .set_en(true);
+                self.general_pmc_mask = config.bits();
             }
         }
         Ok(())
     }
 
+    ///Build a PerfCounter for fixed counter `index`, counting in all rings with PMI enabled.
+    pub fn build_fixed(&mut self,index:u8,any_thread:bool)->Result<(),ErrorMsg>{
+        if self.global_ctrler.get_version_identifier()<2 {
+            return Err(ErrorMsg::UnsupportedFixPMC);
+        }else if index >= FixedCtrCtrl::FIELDS || !self.global_ctrler.get_capabilities().is_fixed_counter_supported(index){
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        self.claim_counter(Counter::Fixed(index))?;
+        self.counter_type = Counter::Fixed(index);
+        self.pmc_index = index;
+        self.aux_msr = None;
+        let mut field = FixedCtrField::default();
+        field.set_os(true).set_usr(true).set_pmi(true);
+        if any_thread && self.global_ctrler.get_capabilities().any_thread_supported(){
+            field.set_any_thread(true);
+        }
+        self.fixed_pmc_mask = field.bits() as u64;
+        Ok(())
+    }
+
     ///Build a PerfCounter for one programmable_pmc_ms from raw eventmask and other attributes.
-    pub fn build_general_from_raw(&mut self,eventmask:u32,umask:u32,user_enabled:bool,os_enabled:bool,counter_mask:u8,edge_detect:bool,pmc_index:u8){
-        self.general_pmc_mask = 0;
-        self.general_pmc_mask |= (eventmask & 0xFF)as u64;
-        self.general_pmc_mask |= ((umask<<8) & (0xFF<<8)) as u64;
-        self.general_pmc_mask |= (((user_enabled as u64) <<16) & (0x1<<16)) as u64;
-        self.general_pmc_mask |= (((os_enabled as u64)<<17) & (0x1<<17)) as u64;
-        self.general_pmc_mask |= ENABLE_GENERAL_PMC_MASK;
-        self.general_pmc_mask |= (counter_mask as u64) << 24;
-        self.general_pmc_mask |= if edge_detect {1<<18}else{0};
-        self.general_pmc_mask |= 1 << 20;
+    pub fn build_general_from_raw(&mut self,eventmask:u32,umask:u32,user_enabled:bool,os_enabled:bool,counter_mask:u8,edge_detect:bool,pmc_index:u8)->Result<(),ErrorMsg>{
+        if pmc_index >= self.global_ctrler.get_number_msr(){
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        self.claim_counter(Counter::Programmable(pmc_index))?;
+        let mut config = PerfEvtSel::default();
+        config.set_event_select(eventmask as u8)
+            .set_umask(umask as u8)
+            .set_usr(user_enabled)
+            .set_os(os_enabled)
+            .set_en(true)
+            .set_cmask(counter_mask)
+            .set_edge(edge_detect)
+            .set_int(true);
+        self.general_pmc_mask = config.bits();
+        self.pmc_index = pmc_index;
+        self.aux_msr = None;
         self.counter_type = Counter::Programmable(pmc_index);
+        Ok(())
     }
 
+    ///Reserve counter `c` in the controler for this PerfCounter, giving up the one held before.
+    /// Fails with CounterInUse if another PerfCounter owns `c`.
+    fn claim_counter(&mut self,c:Counter)->Result<(),ErrorMsg>{
+        self.release_aux_msr();
+        if self.reservation == Some(c){
+            return Ok(());
+        }
+        self.global_ctrler.reserve_counter(c)?;
+        self.release_counter();
+        self.reservation = Some(c);
+        Ok(())
+    }
 
-    ///Counter will not increment in ring 4
+    ///Stop the hardware counter and give its reservation and auxiliary MSR back to the controler.
+    pub fn release_counter(&mut self){
+        if let Some(c) = self.reservation.take(){
+            let _ = self.stop();
+            self.global_ctrler.release_counter(c);
+        }
+        self.release_aux_msr();
+    }
+
+
+    ///Counter will not increment in ring 0
     pub fn exnclude_os(&mut self){
         match self.get_counter_type(){
             Counter::Fixed(_) => {
-                self.fixed_pmc_mask &= !(1<<0);
+                self.fixed_pmc_mask = self.get_fixed_ctrl_field().set_os(false).bits() as u64;
             },
             Counter::Programmable(_) =>{
-                self.general_pmc_mask &= !(1<<17);
+                self.general_pmc_mask = self.get_event_select().set_os(false).bits();
             },
         }
     }
 
-    ///Counter will not increment in ring 0
+    ///Counter will not increment in ring 1 to 3
     pub fn exclude_user(&mut self){
         match self.get_counter_type(){
             Counter::Fixed(_) => {
-                self.fixed_pmc_mask &= !(1<<1);
+                self.fixed_pmc_mask = self.get_fixed_ctrl_field().set_usr(false).bits() as u64;
             },
             Counter::Programmable(_) =>{
-                self.general_pmc_mask &= !(1<<16);
+                self.general_pmc_mask = self.get_event_select().set_usr(false).bits();
             },
         }
-        //self.general_pmc_mask &= !(1<<16);
     }
 
     ///Counter will not produce PMI when overflow
     pub fn disable_interrupt(&mut self){
         match self.get_counter_type(){
             Counter::Fixed(_) => {
-                self.fixed_pmc_mask &= !(1<<3);
+                self.fixed_pmc_mask = self.get_fixed_ctrl_field().set_pmi(false).bits() as u64;
+            },
+            Counter::Programmable(_) =>{
+                self.general_pmc_mask = self.get_event_select().set_int(false).bits();
+            },
+        }
+    }
+
+    ///Counter will produce PMI when overflow
+    pub fn enable_interrupt(&mut self){
+        match self.get_counter_type(){
+            Counter::Fixed(_) => {
+                self.fixed_pmc_mask = self.get_fixed_ctrl_field().set_pmi(true).bits() as u64;
             },
             Counter::Programmable(_) =>{
-                self.general_pmc_mask &= !(1<<20);
+                self.general_pmc_mask = self.get_event_select().set_int(true).bits();
             },
         }
-        //self.general_pmc_mask &= !(1<<20);
     }
 
     pub fn get_pmc_index(&self)-> u8{
@@ -179,80 +372,85 @@ impl  PerfCounter{
     pub fn get_fixed_pmc_mask(&self)->u64{
         self.fixed_pmc_mask
     }
+    ///Typed view of general_pmc_mask.
+    pub fn get_event_select(&self)-> PerfEvtSel{
+        PerfEvtSel::from_bits(self.general_pmc_mask)
+    }
+    ///Typed view of fixed_pmc_mask.
+    pub fn get_fixed_ctrl_field(&self)-> FixedCtrField{
+        FixedCtrField::from_bits(self.fixed_pmc_mask as u8)
+    }
+
+    ///PERF_TYPE_RAW config perf uses for the event of this counter.
+    ///
+    /// Fails with UnsupportedEvent for fixed counters perf has no config for,
+    /// and for auxiliary MSRs perf does not program from config1.
+    pub fn perf_raw_config(&self)->Result<PerfRawConfig,ErrorMsg>{
+        match self.get_counter_type(){
+            Counter::Programmable(_) => {
+                let mask = self.get_general_pmc_mask();
+                let config1 = match self.aux_msr{
+                    Some((msr, value)) if PERF_CONFIG1_MSRS.contains(&msr) => value,
+                    Some(_) => return Err(ErrorMsg::UnsupportedEvent),
+                    None => 0,
+                };
+                Ok(PerfRawConfig{config: mask & PERF_RAW_CONFIG_MASK, config1: config1, kernel: mask & (1<<17) != 0, user: mask & (1<<16) != 0})
+            },
+            Counter::Fixed(_) => {
+                let config = match PERF_FIXED_RAW_CONFIG.get(self.get_pmc_index() as usize) {
+                    Some(config) => *config,
+                    None => return Err(ErrorMsg::UnsupportedEvent),
+                };
+                let field = self.get_fixed_ctrl_field();
+                Ok(PerfRawConfig{config: config, config1: 0, kernel: field.os(), user: field.usr()})
+            },
+        }
+    }
 
+    ///Hardware value of the counter, wrapping at get_bit_width(). See virtual_counter for 64-bit values.
     pub fn read_general_pmc_ctr(&self, index:u8)->u64{
-        let  rcx:u64 = (0+index) as u64;
-        let mut rax:u64;
-        let mut rdx:u64;
-        unsafe{
-            //get general_pmc reading at index
-            asm!(
-                "rdpmc",
-                in("rcx") rcx,
-                out("rax") rax,
-                out("rdx") rdx,
-            );
-        
-        let reading = ((rax<<32>>32) | rdx<<32) & ((0x1<<self.global_ctrler.get_bit_width())-1);
-        // let reading = ((rax<<32>>32) | rdx<<32);
-        /*if self.check_overflow(){
-            return reading + (0x1 << self.get_bit_width());
-        }*/
+        let  rcx:u32 = (0+index) as u32;
+        //get general_pmc reading at index
+        let reading = self.global_ctrler.backend().rdpmc(rcx) & ((0x1<<self.global_ctrler.get_bit_width())-1);
         reading
-        }
     }
 
     pub fn read_fixed_pmc_ctr(&self, index:u8)->u64{
-        let  rcx:u64 = (0+index) as u64 | (1<<30);
-        let mut rax:u64;
-        let mut rdx:u64;
-        unsafe{
-            //get fix_pmc reading at index
-            asm!(
-                "rdpmc",
-                in("rcx") rcx,
-                out("rax") rax,
-                out("rdx") rdx,
-            );
-        
-        let reading = ((rax<<32>>32) | rdx<<32) & ((0x1<<self.global_ctrler.get_bit_width_fixed_counter())-1);
-
-        /*if self.check_overflow(){
-            return reading + (0x1 << self.get_bit_width());
-        }*/
+        let  rcx:u32 = (0+index) as u32 | (1<<30);
+        //get fix_pmc reading at index
+        let reading = self.global_ctrler.backend().rdpmc(rcx) & ((0x1<<self.global_ctrler.get_bit_width_fixed_counter())-1);
         reading
-        }
     }
 
     pub fn set_general_pmc_ctr(&self, index:u8,value:u64){
         let value = value & ((1<<self.global_ctrler.get_bit_width()) - 1);
-        unsafe {
-            if self.global_ctrler.get_perf_capability(){
-                wrmsr(IA32_A_PMC0+index as u32, value);
-            }
-            else{
-                wrmsr(IA32_PMC0+index as u32, value)
-            }
+        if self.global_ctrler.get_perf_capability(){
+            self.global_ctrler.backend().wrmsr(IA32_A_PMC0+index as u32, value);
+        }
+        else{
+            self.global_ctrler.backend().wrmsr(IA32_PMC0+index as u32, value)
         }
     }
 
     pub fn set_general_pmc_ctrl(&self, mask:u64,index:u8){
-        unsafe {wrmsr(0x186+index as u32, mask)}
+        self.global_ctrler.backend().wrmsr(0x186+index as u32, mask)
     }
 
     pub fn set_fixed_pmc_ctr(&self, index:u8,value:u64){
         let value = value & ((1<<self.global_ctrler.get_bit_width_fixed_counter()) - 1);
-        unsafe {wrmsr(0x309+index as u32, value)}
+        self.global_ctrler.backend().wrmsr(0x309+index as u32, value)
     }
 
     pub fn set_fixed_pmc_ctrl(&self, index:u8,enabled_ring_lv:u8,is_pmi_enabled:bool){
-        let rcx:u32 = 0x38D ;
-        let rax:u64 = ((if is_pmi_enabled {8+enabled_ring_lv} else {enabled_ring_lv}) << (index * 4)) as u64; 
-       unsafe{ wrmsr(rcx, rax)}
+        let backend = self.global_ctrler.backend();
+        let mut field = FixedCtrField::from_bits(enabled_ring_lv & 3);
+        field.set_pmi(is_pmi_enabled);
+        let mut ctrl = FixedCtrCtrl::from_bits(backend.rdmsr(IA32_FIXED_CTR_CTRL));
+        ctrl.set_counter(index, field);
+        backend.wrmsr(IA32_FIXED_CTR_CTRL, ctrl.bits())
     }
 
     pub fn enable_general_pmc(&self,index:u8){
-        unsafe {
         /*if self.global_ctrler.get_version_identifier()>=2{
             let rcx:u64 = 0x38f;
             let msr:u64 = rdmsr(0x38f as u32);
@@ -268,12 +466,10 @@ impl  PerfCounter{
             
         }*/
         self.global_ctrler.enable_counter(self.counter_type);
-        wrmsr(0x186+index as u32, self.get_general_pmc_mask());
-        }
+        self.global_ctrler.backend().wrmsr(0x186+index as u32, self.get_general_pmc_mask());
     }
 
     pub fn disable_general_pmc(&self,index:u8){
-        unsafe{
         /*if self.global_ctrler.get_version_identifier()>=2{
             let rcx:u64 = 0x38f;
             let msr:u64 = rdmsr(0x38f as u32);
@@ -288,8 +484,7 @@ impl  PerfCounter{
             )
         }*/
         self.global_ctrler.disable_counter(self.counter_type);
-        wrmsr(0x186+index as u32, 0);
-        }
+        self.global_ctrler.backend().wrmsr(0x186+index as u32, 0);
     }
 
     pub fn enable_fixed_pmc(&self,index:u8){
@@ -307,9 +502,10 @@ impl  PerfCounter{
             )
         }*/
         self.global_ctrler.enable_counter(self.counter_type);
-        let rcx:u32 = 0x38D ;
-        let rax:u64 = (self.get_fixed_pmc_mask()<< (index * 4)) as u64 ; 
-       unsafe{ wrmsr(rcx as u32, rdmsr(rcx) &(!(15<<(index*4)))|rax);}
+        let backend = self.global_ctrler.backend();
+        let mut ctrl = FixedCtrCtrl::from_bits(backend.rdmsr(IA32_FIXED_CTR_CTRL));
+        ctrl.set_counter(index, self.get_fixed_ctrl_field());
+        backend.wrmsr(IA32_FIXED_CTR_CTRL, ctrl.bits());
     }
 
     pub fn disable_fixed_pmc(&self,index:u8){
@@ -328,9 +524,90 @@ impl  PerfCounter{
             )
         }*/
         self.global_ctrler.disable_counter(self.counter_type);
-        let rcx:u32 = 0x38D ;
-        //let rax:u64 = ((if self.get_is_pmc_pmi_enabled() {8+self.get_fixed_pmc_ring_lv()} else {self.get_fixed_pmc_ring_lv()}) << (index * 4)) as u64; 
-       unsafe{ wrmsr(rcx, rdmsr(rcx) & (15<<(index * 4)));}
+        let backend = self.global_ctrler.backend();
+        let mut ctrl = FixedCtrCtrl::from_bits(backend.rdmsr(IA32_FIXED_CTR_CTRL));
+        ctrl.set_counter(index, FixedCtrField::default());
+        backend.wrmsr(IA32_FIXED_CTR_CTRL, ctrl.bits());
+    }
+
+    ///Program the auxiliary MSR of the event, if any. Does nothing if this counter already holds it.
+    pub fn claim_aux_msr(&self)->Result<(),ErrorMsg>{
+        if let Some((msr, value)) = self.aux_msr{
+            if !self.aux_msr_claimed.get(){
+                self.global_ctrler.claim_aux_msr(msr, value)?;
+                self.aux_msr_claimed.set(true);
+            }
+        }
+        Ok(())
+    }
+
+    ///Give up the auxiliary MSR claimed by claim_aux_msr().
+    pub fn release_aux_msr(&self){
+        if let Some((msr, _)) = self.aux_msr{
+            if self.aux_msr_claimed.get(){
+                self.global_ctrler.release_aux_msr(msr);
+                self.aux_msr_claimed.set(false);
+            }
+        }
+    }
+
+    ///Give the reservation and the auxiliary MSR back to the controler without touching any MSR.
+    ///
+    /// For a counter whose CPU can no longer be reached: the hardware keeps whatever was programmed.
+    pub fn abandon(&mut self){
+        if let Some((msr, _)) = self.aux_msr{
+            if self.aux_msr_claimed.get(){
+                self.global_ctrler.abandon_aux_msr(msr);
+                self.aux_msr_claimed.set(false);
+            }
+        }
+        if let Some(c) = self.reservation.take(){
+            self.global_ctrler.release_counter(c);
+        }
+    }
+
+    ///Bit of this counter in IA32_PERF_GLOBAL_CTRL, IA32_PERF_GLOBAL_STATUS and IA32_PERF_GLOBAL_OVF_CTRL.
+    pub fn global_bit(&self)->u64{
+        match self.get_counter_type(){
+            Counter::Fixed(_) => 1 << (self.get_pmc_index() as u64 + 32),
+            Counter::Programmable(_) => 1 << self.get_pmc_index(),
+        }
+    }
+
+    ///Write IA32_PERFEVTSELx or this counter's field of IA32_FIXED_CTR_CTRL, leaving IA32_PERF_GLOBAL_CTRL untouched.
+    pub fn program_ctrl(&self){
+        let index = self.get_pmc_index();
+        match self.get_counter_type(){
+            Counter::Programmable(_) => self.set_general_pmc_ctrl(self.get_general_pmc_mask(), index),
+            Counter::Fixed(_) => {
+                let backend = self.global_ctrler.backend();
+                let mut ctrl = FixedCtrCtrl::from_bits(backend.rdmsr(IA32_FIXED_CTR_CTRL));
+                ctrl.set_counter(index, self.get_fixed_ctrl_field());
+                backend.wrmsr(IA32_FIXED_CTR_CTRL, ctrl.bits());
+            },
+        }
+    }
+
+    ///Clear what program_ctrl() wrote.
+    pub fn clear_ctrl(&self){
+        let index = self.get_pmc_index();
+        match self.get_counter_type(){
+            Counter::Programmable(_) => self.set_general_pmc_ctrl(0, index),
+            Counter::Fixed(_) => {
+                let backend = self.global_ctrler.backend();
+                let mut ctrl = FixedCtrCtrl::from_bits(backend.rdmsr(IA32_FIXED_CTR_CTRL));
+                ctrl.set_counter(index, FixedCtrField::default());
+                backend.wrmsr(IA32_FIXED_CTR_CTRL, ctrl.bits());
+            },
+        }
+    }
+
+    ///Current counter value.
+    pub fn read_ctr(&self)->u64{
+        match self.get_counter_type(){
+            Counter::Programmable(_) => self.read_general_pmc_ctr(self.get_pmc_index()),
+            Counter::Fixed(_) => self.read_fixed_pmc_ctr(self.get_pmc_index()),
+        }
     }
 
     pub fn check_overflow(&self)->bool{
@@ -358,7 +635,13 @@ impl  PerfCounter{
     }
 }
 
-impl<'a> AbstractPerfCounter for PerfCounter {
+impl<'a, B: PmuBackend> Drop for PerfCounter<'a, B> {
+    fn drop(&mut self) {
+        self.release_counter();
+    }
+}
+
+impl<'a, B: PmuBackend> AbstractPerfCounter for PerfCounter<'a, B> {
     fn reset(&self) -> Result<(),ErrorMsg> {
         match self.get_counter_type(){
             Counter::Programmable(_) => self.set_general_pmc_ctr(self.get_pmc_index(),0),
@@ -370,6 +653,7 @@ impl<'a> AbstractPerfCounter for PerfCounter {
     fn start(&self) -> Result<(), ErrorMsg> {
         match self.get_counter_type(){
             Counter::Programmable(_)=>{
+                self.claim_aux_msr()?;
                 self.enable_general_pmc(self.get_pmc_index())
             }
             Counter::Fixed(_)=> {
@@ -402,3 +686,141 @@ impl<'a> AbstractPerfCounter for PerfCounter {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbstractPerfCounter;
+    use super::lookup::{CpuModel, EventTable};
+    use super::capabilities::CpuSignature;
+    use super::simulator::SimulatedPmu;
+
+    const OTHER_L3_MISS: &str = "OFFCORE_RESPONSE.OTHER.L3_MISS.ANY_SNOOP";
+    const OTHER_L3_MISS_HITM: &str = "OFFCORE_RESPONSE.OTHER.L3_MISS.SNOOP_HITM";
+
+    fn controler() -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        ctrler
+    }
+
+    fn skylake() -> EventTable {
+        EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap()
+    }
+
+    #[test]
+    fn aux_msr_is_shared_and_restored() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let table = skylake();
+        let event = table.lookup(OTHER_L3_MISS).unwrap();
+        pmu.wrmsr(MSR_OFFCORE_RSP_0, 0x55);
+        let mut first = PerfCounter::new(&ctrler);
+        first.build_from_intel_hw_event_alternative(event, 0, 0).unwrap();
+        assert_eq!(pmu.rdmsr(MSR_OFFCORE_RSP_0), event.msr_value);
+        let mut second = PerfCounter::new(&ctrler);
+        second.build_from_intel_hw_event_alternative(event, 1, 0).unwrap();
+        //Stopping keeps the claim, only release or drop gives it back.
+        first.start().unwrap();
+        first.stop().unwrap();
+        drop(first);
+        assert_eq!(pmu.rdmsr(MSR_OFFCORE_RSP_0), event.msr_value);
+        drop(second);
+        assert_eq!(pmu.rdmsr(MSR_OFFCORE_RSP_0), 0x55);
+        assert!(ctrler.aux_msr_available(MSR_OFFCORE_RSP_0, 1));
+    }
+
+    #[test]
+    fn aux_msr_conflicts_fail_at_build_time() {
+        let ctrler = controler();
+        let table = skylake();
+        let event = table.lookup(OTHER_L3_MISS).unwrap();
+        let other = table.lookup(OTHER_L3_MISS_HITM).unwrap();
+        assert_ne!(event.msr_value, other.msr_value);
+        let mut first = PerfCounter::new(&ctrler);
+        first.build_from_intel_hw_event_alternative(event, 0, 0).unwrap();
+        let mut second = PerfCounter::new(&ctrler);
+        assert!(matches!(second.build_from_intel_hw_event_alternative(other, 1, 0), Err(ErrorMsg::SharedMsrInUse)));
+        //The failed build does not keep PMC1.
+        assert!(!ctrler.is_reserved(Counter::Programmable(1)));
+        assert_eq!(second.aux_msr, None);
+        drop(first);
+        second.build_from_intel_hw_event_alternative(other, 1, 0).unwrap();
+        assert_eq!(ctrler.backend().rdmsr(MSR_OFFCORE_RSP_0), other.msr_value);
+    }
+
+    #[test]
+    fn two_code_events_pick_a_free_alternative() {
+        let ctrler = controler();
+        let table = skylake();
+        let event = table.lookup(OTHER_L3_MISS).unwrap();
+        let other = table.lookup(OTHER_L3_MISS_HITM).unwrap();
+        assert_eq!(event_alternatives(event), 2);
+        assert_eq!(event_encoding(event, 0).unwrap(), (0xB7, 0x01));
+        assert_eq!(event_encoding(event, 1).unwrap(), (0xBB, 0x01));
+        assert_eq!(event_aux_msr(event, 1), Some((MSR_OFFCORE_RSP_1, event.msr_value)));
+        let mut first = PerfCounter::new(&ctrler);
+        first.build_from_intel_hw_event(event, 0).unwrap();
+        assert_eq!(first.event_alternative, 0);
+        //MSR_OFFCORE_RSP_0 holds the value of `event`, so `other` moves to the second encoding.
+        let mut second = PerfCounter::new(&ctrler);
+        second.build_from_intel_hw_event(other, 1).unwrap();
+        assert_eq!(second.event_alternative, 1);
+        assert_eq!(second.get_event_select().event_select(), 0xBB);
+        assert_eq!(second.aux_msr, Some((MSR_OFFCORE_RSP_1, other.msr_value)));
+        //Both MSRs are taken with other values.
+        let third_event = table.lookup("OFFCORE_RESPONSE.OTHER.L3_MISS.SNOOP_NON_DRAM").unwrap();
+        let mut third = PerfCounter::new(&ctrler);
+        assert!(matches!(third.build_from_intel_hw_event(third_event, 2), Err(ErrorMsg::SharedMsrInUse)));
+        assert!(!ctrler.is_reserved(Counter::Programmable(2)));
+    }
+
+    #[test]
+    fn missing_alternatives_are_an_error() {
+        let ctrler = controler();
+        let table = skylake();
+        let offcore = table.lookup(OTHER_L3_MISS).unwrap();
+        let single = table.lookup("BR_MISP_RETIRED.ALL_BRANCHES").unwrap();
+        assert_eq!(event_alternatives(single), 1);
+        assert!(matches!(event_encoding(single, 1), Err(ErrorMsg::UnsupportedEventEncoding)));
+        assert!(matches!(event_encoding(offcore, 2), Err(ErrorMsg::UnsupportedEventEncoding)));
+        let mut counter = PerfCounter::new(&ctrler);
+        assert!(matches!(counter.build_from_intel_hw_event_alternative(offcore, 0, 2), Err(ErrorMsg::UnsupportedEventEncoding)));
+        assert!(matches!(counter.build_from_intel_hw_event_alternative(single, 0, 1), Err(ErrorMsg::UnsupportedEventEncoding)));
+        assert_eq!(ctrler.get_reserved_counters(), 0);
+    }
+
+    #[test]
+    fn a_counter_has_one_owner() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let mut first = PerfCounter::new(&ctrler);
+        first.build_general_from_raw(0xC4, 0, true, true, 0, false, 2).unwrap();
+        //Building again on the same counter keeps the claim.
+        first.build_general_from_raw(0xC5, 0, true, true, 0, false, 2).unwrap();
+        let mut second = PerfCounter::new(&ctrler);
+        assert!(matches!(second.build_general_from_raw(0xC4, 0, true, true, 0, false, 2), Err(ErrorMsg::CounterInUse)));
+        let mut cycles = PerfCounter::new(&ctrler);
+        cycles.build_fixed(1, false).unwrap();
+        assert!(matches!(second.build_fixed(1, false), Err(ErrorMsg::CounterInUse)));
+        first.start().unwrap();
+        cycles.start().unwrap();
+        assert_ne!(pmu.rdmsr(IA32_PERFEVTSEL2) & (1 << 22), 0);
+        assert_ne!(pmu.rdmsr(IA32_FIXED_CTR_CTRL) & 0xF0, 0);
+        assert_ne!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL) & (1 << 2 | 1 << 33), 0);
+        drop(first);
+        drop(cycles);
+        assert!(!ctrler.is_reserved(Counter::Programmable(2)));
+        assert!(!ctrler.is_reserved(Counter::Fixed(1)));
+        assert_eq!(pmu.rdmsr(IA32_PERFEVTSEL2) & (1 << 22), 0);
+        assert_eq!(pmu.rdmsr(IA32_FIXED_CTR_CTRL) & 0xF0, 0);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL) & (1 << 2 | 1 << 33), 0);
+        second.build_general_from_raw(0xC4, 0, true, true, 0, false, 2).unwrap();
+        //Moving to another counter gives the old one back.
+        second.build_general_from_raw(0xC4, 0, true, true, 0, false, 3).unwrap();
+        assert!(!ctrler.is_reserved(Counter::Programmable(2)));
+        assert!(ctrler.is_reserved(Counter::Programmable(3)));
+        second.build_fixed(1, false).unwrap();
+        assert_eq!(ctrler.get_reserved_counters(), 1 << 33);
+    }
+}
@@ -0,0 +1,306 @@
+//! Time-multiplexed counting of more events than there are PMCs.
+//!
+//! Events are added in groups; every group must fit on the PMCs by itself.
+//! One group is on the hardware at a time, started and stopped as a PerfCounterGroup, and tick() (e.g. from a timer interrupt) moves to the next one.
+//! For every event the time it was enabled and the time it was actually counting are measured with the TSC,
+//! and read() returns the raw count together with the estimate raw * time_enabled / time_running.
+//!
+
+use x86::perfcnt::intel::{EventDescription, Counter};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler, group::PerfCounterGroup, PerfCounter};
+use super::allocator::{self, UnschedulableEvent, MAX_GENERAL_PMC, MAX_FIXED_PMC};
+
+pub const MAX_MUX_EVENTS: usize = 32;
+pub const MAX_MUX_GROUPS: usize = 16;
+const MAX_GROUP_EVENTS: usize = MAX_GENERAL_PMC + MAX_FIXED_PMC;
+
+///Value of one multiplexed event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MuxReading {
+    ///Events counted while the event was on the hardware.
+    pub raw: u64,
+    ///TSC cycles the multiplexer was running.
+    pub time_enabled: u64,
+    ///TSC cycles the event was on the hardware.
+    pub time_running: u64,
+    ///raw scaled to the whole enabled time.
+    pub scaled: u64,
+}
+
+impl MuxReading {
+    fn new(raw:u64, time_enabled:u64, time_running:u64) -> MuxReading {
+        let scaled = if time_running == 0 {
+            0
+        } else if time_running >= time_enabled {
+            raw
+        } else {
+            ((raw as u128 * time_enabled as u128) / time_running as u128) as u64
+        };
+        MuxReading{raw:raw, time_enabled:time_enabled, time_running:time_running, scaled:scaled}
+    }
+}
+
+#[derive(Clone, Copy)]
+struct MuxEvent<'e> {
+    event: &'e EventDescription<'e>,
+    assigned: Counter,
+    raw: u64,
+    time_running: u64,
+}
+
+pub struct Multiplexer<'c, 'e, B: PmuBackend> {
+    ctrler: &'c PerfCounterControler<B>,
+    events: [Option<MuxEvent<'e>>; MAX_MUX_EVENTS],
+    event_count: usize,
+    //First event and number of events of every group.
+    groups: [(usize, usize); MAX_MUX_GROUPS],
+    group_count: usize,
+    active: PerfCounterGroup<'c, B>,
+    current_group: usize,
+    running: bool,
+    time_enabled: u64,
+    last_tsc: u64,
+}
+
+impl<'c, 'e, B: PmuBackend> Multiplexer<'c, 'e, B> {
+    pub fn new(ctrler:&'c PerfCounterControler<B>) -> Multiplexer<'c, 'e, B> {
+        Multiplexer{
+            ctrler: ctrler,
+            events: [None; MAX_MUX_EVENTS],
+            event_count: 0,
+            groups: [(0, 0); MAX_MUX_GROUPS],
+            group_count: 0,
+            active: PerfCounterGroup::new(ctrler),
+            current_group: 0,
+            running: false,
+            time_enabled: 0,
+            last_tsc: 0,
+        }
+    }
+
+    ///Add a group of events that are always counted together.
+    /// Returns the id of the first event; the others follow in order.
+    ///
+    /// Groups can only be added while the multiplexer is stopped.
+    pub fn add_group(&mut self, events:&[&'e EventDescription<'e>]) -> Result<usize, UnschedulableEvent<'e>> {
+        let fail = |reason:ErrorMsg| UnschedulableEvent{index:0, event_name:events.first().map_or("", |e| e.event_name), reason:reason};
+        if self.running {
+            return Err(fail(ErrorMsg::CounterInUse));
+        }
+        if self.group_count == MAX_MUX_GROUPS || self.event_count + events.len() > MAX_MUX_EVENTS || events.len() > MAX_GROUP_EVENTS {
+            return Err(fail(ErrorMsg::CounterOutOfRange));
+        }
+        let mut assignment = [Counter::Programmable(0); MAX_GROUP_EVENTS];
+        allocator::schedule(self.ctrler, events, &mut assignment)?;
+        let first = self.event_count;
+        for (i, event) in events.iter().enumerate() {
+            self.events[first + i] = Some(MuxEvent{event:event, assigned:assignment[i], raw:0, time_running:0});
+        }
+        self.event_count += events.len();
+        self.groups[self.group_count] = (first, events.len());
+        self.group_count += 1;
+        Ok(first)
+    }
+
+    ///Put the first group on the hardware and start measuring time.
+    pub fn start(&mut self) -> Result<(), ErrorMsg> {
+        if self.running || self.group_count == 0 {
+            return Ok(());
+        }
+        self.last_tsc = self.ctrler.backend().rdtsc();
+        if let Err(e) = self.load_group() {
+            self.abort();
+            return Err(e);
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    ///Account the elapsed time and rotate to the next group.
+    ///
+    /// If the next group cannot be put on the hardware, the multiplexer stops on the group it
+    /// was counting, so start() retries from there; the counts and times so far are kept.
+    pub fn tick(&mut self) -> Result<(), ErrorMsg> {
+        if !self.running {
+            return Ok(());
+        }
+        if let Err(e) = self.unload_group() {
+            self.abort();
+            return Err(e);
+        }
+        let previous = self.current_group;
+        self.current_group = (self.current_group + 1) % self.group_count;
+        if let Err(e) = self.load_group() {
+            self.current_group = previous;
+            self.abort();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    ///Take the current group off the hardware and stop measuring time.
+    pub fn stop(&mut self) -> Result<(), ErrorMsg> {
+        if !self.running {
+            return Ok(());
+        }
+        let unloaded = self.unload_group();
+        self.abort();
+        unloaded
+    }
+
+    ///Value of event `id`, including the counts of the group currently on the hardware.
+    pub fn read(&self, id:usize) -> Option<MuxReading> {
+        let e = self.events.get(id)?.as_ref()?;
+        let mut raw = e.raw;
+        let mut time_running = e.time_running;
+        let mut time_enabled = self.time_enabled;
+        if self.running {
+            let elapsed = self.ctrler.backend().rdtsc().wrapping_sub(self.last_tsc);
+            time_enabled += elapsed;
+            let (first, len) = self.groups[self.current_group];
+            if id >= first && id < first + len {
+                if let Some(counter) = self.active.member(id - first) {
+                    raw += counter.read_ctr();
+                }
+                time_running += elapsed;
+            }
+        }
+        Some(MuxReading::new(raw, time_enabled, time_running))
+    }
+
+    ///Number of events added so far.
+    pub fn len(&self) -> usize {
+        self.event_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.event_count == 0
+    }
+
+    //Drop whatever part of a group is on the hardware and stop measuring time.
+    fn abort(&mut self) {
+        self.active = PerfCounterGroup::new(self.ctrler);
+        self.running = false;
+    }
+
+    fn load_group(&mut self) -> Result<(), ErrorMsg> {
+        let (first, len) = self.groups[self.current_group];
+        self.active = PerfCounterGroup::new(self.ctrler);
+        for i in 0..len {
+            let e = self.events[first + i].unwrap();
+            let mut counter = PerfCounter::new(self.ctrler);
+            allocator::build_assigned(&mut counter, e.event, e.assigned)?;
+            self.active.add(counter)?;
+        }
+        self.active.reset()?;
+        self.active.start()
+    }
+
+    fn unload_group(&mut self) -> Result<(), ErrorMsg> {
+        let now = self.ctrler.backend().rdtsc();
+        let elapsed = now.wrapping_sub(self.last_tsc);
+        self.last_tsc = now;
+        self.time_enabled += elapsed;
+        let (first, len) = self.groups[self.current_group];
+        self.active.stop()?;
+        let mut counts = [0u64; MAX_GROUP_EVENTS];
+        self.active.read(&mut counts)?;
+        for (count, e) in counts.iter().zip(self.events[first..first + len].iter_mut()) {
+            let e = e.as_mut().unwrap();
+            e.raw += count;
+            e.time_running += elapsed;
+        }
+        self.active = PerfCounterGroup::new(self.ctrler);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::lookup::{CpuModel, EventTable};
+    use crate::x86_intel::capabilities::CpuSignature;
+    use crate::x86_intel::simulator::{SimulatedPmu, SimRing};
+
+    const BR_MISP: (u8, u8) = (0xC5, 0x00);
+    const BR_INST: (u8, u8) = (0xC4, 0x00);
+
+    fn skylake() -> EventTable {
+        EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap()
+    }
+
+    fn controler() -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        ctrler
+    }
+
+    fn count(pmu:&SimulatedPmu, event:(u8, u8), n:u64) {
+        pmu.count_event(event.0, event.1, SimRing::User, n);
+    }
+
+    #[test]
+    fn rotates_and_scales() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let table = skylake();
+        let mut mux = Multiplexer::new(&ctrler);
+        let misp = mux.add_group(&[table.lookup("BR_MISP_RETIRED.ALL_BRANCHES").unwrap()]).unwrap();
+        let inst = mux.add_group(&[table.lookup("BR_INST_RETIRED.ALL_BRANCHES").unwrap()]).unwrap();
+        assert_eq!((misp, inst, mux.len()), (0, 1, 2));
+        mux.start().unwrap();
+        count(pmu, BR_MISP, 10);
+        count(pmu, BR_INST, 10);
+        pmu.advance_tsc(100);
+        mux.tick().unwrap();
+        count(pmu, BR_MISP, 5);
+        count(pmu, BR_INST, 7);
+        pmu.advance_tsc(300);
+        assert_eq!(mux.read(misp), Some(MuxReading{raw: 10, time_enabled: 400, time_running: 100, scaled: 40}));
+        assert_eq!(mux.read(inst), Some(MuxReading{raw: 7, time_enabled: 400, time_running: 300, scaled: 9}));
+        //Back to the first group.
+        mux.tick().unwrap();
+        count(pmu, BR_MISP, 2);
+        pmu.advance_tsc(100);
+        mux.stop().unwrap();
+        pmu.advance_tsc(1000);
+        assert_eq!(mux.read(misp), Some(MuxReading{raw: 12, time_enabled: 500, time_running: 200, scaled: 30}));
+        assert_eq!(mux.read(inst), Some(MuxReading{raw: 7, time_enabled: 500, time_running: 300, scaled: 11}));
+        assert_eq!(mux.read(2), None);
+        assert_eq!(ctrler.get_reserved_counters(), 0);
+    }
+
+    #[test]
+    fn a_group_that_does_not_load_stops_the_multiplexer() {
+        let ctrler = controler();
+        let pmu = ctrler.backend();
+        let table = skylake();
+        let mut mux = Multiplexer::new(&ctrler);
+        let misp = mux.add_group(&[table.lookup("BR_MISP_RETIRED.ALL_BRANCHES").unwrap()]).unwrap();
+        let second = [table.lookup("BR_INST_RETIRED.ALL_BRANCHES").unwrap(), table.lookup("MACHINE_CLEARS.COUNT").unwrap()];
+        let inst = mux.add_group(&second).unwrap();
+        mux.start().unwrap();
+        //The second group was scheduled on PMC0 and PMC1; take PMC1 while the first one runs.
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_general_from_raw(0xC3, 0x01, true, true, 0, false, 1).unwrap();
+        count(pmu, BR_MISP, 4);
+        pmu.advance_tsc(100);
+        assert!(matches!(mux.tick(), Err(ErrorMsg::CounterInUse)));
+        assert_eq!(ctrler.get_reserved_counters(), 1 << 1, "the part of the group that was built is released");
+        //Stopped: time does not run on and nothing is counted for the group that never loaded.
+        count(pmu, BR_INST, 9);
+        pmu.advance_tsc(100);
+        mux.stop().unwrap();
+        mux.tick().unwrap();
+        assert_eq!(mux.read(misp), Some(MuxReading{raw: 4, time_enabled: 100, time_running: 100, scaled: 4}));
+        assert_eq!(mux.read(inst), Some(MuxReading{raw: 0, time_enabled: 100, time_running: 0, scaled: 0}));
+        //start() resumes with the group that was counting.
+        drop(other);
+        mux.start().unwrap();
+        count(pmu, BR_MISP, 1);
+        pmu.advance_tsc(100);
+        mux.tick().unwrap();
+        assert_eq!(mux.read(misp).unwrap().raw, 5);
+    }
+}
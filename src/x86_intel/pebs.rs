@@ -0,0 +1,641 @@
+//! Precise Event Based Sampling (PEBS).
+//!
+//! With PEBS the processor itself writes a record to the PEBS buffer of the Debug Store when a
+//! counter enabled in IA32_PEBS_ENABLE overflows, then reloads the counter from its reset value in
+//! the DS area. The record holds the architectural state at the sampled instruction, so it has no
+//! skid. A PMI is only raised once the buffer reaches its interrupt threshold
+//! (IA32_PERF_GLOBAL_STATUS bit 62).
+//!
+//! The record layout depends on the PEBS record format in IA32_PERF_CAPABILITIES:
+//! - 0 (Core): RFLAGS, RIP and the general purpose registers, RIP being the next instruction.
+//! - 1 (Nehalem): adds the applicable counters, data linear address, data source and load latency.
+//! - 2 (Haswell): adds the precise eventing IP and TSX abort information.
+//! - 3 (Skylake): adds the TSC.
+//! - 4 and later (adaptive PEBS): a basic group (IP, applicable counters, TSC) followed by the
+//!   groups selected in MSR_PEBS_DATA_CFG: memory info, GPRs, XMM registers and LBR entries.
+//!
+
+use core::marker::PhantomData;
+use crate::ErrorMsg;
+use x86::perfcnt::intel::Counter;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler, PerfCounter};
+use super::debug_store::{self, DebugStore, DS_GENERAL_RESETS, DS_FIXED_RESETS, IA32_MISC_ENABLE, MISC_ENABLE_PEBS_UNAVAILABLE};
+use super::sampling::SampleRegs;
+
+pub const IA32_PEBS_ENABLE: u32 = 0x3F1;
+pub const MSR_PEBS_DATA_CFG: u32 = 0x3F2;
+pub const MSR_PEBS_LD_LAT_THRESHOLD: u32 = 0x3F6;
+///IA32_PERF_GLOBAL_STATUS: the PEBS buffer reached its interrupt threshold.
+pub const GLOBAL_STATUS_PEBS_THRESHOLD: u64 = 1<<62;
+
+///Most LBR entries an adaptive PEBS record can hold.
+pub const MAX_PEBS_LBR_ENTRIES: u8 = 32;
+
+const BASIC_GROUP_LEN: usize = 0x20;
+const MEMINFO_GROUP_LEN: usize = 0x20;
+const GPRS_GROUP_LEN: usize = 0x90;
+const XMM_GROUP_LEN: usize = 0x100;
+const LBR_ENTRY_LEN: usize = 0x18;
+
+///Size of the largest record of any supported format.
+pub const MAX_PEBS_RECORD_LEN: usize = BASIC_GROUP_LEN + MEMINFO_GROUP_LEN + GPRS_GROUP_LEN + XMM_GROUP_LEN
+    + MAX_PEBS_LBR_ENTRIES as usize * LBR_ENTRY_LEN;
+
+const DATA_CFG_MEMINFO: u64 = 1<<0;
+const DATA_CFG_GPRS: u64 = 1<<1;
+const DATA_CFG_XMM: u64 = 1<<2;
+const DATA_CFG_LBRS: u64 = 1<<3;
+
+///Groups of an adaptive PEBS record, as programmed in MSR_PEBS_DATA_CFG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PebsDataCfg {
+    ///Data linear address, data source, latency and TSX information.
+    pub memory_info: bool,
+    ///RFLAGS, RIP and the general purpose registers.
+    pub gprs: bool,
+    ///XMM0-15.
+    pub xmm: bool,
+    ///Number of LBR entries, 0 for none.
+    pub lbr_entries: u8,
+}
+
+impl PebsDataCfg {
+    pub fn bits(&self) -> u64 {
+        let mut bits = 0;
+        if self.memory_info {
+            bits |= DATA_CFG_MEMINFO;
+        }
+        if self.gprs {
+            bits |= DATA_CFG_GPRS;
+        }
+        if self.xmm {
+            bits |= DATA_CFG_XMM;
+        }
+        if self.lbr_entries != 0 {
+            bits |= DATA_CFG_LBRS | (self.lbr_entries as u64 - 1) << 24;
+        }
+        bits
+    }
+
+    pub fn from_bits(bits:u64) -> PebsDataCfg {
+        PebsDataCfg{
+            memory_info: bits & DATA_CFG_MEMINFO != 0,
+            gprs: bits & DATA_CFG_GPRS != 0,
+            xmm: bits & DATA_CFG_XMM != 0,
+            lbr_entries: if bits & DATA_CFG_LBRS != 0 {((bits >> 24) & 0x1F) as u8 + 1} else {0},
+        }
+    }
+
+    ///Size of an adaptive record with these groups.
+    pub fn record_size(&self) -> usize {
+        let mut size = BASIC_GROUP_LEN;
+        if self.memory_info {
+            size += MEMINFO_GROUP_LEN;
+        }
+        if self.gprs {
+            size += GPRS_GROUP_LEN;
+        }
+        if self.xmm {
+            size += XMM_GROUP_LEN;
+        }
+        size + self.lbr_entries as usize * LBR_ENTRY_LEN
+    }
+}
+
+///RFLAGS, RIP and general purpose registers at the sampled instruction.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PebsGprs {
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub regs: SampleRegs,
+}
+
+///One parsed PEBS record.
+#[derive(Debug, Clone, Copy)]
+pub struct PebsRecord<'r> {
+    ///Eventing IP when precise_ip is set, otherwise the IP of the next instruction.
+    pub ip: u64,
+    pub precise_ip: bool,
+    ///Counters the record was written for, with the bit layout of IA32_PERF_GLOBAL_STATUS.
+    pub applicable_counters: u64,
+    ///Data linear address of load and store events.
+    pub data_address: Option<u64>,
+    ///Data source encoding of load events.
+    pub data_source: Option<u64>,
+    ///Load latency in core cycles.
+    pub latency: Option<u64>,
+    ///TSX abort information.
+    pub tsx_info: Option<u64>,
+    pub tsc: Option<u64>,
+    pub gprs: Option<PebsGprs>,
+    ///XMM0-15, 16 bytes each.
+    pub xmm: Option<&'r [u8]>,
+    lbr: &'r [u8],
+}
+
+impl<'r> PebsRecord<'r> {
+    ///LBR entries of an adaptive record as (from, to, info), most recent first.
+    pub fn lbr_entries(&self) -> impl Iterator<Item = (u64, u64, u64)> + 'r {
+        let lbr = self.lbr;
+        lbr.chunks_exact(LBR_ENTRY_LEN).map(|e| (u64_at(e, 0), u64_at(e, 8), u64_at(e, 16)))
+    }
+}
+
+fn u64_at(bytes:&[u8], at:usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(b)
+}
+
+///Size of the records of a legacy format, or of an adaptive format with `cfg`.
+pub fn record_size(format:u8, cfg:PebsDataCfg) -> Option<usize> {
+    match format {
+        0 => Some(0x90),
+        1 => Some(0xB0),
+        2 => Some(0xC0),
+        3 => Some(0xC8),
+        4..=6 => Some(cfg.record_size()),
+        _ => None,
+    }
+}
+
+///GPRs in the order of the legacy formats: RFLAGS, RIP, RAX, RBX, RCX, RDX, RSI, RDI, RBP, RSP, R8-R15.
+fn legacy_gprs(b:&[u8]) -> PebsGprs {
+    let r = |i:usize| u64_at(b, i * 8);
+    PebsGprs{
+        rflags: r(0),
+        rip: r(1),
+        rsp: r(9),
+        regs: SampleRegs{
+            rax: r(2), rbx: r(3), rcx: r(4), rdx: r(5), rsi: r(6), rdi: r(7), rbp: r(8),
+            r8: r(10), r9: r(11), r10: r(12), r11: r(13), r12: r(14), r13: r(15), r14: r(16), r15: r(17),
+        },
+    }
+}
+
+///GPRs in the order of the adaptive GPR group: RFLAGS, RIP, RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8-R15.
+fn adaptive_gprs(b:&[u8]) -> PebsGprs {
+    let r = |i:usize| u64_at(b, i * 8);
+    PebsGprs{
+        rflags: r(0),
+        rip: r(1),
+        rsp: r(6),
+        regs: SampleRegs{
+            rax: r(2), rcx: r(3), rdx: r(4), rbx: r(5), rbp: r(7), rsi: r(8), rdi: r(9),
+            r8: r(10), r9: r(11), r10: r(12), r11: r(13), r12: r(14), r13: r(15), r14: r(16), r15: r(17),
+        },
+    }
+}
+
+///Parse the record at the start of `bytes`. Returns the record and its size.
+///
+/// Adaptive records describe their own groups and size, so `cfg` is only used by the legacy formats.
+pub fn parse_record(format:u8, cfg:PebsDataCfg, bytes:&[u8]) -> Option<(PebsRecord<'_>, usize)> {
+    let size = record_size(format, cfg)?;
+    if format >= 4 {
+        return parse_adaptive(bytes);
+    }
+    if bytes.len() < size {
+        return None;
+    }
+    let mut record = PebsRecord{
+        ip: u64_at(bytes, 8),
+        precise_ip: false,
+        applicable_counters: 1,
+        data_address: None,
+        data_source: None,
+        latency: None,
+        tsx_info: None,
+        tsc: None,
+        gprs: Some(legacy_gprs(bytes)),
+        xmm: None,
+        lbr: &[],
+    };
+    if format >= 1 {
+        record.applicable_counters = u64_at(bytes, 0x90);
+        record.data_address = Some(u64_at(bytes, 0x98));
+        record.data_source = Some(u64_at(bytes, 0xA0));
+        record.latency = Some(u64_at(bytes, 0xA8));
+    }
+    if format >= 2 {
+        record.ip = u64_at(bytes, 0xB0);
+        record.precise_ip = true;
+        record.tsx_info = Some(u64_at(bytes, 0xB8));
+    }
+    if format >= 3 {
+        record.tsc = Some(u64_at(bytes, 0xC0));
+    }
+    Some((record, size))
+}
+
+fn parse_adaptive(bytes:&[u8]) -> Option<(PebsRecord<'_>, usize)> {
+    if bytes.len() < BASIC_GROUP_LEN {
+        return None;
+    }
+    let format_size = u64_at(bytes, 0);
+    let size = (format_size >> 48) as usize;
+    let groups = PebsDataCfg::from_bits(format_size & 0xFFFF_FFFF);
+    if size < groups.record_size() || bytes.len() < size {
+        return None;
+    }
+    let mut record = PebsRecord{
+        ip: u64_at(bytes, 0x08),
+        precise_ip: true,
+        applicable_counters: u64_at(bytes, 0x10),
+        data_address: None,
+        data_source: None,
+        latency: None,
+        tsx_info: None,
+        tsc: Some(u64_at(bytes, 0x18)),
+        gprs: None,
+        xmm: None,
+        lbr: &[],
+    };
+    let mut at = BASIC_GROUP_LEN;
+    if groups.memory_info {
+        record.data_address = Some(u64_at(bytes, at));
+        record.data_source = Some(u64_at(bytes, at + 8));
+        record.latency = Some(u64_at(bytes, at + 16));
+        record.tsx_info = Some(u64_at(bytes, at + 24));
+        at += MEMINFO_GROUP_LEN;
+    }
+    if groups.gprs {
+        record.gprs = Some(adaptive_gprs(&bytes[at..at + GPRS_GROUP_LEN]));
+        at += GPRS_GROUP_LEN;
+    }
+    if groups.xmm {
+        record.xmm = Some(&bytes[at..at + XMM_GROUP_LEN]);
+        at += XMM_GROUP_LEN;
+    }
+    record.lbr = &bytes[at..at + groups.lbr_entries as usize * LBR_ENTRY_LEN];
+    Some((record, size))
+}
+
+///PEBS on the counters of one controler, writing to a caller provided buffer.
+///
+/// The PEBS half of the processor's DebugStore is claimed for the lifetime of the Pebs; a Bts can
+/// use the other half at the same time.
+pub struct Pebs<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    ds: &'a DebugStore<'a, B>,
+    //Written by the processor, so only read through debug_store::read_buffer().
+    buffer: *mut u8,
+    len: usize,
+    _buffer: PhantomData<&'a mut [u8]>,
+    format: u8,
+    adaptive: bool,
+    data_cfg: PebsDataCfg,
+    record_size: usize,
+    threshold: usize,
+    //Bits this Pebs set in IA32_PEBS_ENABLE.
+    enabled: u64,
+}
+
+impl<'a, B: PmuBackend> Pebs<'a, B> {
+    ///Check PEBS is available, claim the PEBS half of `ds` and lay it out for `buffer`.
+    ///
+    /// The buffer threshold interrupt is raised when the buffer is full; see set_threshold().
+    pub fn new(ds:&'a DebugStore<'a, B>, buffer:&'a mut [u8]) -> Result<Pebs<'a, B>, ErrorMsg> {
+        let ctrler = ds.controler();
+        let caps = match ctrler.get_capabilities().perf_capabilities {
+            Some(caps) => caps,
+            None => return Err(ErrorMsg::UnsupportedFeature),
+        };
+        if ctrler.backend().rdmsr(IA32_MISC_ENABLE) & MISC_ENABLE_PEBS_UNAVAILABLE != 0 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        let format = caps.pebs_record_format;
+        let data_cfg = PebsDataCfg::default();
+        let record_size = record_size(format, data_cfg).ok_or(ErrorMsg::UnsupportedFeature)?;
+        ds.claim_pebs()?;
+        let mut pebs = Pebs{
+            ctrler: ctrler,
+            ds: ds,
+            buffer: buffer.as_mut_ptr(),
+            len: buffer.len(),
+            _buffer: PhantomData,
+            format: format,
+            adaptive: format >= 4 && caps.pebs_baseline,
+            data_cfg: data_cfg,
+            record_size: record_size,
+            threshold: usize::MAX,
+            enabled: 0,
+        };
+        pebs.layout()?;
+        Ok(pebs)
+    }
+
+    pub fn record_format(&self) -> u8 {
+        self.format
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    pub fn record_size(&self) -> usize {
+        self.record_size
+    }
+
+    ///Records the buffer holds.
+    pub fn capacity(&self) -> usize {
+        self.len / self.record_size
+    }
+
+    ///Raise the threshold interrupt once `records` records are in the buffer.
+    pub fn set_threshold(&mut self, records:usize) -> Result<(), ErrorMsg> {
+        self.threshold = records.max(1);
+        self.layout()
+    }
+
+    ///Select the groups of adaptive records. Only valid with adaptive PEBS, before arm().
+    pub fn set_data_cfg(&mut self, cfg:PebsDataCfg) -> Result<(), ErrorMsg> {
+        if !self.adaptive || cfg.lbr_entries > MAX_PEBS_LBR_ENTRIES {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        self.data_cfg = cfg;
+        self.record_size = cfg.record_size();
+        self.layout()?;
+        self.ctrler.backend().wrmsr(MSR_PEBS_DATA_CFG, cfg.bits());
+        Ok(())
+    }
+
+    fn layout(&mut self) -> Result<(), ErrorMsg> {
+        let records = self.capacity();
+        if records == 0 {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        let base = self.buffer as u64;
+        let threshold = self.threshold.min(records);
+        self.ds.set_pebs_buffer(base, base + (records * self.record_size) as u64, base + (threshold * self.record_size) as u64);
+        Ok(())
+    }
+
+    ///Reset the buffer and point IA32_DS_AREA at the DebugStore, if it does not already.
+    pub fn arm(&mut self) -> Result<(), ErrorMsg> {
+        self.layout()?;
+        self.ds.install();
+        Ok(())
+    }
+
+    ///Make `counter` write a PEBS record every `period` events instead of raising a PMI.
+    ///
+    /// The counter must be built for a PEBS capable event; fixed counters need adaptive PEBS.
+    /// Fails with UnsupportedFeature until arm() installed the DebugStore, since the processor
+    /// would write the records through whatever IA32_DS_AREA points at. Start the counter as usual afterwards.
+    pub fn enable(&mut self, counter:&mut PerfCounter<'_, B>, period:u64) -> Result<(), ErrorMsg> {
+        if !core::ptr::eq(counter.global_ctrler, self.ctrler) || period == 0 {
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        if !self.ds.is_installed() {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        let (width, bit) = match counter.get_counter_type() {
+            Counter::Programmable(_) => {
+                let index = counter.get_pmc_index() as usize;
+                if index >= DS_GENERAL_RESETS {
+                    return Err(ErrorMsg::CounterOutOfRange);
+                }
+                (self.ctrler.get_bit_width(), 1u64 << index)
+            },
+            Counter::Fixed(_) => {
+                let index = counter.get_pmc_index() as usize;
+                if !self.adaptive {
+                    return Err(ErrorMsg::UnsupportedFeature);
+                }
+                if index >= DS_FIXED_RESETS {
+                    return Err(ErrorMsg::CounterOutOfRange);
+                }
+                (self.ctrler.get_bit_width_fixed_counter(), 1u64 << (32 + index))
+            },
+        };
+        let reset = 0u64.wrapping_sub(period) & ((1u64 << width.min(63)) - 1);
+        match counter.get_counter_type() {
+            Counter::Programmable(_) => self.ds.set_pebs_counter_reset(counter.get_pmc_index() as usize, reset),
+            Counter::Fixed(_) => self.ds.set_pebs_fixed_counter_reset(counter.get_pmc_index() as usize, reset),
+        }
+        counter.disable_interrupt();
+        counter.overflow_after(period - 1);
+        self.set_enable_bits(self.enabled | bit);
+        Ok(())
+    }
+
+    ///Record loads slower than `cycles` with a load latency event on `counter`
+    /// (MEM_TRANS_RETIRED.LOAD_LATENCY_*), which must already be enabled with enable().
+    pub fn enable_load_latency(&mut self, counter:&PerfCounter<'_, B>, cycles:u16) -> Result<(), ErrorMsg> {
+        if self.format == 0 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        let index = match counter.get_counter_type() {
+            Counter::Programmable(_) => counter.get_pmc_index() as u64,
+            Counter::Fixed(_) => return Err(ErrorMsg::UnsupportedEvent),
+        };
+        if self.enabled & (1 << index) == 0 {
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        self.ctrler.backend().wrmsr(MSR_PEBS_LD_LAT_THRESHOLD, cycles as u64);
+        //Before adaptive PEBS, load latency has its own enable bits above the counter bits.
+        if !self.adaptive {
+            self.set_enable_bits(self.enabled | 1 << (32 + index));
+        }
+        Ok(())
+    }
+
+    ///Clear every bit this Pebs set in IA32_PEBS_ENABLE.
+    pub fn disable(&mut self) {
+        self.set_enable_bits(0);
+    }
+
+    fn set_enable_bits(&mut self, bits:u64) {
+        let backend = self.ctrler.backend();
+        let others = backend.rdmsr(IA32_PEBS_ENABLE) & !self.enabled;
+        backend.wrmsr(IA32_PEBS_ENABLE, others | bits);
+        self.enabled = bits;
+    }
+
+    ///Bytes of records written since the last drain.
+    pub fn pending_bytes(&self) -> usize {
+        (self.ds.pebs_index().saturating_sub(self.buffer as u64) as usize).min(self.len)
+    }
+
+    ///Pass every record written since the last drain to `f`, oldest first, and empty the buffer.
+    /// Returns the number of records.
+    pub fn drain(&mut self, mut f:impl FnMut(&PebsRecord)) -> usize {
+        let end = self.pending_bytes();
+        let mut record = [0u8; MAX_PEBS_RECORD_LEN];
+        let mut at = 0;
+        let mut count = 0;
+        while at < end {
+            //Copy the record out of the buffer before parsing it; record_size() covers it whatever the format.
+            let len = (end - at).min(self.record_size);
+            unsafe { debug_store::read_buffer(self.buffer.add(at), &mut record[..len]); }
+            match parse_record(self.format, self.data_cfg, &record[..len]) {
+                Some((record, size)) => {
+                    f(&record);
+                    at += size;
+                    count += 1;
+                },
+                None => break,
+            }
+        }
+        self.ds.set_pebs_index(self.buffer as u64);
+        count
+    }
+
+    ///PMI handler entry point for the PEBS buffer threshold interrupt.
+    ///
+    /// If IA32_PERF_GLOBAL_STATUS reports the threshold was reached, drains the buffer into `f`,
+    /// clears the status bit and unmasks the LVT entry. Returns the number of records.
+    pub fn handle_interrupt(&mut self, f:impl FnMut(&PebsRecord)) -> usize {
+        if self.ctrler.read_overflow_status() & GLOBAL_STATUS_PEBS_THRESHOLD == 0 {
+            return 0;
+        }
+        let count = self.drain(f);
+        self.ctrler.set_overflow_ctrl(GLOBAL_STATUS_PEBS_THRESHOLD);
+        self.ctrler.reset_overflow_interrput();
+        count
+    }
+}
+
+impl<'a, B: PmuBackend> Drop for Pebs<'a, B> {
+    fn drop(&mut self) {
+        //The processor must not write to the buffer once it is given back.
+        self.disable();
+        self.ds.release_pebs();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::debug_store::DebugStoreArea;
+    use crate::x86_intel::simulator::SimulatedPmu;
+    use x86::cpuid::CpuIdResult;
+
+    //Record whose n-th u64 is 0x1000 + n.
+    fn words(len:usize) -> [u8; MAX_PEBS_RECORD_LEN] {
+        let mut bytes = [0u8; MAX_PEBS_RECORD_LEN];
+        for (i, word) in bytes[..len].chunks_exact_mut(8).enumerate() {
+            word.copy_from_slice(&(0x1000 + i as u64).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn w(n:u64) -> u64 {
+        0x1000 + n
+    }
+
+    #[test]
+    fn legacy_formats() {
+        let bytes = words(0xC8);
+        for (format, size) in [(0u8, 0x90), (1, 0xB0), (2, 0xC0), (3, 0xC8)] {
+            assert!(parse_record(format, PebsDataCfg::default(), &bytes[..size - 1]).is_none());
+            let (record, len) = parse_record(format, PebsDataCfg::default(), &bytes[..size]).unwrap();
+            assert_eq!(len, size);
+            let gprs = record.gprs.unwrap();
+            assert_eq!((gprs.rflags, gprs.rip, gprs.rsp), (w(0), w(1), w(9)));
+            assert_eq!((gprs.regs.rax, gprs.regs.rbx, gprs.regs.rcx, gprs.regs.rdx), (w(2), w(3), w(4), w(5)));
+            assert_eq!((gprs.regs.rsi, gprs.regs.rdi, gprs.regs.rbp), (w(6), w(7), w(8)));
+            assert_eq!((gprs.regs.r8, gprs.regs.r15), (w(10), w(17)));
+            assert_eq!(record.applicable_counters, if format >= 1 {w(18)} else {1});
+            assert_eq!(record.data_address, (format >= 1).then_some(w(19)));
+            assert_eq!(record.data_source, (format >= 1).then_some(w(20)));
+            assert_eq!(record.latency, (format >= 1).then_some(w(21)));
+            assert_eq!((record.ip, record.precise_ip), if format >= 2 {(w(22), true)} else {(w(1), false)});
+            assert_eq!(record.tsx_info, (format >= 2).then_some(w(23)));
+            assert_eq!(record.tsc, (format >= 3).then_some(w(24)));
+            assert!(record.xmm.is_none());
+            assert_eq!(record.lbr_entries().count(), 0);
+        }
+        assert!(parse_record(7, PebsDataCfg::default(), &bytes).is_none());
+    }
+
+    #[test]
+    fn adaptive_groups() {
+        let cfg = PebsDataCfg{memory_info: true, gprs: true, xmm: true, lbr_entries: 2};
+        let size = cfg.record_size();
+        assert_eq!(size, 0x20 + 0x20 + 0x90 + 0x100 + 2 * 0x18);
+        let mut bytes = words(size);
+        bytes[..8].copy_from_slice(&((size as u64) << 48 | cfg.bits()).to_le_bytes());
+        //Adaptive records describe their groups, whatever cfg is passed.
+        let (record, len) = parse_record(4, PebsDataCfg::default(), &bytes[..size]).unwrap();
+        assert_eq!(len, size);
+        assert_eq!((record.ip, record.applicable_counters, record.tsc), (w(1), w(2), Some(w(3))));
+        assert!(record.precise_ip);
+        assert_eq!((record.data_address, record.data_source), (Some(w(4)), Some(w(5))));
+        assert_eq!((record.latency, record.tsx_info), (Some(w(6)), Some(w(7))));
+        let gprs = record.gprs.unwrap();
+        assert_eq!((gprs.rflags, gprs.rip, gprs.rsp), (w(8), w(9), w(14)));
+        assert_eq!((gprs.regs.rax, gprs.regs.rcx, gprs.regs.rdx, gprs.regs.rbx), (w(10), w(11), w(12), w(13)));
+        assert_eq!((gprs.regs.rbp, gprs.regs.rsi, gprs.regs.rdi), (w(15), w(16), w(17)));
+        assert_eq!((gprs.regs.r8, gprs.regs.r15), (w(18), w(25)));
+        let xmm = record.xmm.unwrap();
+        assert_eq!(xmm.len(), 0x100);
+        assert_eq!(u64_at(xmm, 0), w(26));
+        let mut lbr = record.lbr_entries();
+        assert_eq!(lbr.next(), Some((w(58), w(59), w(60))));
+        assert_eq!(lbr.next(), Some((w(61), w(62), w(63))));
+        assert_eq!(lbr.next(), None);
+        assert!(parse_record(4, cfg, &bytes[..size - 1]).is_none());
+    }
+
+    #[test]
+    fn adaptive_basic_group_only() {
+        let mut bytes = words(0x20);
+        bytes[..8].copy_from_slice(&(0x20u64 << 48).to_le_bytes());
+        let (record, len) = parse_record(5, PebsDataCfg::default(), &bytes[..0x20]).unwrap();
+        assert_eq!(len, 0x20);
+        assert_eq!(record.ip, w(1));
+        assert!(record.data_address.is_none() && record.gprs.is_none() && record.xmm.is_none());
+        //A size too small for the groups it claims is rejected.
+        bytes[..8].copy_from_slice(&(0x20u64 << 48 | DATA_CFG_GPRS).to_le_bytes());
+        assert!(parse_record(5, PebsDataCfg::default(), &bytes).is_none());
+    }
+
+    #[test]
+    fn drain_copies_records_out_of_the_buffer() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax:0, ebx:0, ecx:1<<15, edx:1<<21}).unwrap();
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        let mut area = DebugStoreArea::new();
+        let ds = DebugStore::new(&ctrler, &mut area).unwrap();
+        let mut buffer = [0u8; 0x90 * 4];
+        buffer[..0x90].copy_from_slice(&words(0x90)[..0x90]);
+        buffer[0x90 + 8..0x90 + 16].copy_from_slice(&0x2000u64.to_le_bytes());
+        let base = buffer.as_ptr() as u64;
+        let mut pebs = Pebs::new(&ds, &mut buffer).unwrap();
+        assert_eq!(pebs.record_format(), 0);
+        ds.set_pebs_index(base + 2 * 0x90);
+        let mut ips = [0u64; 2];
+        let mut n = 0;
+        assert_eq!(pebs.drain(|record| { ips[n] = record.ip; n += 1; }), 2);
+        assert_eq!(ips, [w(1), 0x2000]);
+        assert_eq!(pebs.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn enable_needs_an_armed_pebs() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax:0, ebx:0, ecx:1<<15, edx:1<<21}).unwrap();
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        let mut area = DebugStoreArea::new();
+        let ds = DebugStore::new(&ctrler, &mut area).unwrap();
+        let mut buffer = [0u8; 0x90 * 4];
+        let mut pebs = Pebs::new(&ds, &mut buffer).unwrap();
+        let mut counter = PerfCounter::new(&ctrler);
+        counter.build_general_from_raw(0xC4, 0, true, true, 0, false, 1).unwrap();
+        assert!(matches!(pebs.enable(&mut counter, 1000), Err(ErrorMsg::UnsupportedFeature)));
+        assert_eq!(ctrler.backend().rdmsr(IA32_PEBS_ENABLE), 0);
+        pebs.arm().unwrap();
+        assert!(ds.is_installed());
+        pebs.enable(&mut counter, 1000).unwrap();
+        assert_eq!(ctrler.backend().rdmsr(IA32_PEBS_ENABLE), 1 << 1);
+        assert_eq!(ctrler.backend().general_counter(1), (1 << 48) - 1000);
+        pebs.disable();
+        assert_eq!(ctrler.backend().rdmsr(IA32_PEBS_ENABLE), 0);
+    }
+}
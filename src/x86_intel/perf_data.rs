@@ -0,0 +1,564 @@
+//! Export samples as a perf.data file that `perf report` and `perf script` can read.
+//!
+//! The file has the layout of `perf record` output: the file header, one attribute per sampled
+//! event (with its sample id), then the data section with PERF_RECORD_COMM, PERF_RECORD_MMAP and
+//! one PERF_RECORD_SAMPLE per sampling::Sample. Every sample carries
+//! PERF_SAMPLE_IP | TID | TIME | ID | CPU | PERIOD; the time is the raw TSC value.
+//!
+//! The output goes to a ByteSink and nothing is buffered, so the samples are walked twice:
+//! once to size the data section and once to write it.
+//! PerfDataReader parses such a file back.
+//!
+
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::str;
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, sampling::Sample, PerfCounter};
+
+pub const PERF_MAGIC: u64 = 0x32454c4946524550; //"PERFILE2"
+pub const PERF_HEADER_SIZE: usize = 104;
+pub const PERF_ATTR_SIZE: usize = 112;
+const PERF_FILE_ATTR_SIZE: usize = PERF_ATTR_SIZE + 16;
+
+pub const PERF_TYPE_RAW: u32 = 4;
+
+pub const PERF_RECORD_MMAP: u32 = 1;
+pub const PERF_RECORD_COMM: u32 = 3;
+pub const PERF_RECORD_SAMPLE: u32 = 9;
+
+pub const PERF_RECORD_MISC_KERNEL: u16 = 1;
+pub const PERF_RECORD_MISC_USER: u16 = 2;
+
+pub const PERF_SAMPLE_IP: u64 = 1<<0;
+pub const PERF_SAMPLE_TID: u64 = 1<<1;
+pub const PERF_SAMPLE_TIME: u64 = 1<<2;
+pub const PERF_SAMPLE_ID: u64 = 1<<6;
+pub const PERF_SAMPLE_CPU: u64 = 1<<7;
+pub const PERF_SAMPLE_PERIOD: u64 = 1<<8;
+///sample_type of the events written by PerfData.
+pub const SAMPLE_TYPE: u64 = PERF_SAMPLE_IP | PERF_SAMPLE_TID | PERF_SAMPLE_TIME | PERF_SAMPLE_ID | PERF_SAMPLE_CPU | PERF_SAMPLE_PERIOD;
+
+const ATTR_EXCLUDE_USER: u64 = 1<<4;
+const ATTR_EXCLUDE_KERNEL: u64 = 1<<5;
+const ATTR_EXCLUDE_HV: u64 = 1<<6;
+
+const RECORD_HEADER_SIZE: usize = 8;
+const SAMPLE_RECORD_SIZE: usize = RECORD_HEADER_SIZE + 6 * 8;
+
+///Destination of the bytes of a perf.data file.
+pub trait ByteSink {
+    fn write_bytes(&mut self, bytes:&[u8]) -> Result<(), ErrorMsg>;
+}
+
+///ByteSink filling a byte slice.
+pub struct SliceSink<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> SliceSink<'b> {
+    pub fn new(buf:&'b mut [u8]) -> SliceSink<'b> {
+        SliceSink{buf: buf, pos: 0}
+    }
+
+    ///Bytes written so far.
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+}
+
+impl<'b> ByteSink for SliceSink<'b> {
+    fn write_bytes(&mut self, bytes:&[u8]) -> Result<(), ErrorMsg> {
+        let end = self.pos + bytes.len();
+        if end > self.buf.len() {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ByteSink for std::vec::Vec<u8> {
+    fn write_bytes(&mut self, bytes:&[u8]) -> Result<(), ErrorMsg> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+///One sampled event, in the order of the Sampler ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfDataEvent {
+    ///PERF_TYPE_RAW config, see PerfCounter::perf_raw_config().
+    pub config: u64,
+    pub sample_period: u64,
+    pub exclude_kernel: bool,
+    pub exclude_user: bool,
+}
+
+impl PerfDataEvent {
+    ///Event of a counter sampled every `sample_period` events.
+    pub fn from_perf_counter<B: PmuBackend>(counter:&PerfCounter<B>, sample_period:u64) -> Result<PerfDataEvent, ErrorMsg> {
+        let raw = counter.perf_raw_config()?;
+        Ok(PerfDataEvent{
+            config: raw.config,
+            sample_period: sample_period,
+            exclude_kernel: !raw.kernel,
+            exclude_user: !raw.user,
+        })
+    }
+}
+
+///PERF_RECORD_COMM: name of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comm<'s> {
+    pub pid: u32,
+    pub tid: u32,
+    pub comm: &'s str,
+}
+
+///PERF_RECORD_MMAP: a file mapped in a process. The kernel image uses pid u32::MAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mmap<'s> {
+    pub pid: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub pgoff: u64,
+    pub filename: &'s str,
+}
+
+fn string_size(s:&str) -> usize {
+    (s.len() + 1 + 7) & !7
+}
+
+fn comm_size(comm:&Comm) -> usize {
+    RECORD_HEADER_SIZE + 8 + string_size(comm.comm)
+}
+
+fn mmap_size(mmap:&Mmap) -> usize {
+    RECORD_HEADER_SIZE + 32 + string_size(mmap.filename)
+}
+
+struct Emitter<'s, S: ByteSink> {
+    sink: &'s mut S,
+    written: u64,
+}
+
+impl<'s, S: ByteSink> Emitter<'s, S> {
+    fn bytes(&mut self, bytes:&[u8]) -> Result<(), ErrorMsg> {
+        self.sink.write_bytes(bytes)?;
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn u16(&mut self, v:u16) -> Result<(), ErrorMsg> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v:u32) -> Result<(), ErrorMsg> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v:u64) -> Result<(), ErrorMsg> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn section(&mut self, offset:usize, size:usize) -> Result<(), ErrorMsg> {
+        self.u64(offset as u64)?;
+        self.u64(size as u64)
+    }
+
+    ///Fails with BufferTooSmall if the record does not fit the 16 bit size field.
+    fn record_header(&mut self, kind:u32, misc:u16, size:usize) -> Result<(), ErrorMsg> {
+        let size = u16::try_from(size).map_err(|_| ErrorMsg::BufferTooSmall)?;
+        self.u32(kind)?;
+        self.u16(misc)?;
+        self.u16(size)
+    }
+
+    ///NUL terminated string padded to 8 bytes.
+    fn string(&mut self, s:&str) -> Result<(), ErrorMsg> {
+        self.bytes(s.as_bytes())?;
+        for _ in s.len()..string_size(s) {
+            self.bytes(&[0])?;
+        }
+        Ok(())
+    }
+
+    fn attr(&mut self, event:&PerfDataEvent) -> Result<(), ErrorMsg> {
+        let mut flags = ATTR_EXCLUDE_HV;
+        if event.exclude_kernel {
+            flags |= ATTR_EXCLUDE_KERNEL;
+        }
+        if event.exclude_user {
+            flags |= ATTR_EXCLUDE_USER;
+        }
+        self.u32(PERF_TYPE_RAW)?;
+        self.u32(PERF_ATTR_SIZE as u32)?;
+        self.u64(event.config)?;
+        self.u64(event.sample_period)?;
+        self.u64(SAMPLE_TYPE)?;
+        self.u64(0)?; //read_format
+        self.u64(flags)?;
+        //wakeup_events to reserved, all unused.
+        self.bytes(&[0; PERF_ATTR_SIZE - 48])
+    }
+}
+
+///Content of a perf.data file besides the samples.
+pub struct PerfData<'a> {
+    ///Sampled events; Sample::id indexes this slice.
+    pub events: &'a [PerfDataEvent],
+    pub comms: &'a [Comm<'a>],
+    pub mmaps: &'a [Mmap<'a>],
+    ///pid and tid the samples are attributed to.
+    pub pid: u32,
+    pub tid: u32,
+}
+
+impl<'a> PerfData<'a> {
+    ///Write the file to `sink`. Samples whose id has no event are skipped.
+    /// Returns the number of bytes written.
+    ///
+    /// Fails with BufferTooSmall before writing anything if a name makes its record larger than
+    /// the 64 KiB a record header can describe.
+    pub fn write<'s, S: ByteSink, I: Iterator<Item = &'s Sample> + Clone>(&self, sink:&mut S, samples:I) -> Result<u64, ErrorMsg> {
+        let too_large = |size:usize| size > u16::MAX as usize;
+        if self.comms.iter().map(comm_size).any(too_large) || self.mmaps.iter().map(mmap_size).any(too_large) {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        let n = self.events.len();
+        let attrs_offset = PERF_HEADER_SIZE;
+        let ids_offset = attrs_offset + n * PERF_FILE_ATTR_SIZE;
+        let data_offset = ids_offset + n * 8;
+        let sample_count = samples.clone().filter(|s| (s.id as usize) < n).count();
+        let data_size = self.comms.iter().map(comm_size).sum::<usize>()
+            + self.mmaps.iter().map(mmap_size).sum::<usize>()
+            + sample_count * SAMPLE_RECORD_SIZE;
+
+        let mut out = Emitter{sink: sink, written: 0};
+        out.u64(PERF_MAGIC)?;
+        out.u64(PERF_HEADER_SIZE as u64)?;
+        out.u64(PERF_FILE_ATTR_SIZE as u64)?;
+        out.section(attrs_offset, n * PERF_FILE_ATTR_SIZE)?;
+        out.section(data_offset, data_size)?;
+        out.section(0, 0)?; //event_types
+        out.bytes(&[0; 32])?; //feature bitmap
+
+        for (i, event) in self.events.iter().enumerate() {
+            out.attr(event)?;
+            out.section(ids_offset + i * 8, 8)?;
+        }
+        for i in 0..n {
+            out.u64(i as u64 + 1)?;
+        }
+
+        for comm in self.comms {
+            out.record_header(PERF_RECORD_COMM, 0, comm_size(comm))?;
+            out.u32(comm.pid)?;
+            out.u32(comm.tid)?;
+            out.string(comm.comm)?;
+        }
+        for mmap in self.mmaps {
+            let misc = if mmap.pid == u32::MAX {PERF_RECORD_MISC_KERNEL} else {PERF_RECORD_MISC_USER};
+            out.record_header(PERF_RECORD_MMAP, misc, mmap_size(mmap))?;
+            out.u32(mmap.pid)?;
+            out.u32(mmap.tid)?;
+            out.u64(mmap.addr)?;
+            out.u64(mmap.len)?;
+            out.u64(mmap.pgoff)?;
+            out.string(mmap.filename)?;
+        }
+        for sample in samples {
+            let event = match self.events.get(sample.id as usize) {
+                Some(event) => event,
+                None => continue,
+            };
+            let misc = if sample.user {PERF_RECORD_MISC_USER} else {PERF_RECORD_MISC_KERNEL};
+            out.record_header(PERF_RECORD_SAMPLE, misc, SAMPLE_RECORD_SIZE)?;
+            out.u64(sample.rip)?;
+            out.u32(self.pid)?;
+            out.u32(self.tid)?;
+            out.u64(sample.tsc)?;
+            out.u64(sample.id as u64 + 1)?;
+            out.u32(sample.cpu)?;
+            out.u32(0)?;
+            out.u64(event.sample_period)?;
+        }
+        Ok(out.written)
+    }
+}
+
+///Event attribute read back from a perf.data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfDataAttr {
+    pub type_: u32,
+    pub config: u64,
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub flags: u64,
+    ///First sample id of the event, 0 if it has none.
+    pub id: u64,
+}
+
+///Record read back from a perf.data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfRecord<'a> {
+    Comm{pid:u32, tid:u32, comm:&'a str},
+    Mmap{pid:u32, tid:u32, addr:u64, len:u64, pgoff:u64, filename:&'a str},
+    Sample{misc:u16, ip:u64, pid:u32, tid:u32, time:u64, id:u64, cpu:u32, period:u64},
+    ///Any other record type, or a sample with another sample_type.
+    Other{kind:u32, misc:u16, body:&'a [u8]},
+}
+
+fn u16_at(bytes:&[u8], at:usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(bytes:&[u8], at:usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(bytes:&[u8], at:usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn string_at(bytes:&[u8], at:usize) -> Option<&str> {
+    let tail = bytes.get(at..)?;
+    let end = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+    str::from_utf8(&tail[..end]).ok()
+}
+
+///Parser of perf.data files written by PerfData, or by `perf record` with the same sample_type.
+pub struct PerfDataReader<'a> {
+    bytes: &'a [u8],
+    attr_size: usize,
+    attrs: (usize, usize),
+    data: (usize, usize),
+}
+
+impl<'a> PerfDataReader<'a> {
+    pub fn parse(bytes:&'a [u8]) -> Result<PerfDataReader<'a>, ErrorMsg> {
+        let field = |at:usize| u64_at(bytes, at).map(|v| v as usize).ok_or(ErrorMsg::InvalidFormat);
+        if u64_at(bytes, 0) != Some(PERF_MAGIC) || field(8)? < PERF_HEADER_SIZE {
+            return Err(ErrorMsg::InvalidFormat);
+        }
+        let reader = PerfDataReader{
+            bytes: bytes,
+            attr_size: field(16)?,
+            attrs: (field(24)?, field(32)?),
+            data: (field(40)?, field(48)?),
+        };
+        let in_file = |(offset, size):(usize, usize)| offset.checked_add(size).is_some_and(|end| end <= bytes.len());
+        if reader.attr_size < 16 || !in_file(reader.attrs) || !in_file(reader.data) {
+            return Err(ErrorMsg::InvalidFormat);
+        }
+        Ok(reader)
+    }
+
+    pub fn attr_count(&self) -> usize {
+        self.attrs.1 / self.attr_size
+    }
+
+    pub fn attr(&self, index:usize) -> Option<PerfDataAttr> {
+        if index >= self.attr_count() {
+            return None;
+        }
+        let at = self.attrs.0 + index * self.attr_size;
+        let ids_section = at + self.attr_size - 16;
+        let ids_offset = u64_at(self.bytes, ids_section)? as usize;
+        let ids_size = u64_at(self.bytes, ids_section + 8)?;
+        Some(PerfDataAttr{
+            type_: u32_at(self.bytes, at)?,
+            config: u64_at(self.bytes, at + 8)?,
+            sample_period: u64_at(self.bytes, at + 16)?,
+            sample_type: u64_at(self.bytes, at + 24)?,
+            flags: u64_at(self.bytes, at + 40)?,
+            id: if ids_size >= 8 {u64_at(self.bytes, ids_offset)?} else {0},
+        })
+    }
+
+    ///Records of the data section, in file order. Iteration stops at the first malformed record.
+    pub fn records(&self) -> PerfRecords<'a> {
+        let sample_type = self.attr(0).map_or(0, |attr| attr.sample_type);
+        PerfRecords{
+            data: &self.bytes[self.data.0..self.data.0 + self.data.1],
+            pos: 0,
+            sample_type: sample_type,
+        }
+    }
+}
+
+pub struct PerfRecords<'a> {
+    data: &'a [u8],
+    pos: usize,
+    sample_type: u64,
+}
+
+impl<'a> PerfRecords<'a> {
+    fn parse(&self, kind:u32, misc:u16, body:&'a [u8]) -> Option<PerfRecord<'a>> {
+        Some(match kind {
+            PERF_RECORD_COMM => PerfRecord::Comm{
+                pid: u32_at(body, 0)?,
+                tid: u32_at(body, 4)?,
+                comm: string_at(body, 8)?,
+            },
+            PERF_RECORD_MMAP => PerfRecord::Mmap{
+                pid: u32_at(body, 0)?,
+                tid: u32_at(body, 4)?,
+                addr: u64_at(body, 8)?,
+                len: u64_at(body, 16)?,
+                pgoff: u64_at(body, 24)?,
+                filename: string_at(body, 32)?,
+            },
+            PERF_RECORD_SAMPLE if self.sample_type == SAMPLE_TYPE => PerfRecord::Sample{
+                misc: misc,
+                ip: u64_at(body, 0)?,
+                pid: u32_at(body, 8)?,
+                tid: u32_at(body, 12)?,
+                time: u64_at(body, 16)?,
+                id: u64_at(body, 24)?,
+                cpu: u32_at(body, 32)?,
+                period: u64_at(body, 40)?,
+            },
+            _ => PerfRecord::Other{kind: kind, misc: misc, body: body},
+        })
+    }
+}
+
+impl<'a> Iterator for PerfRecords<'a> {
+    type Item = PerfRecord<'a>;
+
+    fn next(&mut self) -> Option<PerfRecord<'a>> {
+        let kind = u32_at(self.data, self.pos)?;
+        let misc = u16_at(self.data, self.pos + 4)?;
+        let size = u16_at(self.data, self.pos + 6)? as usize;
+        if size < RECORD_HEADER_SIZE {
+            return None;
+        }
+        let body = self.data.get(self.pos + RECORD_HEADER_SIZE..self.pos + size)?;
+        self.pos += size;
+        self.parse(kind, misc, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86::perfcnt::intel::Counter;
+
+    const EVENTS: [PerfDataEvent; 2] = [
+        PerfDataEvent{config: 0x00C0, sample_period: 100_000, exclude_kernel: false, exclude_user: false},
+        PerfDataEvent{config: 0x2EB7, sample_period: 5_000, exclude_kernel: true, exclude_user: false},
+    ];
+
+    fn sample(id:u8, rip:u64, user:bool, tsc:u64) -> Sample {
+        Sample{
+            rip: rip,
+            rsp: 0,
+            user: user,
+            cpu: 3,
+            tsc: tsc,
+            counter: Counter::Programmable(id),
+            id: id,
+            pmi: 0,
+            regs: None,
+        }
+    }
+
+    fn write(data:&PerfData, samples:&[Sample], buf:&mut [u8]) -> usize {
+        let mut sink = SliceSink::new(buf);
+        let written = data.write(&mut sink, samples.iter()).unwrap();
+        assert_eq!(written as usize, sink.len());
+        sink.len()
+    }
+
+    #[test]
+    fn round_trip() {
+        let comms = [Comm{pid: 42, tid: 43, comm: "workload"}];
+        let mmaps = [
+            Mmap{pid: u32::MAX, tid: 0, addr: 0xFFFF_FFFF_8100_0000, len: 0x100_0000, pgoff: 0, filename: "[kernel.kallsyms]_text"},
+            Mmap{pid: 42, tid: 43, addr: 0x40_0000, len: 0x1000, pgoff: 0x200, filename: "/bin/workload"},
+        ];
+        let data = PerfData{events: &EVENTS, comms: &comms, mmaps: &mmaps, pid: 42, tid: 43};
+        //The sample with id 7 has no event and is skipped.
+        let samples = [sample(0, 0x40_1000, true, 1000), sample(7, 0, true, 1500), sample(1, 0xFFFF_FFFF_8100_2000, false, 2000)];
+        let mut buf = [0u8; 1024];
+        let len = write(&data, &samples, &mut buf);
+
+        let reader = PerfDataReader::parse(&buf[..len]).unwrap();
+        assert_eq!(u64_at(&buf, 8), Some(PERF_HEADER_SIZE as u64));
+        assert_eq!(u64_at(&buf, 16), Some(PERF_FILE_ATTR_SIZE as u64));
+        assert_eq!(reader.attr_count(), 2);
+        assert_eq!(reader.attr(0), Some(PerfDataAttr{type_: PERF_TYPE_RAW, config: 0x00C0, sample_period: 100_000,
+            sample_type: SAMPLE_TYPE, flags: ATTR_EXCLUDE_HV, id: 1}));
+        assert_eq!(reader.attr(1), Some(PerfDataAttr{type_: PERF_TYPE_RAW, config: 0x2EB7, sample_period: 5_000,
+            sample_type: SAMPLE_TYPE, flags: ATTR_EXCLUDE_HV | ATTR_EXCLUDE_KERNEL, id: 2}));
+        assert_eq!(reader.attr(2), None);
+
+        let mut records = reader.records();
+        assert_eq!(records.next(), Some(PerfRecord::Comm{pid: 42, tid: 43, comm: "workload"}));
+        assert_eq!(records.next(), Some(PerfRecord::Mmap{pid: u32::MAX, tid: 0, addr: 0xFFFF_FFFF_8100_0000,
+            len: 0x100_0000, pgoff: 0, filename: "[kernel.kallsyms]_text"}));
+        assert_eq!(records.next(), Some(PerfRecord::Mmap{pid: 42, tid: 43, addr: 0x40_0000, len: 0x1000,
+            pgoff: 0x200, filename: "/bin/workload"}));
+        assert_eq!(records.next(), Some(PerfRecord::Sample{misc: PERF_RECORD_MISC_USER, ip: 0x40_1000, pid: 42,
+            tid: 43, time: 1000, id: 1, cpu: 3, period: 100_000}));
+        assert_eq!(records.next(), Some(PerfRecord::Sample{misc: PERF_RECORD_MISC_KERNEL, ip: 0xFFFF_FFFF_8100_2000,
+            pid: 42, tid: 43, time: 2000, id: 2, cpu: 3, period: 5_000}));
+        assert_eq!(records.next(), None);
+    }
+
+    #[test]
+    fn records_are_8_byte_aligned() {
+        let comms = [Comm{pid: 1, tid: 1, comm: "init"}, Comm{pid: 2, tid: 2, comm: "exactly8"}];
+        let data = PerfData{events: &EVENTS[..1], comms: &comms, mmaps: &[], pid: 1, tid: 1};
+        let mut buf = [0u8; 512];
+        let len = write(&data, &[], &mut buf);
+        let reader = PerfDataReader::parse(&buf[..len]).unwrap();
+        let data_offset = u64_at(&buf, 40).unwrap() as usize;
+        //"exactly8" needs a second word for its NUL.
+        assert_eq!(u16_at(&buf, data_offset + 6), Some(24));
+        assert_eq!(u16_at(&buf, data_offset + 24 + 6), Some(32));
+        assert_eq!(reader.records().count(), 2);
+    }
+
+    #[test]
+    fn short_buffer() {
+        let data = PerfData{events: &EVENTS, comms: &[], mmaps: &[], pid: 0, tid: 0};
+        let mut buf = [0u8; PERF_HEADER_SIZE + 8];
+        let mut sink = SliceSink::new(&mut buf);
+        assert!(matches!(data.write(&mut sink, [].iter()), Err(ErrorMsg::BufferTooSmall)));
+    }
+
+    #[test]
+    fn oversized_record_is_rejected() {
+        let mut name = [b'a'; 70_000];
+        name[0] = b'/';
+        let mmaps = [Mmap{pid: 1, tid: 1, addr: 0, len: 0, pgoff: 0, filename: str::from_utf8(&name).unwrap()}];
+        let data = PerfData{events: &EVENTS, comms: &[], mmaps: &mmaps, pid: 0, tid: 0};
+        let mut buf = [0u8; 16];
+        let mut sink = SliceSink::new(&mut buf);
+        assert!(matches!(data.write(&mut sink, [].iter()), Err(ErrorMsg::BufferTooSmall)));
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let data = PerfData{events: &EVENTS, comms: &[], mmaps: &[], pid: 0, tid: 0};
+        let mut buf = [0u8; 512];
+        let len = write(&data, &[], &mut buf);
+        assert!(PerfDataReader::parse(&buf[..len]).is_ok());
+        assert!(matches!(PerfDataReader::parse(&buf[..len - 1]), Err(ErrorMsg::InvalidFormat)));
+        buf[0] = 0;
+        assert!(matches!(PerfDataReader::parse(&buf[..len]), Err(ErrorMsg::InvalidFormat)));
+    }
+}
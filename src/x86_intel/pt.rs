@@ -0,0 +1,469 @@
+//! Intel Processor Trace (PT) setup.
+//!
+//! IA32_RTIT_CTL selects what is traced (privilege levels, CR3 and IP filters) and which timing
+//! packets are generated; the trace goes either to one contiguous physical range or to the output
+//! regions listed in a Table of Physical Addresses (ToPA). A ToPA entry with INT set raises a PMI
+//! (IA32_PERF_GLOBAL_STATUS bit 55) when its region fills, one with STOP stops tracing there.
+//! The captured bytes are decoded offline with pt_decode::PtDecoder.
+//!
+//! All addresses handed to the hardware here are physical; the caller translates them.
+//!
+
+use x86::cpuid::CpuIdResult;
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler};
+
+pub const IA32_RTIT_OUTPUT_BASE: u32 = 0x560;
+pub const IA32_RTIT_OUTPUT_MASK_PTRS: u32 = 0x561;
+pub const IA32_RTIT_CTL: u32 = 0x570;
+pub const IA32_RTIT_STATUS: u32 = 0x571;
+pub const IA32_RTIT_CR3_MATCH: u32 = 0x572;
+///IA32_RTIT_ADDRn_A is IA32_RTIT_ADDR0_A + 2n, IA32_RTIT_ADDRn_B follows it.
+pub const IA32_RTIT_ADDR0_A: u32 = 0x580;
+
+pub const RTIT_CTL_TRACE_EN: u64 = 1<<0;
+pub const RTIT_CTL_CYC_EN: u64 = 1<<1;
+pub const RTIT_CTL_OS: u64 = 1<<2;
+pub const RTIT_CTL_USER: u64 = 1<<3;
+pub const RTIT_CTL_CR3_FILTER: u64 = 1<<7;
+pub const RTIT_CTL_TOPA: u64 = 1<<8;
+pub const RTIT_CTL_MTC_EN: u64 = 1<<9;
+pub const RTIT_CTL_TSC_EN: u64 = 1<<10;
+pub const RTIT_CTL_DIS_RETC: u64 = 1<<11;
+pub const RTIT_CTL_BRANCH_EN: u64 = 1<<13;
+const RTIT_CTL_MTC_FREQ_SHIFT: u32 = 14;
+const RTIT_CTL_CYC_THRESH_SHIFT: u32 = 19;
+const RTIT_CTL_PSB_FREQ_SHIFT: u32 = 24;
+const RTIT_CTL_ADDR_CFG_SHIFT: u32 = 32;
+
+pub const RTIT_STATUS_FILTER_EN: u64 = 1<<0;
+pub const RTIT_STATUS_CONTEXT_EN: u64 = 1<<1;
+pub const RTIT_STATUS_TRIGGER_EN: u64 = 1<<2;
+pub const RTIT_STATUS_ERROR: u64 = 1<<4;
+pub const RTIT_STATUS_STOPPED: u64 = 1<<5;
+
+///IA32_PERF_GLOBAL_STATUS: a ToPA region with INT set is full.
+pub const GLOBAL_STATUS_TRACE_TOPA_PMI: u64 = 1<<55;
+
+///Most IP filter ranges in IA32_RTIT_CTL.
+pub const MAX_PT_ADDR_RANGES: usize = 4;
+
+///Intel PT features from CPUID leaf 14H.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PtCapabilities {
+    ///Sub-leaf 0 EBX[0]. IA32_RTIT_CTL.CR3Filter and IA32_RTIT_CR3_MATCH.
+    pub cr3_filtering: bool,
+    ///Sub-leaf 0 EBX[1]. Configurable PSB frequency and CYC packets.
+    pub psb_cyc: bool,
+    ///Sub-leaf 0 EBX[2]. IP filtering and TraceStop.
+    pub ip_filtering: bool,
+    ///Sub-leaf 0 EBX[3]. MTC packets.
+    pub mtc: bool,
+    ///Sub-leaf 0 ECX[0]. ToPA output.
+    pub topa: bool,
+    ///Sub-leaf 0 ECX[1]. ToPA tables with more than one output region.
+    pub topa_multiple_entries: bool,
+    ///Sub-leaf 0 ECX[2]. Single range output.
+    pub single_range_output: bool,
+    ///Sub-leaf 0 ECX[31]. IPs in packets are linear addresses including the CS base.
+    pub lip: bool,
+    ///Sub-leaf 1 EAX[2:0]. Number of IP filter ranges.
+    pub addr_ranges: u8,
+    ///Sub-leaf 1 EAX[31:16]. Supported MTC periods (RTIT_CTL.MTCFreq values).
+    pub mtc_periods: u16,
+    ///Sub-leaf 1 EBX[15:0]. Supported cycle thresholds (RTIT_CTL.CycThresh values).
+    pub cyc_thresholds: u16,
+    ///Sub-leaf 1 EBX[31:16]. Supported PSB frequencies (RTIT_CTL.PSBFreq values).
+    pub psb_periods: u16,
+}
+
+impl PtCapabilities {
+    ///Decode sub-leaves 0 and 1 of CPUID leaf 14H.
+    pub const fn decode(leaf0:CpuIdResult, leaf1:CpuIdResult) -> PtCapabilities {
+        PtCapabilities{
+            cr3_filtering: leaf0.ebx & (1<<0) != 0,
+            psb_cyc: leaf0.ebx & (1<<1) != 0,
+            ip_filtering: leaf0.ebx & (1<<2) != 0,
+            mtc: leaf0.ebx & (1<<3) != 0,
+            topa: leaf0.ecx & (1<<0) != 0,
+            topa_multiple_entries: leaf0.ecx & (1<<1) != 0,
+            single_range_output: leaf0.ecx & (1<<2) != 0,
+            lip: leaf0.ecx & (1<<31) != 0,
+            addr_ranges: if leaf0.eax >= 1 {(leaf1.eax & 0x7) as u8} else {0},
+            mtc_periods: if leaf0.eax >= 1 {(leaf1.eax >> 16) as u16} else {0},
+            cyc_thresholds: if leaf0.eax >= 1 {leaf1.ebx as u16} else {0},
+            psb_periods: if leaf0.eax >= 1 {(leaf1.ebx >> 16) as u16} else {0},
+        }
+    }
+}
+
+///What an IP range of IA32_RTIT_CTL does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtAddrFilterKind {
+    ///Only trace inside the range.
+    Filter = 1,
+    ///Stop tracing when an instruction in the range is executed.
+    TraceStop = 2,
+}
+
+///IP range [start, end], both ends included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtAddrFilter {
+    pub start: u64,
+    pub end: u64,
+    pub kind: PtAddrFilterKind,
+}
+
+///What to trace and which packets to generate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtConfig {
+    ///Trace ring 0.
+    pub kernel: bool,
+    ///Trace ring 1 to 3.
+    pub user: bool,
+    ///Generate the control flow packets (TNT, TIP, FUP, ...).
+    pub branches: bool,
+    ///Generate TSC packets.
+    pub tsc: bool,
+    ///Generate MTC packets every 2^(n-1) crystal clock ticks (RTIT_CTL.MTCFreq).
+    pub mtc_period: Option<u8>,
+    ///Generate CYC packets, at most every 2^(n-1) cycles for n > 0 (RTIT_CTL.CycThresh).
+    pub cyc_threshold: Option<u8>,
+    ///PSB every 2^(n+11) output bytes (RTIT_CTL.PSBFreq). Needs PtCapabilities::psb_cyc unless 0.
+    pub psb_period: u8,
+    ///Do not compress returns into TNT bits, so every return gets a TIP.
+    pub dis_retc: bool,
+    ///Only trace while CR3 holds this value.
+    pub cr3_match: Option<u64>,
+    pub addr_filters: [Option<PtAddrFilter>; MAX_PT_ADDR_RANGES],
+}
+
+impl PtConfig {
+    ///Branches of both privilege levels with TSC packets.
+    pub const fn new() -> PtConfig {
+        PtConfig{
+            kernel: true,
+            user: true,
+            branches: true,
+            tsc: true,
+            mtc_period: None,
+            cyc_threshold: None,
+            psb_period: 0,
+            dis_retc: false,
+            cr3_match: None,
+            addr_filters: [None; MAX_PT_ADDR_RANGES],
+        }
+    }
+
+    ///IA32_RTIT_CTL for this configuration, without TraceEn and the output selection.
+    pub fn ctl_bits(&self, caps:&PtCapabilities) -> Result<u64, ErrorMsg> {
+        let mut ctl = 0;
+        if self.kernel {
+            ctl |= RTIT_CTL_OS;
+        }
+        if self.user {
+            ctl |= RTIT_CTL_USER;
+        }
+        if self.branches {
+            ctl |= RTIT_CTL_BRANCH_EN;
+        }
+        if self.tsc {
+            ctl |= RTIT_CTL_TSC_EN;
+        }
+        if self.dis_retc {
+            ctl |= RTIT_CTL_DIS_RETC;
+        }
+        if let Some(period) = self.mtc_period {
+            if !caps.mtc || period > 15 || caps.mtc_periods & (1 << period) == 0 {
+                return Err(ErrorMsg::UnsupportedFeature);
+            }
+            ctl |= RTIT_CTL_MTC_EN | (period as u64) << RTIT_CTL_MTC_FREQ_SHIFT;
+        }
+        if let Some(threshold) = self.cyc_threshold {
+            if !caps.psb_cyc || threshold > 15 || caps.cyc_thresholds & (1 << threshold) == 0 {
+                return Err(ErrorMsg::UnsupportedFeature);
+            }
+            ctl |= RTIT_CTL_CYC_EN | (threshold as u64) << RTIT_CTL_CYC_THRESH_SHIFT;
+        }
+        if self.psb_period != 0 {
+            if !caps.psb_cyc || self.psb_period > 15 || caps.psb_periods & (1 << self.psb_period) == 0 {
+                return Err(ErrorMsg::UnsupportedFeature);
+            }
+            ctl |= (self.psb_period as u64) << RTIT_CTL_PSB_FREQ_SHIFT;
+        }
+        if self.cr3_match.is_some() {
+            if !caps.cr3_filtering {
+                return Err(ErrorMsg::UnsupportedFeature);
+            }
+            ctl |= RTIT_CTL_CR3_FILTER;
+        }
+        for (i, filter) in self.addr_filters.iter().enumerate() {
+            if let Some(filter) = filter {
+                if !caps.ip_filtering || i >= caps.addr_ranges as usize || filter.start > filter.end {
+                    return Err(ErrorMsg::UnsupportedFeature);
+                }
+                ctl |= (filter.kind as u64) << (RTIT_CTL_ADDR_CFG_SHIFT + 4 * i as u32);
+            }
+        }
+        Ok(ctl)
+    }
+}
+
+impl Default for PtConfig {
+    fn default() -> PtConfig {
+        PtConfig::new()
+    }
+}
+
+///Where the trace is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtOutput {
+    ///Physical address of a finished TopaTable.
+    Topa{table: u64},
+    ///Physical range of `size` bytes, a power of two of at least 128, aligned to its size.
+    ///The trace wraps around at its end.
+    SingleRange{base: u64, size: u64},
+}
+
+const TOPA_END: u64 = 1<<0;
+const TOPA_INT: u64 = 1<<2;
+const TOPA_STOP: u64 = 1<<4;
+const TOPA_SIZE_SHIFT: u32 = 6;
+const TOPA_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+///Table of Physical Addresses: output regions filled one after the other.
+///
+/// N is the number of entries including the END entry finish() appends.
+#[repr(C, align(4096))]
+pub struct TopaTable<const N: usize> {
+    entries: [u64; N],
+    len: usize,
+}
+
+impl<const N: usize> TopaTable<N> {
+    pub const fn new() -> TopaTable<N> {
+        TopaTable{entries: [0; N], len: 0}
+    }
+
+    ///Append the region of 4KiB << `size_shift` bytes at physical address `base`, aligned to its size.
+    /// `interrupt` raises a PMI when it is full, `stop` stops tracing then.
+    pub fn add_region(&mut self, base:u64, size_shift:u8, interrupt:bool, stop:bool) -> Result<(), ErrorMsg> {
+        if size_shift > 15 || base & !TOPA_ADDR_MASK != 0 || base & ((0x1000u64 << size_shift) - 1) != 0 {
+            return Err(ErrorMsg::InvalidFormat);
+        }
+        //One entry stays free for the END entry.
+        if self.len + 1 >= N {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        let mut entry = base | (size_shift as u64) << TOPA_SIZE_SHIFT;
+        if interrupt {
+            entry |= TOPA_INT;
+        }
+        if stop {
+            entry |= TOPA_STOP;
+        }
+        self.entries[self.len] = entry;
+        self.len += 1;
+        Ok(())
+    }
+
+    ///Close the table with an END entry pointing back at it, `table` being its physical address,
+    /// so the output wraps around to the first region.
+    pub fn finish(&mut self, table:u64) -> Result<(), ErrorMsg> {
+        if self.len == 0 || self.len == N || table & !TOPA_ADDR_MASK != 0 {
+            return Err(ErrorMsg::InvalidFormat);
+        }
+        self.entries[self.len] = table | TOPA_END;
+        Ok(())
+    }
+
+    ///Output regions, without the END entry.
+    pub fn regions(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.entries[..self.len].iter().map(|e| (e & TOPA_ADDR_MASK, 0x1000u64 << ((e >> TOPA_SIZE_SHIFT) & 0xF)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<const N: usize> Default for TopaTable<N> {
+    fn default() -> TopaTable<N> {
+        TopaTable::new()
+    }
+}
+
+///Intel PT control of one controler's processor.
+pub struct IntelPt<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    caps: PtCapabilities,
+}
+
+impl<'a, B: PmuBackend> IntelPt<'a, B> {
+    ///Check CPUID.(EAX=07H,ECX=0):EBX[25] and read leaf 14H.
+    pub fn new(ctrler:&'a PerfCounterControler<B>) -> Result<IntelPt<'a, B>, ErrorMsg> {
+        let backend = ctrler.backend();
+        if backend.cpuid(0x07, 0).ebx & (1<<25) == 0 {
+            return Err(ErrorMsg::UnsupportedFeature);
+        }
+        Ok(IntelPt{
+            ctrler: ctrler,
+            caps: PtCapabilities::decode(backend.cpuid(0x14, 0), backend.cpuid(0x14, 1)),
+        })
+    }
+
+    pub fn capabilities(&self) -> PtCapabilities {
+        self.caps
+    }
+
+    ///Program filters and output. Tracing must be stopped.
+    pub fn configure(&self, config:&PtConfig, output:PtOutput) -> Result<(), ErrorMsg> {
+        let backend = self.ctrler.backend();
+        if self.is_enabled() {
+            return Err(ErrorMsg::CounterInUse);
+        }
+        let mut ctl = config.ctl_bits(&self.caps)?;
+        let (base, mask_ptrs) = match output {
+            PtOutput::Topa{table} => {
+                if !self.caps.topa || table & 0xFFF != 0 {
+                    return Err(ErrorMsg::UnsupportedFeature);
+                }
+                ctl |= RTIT_CTL_TOPA;
+                (table, 0x7F)
+            },
+            PtOutput::SingleRange{base, size} => {
+                if !self.caps.single_range_output || !size.is_power_of_two() || size < 128 || base & (size - 1) != 0 {
+                    return Err(ErrorMsg::UnsupportedFeature);
+                }
+                (base, (size - 1) & 0xFFFF_FFFF)
+            },
+        };
+        backend.wrmsr(IA32_RTIT_OUTPUT_BASE, base);
+        backend.wrmsr(IA32_RTIT_OUTPUT_MASK_PTRS, mask_ptrs);
+        if let Some(cr3) = config.cr3_match {
+            backend.wrmsr(IA32_RTIT_CR3_MATCH, cr3 & !0x1F);
+        }
+        for (i, filter) in config.addr_filters.iter().enumerate() {
+            if let Some(filter) = filter {
+                backend.wrmsr(IA32_RTIT_ADDR0_A + 2 * i as u32, filter.start);
+                backend.wrmsr(IA32_RTIT_ADDR0_A + 2 * i as u32 + 1, filter.end);
+            }
+        }
+        backend.wrmsr(IA32_RTIT_STATUS, 0);
+        backend.wrmsr(IA32_RTIT_CTL, ctl);
+        Ok(())
+    }
+
+    pub fn start(&self) {
+        let backend = self.ctrler.backend();
+        backend.wrmsr(IA32_RTIT_CTL, backend.rdmsr(IA32_RTIT_CTL) | RTIT_CTL_TRACE_EN);
+    }
+
+    pub fn stop(&self) {
+        let backend = self.ctrler.backend();
+        backend.wrmsr(IA32_RTIT_CTL, backend.rdmsr(IA32_RTIT_CTL) & !RTIT_CTL_TRACE_EN);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ctrler.backend().rdmsr(IA32_RTIT_CTL) & RTIT_CTL_TRACE_EN != 0
+    }
+
+    ///IA32_RTIT_STATUS.
+    pub fn status(&self) -> u64 {
+        self.ctrler.backend().rdmsr(IA32_RTIT_STATUS)
+    }
+
+    ///Current ToPA entry and byte offset into its region. With single range output the entry is 0.
+    pub fn write_position(&self) -> (usize, u64) {
+        let mask_ptrs = self.ctrler.backend().rdmsr(IA32_RTIT_OUTPUT_MASK_PTRS);
+        let topa = self.ctrler.backend().rdmsr(IA32_RTIT_CTL) & RTIT_CTL_TOPA != 0;
+        let entry = if topa {((mask_ptrs & 0xFFFF_FFFF) >> 7) as usize} else {0};
+        (entry, mask_ptrs >> 32)
+    }
+
+    ///Acknowledge a ToPA PMI after draining the full regions: clear IA32_PERF_GLOBAL_STATUS bit 55
+    /// and unmask the LVT entry.
+    pub fn acknowledge_topa_pmi(&self) -> bool {
+        if self.ctrler.read_overflow_status() & GLOBAL_STATUS_TRACE_TOPA_PMI == 0 {
+            return false;
+        }
+        self.ctrler.set_overflow_ctrl(GLOBAL_STATUS_TRACE_TOPA_PMI);
+        self.ctrler.reset_overflow_interrput();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topa_table() {
+        let mut table: TopaTable<3> = TopaTable::new();
+        assert!(matches!(table.finish(0x20000), Err(ErrorMsg::InvalidFormat)));
+        //64KiB regions must be 64KiB aligned.
+        assert!(matches!(table.add_region(0x11000, 4, false, false), Err(ErrorMsg::InvalidFormat)));
+        assert!(matches!(table.add_region(0x10000, 16, false, false), Err(ErrorMsg::InvalidFormat)));
+        assert!(matches!(table.add_region(1u64 << 52, 0, false, false), Err(ErrorMsg::InvalidFormat)));
+        table.add_region(0x10000, 4, true, false).unwrap();
+        table.add_region(0x3000, 0, false, true).unwrap();
+        //The last entry is kept for END.
+        assert!(matches!(table.add_region(0x4000, 0, false, false), Err(ErrorMsg::BufferTooSmall)));
+        assert!(matches!(table.finish(0x20001), Err(ErrorMsg::InvalidFormat)));
+        table.finish(0x20000).unwrap();
+        assert_eq!(table.entries, [0x10000 | 4 << TOPA_SIZE_SHIFT | TOPA_INT, 0x3000 | TOPA_STOP, 0x20000 | TOPA_END]);
+        let mut regions = table.regions();
+        assert_eq!(regions.next(), Some((0x10000, 0x10000)));
+        assert_eq!(regions.next(), Some((0x3000, 0x1000)));
+        assert_eq!(regions.next(), None);
+    }
+
+    #[test]
+    fn ctl_bits_follow_the_capabilities() {
+        let none = PtCapabilities::default();
+        let config = PtConfig::new();
+        assert_eq!(config.ctl_bits(&none).unwrap(), RTIT_CTL_OS | RTIT_CTL_USER | RTIT_CTL_BRANCH_EN | RTIT_CTL_TSC_EN);
+        let caps = PtCapabilities{
+            cr3_filtering: true,
+            psb_cyc: true,
+            ip_filtering: true,
+            mtc: true,
+            addr_ranges: 2,
+            mtc_periods: 1<<3,
+            cyc_thresholds: 1<<2,
+            psb_periods: 1<<5,
+            ..PtCapabilities::default()
+        };
+        let full = PtConfig{
+            kernel: false,
+            tsc: false,
+            mtc_period: Some(3),
+            cyc_threshold: Some(2),
+            psb_period: 5,
+            dis_retc: true,
+            cr3_match: Some(0x1000),
+            addr_filters: [None, Some(PtAddrFilter{start: 0x1000, end: 0x2000, kind: PtAddrFilterKind::TraceStop}), None, None],
+            ..PtConfig::new()
+        };
+        assert_eq!(full.ctl_bits(&caps).unwrap(), RTIT_CTL_USER | RTIT_CTL_BRANCH_EN | RTIT_CTL_DIS_RETC
+            | RTIT_CTL_MTC_EN | 3 << 14 | RTIT_CTL_CYC_EN | 2 << 19 | 5 << 24 | RTIT_CTL_CR3_FILTER | 2 << 36);
+        let unsupported = [
+            PtConfig{mtc_period: Some(4), ..full},
+            PtConfig{cyc_threshold: Some(3), ..full},
+            PtConfig{psb_period: 6, ..full},
+            PtConfig{addr_filters: [None, None, Some(PtAddrFilter{start: 0x1000, end: 0x2000, kind: PtAddrFilterKind::Filter}), None], ..full},
+            PtConfig{addr_filters: [Some(PtAddrFilter{start: 0x2000, end: 0x1000, kind: PtAddrFilterKind::Filter}), None, None, None], ..full},
+        ];
+        for config in unsupported.iter() {
+            assert!(matches!(config.ctl_bits(&caps), Err(ErrorMsg::UnsupportedFeature)));
+        }
+        assert!(matches!(full.ctl_bits(&PtCapabilities{mtc: false, ..caps}), Err(ErrorMsg::UnsupportedFeature)));
+        assert!(matches!(full.ctl_bits(&PtCapabilities{psb_cyc: false, ..caps}), Err(ErrorMsg::UnsupportedFeature)));
+        assert!(matches!(full.ctl_bits(&PtCapabilities{cr3_filtering: false, ..caps}), Err(ErrorMsg::UnsupportedFeature)));
+        assert!(matches!(full.ctl_bits(&PtCapabilities{ip_filtering: false, ..caps}), Err(ErrorMsg::UnsupportedFeature)));
+    }
+}
@@ -0,0 +1,468 @@
+//! Intel PT packet decoder.
+//!
+//! PtDecoder splits a captured trace buffer into packets. A buffer that wrapped around starts in
+//! the middle of a packet, so decoding starts at the first PSB; after an undecodable byte the
+//! decoder reports an error and resynchronizes at the next PSB.
+//!
+//! IPs of TIP, TIP.PGE, TIP.PGD and FUP packets are compressed against the last IP seen since the
+//! last PSB; the decoder keeps that state and yields full addresses. TNT bits are returned oldest
+//! branch first in bit 0.
+//!
+//! Packets are only split, not matched against the traced binary: reconstructing the executed
+//! instructions needs the code, which is outside the scope of this crate.
+//!
+
+use crate::ErrorMsg;
+
+const PSB: [u8; 16] = [0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82, 0x02, 0x82];
+
+///Execution mode reported by MODE.Exec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtExecMode {
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtPacket {
+    Pad,
+    ///Packet stream boundary: the decoder state is reset here.
+    Psb,
+    ///End of the status packets following a PSB.
+    PsbEnd,
+    ///Taken (1) or not taken (0) conditional branches and compressed returns, oldest in bit 0.
+    Tnt{bits: u64, count: u8},
+    ///Target of an indirect branch, far transfer or uncompressed return. None if suppressed.
+    Tip{ip: Option<u64>},
+    ///Tracing was enabled, resuming at `ip`.
+    TipPge{ip: Option<u64>},
+    ///Tracing was disabled, the next IP being `ip`.
+    TipPgd{ip: Option<u64>},
+    ///Source IP of an asynchronous event (interrupt, exception, ...) or of the next TIP.
+    Fup{ip: Option<u64>},
+    ModeExec{mode: PtExecMode},
+    ModeTsx{in_tx: bool, abort: bool},
+    ///Core cycles since the previous CYC packet.
+    Cyc{cycles: u64},
+    ///Bits 7:0 of the crystal clock counter, shifted right by the MTC frequency.
+    Mtc{ctc: u8},
+    ///Bits 55:0 of the TSC.
+    Tsc{tsc: u64},
+    ///Crystal clock counter and fast counter at the last TSC packet.
+    Tma{ctc: u16, fast_counter: u16},
+    ///Core to bus clock ratio.
+    Cbr{ratio: u8},
+    ///CR3 of the new address space; `non_root` in VMX non-root operation.
+    Pip{cr3: u64, non_root: bool},
+    Vmcs{base: u64},
+    ///Internal buffer overflow, packets were lost.
+    Ovf,
+    TraceStop,
+    Mnt{payload: u64},
+    Ptw{payload: u64, ip: bool},
+    Exstop{ip: bool},
+    Mwait{hints: u32, extensions: u32},
+    Pwre{payload: u16},
+    Pwrx{payload: u64},
+}
+
+fn le(bytes:&[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |v, b| v << 8 | *b as u64)
+}
+
+///Iterator over the packets of a trace buffer, yielding their offset and the packet.
+pub struct PtDecoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    last_ip: u64,
+    synced: bool,
+}
+
+impl<'a> PtDecoder<'a> {
+    pub fn new(bytes:&'a [u8]) -> PtDecoder<'a> {
+        PtDecoder{bytes: bytes, pos: 0, last_ip: 0, synced: false}
+    }
+
+    ///Offset of the next packet.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    ///Move to the next PSB at or after the current position. Returns false if there is none.
+    pub fn sync(&mut self) -> bool {
+        match self.bytes[self.pos..].windows(PSB.len()).position(|w| w == PSB) {
+            Some(offset) => {
+                self.pos += offset;
+                self.synced = true;
+                true
+            },
+            None => {
+                self.pos = self.bytes.len();
+                false
+            },
+        }
+    }
+
+    fn ip(&mut self, ip_bytes:u8, payload:&[u8]) -> Result<Option<u64>, ErrorMsg> {
+        let value = le(payload);
+        let ip = match ip_bytes {
+            0 => return Ok(None),
+            1 => self.last_ip & !0xFFFF | value,
+            2 => self.last_ip & !0xFFFF_FFFF | value,
+            3 => ((value << 16) as i64 >> 16) as u64,
+            4 => self.last_ip & !0xFFFF_FFFF_FFFF | value,
+            6 => value,
+            _ => return Err(ErrorMsg::InvalidFormat),
+        };
+        self.last_ip = ip;
+        Ok(Some(ip))
+    }
+
+    //Decode the packet at self.pos, returning it and its length.
+    fn decode(&mut self) -> Result<(PtPacket, usize), ErrorMsg> {
+        let all = self.bytes;
+        let bytes = &all[self.pos..];
+        let need = |len:usize| if bytes.len() < len {Err(ErrorMsg::InvalidFormat)} else {Ok(())};
+        let b0 = bytes[0];
+        match b0 {
+            0x00 => return Ok((PtPacket::Pad, 1)),
+            0x02 => return self.decode_extended(),
+            0x19 => {
+                need(8)?;
+                return Ok((PtPacket::Tsc{tsc: le(&bytes[1..8])}, 8));
+            },
+            0x59 => {
+                need(2)?;
+                return Ok((PtPacket::Mtc{ctc: bytes[1]}, 2));
+            },
+            0x99 => {
+                need(2)?;
+                let payload = bytes[1];
+                let packet = match payload >> 5 {
+                    0 => PtPacket::ModeExec{mode: if payload & 1 != 0 {
+                        PtExecMode::Bits64
+                    } else if payload & 2 != 0 {
+                        PtExecMode::Bits32
+                    } else {
+                        PtExecMode::Bits16
+                    }},
+                    1 => PtPacket::ModeTsx{in_tx: payload & 1 != 0, abort: payload & 2 != 0},
+                    _ => return Err(ErrorMsg::InvalidFormat),
+                };
+                return Ok((packet, 2));
+            },
+            _ => {},
+        }
+        if b0 & 1 == 0 {
+            //Short TNT: payload below the highest set bit, which is the stop bit, bit 0 being reserved.
+            let stop = 7 - b0.leading_zeros() as u8;
+            let count = stop - 1;
+            let bits = (0..count).fold(0, |bits, i| bits | (((b0 >> (stop - 1 - i)) & 1) as u64) << i);
+            return Ok((PtPacket::Tnt{bits: bits, count: count}, 1));
+        }
+        if b0 & 3 == 3 {
+            let mut cycles = (b0 >> 3) as u64;
+            let mut len = 1;
+            if b0 & 4 != 0 {
+                let mut shift = 5;
+                loop {
+                    need(len + 1)?;
+                    let b = bytes[len];
+                    len += 1;
+                    if shift < 64 {
+                        cycles |= ((b >> 1) as u64) << shift;
+                    }
+                    shift += 7;
+                    if b & 1 == 0 {
+                        break;
+                    }
+                }
+            }
+            return Ok((PtPacket::Cyc{cycles: cycles}, len));
+        }
+        let ip_bytes = b0 >> 5;
+        let payload_len = match ip_bytes {
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            3 | 4 => 6,
+            6 => 8,
+            _ => return Err(ErrorMsg::InvalidFormat),
+        };
+        need(1 + payload_len)?;
+        let make: fn(Option<u64>) -> PtPacket = match b0 & 0x1F {
+            0x0D => |ip| PtPacket::Tip{ip: ip},
+            0x11 => |ip| PtPacket::TipPge{ip: ip},
+            0x01 => |ip| PtPacket::TipPgd{ip: ip},
+            0x1D => |ip| PtPacket::Fup{ip: ip},
+            _ => return Err(ErrorMsg::InvalidFormat),
+        };
+        let ip = self.ip(ip_bytes, &bytes[1..1 + payload_len])?;
+        Ok((make(ip), 1 + payload_len))
+    }
+
+    fn decode_extended(&mut self) -> Result<(PtPacket, usize), ErrorMsg> {
+        let all = self.bytes;
+        let bytes = &all[self.pos..];
+        let need = |len:usize| if bytes.len() < len {Err(ErrorMsg::InvalidFormat)} else {Ok(())};
+        need(2)?;
+        let b1 = bytes[1];
+        let (packet, len) = match b1 {
+            0x82 => {
+                need(PSB.len())?;
+                if bytes[..PSB.len()] != PSB {
+                    return Err(ErrorMsg::InvalidFormat);
+                }
+                self.last_ip = 0;
+                (PtPacket::Psb, PSB.len())
+            },
+            0x23 => (PtPacket::PsbEnd, 2),
+            0xA3 => {
+                need(8)?;
+                let payload = le(&bytes[2..8]);
+                if payload == 0 {
+                    return Err(ErrorMsg::InvalidFormat);
+                }
+                let count = (63 - payload.leading_zeros()) as u8;
+                let bits = (0..count).fold(0, |bits, i| bits | ((payload >> (count - 1 - i)) & 1) << i);
+                (PtPacket::Tnt{bits: bits, count: count}, 8)
+            },
+            0xF3 => (PtPacket::Ovf, 2),
+            0x83 => (PtPacket::TraceStop, 2),
+            0x03 => {
+                need(4)?;
+                (PtPacket::Cbr{ratio: bytes[2]}, 4)
+            },
+            0x43 => {
+                need(8)?;
+                let payload = le(&bytes[2..8]);
+                (PtPacket::Pip{cr3: (payload >> 1) << 5, non_root: payload & 1 != 0}, 8)
+            },
+            0x73 => {
+                need(7)?;
+                let ctc = le(&bytes[2..4]) as u16;
+                let fast_counter = bytes[5] as u16 | ((bytes[6] & 1) as u16) << 8;
+                (PtPacket::Tma{ctc: ctc, fast_counter: fast_counter}, 7)
+            },
+            0xC8 => {
+                need(7)?;
+                (PtPacket::Vmcs{base: le(&bytes[2..7]) << 12}, 7)
+            },
+            0xC3 => {
+                need(11)?;
+                if bytes[2] != 0x88 {
+                    return Err(ErrorMsg::InvalidFormat);
+                }
+                (PtPacket::Mnt{payload: le(&bytes[3..11])}, 11)
+            },
+            0x62 | 0xE2 => (PtPacket::Exstop{ip: b1 & 0x80 != 0}, 2),
+            0xC2 => {
+                need(10)?;
+                (PtPacket::Mwait{hints: le(&bytes[2..6]) as u32, extensions: le(&bytes[6..10]) as u32}, 10)
+            },
+            0x22 => {
+                need(4)?;
+                (PtPacket::Pwre{payload: le(&bytes[2..4]) as u16}, 4)
+            },
+            0xA2 => {
+                need(7)?;
+                (PtPacket::Pwrx{payload: le(&bytes[2..7])}, 7)
+            },
+            b if b & 0x1F == 0x12 => {
+                let payload_len = match (b >> 5) & 3 {
+                    0 => 4,
+                    1 => 8,
+                    _ => return Err(ErrorMsg::InvalidFormat),
+                };
+                need(2 + payload_len)?;
+                (PtPacket::Ptw{payload: le(&bytes[2..2 + payload_len]), ip: b & 0x80 != 0}, 2 + payload_len)
+            },
+            _ => return Err(ErrorMsg::InvalidFormat),
+        };
+        Ok((packet, len))
+    }
+}
+
+impl<'a> Iterator for PtDecoder<'a> {
+    type Item = Result<(usize, PtPacket), ErrorMsg>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.synced && !self.sync() {
+            return None;
+        }
+        if self.pos >= self.bytes.len() {
+            return None;
+        }
+        let offset = self.pos;
+        match self.decode() {
+            Ok((packet, len)) => {
+                self.pos += len;
+                Some(Ok((offset, packet)))
+            },
+            Err(e) => {
+                //Skip the bad byte; the next call resynchronizes at a PSB.
+                self.pos += 1;
+                self.synced = false;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSBEND: [u8; 2] = [0x02, 0x23];
+
+    fn next(decoder:&mut PtDecoder) -> PtPacket {
+        decoder.next().unwrap().unwrap().1
+    }
+
+    //Copy PSB, PSBEND and `packets` to `out`, returning the length.
+    fn stream(out:&mut [u8], packets:&[u8]) -> usize {
+        out[..16].copy_from_slice(&PSB);
+        out[16..18].copy_from_slice(&PSBEND);
+        out[18..18 + packets.len()].copy_from_slice(packets);
+        18 + packets.len()
+    }
+
+    #[test]
+    fn psb_and_psbend() {
+        let mut bytes = [0u8; 19];
+        let len = stream(&mut bytes, &[0x00]);
+        let mut decoder = PtDecoder::new(&bytes[..len]);
+        assert!(matches!(decoder.next(), Some(Ok((0, PtPacket::Psb)))));
+        assert!(matches!(decoder.next(), Some(Ok((16, PtPacket::PsbEnd)))));
+        assert!(matches!(decoder.next(), Some(Ok((18, PtPacket::Pad)))));
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    fn tnt_bit_order() {
+        let mut bytes = [0u8; 64];
+        let len = stream(&mut bytes, &[
+            //Short TNT: stop bit 3, oldest branch in bit 2.
+            0x0A,
+            0xFE,
+            0x84,
+            //Long TNT: stop bit 47, oldest branch in bit 46.
+            0x02, 0xA3, 0x01, 0x00, 0x00, 0x00, 0x00, 0xC0,
+        ]);
+        let mut decoder = PtDecoder::new(&bytes[..len]);
+        next(&mut decoder);
+        next(&mut decoder);
+        assert_eq!(next(&mut decoder), PtPacket::Tnt{bits: 0b10, count: 2});
+        assert_eq!(next(&mut decoder), PtPacket::Tnt{bits: 0x3F, count: 6});
+        assert_eq!(next(&mut decoder), PtPacket::Tnt{bits: 0x10, count: 6});
+        assert_eq!(next(&mut decoder), PtPacket::Tnt{bits: 1 | 1<<46, count: 47});
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    fn ip_compression() {
+        let mut bytes = [0u8; 128];
+        let mut len = stream(&mut bytes, &[
+            //FUP, IPBytes 110: full IP.
+            0xDD, 0x78, 0x56, 0x34, 0x12, 0x00, 0x80, 0xFF, 0xFF,
+            //TIP, IPBytes 001: bits 15:0.
+            0x2D, 0xCD, 0xAB,
+            //TIP, IPBytes 010: bits 31:0.
+            0x4D, 0x22, 0x22, 0x11, 0x11,
+            //TIP, IPBytes 100: bits 47:0.
+            0x8D, 0x00, 0x10, 0x00, 0x00, 0x00, 0x70,
+            //TIP, IPBytes 011: bits 47:0 sign extended.
+            0x6D, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80,
+            0x6D, 0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0x7F,
+            //TIP, IPBytes 000: suppressed, the last IP is kept.
+            0x0D,
+            //TIP.PGE, IPBytes 001.
+            0x31, 0x10, 0x00,
+            //TIP.PGD, IPBytes 000.
+            0x01,
+        ]);
+        //The next PSB resets the last IP.
+        bytes[len..len + 16].copy_from_slice(&PSB);
+        len += 16;
+        bytes[len..len + 3].copy_from_slice(&[0x2D, 0x34, 0x12]);
+        len += 3;
+        let mut decoder = PtDecoder::new(&bytes[..len]);
+        next(&mut decoder);
+        next(&mut decoder);
+        assert_eq!(next(&mut decoder), PtPacket::Fup{ip: Some(0xFFFF_8000_1234_5678)});
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: Some(0xFFFF_8000_1234_ABCD)});
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: Some(0xFFFF_8000_1111_2222)});
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: Some(0xFFFF_7000_0000_1000)});
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: Some(0xFFFF_8000_0000_0000)});
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: Some(0x0000_7FFF_FFFF_F000)});
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: None});
+        assert_eq!(next(&mut decoder), PtPacket::TipPge{ip: Some(0x0000_7FFF_FFFF_0010)});
+        assert_eq!(next(&mut decoder), PtPacket::TipPgd{ip: None});
+        assert_eq!(next(&mut decoder), PtPacket::Psb);
+        assert_eq!(next(&mut decoder), PtPacket::Tip{ip: Some(0x1234)});
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    fn mode_and_timing_packets() {
+        let mut bytes = [0u8; 64];
+        let len = stream(&mut bytes, &[
+            0x99, 0x01,
+            0x99, 0x02,
+            0x99, 0x00,
+            0x99, 0x21,
+            0x99, 0x22,
+            //CYC: bits 4:0 in the header, 7 more bits per byte while bit 0 (Exp) is set.
+            0x0B,
+            0xAF, 0x0B, 0x02,
+            0x59, 0x42,
+            0x19, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12,
+            0x02, 0x73, 0x34, 0x12, 0x00, 0xCD, 0x01,
+        ]);
+        let mut decoder = PtDecoder::new(&bytes[..len]);
+        next(&mut decoder);
+        next(&mut decoder);
+        assert_eq!(next(&mut decoder), PtPacket::ModeExec{mode: PtExecMode::Bits64});
+        assert_eq!(next(&mut decoder), PtPacket::ModeExec{mode: PtExecMode::Bits32});
+        assert_eq!(next(&mut decoder), PtPacket::ModeExec{mode: PtExecMode::Bits16});
+        assert_eq!(next(&mut decoder), PtPacket::ModeTsx{in_tx: true, abort: false});
+        assert_eq!(next(&mut decoder), PtPacket::ModeTsx{in_tx: false, abort: true});
+        assert_eq!(next(&mut decoder), PtPacket::Cyc{cycles: 1});
+        assert_eq!(next(&mut decoder), PtPacket::Cyc{cycles: 21 | 5<<5 | 1<<12});
+        assert_eq!(next(&mut decoder), PtPacket::Mtc{ctc: 0x42});
+        assert_eq!(next(&mut decoder), PtPacket::Tsc{tsc: 0x0012_3456_789A_BCDE});
+        assert_eq!(next(&mut decoder), PtPacket::Tma{ctc: 0x1234, fast_counter: 0x1CD});
+        assert!(decoder.next().is_none());
+    }
+
+    #[test]
+    fn starts_at_the_first_psb() {
+        //The tail of a TSC packet, left over from a wrapped buffer.
+        let mut bytes = [0u8; 24];
+        bytes[..3].copy_from_slice(&[0x34, 0x56, 0x12]);
+        stream(&mut bytes[3..], &[0x0A]);
+        let mut decoder = PtDecoder::new(&bytes[..22]);
+        assert!(matches!(decoder.next(), Some(Ok((3, PtPacket::Psb)))));
+        assert!(matches!(decoder.next(), Some(Ok((19, PtPacket::PsbEnd)))));
+        assert_eq!(next(&mut decoder), PtPacket::Tnt{bits: 0b10, count: 2});
+        assert!(decoder.next().is_none());
+        assert!(PtDecoder::new(&bytes[..3]).next().is_none());
+    }
+
+    #[test]
+    fn resyncs_after_a_bad_byte() {
+        let mut bytes = [0u8; 64];
+        //IPBytes 101 is reserved; the TIP after it is skipped up to the next PSB.
+        let mut len = stream(&mut bytes, &[0xA1, 0x2D, 0x34, 0x12]);
+        len += stream(&mut bytes[len..], &[0x0A]);
+        let mut decoder = PtDecoder::new(&bytes[..len]);
+        next(&mut decoder);
+        next(&mut decoder);
+        assert!(matches!(decoder.next(), Some(Err(ErrorMsg::InvalidFormat))));
+        assert!(matches!(decoder.next(), Some(Ok((22, PtPacket::Psb)))));
+        assert_eq!(next(&mut decoder), PtPacket::PsbEnd);
+        assert_eq!(next(&mut decoder), PtPacket::Tnt{bits: 0b10, count: 2});
+        assert!(decoder.next().is_none());
+    }
+}
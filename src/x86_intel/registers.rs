@@ -0,0 +1,245 @@
+//! Typed values of IA32_PERFEVTSELx and IA32_FIXED_CTR_CTRL.
+//!
+//! The types wrap the raw register value, so from_bits(x).bits() == x for every x,
+//! including reserved bits.
+//!
+
+macro_rules! flag {
+    ($(#[$doc:meta])* $get:ident, $set:ident, $bit:expr) => {
+        $(#[$doc])*
+        pub fn $get(&self) -> bool {
+            (self.0 >> $bit) & 1 == 1
+        }
+        pub fn $set(&mut self, value:bool) -> &mut Self {
+            self.0 = (self.0 & !(1 << $bit)) | ((value as u64) << $bit);
+            self
+        }
+    };
+}
+
+macro_rules! field {
+    ($(#[$doc:meta])* $get:ident, $set:ident, $shift:expr) => {
+        $(#[$doc])*
+        pub fn $get(&self) -> u8 {
+            (self.0 >> $shift) as u8
+        }
+        pub fn $set(&mut self, value:u8) -> &mut Self {
+            self.0 = (self.0 & !(0xFF << $shift)) | ((value as u64) << $shift);
+            self
+        }
+    };
+}
+
+///Value of an IA32_PERFEVTSELx MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PerfEvtSel(u64);
+
+impl PerfEvtSel {
+    ///Bits that are neither architectural nor TSX fields.
+    pub const RESERVED_MASK: u64 = !0x3_FFFF_FFFF;
+
+    pub const fn from_bits(bits:u64) -> PerfEvtSel {
+        PerfEvtSel(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    ///Any reserved bit set; writing such a value raises #GP.
+    pub fn has_reserved_bits(&self) -> bool {
+        self.0 & Self::RESERVED_MASK != 0
+    }
+
+    field!(
+        ///Bits 7:0. Event select.
+        event_select, set_event_select, 0);
+    field!(
+        ///Bits 15:8. Unit mask.
+        umask, set_umask, 8);
+    flag!(
+        ///Bit 16. Count while CPL > 0.
+        usr, set_usr, 16);
+    flag!(
+        ///Bit 17. Count while CPL = 0.
+        os, set_os, 17);
+    flag!(
+        ///Bit 18. Edge detect.
+        edge, set_edge, 18);
+    flag!(
+        ///Bit 19. Pin control.
+        pc, set_pc, 19);
+    flag!(
+        ///Bit 20. APIC interrupt on overflow.
+        int, set_int, 20);
+    flag!(
+        ///Bit 21. Count on any thread of the core.
+        any_thread, set_any_thread, 21);
+    flag!(
+        ///Bit 22. Enable counter.
+        en, set_en, 22);
+    flag!(
+        ///Bit 23. Invert counter mask.
+        inv, set_inv, 23);
+    field!(
+        ///Bits 31:24. Counter mask.
+        cmask, set_cmask, 24);
+    flag!(
+        ///Bit 32. Count only inside transactional regions.
+        in_tx, set_in_tx, 32);
+    flag!(
+        ///Bit 33. Exclude aborted transactional regions from the count.
+        in_txcp, set_in_txcp, 33);
+}
+
+///One counter's 4-bit field of IA32_FIXED_CTR_CTRL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FixedCtrField(u64);
+
+impl FixedCtrField {
+    pub const fn from_bits(bits:u8) -> FixedCtrField {
+        FixedCtrField((bits & 0xF) as u64)
+    }
+
+    pub const fn bits(&self) -> u8 {
+        self.0 as u8
+    }
+
+    flag!(
+        ///Bit 0. Count while CPL = 0.
+        os, set_os, 0);
+    flag!(
+        ///Bit 1. Count while CPL > 0.
+        usr, set_usr, 1);
+    flag!(
+        ///Bit 2. Count on any thread of the core.
+        any_thread, set_any_thread, 2);
+    flag!(
+        ///Bit 3. APIC interrupt on overflow.
+        pmi, set_pmi, 3);
+
+    ///The counter counts at some privilege level.
+    pub fn is_enabled(&self) -> bool {
+        self.0 & 3 != 0
+    }
+}
+
+///Value of the IA32_FIXED_CTR_CTRL MSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FixedCtrCtrl(u64);
+
+impl FixedCtrCtrl {
+    ///Fixed counters with a field in the MSR.
+    pub const FIELDS: u8 = 16;
+
+    pub const fn from_bits(bits:u64) -> FixedCtrCtrl {
+        FixedCtrCtrl(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    ///Field of fixed counter `index`. Panics if `index` is not below FIELDS.
+    pub fn counter(&self, index:u8) -> FixedCtrField {
+        FixedCtrField::from_bits((self.0 >> Self::shift(index)) as u8)
+    }
+
+    ///Replace the field of fixed counter `index`, leaving the other counters untouched.
+    /// Panics if `index` is not below FIELDS.
+    pub fn set_counter(&mut self, index:u8, field:FixedCtrField) -> &mut Self {
+        let shift = Self::shift(index);
+        self.0 = (self.0 & !(0xF << shift)) | ((field.bits() as u64) << shift);
+        self
+    }
+
+    fn shift(index:u8) -> u32 {
+        assert!(index < Self::FIELDS, "IA32_FIXED_CTR_CTRL has no field for fixed counter {}", index);
+        index as u32 * 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfevtsel_fields() {
+        let mut sel = PerfEvtSel::default();
+        sel.set_event_select(0xC4).set_umask(0x20).set_usr(true).set_os(true).set_edge(true)
+            .set_int(true).set_en(true).set_inv(true).set_cmask(0x3).set_in_tx(true);
+        assert_eq!(sel.bits(), 0x1_03D7_20C4);
+        assert_eq!(sel.event_select(), 0xC4);
+        assert_eq!(sel.umask(), 0x20);
+        assert!(sel.usr() && sel.os() && sel.edge() && sel.int() && sel.en() && sel.inv());
+        assert!(!sel.pc() && !sel.any_thread() && !sel.in_txcp());
+        assert!(sel.in_tx());
+        assert_eq!(sel.cmask(), 0x3);
+        assert!(!sel.has_reserved_bits());
+    }
+
+    #[test]
+    fn perfevtsel_setters_touch_only_their_field() {
+        let mut sel = PerfEvtSel::from_bits(!0);
+        sel.set_umask(0).set_any_thread(false).set_cmask(0);
+        assert_eq!(sel.bits(), !(0xFF00 | 1 << 21 | 0xFF00_0000));
+        assert_eq!(sel.event_select(), 0xFF);
+        assert!(sel.pc() && sel.in_txcp());
+        assert!(sel.has_reserved_bits());
+    }
+
+    #[test]
+    fn perfevtsel_round_trips_raw_bits() {
+        for bits in [0, 0x0043_003C, 0x3_FFFF_FFFF, 1 << 34, !0] {
+            let mut sel = PerfEvtSel::from_bits(bits);
+            assert_eq!(sel.bits(), bits);
+            let (event, umask, cmask, en, in_tx) = (sel.event_select(), sel.umask(), sel.cmask(), sel.en(), sel.in_tx());
+            sel.set_event_select(event).set_umask(umask).set_cmask(cmask).set_en(en).set_in_tx(in_tx);
+            assert_eq!(sel.bits(), bits);
+        }
+    }
+
+    #[test]
+    fn fixed_ctr_field() {
+        let mut field = FixedCtrField::default();
+        assert!(!field.is_enabled());
+        field.set_usr(true).set_pmi(true);
+        assert_eq!(field.bits(), 0xA);
+        assert!(field.is_enabled() && field.usr() && field.pmi());
+        assert!(!field.os() && !field.any_thread());
+        assert_eq!(FixedCtrField::from_bits(0xF7).bits(), 0x7);
+    }
+
+    #[test]
+    fn fixed_ctr_ctrl_counters() {
+        let mut ctrl = FixedCtrCtrl::from_bits(0x333);
+        let mut field = FixedCtrField::default();
+        field.set_os(true).set_any_thread(true);
+        ctrl.set_counter(1, field);
+        assert_eq!(ctrl.bits(), 0x353);
+        assert_eq!(ctrl.counter(0).bits(), 0x3);
+        assert_eq!(ctrl.counter(1), field);
+        assert_eq!(ctrl.counter(2).bits(), 0x3);
+        ctrl.set_counter(3, FixedCtrField::from_bits(0xB));
+        assert_eq!(ctrl.bits(), 0xB353);
+        for index in 0..4 {
+            let mut copy = FixedCtrCtrl::from_bits(ctrl.bits());
+            copy.set_counter(index, ctrl.counter(index));
+            assert_eq!(copy, ctrl);
+        }
+    }
+
+    #[test]
+    fn fixed_ctr_ctrl_last_field() {
+        let mut ctrl = FixedCtrCtrl::default();
+        ctrl.set_counter(15, FixedCtrField::from_bits(0xB));
+        assert_eq!(ctrl.bits(), 0xB << 60);
+        assert_eq!(ctrl.counter(15).bits(), 0xB);
+    }
+
+    #[test]
+    #[should_panic]
+    fn fixed_ctr_ctrl_has_16_fields() {
+        FixedCtrCtrl::default().set_counter(16, FixedCtrField::from_bits(0x3));
+    }
+}
@@ -0,0 +1,380 @@
+//! Lock-free single-producer/single-consumer ring buffer for records produced in interrupt context.
+//!
+//! A SampleRing is a fixed array of bytes holding variable-length records, each an 8 byte header
+//! (kind and payload length) followed by the payload padded to 8 bytes. Records never wrap around
+//! the end of the array: the space left there is filled with a padding record.
+//!
+//! The producer (a PMI or NMI handler) and the consumer (a thread draining the samples) each take
+//! their handle once, with producer() and consumer(). A full ring drops the new record and counts it as lost.
+//! A record is at most half the ring, see SampleRing::max_payload().
+//! Use one ring per logical CPU, so that the producer of a ring only ever runs on one CPU.
+//!
+
+use core::cell::UnsafeCell;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use super::sampling::{PmiRecord, Sample, SampleSink};
+
+pub const RECORD_HEADER_LEN: usize = 8;
+///Record kind of the padding before the end of the ring. Never returned by drain().
+pub const RECORD_PADDING: u32 = 0;
+///Record kind of a sampling::Sample, see Sample::encode().
+pub const RECORD_SAMPLE: u32 = 1;
+///Record kind of a sampling::PmiRecord, see PmiRecord::encode().
+pub const RECORD_PMI: u32 = 2;
+
+const fn record_len(payload:usize) -> usize {
+    RECORD_HEADER_LEN + ((payload + 7) & !7)
+}
+
+pub struct SampleRing<const N: usize> {
+    data: UnsafeCell<[u8; N]>,
+    //Bytes ever written and ever consumed; positions in data are taken modulo N.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    lost: AtomicU64,
+    producer_taken: AtomicBool,
+    consumer_taken: AtomicBool,
+}
+
+//The producer only writes the free part of data and the consumer only reads the filled part;
+//head and tail hand the bytes over between them.
+unsafe impl<const N: usize> Sync for SampleRing<N> {}
+
+impl<const N: usize> SampleRing<N> {
+    ///Empty ring. N must be a power of two of at least 16 bytes.
+    pub const fn new() -> SampleRing<N> {
+        assert!(N.is_power_of_two() && N >= 16);
+        SampleRing{
+            data: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            lost: AtomicU64::new(0),
+            producer_taken: AtomicBool::new(false),
+            consumer_taken: AtomicBool::new(false),
+        }
+    }
+
+    ///Producer handle, or None if it is already taken.
+    pub fn producer(&self) -> Option<RingProducer<'_, N>> {
+        if self.producer_taken.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        Some(RingProducer{ring: self})
+    }
+
+    ///Consumer handle, or None if it is already taken.
+    pub fn consumer(&self) -> Option<RingConsumer<'_, N>> {
+        if self.consumer_taken.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        Some(RingConsumer{ring: self})
+    }
+
+    ///Records dropped because the ring was full.
+    pub fn lost(&self) -> u64 {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    ///Bytes currently used, headers and padding included.
+    pub fn used(&self) -> usize {
+        self.head.load(Ordering::Acquire).wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    ///Largest payload a record can have.
+    ///
+    /// A record takes at most half the ring, so it always fits an empty ring, wherever the
+    /// end of the array forces it to be padded.
+    pub fn max_payload(&self) -> usize {
+        N / 2 - RECORD_HEADER_LEN
+    }
+
+    fn data(&self) -> *mut u8 {
+        self.data.get() as *mut u8
+    }
+}
+
+impl<const N: usize> Default for SampleRing<N> {
+    fn default() -> SampleRing<N> {
+        SampleRing::new()
+    }
+}
+
+unsafe fn write_header(at:*mut u8, kind:u32, len:u32) {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    header[0..4].copy_from_slice(&kind.to_le_bytes());
+    header[4..8].copy_from_slice(&len.to_le_bytes());
+    core::ptr::copy_nonoverlapping(header.as_ptr(), at, RECORD_HEADER_LEN);
+}
+
+unsafe fn read_header(at:*const u8) -> (u32, usize) {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    core::ptr::copy_nonoverlapping(at, header.as_mut_ptr(), RECORD_HEADER_LEN);
+    let kind = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    (kind, len as usize)
+}
+
+///Writing side of a SampleRing. Gives the handle back to the ring when dropped.
+pub struct RingProducer<'a, const N: usize> {
+    ring: &'a SampleRing<N>,
+}
+
+impl<'a, const N: usize> RingProducer<'a, N> {
+    ///Append a record. Returns false if it was dropped.
+    pub fn write(&mut self, kind:u32, payload:&[u8]) -> bool {
+        self.write_with(kind, payload.len(), |out| out.copy_from_slice(payload))
+    }
+
+    ///Append a record of `len` bytes filled in place by `fill`. Returns false if it was dropped.
+    ///
+    /// Records of kind RECORD_PADDING or larger than max_payload() are refused.
+    pub fn write_with(&mut self, kind:u32, len:usize, fill:impl FnOnce(&mut [u8])) -> bool {
+        let ring = self.ring;
+        if kind == RECORD_PADDING || len > ring.max_payload() {
+            ring.lost.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let size = record_len(len);
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        let free = N - head.wrapping_sub(tail);
+        let offset = head & (N - 1);
+        let padding = if offset + size > N {N - offset} else {0};
+        if padding + size > free {
+            ring.lost.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let data = ring.data();
+        unsafe {
+            if padding != 0 {
+                write_header(data.add(offset), RECORD_PADDING, (padding - RECORD_HEADER_LEN) as u32);
+            }
+            let start = (offset + padding) & (N - 1);
+            write_header(data.add(start), kind, len as u32);
+            fill(slice::from_raw_parts_mut(data.add(start + RECORD_HEADER_LEN), len));
+        }
+        ring.head.store(head.wrapping_add(padding + size), Ordering::Release);
+        true
+    }
+}
+
+impl<'a, const N: usize> SampleSink for RingProducer<'a, N> {
+    fn push(&mut self, sample:&Sample) -> bool {
+        self.write_with(RECORD_SAMPLE, sample.encoded_len(), |out| { sample.encode(out); })
+    }
+
+    fn push_pmi(&mut self, record:&PmiRecord) -> bool {
+        self.write_with(RECORD_PMI, record.encoded_len(), |out| { record.encode(out); })
+    }
+}
+
+impl<'a, const N: usize> Drop for RingProducer<'a, N> {
+    fn drop(&mut self) {
+        self.ring.producer_taken.store(false, Ordering::Release);
+    }
+}
+
+///Reading side of a SampleRing. Gives the handle back to the ring when dropped.
+pub struct RingConsumer<'a, const N: usize> {
+    ring: &'a SampleRing<N>,
+}
+
+impl<'a, const N: usize> RingConsumer<'a, N> {
+    pub fn is_empty(&self) -> bool {
+        self.ring.used() == 0
+    }
+
+    ///Pass every record written so far to `f` as (kind, payload), oldest first, and free them.
+    /// Returns the number of records passed.
+    pub fn drain(&mut self, f:impl FnMut(u32, &[u8])) -> usize {
+        self.drain_max(usize::MAX, f)
+    }
+
+    ///Like drain(), but stops after `max` records.
+    pub fn drain_max(&mut self, max:usize, mut f:impl FnMut(u32, &[u8])) -> usize {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Acquire);
+        let mut tail = ring.tail.load(Ordering::Relaxed);
+        let data = ring.data();
+        let mut count = 0;
+        while tail != head && count < max {
+            let offset = tail & (N - 1);
+            let (kind, len) = unsafe { read_header(data.add(offset)) };
+            if kind != RECORD_PADDING {
+                f(kind, unsafe { slice::from_raw_parts(data.add(offset + RECORD_HEADER_LEN), len) });
+                count += 1;
+            }
+            tail = tail.wrapping_add(record_len(len));
+            ring.tail.store(tail, Ordering::Release);
+        }
+        count
+    }
+
+    ///Drain the RECORD_SAMPLE and RECORD_PMI records, in the order they were written, skipping other kinds.
+    pub fn drain_sampled(&mut self, mut on_sample:impl FnMut(Sample), mut on_pmi:impl FnMut(PmiRecord)) -> usize {
+        let mut count = 0;
+        self.drain(|kind, payload| {
+            match kind {
+                RECORD_SAMPLE => if let Some(sample) = Sample::decode(payload) {
+                    on_sample(sample);
+                    count += 1;
+                },
+                RECORD_PMI => if let Some(record) = PmiRecord::decode(payload) {
+                    on_pmi(record);
+                    count += 1;
+                },
+                _ => {},
+            }
+        });
+        count
+    }
+
+    ///Drain the RECORD_SAMPLE records as Samples, skipping other kinds.
+    pub fn drain_samples(&mut self, mut f:impl FnMut(Sample)) -> usize {
+        let mut count = 0;
+        self.drain(|kind, payload| {
+            if kind == RECORD_SAMPLE {
+                if let Some(sample) = Sample::decode(payload) {
+                    f(sample);
+                    count += 1;
+                }
+            }
+        });
+        count
+    }
+}
+
+impl<'a, const N: usize> Drop for RingConsumer<'a, N> {
+    fn drop(&mut self) {
+        self.ring.consumer_taken.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86::perfcnt::intel::Counter;
+    use super::super::callchain::Callchain;
+    use super::super::lbr::LbrStack;
+
+    fn payloads<const N: usize>(consumer:&mut RingConsumer<'_, N>, max:usize) -> ([(u32, u8, usize); 8], usize) {
+        let mut seen = [(0, 0, 0); 8];
+        let mut len = 0;
+        consumer.drain_max(max, |kind, payload| {
+            seen[len] = (kind, payload.first().copied().unwrap_or(0), payload.len());
+            len += 1;
+        });
+        (seen, len)
+    }
+
+    #[test]
+    fn records_are_padded_at_the_end_of_the_ring() {
+        let ring = SampleRing::<64>::new();
+        let mut producer = ring.producer().unwrap();
+        let mut consumer = ring.consumer().unwrap();
+        assert_eq!(ring.max_payload(), 24);
+        assert!(producer.write(3, &[1; 24]));
+        assert!(producer.write(4, &[2; 13]));
+        assert_eq!(ring.used(), 32 + 24);
+        assert_eq!(payloads(&mut consumer, 8).1, 2);
+        //8 bytes left before the end: a padding record, then the record at the start.
+        assert!(producer.write(5, &[3; 20]));
+        assert_eq!(ring.used(), 8 + 32);
+        let (seen, len) = payloads(&mut consumer, 8);
+        assert_eq!(seen[..len], [(5, 3, 20)]);
+        assert!(consumer.is_empty());
+        assert_eq!(ring.lost(), 0);
+    }
+
+    #[test]
+    fn a_largest_record_always_fits_an_empty_ring() {
+        let ring = SampleRing::<64>::new();
+        let mut producer = ring.producer().unwrap();
+        let mut consumer = ring.consumer().unwrap();
+        for _ in 0..64 / 8 {
+            assert!(producer.write(1, &[7; 24]));
+            assert_eq!(payloads(&mut consumer, 8).1, 1);
+            //Move the head on by 8 bytes.
+            assert!(producer.write(1, &[]));
+            assert_eq!(payloads(&mut consumer, 8).1, 1);
+        }
+        assert_eq!(ring.lost(), 0);
+    }
+
+    #[test]
+    fn a_full_ring_counts_lost_records() {
+        let ring = SampleRing::<64>::new();
+        let mut producer = ring.producer().unwrap();
+        let mut consumer = ring.consumer().unwrap();
+        assert!(!producer.write(1, &[0; 25]));
+        assert!(!producer.write(RECORD_PADDING, &[]));
+        assert_eq!(ring.lost(), 2);
+        assert!(producer.write(1, &[1; 24]));
+        assert!(producer.write(2, &[2; 24]));
+        assert_eq!(ring.used(), 64);
+        assert!(!producer.write(3, &[]));
+        assert_eq!(ring.lost(), 3);
+        assert_eq!(payloads(&mut consumer, 1).0[0], (1, 1, 24));
+        assert!(producer.write(3, &[3; 24]));
+        let (seen, len) = payloads(&mut consumer, 8);
+        assert_eq!(seen[..len], [(2, 2, 24), (3, 3, 24)]);
+    }
+
+    #[test]
+    fn drain_max_keeps_the_order() {
+        let ring = SampleRing::<256>::new();
+        let mut producer = ring.producer().unwrap();
+        let mut consumer = ring.consumer().unwrap();
+        for i in 0..5 {
+            assert!(producer.write(10 + i as u32, &[i; 9]));
+        }
+        let (seen, len) = payloads(&mut consumer, 2);
+        assert_eq!(seen[..len], [(10, 0, 9), (11, 1, 9)]);
+        let (seen, len) = payloads(&mut consumer, usize::MAX);
+        assert_eq!(seen[..len], [(12, 2, 9), (13, 3, 9), (14, 4, 9)]);
+        assert_eq!(consumer.drain(|_, _| {}), 0);
+    }
+
+    #[test]
+    fn drain_sampled_decodes_samples_and_pmi_records() {
+        let ring = SampleRing::<1024>::new();
+        let mut producer = ring.producer().unwrap();
+        let mut consumer = ring.consumer().unwrap();
+        let mut callchain = Callchain::new();
+        callchain.push(0x40_1000);
+        let record = PmiRecord{pmi: 7, cpu: 1, callchain: callchain, branches: LbrStack::new()};
+        let sample = Sample{rip: 0x40_1000, rsp: 0x7FFF_0000, user: true, cpu: 1, tsc: 99, counter: Counter::Fixed(0), id: 1, pmi: 7, regs: None};
+        assert!(producer.push_pmi(&record));
+        assert!(producer.write(9, &[0; 8]));
+        assert!(producer.push(&sample));
+        //Decimal digits of the callbacks, in call order: 1 for a PMI record, 2 for a sample.
+        let order = core::cell::Cell::new(0);
+        let count = consumer.drain_sampled(|s| {
+            assert_eq!((s.rip, s.tsc, s.counter, s.id), (0x40_1000, 99, Counter::Fixed(0), 1));
+            order.set(order.get() * 10 + 2);
+        }, |r| {
+            assert_eq!(r, record);
+            order.set(order.get() * 10 + 1);
+        });
+        assert_eq!((count, order.get()), (2, 12));
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn handles_are_taken_once() {
+        let ring = SampleRing::<64>::new();
+        let producer = ring.producer().unwrap();
+        let consumer = ring.consumer().unwrap();
+        assert!(ring.producer().is_none());
+        assert!(ring.consumer().is_none());
+        drop(producer);
+        drop(consumer);
+        assert!(ring.producer().is_some());
+        assert!(ring.consumer().is_some());
+    }
+}
@@ -0,0 +1,719 @@
+//! Statistical sampling driven by the Performance Monitoring Interrupt (PMI).
+//!
+//! Every sampled counter is armed to overflow after `period` events. When it does, the PMI handler
+//! calls Sampler::handle_pmi() with the interrupted context: one Sample is recorded per overflowed
+//! counter, the counter is re-armed with its period, its overflow status is cleared and the LVT
+//! entry is unmasked for the next PMI.
+//!
+//! With set_callchain() every PMI also records the call chain of the interrupted context,
+//! walked through its frame pointers (see callchain). With set_lbr() it records the LBR stack,
+//! which a PMI freezes when the LBRs are enabled with FREEZE_LBRS_ON_PMI (see lbr).
+//! All counters that overflowed in one PMI share that context, so it is stored once, in a PmiRecord
+//! pushed before the samples; a Sample refers to it through its pmi number.
+//!
+//! Samples go to a SampleSink, e.g. a SampleBuffer or the producer side of a ring_buffer::SampleRing.
+//! The kernel still has to signal the end of interrupt to the local APIC.
+//!
+
+use x86::perfcnt::intel::Counter;
+use crate::{AbstractPerfCounter, ErrorMsg};
+use super::{backend::PmuBackend, callchain::{self, Callchain, CallchainConfig, MAX_CALLCHAIN_DEPTH}, globle_ctrl::PerfCounterControler,
+    lbr::{LbrEntry, LbrLayout, LbrStack, MAX_LBR_ENTRIES}, PerfCounter};
+
+pub const MAX_SAMPLED_COUNTERS: usize = 16;
+
+///Stack frame pushed by the CPU when delivering an interrupt in 64-bit mode.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterruptFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+///General purpose registers of the interrupted context, saved by the interrupt entry code.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampleRegs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+///One sample taken on a counter overflow.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    ///Instruction pointer of the interrupted context.
+    pub rip: u64,
+    ///Stack pointer of the interrupted context.
+    pub rsp: u64,
+    ///The interrupted context ran in ring 1 to 3.
+    pub user: bool,
+    pub cpu: u32,
+    pub tsc: u64,
+    ///Counter that overflowed.
+    pub counter: Counter,
+    ///Position of the counter in the Sampler.
+    pub id: u8,
+    ///Number of the PMI that took the sample, counted by the Sampler from 0.
+    /// The PmiRecord with the same number, if any, holds its call chain and branches.
+    pub pmi: u64,
+    pub regs: Option<SampleRegs>,
+}
+
+///Context shared by the samples of one PMI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmiRecord {
+    ///Sample::pmi of the samples this record belongs to.
+    pub pmi: u64,
+    pub cpu: u32,
+    ///Empty unless the Sampler walks call chains.
+    pub callchain: Callchain,
+    ///Empty unless the Sampler snapshots the LBR stack.
+    pub branches: LbrStack,
+}
+
+impl SampleRegs {
+    fn to_array(self) -> [u64; 15] {
+        [self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15]
+    }
+
+    fn from_array(r:[u64; 15]) -> SampleRegs {
+        SampleRegs{
+            rax: r[0], rbx: r[1], rcx: r[2], rdx: r[3], rsi: r[4], rdi: r[5], rbp: r[6],
+            r8: r[7], r9: r[8], r10: r[9], r11: r[10], r12: r[11], r13: r[12], r14: r[13], r15: r[14],
+        }
+    }
+}
+
+const SAMPLE_HEADER_LEN: usize = 40;
+const SAMPLE_REGS_LEN: usize = 15 * 8;
+const SAMPLE_FLAG_USER: u8 = 1<<0;
+const SAMPLE_FLAG_REGS: u8 = 1<<1;
+const PMI_HEADER_LEN: usize = 16;
+const PMI_FLAG_CALLCHAIN: u32 = 1<<0;
+const PMI_FLAG_BRANCHES: u32 = 1<<1;
+const PMI_BRANCH_LEN: usize = 3 * 8;
+
+fn u64_at(bytes:&[u8], at:usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(b)
+}
+
+fn u32_at(bytes:&[u8], at:usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+impl Sample {
+    ///Number of bytes encode() writes.
+    pub fn encoded_len(&self) -> usize {
+        SAMPLE_HEADER_LEN + if self.regs.is_some() {SAMPLE_REGS_LEN} else {0}
+    }
+
+    ///Serialize the sample in little endian into `out`, which must hold encoded_len() bytes.
+    /// Returns the number of bytes written, 0 if `out` is too small.
+    pub fn encode(&self, out:&mut [u8]) -> usize {
+        let len = self.encoded_len();
+        if out.len() < len {
+            return 0;
+        }
+        let (counter_type, index) = match self.counter {
+            Counter::Programmable(index) => (0, index),
+            Counter::Fixed(index) => (1, index),
+        };
+        let mut flags = 0;
+        if self.user {
+            flags |= SAMPLE_FLAG_USER;
+        }
+        if self.regs.is_some() {
+            flags |= SAMPLE_FLAG_REGS;
+        }
+        out[0..8].copy_from_slice(&self.rip.to_le_bytes());
+        out[8..16].copy_from_slice(&self.rsp.to_le_bytes());
+        out[16..24].copy_from_slice(&self.tsc.to_le_bytes());
+        out[24..28].copy_from_slice(&self.cpu.to_le_bytes());
+        out[28] = counter_type;
+        out[29] = index;
+        out[30] = self.id;
+        out[31] = flags;
+        out[32..40].copy_from_slice(&self.pmi.to_le_bytes());
+        let mut at = SAMPLE_HEADER_LEN;
+        if let Some(regs) = self.regs {
+            for r in regs.to_array().iter() {
+                out[at..at + 8].copy_from_slice(&r.to_le_bytes());
+                at += 8;
+            }
+        }
+        len
+    }
+
+    ///Parse a sample written by encode().
+    pub fn decode(bytes:&[u8]) -> Option<Sample> {
+        if bytes.len() < SAMPLE_HEADER_LEN {
+            return None;
+        }
+        let u64_at = |at:usize| u64_at(bytes, at);
+        let mut at = SAMPLE_HEADER_LEN;
+        let flags = bytes[31];
+        let counter = match bytes[28] {
+            0 => Counter::Programmable(bytes[29]),
+            1 => Counter::Fixed(bytes[29]),
+            _ => return None,
+        };
+        let regs = if flags & SAMPLE_FLAG_REGS != 0 {
+            if bytes.len() < at + SAMPLE_REGS_LEN {
+                return None;
+            }
+            let mut r = [0u64; 15];
+            for v in r.iter_mut() {
+                *v = u64_at(at);
+                at += 8;
+            }
+            Some(SampleRegs::from_array(r))
+        } else {
+            None
+        };
+        Some(Sample{
+            rip: u64_at(0),
+            rsp: u64_at(8),
+            user: flags & SAMPLE_FLAG_USER != 0,
+            cpu: u32_at(bytes, 24),
+            tsc: u64_at(16),
+            counter: counter,
+            id: bytes[30],
+            pmi: u64_at(32),
+            regs: regs,
+        })
+    }
+}
+
+impl PmiRecord {
+    ///Nothing to record: the samples of the PMI are complete on their own.
+    pub fn is_empty(&self) -> bool {
+        self.callchain.is_empty() && self.branches.is_empty()
+    }
+
+    ///Number of bytes encode() writes.
+    pub fn encoded_len(&self) -> usize {
+        let mut len = PMI_HEADER_LEN;
+        if !self.callchain.is_empty() {
+            len += 8 + 8 * self.callchain.len();
+        }
+        if !self.branches.is_empty() {
+            len += 8 + PMI_BRANCH_LEN * self.branches.len();
+        }
+        len
+    }
+
+    ///Serialize the record in little endian into `out`, which must hold encoded_len() bytes.
+    /// Returns the number of bytes written, 0 if `out` is too small.
+    pub fn encode(&self, out:&mut [u8]) -> usize {
+        let len = self.encoded_len();
+        if out.len() < len {
+            return 0;
+        }
+        let mut flags = 0;
+        if !self.callchain.is_empty() {
+            flags |= PMI_FLAG_CALLCHAIN;
+        }
+        if !self.branches.is_empty() {
+            flags |= PMI_FLAG_BRANCHES;
+        }
+        out[0..8].copy_from_slice(&self.pmi.to_le_bytes());
+        out[8..12].copy_from_slice(&self.cpu.to_le_bytes());
+        out[12..16].copy_from_slice(&flags.to_le_bytes());
+        let mut at = PMI_HEADER_LEN;
+        if !self.callchain.is_empty() {
+            out[at..at + 8].copy_from_slice(&(self.callchain.len() as u64).to_le_bytes());
+            at += 8;
+            for ip in self.callchain.as_slice() {
+                out[at..at + 8].copy_from_slice(&ip.to_le_bytes());
+                at += 8;
+            }
+        }
+        if !self.branches.is_empty() {
+            out[at..at + 8].copy_from_slice(&(self.branches.len() as u64).to_le_bytes());
+            at += 8;
+            for entry in self.branches.as_slice() {
+                for v in [entry.from, entry.to, entry.flag_bits()].iter() {
+                    out[at..at + 8].copy_from_slice(&v.to_le_bytes());
+                    at += 8;
+                }
+            }
+        }
+        len
+    }
+
+    ///Parse a record written by encode().
+    pub fn decode(bytes:&[u8]) -> Option<PmiRecord> {
+        if bytes.len() < PMI_HEADER_LEN {
+            return None;
+        }
+        let flags = u32_at(bytes, 12);
+        let mut at = PMI_HEADER_LEN;
+        let mut chain = Callchain::new();
+        if flags & PMI_FLAG_CALLCHAIN != 0 {
+            if bytes.len() < at + 8 {
+                return None;
+            }
+            let depth = u64_at(bytes, at) as usize;
+            at += 8;
+            if depth > MAX_CALLCHAIN_DEPTH || bytes.len() < at + 8 * depth {
+                return None;
+            }
+            for i in 0..depth {
+                chain.push(u64_at(bytes, at + 8 * i));
+            }
+            at += 8 * depth;
+        }
+        let mut branches = LbrStack::new();
+        if flags & PMI_FLAG_BRANCHES != 0 {
+            if bytes.len() < at + 8 {
+                return None;
+            }
+            let count = u64_at(bytes, at) as usize;
+            at += 8;
+            if count > MAX_LBR_ENTRIES || bytes.len() < at + PMI_BRANCH_LEN * count {
+                return None;
+            }
+            for i in 0..count {
+                let entry = at + PMI_BRANCH_LEN * i;
+                branches.push(LbrEntry::from_flag_bits(u64_at(bytes, entry), u64_at(bytes, entry + 8), u64_at(bytes, entry + 16)));
+            }
+        }
+        Some(PmiRecord{
+            pmi: u64_at(bytes, 0),
+            cpu: u32_at(bytes, 8),
+            callchain: chain,
+            branches: branches,
+        })
+    }
+}
+
+///Destination of the samples recorded by a Sampler.
+pub trait SampleSink {
+    ///Store a sample. Returns false if it was dropped.
+    fn push(&mut self, sample:&Sample) -> bool;
+
+    ///Store the context shared by the samples of one PMI, pushed before them.
+    /// Returns false if it was dropped.
+    fn push_pmi(&mut self, record:&PmiRecord) -> bool;
+}
+
+///SampleSink filling fixed arrays of N samples and R PMI records, and dropping them once full.
+///
+/// With the default R of 0 call chains and branches are dropped.
+pub struct SampleBuffer<const N: usize, const R: usize = 0> {
+    samples: [Option<Sample>; N],
+    len: usize,
+    records: [Option<PmiRecord>; R],
+    records_len: usize,
+}
+
+impl<const N: usize, const R: usize> SampleBuffer<N, R> {
+    pub fn new() -> SampleBuffer<N, R> {
+        SampleBuffer{
+            samples: [None; N],
+            len: 0,
+            records: [None; R],
+            records_len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Sample> {
+        self.samples[..self.len].iter().filter_map(|s| s.as_ref())
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &PmiRecord> {
+        self.records[..self.records_len].iter().filter_map(|r| r.as_ref())
+    }
+
+    ///PMI record `sample` refers to, if it was kept.
+    pub fn record_of(&self, sample:&Sample) -> Option<&PmiRecord> {
+        self.records().find(|r| r.pmi == sample.pmi)
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.records_len = 0;
+    }
+}
+
+impl<const N: usize, const R: usize> Default for SampleBuffer<N, R> {
+    fn default() -> SampleBuffer<N, R> {
+        SampleBuffer::new()
+    }
+}
+
+impl<const N: usize, const R: usize> SampleSink for SampleBuffer<N, R> {
+    fn push(&mut self, sample:&Sample) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.samples[self.len] = Some(*sample);
+        self.len += 1;
+        true
+    }
+
+    fn push_pmi(&mut self, record:&PmiRecord) -> bool {
+        if self.records_len == R {
+            return false;
+        }
+        self.records[self.records_len] = Some(*record);
+        self.records_len += 1;
+        true
+    }
+}
+
+struct SampledCounter<'a, B: PmuBackend> {
+    counter: PerfCounter<'a, B>,
+    period: u64,
+}
+
+///Samples the interrupted context every `period` events of each counter.
+pub struct Sampler<'a, B: PmuBackend, S: SampleSink> {
+    ctrler: &'a PerfCounterControler<B>,
+    counters: [Option<SampledCounter<'a, B>>; MAX_SAMPLED_COUNTERS],
+    len: usize,
+    sink: S,
+    cpu: u32,
+    lost: u64,
+    pmis: u64,
+    running: bool,
+    callchain: Option<CallchainConfig>,
+    lbr: Option<LbrLayout>,
+}
+
+impl<'a, B: PmuBackend, S: SampleSink> Sampler<'a, B, S> {
+    ///Sampler for the PMU of logical CPU `cpu`, storing samples into `sink`.
+    pub fn new(ctrler:&'a PerfCounterControler<B>, cpu:u32, sink:S) -> Sampler<'a, B, S> {
+        Sampler{
+            ctrler: ctrler,
+            counters: core::array::from_fn(|_| None),
+            len: 0,
+            sink: sink,
+            cpu: cpu,
+            lost: 0,
+            pmis: 0,
+            running: false,
+            callchain: None,
+            lbr: None,
+        }
+    }
+
+    ///Sample a built counter every `period` events. Returns the id recorded in its samples.
+    pub fn add(&mut self, mut counter:PerfCounter<'a, B>, period:u64) -> Result<u8, ErrorMsg> {
+        if self.running {
+            return Err(ErrorMsg::CounterInUse);
+        }
+        if self.len == MAX_SAMPLED_COUNTERS || !core::ptr::eq(counter.global_ctrler, self.ctrler) || period == 0 {
+            return Err(ErrorMsg::CounterOutOfRange);
+        }
+        counter.enable_interrupt();
+        self.counters[self.len] = Some(SampledCounter{counter:counter, period:period});
+        self.len += 1;
+        Ok((self.len - 1) as u8)
+    }
+
+    ///Walk the frame pointers of the interrupted context in handle_pmi(), within the limits of `config`.
+    /// None turns call chains off.
+    ///
+    /// # Safety
+    /// Every address in `config.stack` must stay mapped and readable while the Sampler handles PMIs.
+    pub unsafe fn set_callchain(&mut self, config:Option<CallchainConfig>) {
+        self.callchain = config;
+    }
+
+    ///Snapshot the LBR stack described by `layout` in handle_pmi() and unfreeze it afterwards.
+    /// None turns branch records off. Enabling the LBRs is left to lbr::Lbr.
+    pub fn set_lbr(&mut self, layout:Option<LbrLayout>) {
+        self.lbr = layout;
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    ///Samples and PMI records the sink refused.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &SampledCounter<'a, B>> {
+        self.counters[..self.len].iter().filter_map(|c| c.as_ref())
+    }
+
+    ///Deliver PMIs on `interrput_vec`, arm every counter with its period and start them.
+    pub fn start(&mut self, interrput_vec:u8) -> Result<(), ErrorMsg> {
+        if self.running {
+            return Ok(());
+        }
+        self.ctrler.register_overflow_interrput(interrput_vec);
+        for sampled in self.iter() {
+            sampled.counter.global_ctrler.clear_overflow_bit(sampled.counter.get_counter_type());
+            sampled.counter.overflow_after(sampled.period - 1);
+            sampled.counter.start()?;
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    ///Stop all counters. PMIs already pending are still handled.
+    pub fn stop(&mut self) -> Result<(), ErrorMsg> {
+        if !self.running {
+            return Ok(());
+        }
+        for sampled in self.iter() {
+            sampled.counter.stop()?;
+        }
+        self.running = false;
+        Ok(())
+    }
+
+    ///PMI handler entry point.
+    ///
+    /// Records a sample for every overflowed counter of this Sampler, re-arms it, clears its overflow
+    /// status and unmasks the LVT entry. Overflows of other counters are left in IA32_PERF_GLOBAL_STATUS.
+    /// The call chain and the LBR stack are only read, and the LBR stack unfrozen, when one of these counters overflowed.
+    /// Call chains need the RBP of the interrupted context, so they are only walked when `regs` is given.
+    /// Returns the number of overflowed counters handled.
+    pub fn handle_pmi(&mut self, frame:&InterruptFrame, regs:Option<&SampleRegs>) -> usize {
+        let tsc = self.ctrler.backend().rdtsc();
+        let status = self.ctrler.read_overflow_status();
+        let sampled = self.iter().fold(0, |mask, sampled| mask | sampled.counter.global_bit());
+        let pmi = self.pmis;
+        let ours = status & sampled != 0;
+        if ours {
+            self.pmis += 1;
+            let chain = match (self.callchain.as_ref(), regs) {
+                //The stack range was promised readable by set_callchain().
+                (Some(config), Some(regs)) => unsafe { callchain::walk_frame_pointers(frame.rip, regs.rbp, frame.rsp, config) },
+                _ => Callchain::new(),
+            };
+            let branches = match self.lbr.as_ref() {
+                Some(layout) => layout.read(self.ctrler),
+                None => LbrStack::new(),
+            };
+            let record = PmiRecord{pmi: pmi, cpu: self.cpu, callchain: chain, branches: branches};
+            if !record.is_empty() && !self.sink.push_pmi(&record) {
+                self.lost += 1;
+            }
+        }
+        let mut handled = 0;
+        for id in 0..self.len {
+            let sampled = match self.counters[id].as_ref() {
+                Some(sampled) => sampled,
+                None => continue,
+            };
+            let counter = &sampled.counter;
+            if status & counter.global_bit() == 0 {
+                continue;
+            }
+            let sample = Sample{
+                rip: frame.rip,
+                rsp: frame.rsp,
+                user: frame.cs & 3 != 0,
+                cpu: self.cpu,
+                tsc: tsc,
+                counter: counter.get_counter_type(),
+                id: id as u8,
+                pmi: pmi,
+                regs: regs.copied(),
+            };
+            counter.overflow_after(sampled.period - 1);
+            self.ctrler.clear_overflow_bit(counter.get_counter_type());
+            if !self.sink.push(&sample) {
+                self.lost += 1;
+            }
+            handled += 1;
+        }
+        //A PMI of another counter leaves the LBR stack frozen for its owner.
+        if let (true, Some(layout)) = (ours, self.lbr.as_ref()) {
+            layout.unfreeze(self.ctrler);
+        }
+        self.ctrler.reset_overflow_interrput();
+        handled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_round_trip() {
+        let sample = Sample{
+            rip: 0xFFFF_FFFF_8100_1234,
+            rsp: 0xFFFF_C900_0000_3F00,
+            user: false,
+            cpu: 5,
+            tsc: 0x1234_5678_9ABC,
+            counter: Counter::Fixed(1),
+            id: 2,
+            pmi: 77,
+            regs: Some(SampleRegs{rax: 1, rbp: 7, r15: 15, ..Default::default()}),
+        };
+        let mut buf = [0u8; 256];
+        let len = sample.encode(&mut buf);
+        assert_eq!(len, sample.encoded_len());
+        let back = Sample::decode(&buf[..len]).unwrap();
+        assert_eq!((back.rip, back.rsp, back.user, back.cpu, back.tsc), (sample.rip, sample.rsp, false, 5, sample.tsc));
+        assert_eq!((back.counter, back.id, back.pmi), (Counter::Fixed(1), 2, 77));
+        let regs = back.regs.unwrap();
+        assert_eq!((regs.rax, regs.rbp, regs.r15, regs.rdx), (1, 7, 15, 0));
+        assert!(Sample::decode(&buf[..len - 8]).is_none());
+        assert_eq!(sample.encode(&mut buf[..len - 1]), 0);
+    }
+
+    #[test]
+    fn pmi_record_round_trip() {
+        let mut record = PmiRecord{pmi: 3, cpu: 1, callchain: Callchain::new(), branches: LbrStack::new()};
+        assert!(record.is_empty());
+        for ip in [0x40_1000, 0x40_2000, 0x40_3000] {
+            record.callchain.push(ip);
+        }
+        record.branches.push(LbrEntry{from: 0x40_1010, to: 0x40_1100, mispredicted: Some(true), in_tx: false, abort: false, cycles: None});
+        record.branches.push(LbrEntry{from: 0x40_1200, to: 0x40_1000, mispredicted: None, in_tx: true, abort: true, cycles: None});
+        let mut buf = [0u8; 256];
+        let len = record.encode(&mut buf);
+        assert_eq!(len, PMI_HEADER_LEN + 8 + 3 * 8 + 8 + 2 * PMI_BRANCH_LEN);
+        assert_eq!(PmiRecord::decode(&buf[..len]), Some(record));
+        assert_eq!(PmiRecord::decode(&buf[..len - 1]), None);
+    }
+
+    #[test]
+    fn sample_buffer_keeps_records_apart() {
+        let mut buffer: SampleBuffer<2, 1> = SampleBuffer::new();
+        let mut record = PmiRecord{pmi: 9, cpu: 0, callchain: Callchain::new(), branches: LbrStack::new()};
+        record.callchain.push(0x1000);
+        assert!(buffer.push_pmi(&record));
+        assert!(!buffer.push_pmi(&record));
+        let sample = Sample{rip: 0x1000, rsp: 0, user: true, cpu: 0, tsc: 0, counter: Counter::Programmable(0), id: 0, pmi: 9, regs: None};
+        assert!(buffer.push(&sample));
+        assert_eq!(buffer.record_of(&sample), Some(&record));
+        let mut without = SampleBuffer::<2>::new();
+        assert!(!without.push_pmi(&record));
+    }
+
+    #[test]
+    fn handle_pmi_samples_and_rearms_the_overflowed_counters() {
+        use x86::msr::{IA32_PERF_GLOBAL_OVF_CTRL, IA32_PERF_GLOBAL_STAUS};
+        use super::super::simulator::{SimulatedPmu, SimRing, MAX_MSR_WRITE_LOG};
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        let pmu = ctrler.backend();
+        //Only room for one of the two samples of the PMI.
+        let mut sampler = Sampler::new(&ctrler, 3, SampleBuffer::<1>::new());
+        let mut branches = PerfCounter::new(&ctrler);
+        branches.build_general_from_raw(0xC4, 0, true, true, 0, false, 0).unwrap();
+        assert!(matches!(sampler.add(branches, 100), Ok(0)));
+        let mut instructions = PerfCounter::new(&ctrler);
+        instructions.build_fixed(0, false).unwrap();
+        assert!(matches!(sampler.add(instructions, 1000), Ok(1)));
+        //Counter of someone else, overflowing in the same PMI.
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_general_from_raw(0xC5, 0, true, true, 0, false, 3).unwrap();
+        other.overflow_after(0);
+        other.start().unwrap();
+        sampler.start(0x40).unwrap();
+
+        pmu.count_event(0xC4, 0, SimRing::User, 100);
+        pmu.retire_instructions(SimRing::User, 1000);
+        pmu.count_event(0xC5, 0, SimRing::User, 1);
+        assert_eq!(pmu.pmi_count(), 1);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1 | 1 << 3 | 1 << 32);
+        let mut log = [(0, 0); MAX_MSR_WRITE_LOG];
+        pmu.take_msr_writes(&mut log);
+        let frame = InterruptFrame{rip: 0x40_1000, cs: 0x33, rsp: 0x7FFF_0000, ..Default::default()};
+        assert_eq!(sampler.handle_pmi(&frame, None), 2);
+
+        assert_eq!(sampler.lost(), 1);
+        let sample = sampler.sink().iter().next().unwrap();
+        assert_eq!((sample.rip, sample.rsp, sample.user, sample.cpu), (0x40_1000, 0x7FFF_0000, true, 3));
+        assert_eq!((sample.counter, sample.id, sample.pmi), (Counter::Programmable(0), 0, 0));
+        assert_eq!(pmu.general_counter(0), (1 << 48) - 100);
+        assert_eq!(pmu.fixed_counter(0), (1 << 48) - 1000);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1 << 3);
+        let n = pmu.take_msr_writes(&mut log);
+        assert!(log[..n].iter().filter(|(msr, _)| *msr == IA32_PERF_GLOBAL_OVF_CTRL).all(|(_, value)| value & 1 << 3 == 0));
+        assert_eq!(pmu.read_lvt_perfmon(), 0x40);
+
+        //Only the other counter is pending: nothing to sample.
+        assert_eq!(sampler.handle_pmi(&frame, None), 0);
+        assert_eq!((sampler.sink().len(), sampler.lost()), (1, 1));
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1 << 3);
+        pmu.count_event(0xC4, 0, SimRing::Kernel, 100);
+        assert_eq!(pmu.pmi_count(), 2);
+    }
+
+    #[test]
+    fn handle_pmi_unfreezes_the_lbrs_only_for_its_counters() {
+        use x86::msr::IA32_PERF_GLOBAL_OVF_CTRL;
+        use super::super::lbr::{GLOBAL_STATUS_LBR_FRZ, MSR_LBR_INFO_0};
+        use super::super::simulator::{SimulatedPmu, SimRing, MAX_MSR_WRITE_LOG};
+        let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+        ctrler.init();
+        let pmu = ctrler.backend();
+        let mut sampler = Sampler::new(&ctrler, 0, SampleBuffer::<4, 4>::new());
+        let mut branches = PerfCounter::new(&ctrler);
+        branches.build_general_from_raw(0xC4, 0, true, true, 0, false, 0).unwrap();
+        sampler.add(branches, 100).unwrap();
+        sampler.set_lbr(Some(LbrLayout{depth: 4, from_msr: 0x680, to_msr: 0x6C0, info_msr: Some(MSR_LBR_INFO_0), format: 5, has_select: true}));
+        pmu.wrmsr(0x680, 0x40_1000);
+        pmu.wrmsr(0x6C0, 0x40_2000);
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_general_from_raw(0xC5, 0, true, true, 0, false, 1).unwrap();
+        other.overflow_after(0);
+        other.start().unwrap();
+        sampler.start(0x40).unwrap();
+        let unfrozen = |log:&[(u32, u64)]| log.iter().any(|(msr, value)| *msr == IA32_PERF_GLOBAL_OVF_CTRL && value & GLOBAL_STATUS_LBR_FRZ != 0);
+        let mut log = [(0, 0); MAX_MSR_WRITE_LOG];
+        let frame = InterruptFrame::default();
+
+        pmu.count_event(0xC5, 0, SimRing::User, 1);
+        pmu.take_msr_writes(&mut log);
+        assert_eq!(sampler.handle_pmi(&frame, None), 0);
+        let n = pmu.take_msr_writes(&mut log);
+        assert!(!unfrozen(&log[..n]));
+        assert_eq!(sampler.sink().records().count(), 0);
+
+        pmu.count_event(0xC4, 0, SimRing::User, 100);
+        assert_eq!(sampler.handle_pmi(&frame, None), 1);
+        let n = pmu.take_msr_writes(&mut log);
+        assert!(unfrozen(&log[..n]));
+        let record = sampler.sink().records().next().unwrap();
+        assert_eq!(record.branches.as_slice()[0].to, 0x40_2000);
+    }
+}
@@ -0,0 +1,698 @@
+//! Software model of the Intel architectural performance monitoring unit.
+//!
+//! SimulatedPmu implements PmuBackend so PerfCounter and PerfCounterControler can run on top of it.
+//! It models IA32_PERFEVTSELx, IA32_PMCx/IA32_A_PMCx, IA32_FIXED_CTRx, IA32_FIXED_CTR_CTRL,
+//! IA32_PERF_GLOBAL_CTRL/STATUS/OVF_CTRL and the LVT Performance Monitoring Counters entry.
+//! Counter bit widths and counts are taken from the configured CPUID.0AH leaf.
+//!
+//! Events are fed in with count_event() and the architectural helpers,
+//! e.g. retire_instructions(). Each call models one cycle in which the event occurred `n` times.
+//!
+
+use core::cell::RefCell;
+use x86::{cpuid::CpuIdResult, msr::*};
+use crate::ErrorMsg;
+use super::backend::PmuBackend;
+
+pub const MAX_GENERAL_PMC: usize = 8;
+pub const MAX_FIXED_PMC: usize = 4;
+const MAX_EXTRA_MSR: usize = 32;
+const MAX_CPUID_LEAF: usize = 8;
+///MSR writes kept for take_msr_writes().
+pub const MAX_MSR_WRITE_LOG: usize = 32;
+
+const EVTSEL_USR: u64 = 1<<16;
+const EVTSEL_OS: u64 = 1<<17;
+const EVTSEL_EDGE: u64 = 1<<18;
+const EVTSEL_INT: u64 = 1<<20;
+const EVTSEL_EN: u64 = 1<<22;
+const EVTSEL_INV: u64 = 1<<23;
+//IN_TX and IN_TXCP are the highest defined IA32_PERFEVTSELx bits.
+const EVTSEL_RESERVED: u64 = !0 << 34;
+const LVT_MASKED: u32 = 1<<16;
+
+///Privilege level an event is reported at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimRing {
+    Kernel,
+    User,
+}
+
+struct SimState {
+    cpuid: [(u32, u32, CpuIdResult); MAX_CPUID_LEAF],
+    cpuid_len: usize,
+    perfevtsel: [u64; MAX_GENERAL_PMC],
+    pmc: [u64; MAX_GENERAL_PMC],
+    //Condition of the previous cycle, used for edge detection.
+    last_condition: [bool; MAX_GENERAL_PMC],
+    fixed_ctr: [u64; MAX_FIXED_PMC],
+    fixed_ctr_ctrl: u64,
+    global_ctrl: u64,
+    global_status: u64,
+    global_ovf_ctrl: u64,
+    perf_capabilities: u64,
+    extra_msr: [(u32, u64); MAX_EXTRA_MSR],
+    extra_msr_len: usize,
+    lvt_perfmon: u32,
+    tsc: u64,
+    pmi_count: u64,
+    gp_faults: u64,
+    msr_writes: [(u32, u64); MAX_MSR_WRITE_LOG],
+    msr_writes_len: usize,
+    before_rdpmc: Option<fn(&SimulatedPmu)>,
+}
+
+///Simulated PMU of one logical processor.
+pub struct SimulatedPmu {
+    state: RefCell<SimState>,
+}
+
+impl SimulatedPmu {
+    ///Build a simulated PMU from the raw CPUID.0AH register values.
+    ///
+    /// IA32_PERF_CAPABILITIES is absent until set_perf_capabilities() is called.
+    pub fn new(eax:u32, ebx:u32, ecx:u32, edx:u32) -> SimulatedPmu {
+        let zero = CpuIdResult{eax:0, ebx:0, ecx:0, edx:0};
+        let pmu = SimulatedPmu{
+            state: RefCell::new(SimState{
+                cpuid: [(0, 0, zero); MAX_CPUID_LEAF],
+                cpuid_len: 0,
+                perfevtsel: [0; MAX_GENERAL_PMC],
+                pmc: [0; MAX_GENERAL_PMC],
+                last_condition: [false; MAX_GENERAL_PMC],
+                fixed_ctr: [0; MAX_FIXED_PMC],
+                fixed_ctr_ctrl: 0,
+                global_ctrl: 0,
+                global_status: 0,
+                global_ovf_ctrl: 0,
+                perf_capabilities: 0,
+                extra_msr: [(0, 0); MAX_EXTRA_MSR],
+                extra_msr_len: 0,
+                lvt_perfmon: LVT_MASKED,
+                tsc: 0,
+                pmi_count: 0,
+                gp_faults: 0,
+                msr_writes: [(0, 0); MAX_MSR_WRITE_LOG],
+                msr_writes_len: 0,
+                before_rdpmc: None,
+            }),
+        };
+        //The table is empty, so both leaves fit.
+        let _ = pmu.set_cpuid(0x0A, 0, CpuIdResult{eax, ebx, ecx, edx});
+        let _ = pmu.set_cpuid(0x01, 0, zero);
+        //All general purpose counters are enabled in IA32_PERF_GLOBAL_CTRL on reset.
+        let mut state = pmu.state.borrow_mut();
+        state.global_ctrl = (1u64 << pmu.general_count_of(&state)) - 1;
+        drop(state);
+        pmu
+    }
+
+    ///Version 4 PMU with four 48 bit general counters, three 48 bit fixed counters and full-width writes.
+    pub fn default_v4() -> SimulatedPmu {
+        let pmu = SimulatedPmu::new(4 | 4<<8 | 48<<16 | 7<<24, 0, 0, 3 | 48<<5);
+        //Leaf 01H is already in the table.
+        let _ = pmu.set_perf_capabilities(1<<13);
+        pmu
+    }
+
+    ///Override the result of one CPUID leaf.
+    /// Fails with BufferTooSmall once MAX_CPUID_LEAF distinct leaves are set.
+    pub fn set_cpuid(&self, leaf:u32, subleaf:u32, result:CpuIdResult) -> Result<(), ErrorMsg> {
+        let mut state = self.state.borrow_mut();
+        let len = state.cpuid_len;
+        for i in 0..len {
+            if state.cpuid[i].0 == leaf && state.cpuid[i].1 == subleaf {
+                state.cpuid[i].2 = result;
+                return Ok(());
+            }
+        }
+        if len == MAX_CPUID_LEAF {
+            return Err(ErrorMsg::BufferTooSmall);
+        }
+        state.cpuid[len] = (leaf, subleaf, result);
+        state.cpuid_len += 1;
+        Ok(())
+    }
+
+    ///Make IA32_PERF_CAPABILITIES readable (CPUID.01H:ECX.PDCM) with the given value.
+    pub fn set_perf_capabilities(&self, value:u64) -> Result<(), ErrorMsg> {
+        let mut leaf1 = self.cpuid(0x01, 0);
+        leaf1.ecx |= 1<<15;
+        self.set_cpuid(0x01, 0, leaf1)?;
+        self.state.borrow_mut().perf_capabilities = value;
+        Ok(())
+    }
+
+    ///Number of PMIs delivered through the LVT entry so far.
+    pub fn pmi_count(&self) -> u64 {
+        self.state.borrow().pmi_count
+    }
+
+    ///Number of accesses real hardware would have answered with #GP.
+    pub fn gp_faults(&self) -> u64 {
+        self.state.borrow().gp_faults
+    }
+
+    ///Run `f` at the start of the next RDPMC, e.g. to count events between two reads of a race.
+    pub fn before_next_rdpmc(&self, f:fn(&SimulatedPmu)) {
+        self.state.borrow_mut().before_rdpmc = Some(f);
+    }
+
+    ///Copy the MSR writes since the last call into `out`, oldest first, and forget them.
+    /// Returns how many were copied. Writes beyond the first MAX_MSR_WRITE_LOG are not kept.
+    pub fn take_msr_writes(&self, out:&mut [(u32, u64)]) -> usize {
+        let mut state = self.state.borrow_mut();
+        let len = state.msr_writes_len.min(out.len());
+        out[..len].copy_from_slice(&state.msr_writes[..len]);
+        state.msr_writes_len = 0;
+        len
+    }
+
+    ///Interrupt vector currently programmed in the LVT entry.
+    pub fn pmi_vector(&self) -> u8 {
+        self.state.borrow().lvt_perfmon as u8
+    }
+
+    ///Advance the time stamp counter by `cycles`.
+    pub fn advance_tsc(&self, cycles:u64) {
+        let mut state = self.state.borrow_mut();
+        state.tsc = state.tsc.wrapping_add(cycles);
+    }
+
+    ///Raw counter value of programmable counter `index`, bypassing RDPMC.
+    pub fn general_counter(&self, index:u8) -> u64 {
+        self.state.borrow().pmc[index as usize]
+    }
+
+    ///Raw counter value of fixed counter `index`, bypassing RDPMC.
+    pub fn fixed_counter(&self, index:u8) -> u64 {
+        self.state.borrow().fixed_ctr[index as usize]
+    }
+
+    ///Report `n` occurrences of the event `event_code`/`umask` within one cycle.
+    pub fn count_event(&self, event_code:u8, umask:u8, ring:SimRing, n:u64) {
+        let mut state = self.state.borrow_mut();
+        let ring_bit = match ring { SimRing::Kernel => EVTSEL_OS, SimRing::User => EVTSEL_USR };
+        for i in 0..self.general_count_of(&state) {
+            let sel = state.perfevtsel[i];
+            if sel & EVTSEL_EN == 0 || sel & 0xFF != event_code as u64 || (sel>>8) & 0xFF != umask as u64 {
+                continue;
+            }
+            if self.version_of(&state) >= 2 && (state.global_ctrl>>i) & 1 == 0 {
+                continue;
+            }
+            if sel & ring_bit == 0 {
+                continue;
+            }
+            let cmask = (sel>>24) & 0xFF;
+            let increment = if cmask == 0 {
+                n
+            } else {
+                let condition = (n >= cmask) != (sel & EVTSEL_INV != 0);
+                let previous = state.last_condition[i];
+                state.last_condition[i] = condition;
+                if sel & EVTSEL_EDGE != 0 {
+                    (condition && !previous) as u64
+                } else {
+                    condition as u64
+                }
+            };
+            let width = self.general_width_of(&state);
+            let pmi = sel & EVTSEL_INT != 0;
+            let (value, overflowed) = Self::advance(state.pmc[i], increment, width);
+            state.pmc[i] = value;
+            if overflowed {
+                self.overflow(&mut state, i as u32, pmi);
+            }
+        }
+    }
+
+    ///Count fixed counter `index` `n` times.
+    pub fn count_fixed(&self, index:u8, ring:SimRing, n:u64) {
+        let mut state = self.state.borrow_mut();
+        let i = index as usize;
+        if self.version_of(&state) < 2 || i >= self.fixed_count_of(&state) {
+            return;
+        }
+        let ctrl = (state.fixed_ctr_ctrl >> (4*i)) & 0xF;
+        let ring_bit = match ring { SimRing::Kernel => 1, SimRing::User => 2 };
+        if ctrl & ring_bit == 0 || (state.global_ctrl>>(32+i)) & 1 == 0 {
+            return;
+        }
+        let width = self.fixed_width_of(&state);
+        let (value, overflowed) = Self::advance(state.fixed_ctr[i], n, width);
+        state.fixed_ctr[i] = value;
+        if overflowed {
+            self.overflow(&mut state, 32+i as u32, ctrl & 8 != 0);
+        }
+    }
+
+    ///INST_RETIRED.ANY: fixed counter 0 and event 0xC0 umask 0x00.
+    pub fn retire_instructions(&self, ring:SimRing, n:u64) {
+        self.count_fixed(0, ring, n);
+        self.count_event(0xC0, 0x00, ring, n);
+    }
+
+    ///CPU_CLK_UNHALTED.CORE: fixed counter 1 and event 0x3C umask 0x00.
+    pub fn unhalted_core_cycles(&self, ring:SimRing, n:u64) {
+        self.count_fixed(1, ring, n);
+        self.count_event(0x3C, 0x00, ring, n);
+    }
+
+    ///CPU_CLK_UNHALTED.REF: fixed counter 2 and event 0x3C umask 0x01.
+    pub fn unhalted_reference_cycles(&self, ring:SimRing, n:u64) {
+        self.count_fixed(2, ring, n);
+        self.count_event(0x3C, 0x01, ring, n);
+    }
+
+    ///Report that the PEBS buffer reached its interrupt threshold (IA32_PERF_GLOBAL_STATUS bit 62).
+    /// Records are not simulated; the test writes them to the buffer and moves the DS index itself.
+    pub fn pebs_threshold_reached(&self) {
+        let mut state = self.state.borrow_mut();
+        self.overflow(&mut state, 62, true);
+    }
+
+    fn advance(value:u64, n:u64, width:u8) -> (u64, bool) {
+        let mask = Self::width_mask(width);
+        let sum = (value as u128) + (n as u128);
+        (sum as u64 & mask, sum > mask as u128)
+    }
+
+    fn width_mask(width:u8) -> u64 {
+        if width >= 64 { !0 } else { (1u64 << width) - 1 }
+    }
+
+    fn overflow(&self, state:&mut SimState, status_bit:u32, pmi:bool) {
+        state.global_status |= 1 << status_bit;
+        if pmi && state.lvt_perfmon & LVT_MASKED == 0 {
+            state.pmi_count += 1;
+            //The LVT entry is masked on delivery until software clears it again.
+            state.lvt_perfmon |= LVT_MASKED;
+        }
+    }
+
+    fn leaf_of(state:&SimState, leaf:u32, subleaf:u32) -> CpuIdResult {
+        for i in 0..state.cpuid_len {
+            if state.cpuid[i].0 == leaf && state.cpuid[i].1 == subleaf {
+                return state.cpuid[i].2;
+            }
+        }
+        CpuIdResult{eax:0, ebx:0, ecx:0, edx:0}
+    }
+
+    fn leaf_0a_of(&self, state:&SimState) -> CpuIdResult {
+        Self::leaf_of(state, 0x0A, 0)
+    }
+
+    fn version_of(&self, state:&SimState) -> u8 {
+        self.leaf_0a_of(state).eax as u8
+    }
+
+    fn general_count_of(&self, state:&SimState) -> usize {
+        ((self.leaf_0a_of(state).eax >> 8) as u8 as usize).min(MAX_GENERAL_PMC)
+    }
+
+    fn general_width_of(&self, state:&SimState) -> u8 {
+        (self.leaf_0a_of(state).eax >> 16) as u8
+    }
+
+    fn fixed_count_of(&self, state:&SimState) -> usize {
+        ((self.leaf_0a_of(state).edx & 31) as usize).min(MAX_FIXED_PMC)
+    }
+
+    fn fixed_width_of(&self, state:&SimState) -> u8 {
+        ((self.leaf_0a_of(state).edx >> 5) & 0xFF) as u8
+    }
+
+    //Architectural PMU MSRs the configured CPUID.0AH and IA32_PERF_CAPABILITIES do not provide.
+    //Callers match the implemented ones first.
+    fn is_unimplemented(msr:u32, general:u32, fixed:u32) -> bool {
+        match msr {
+            m if m >= IA32_A_PMC0 && m < IA32_A_PMC0 + general => true,
+            m if m >= IA32_FIXED_CTR0 && m < IA32_FIXED_CTR0 + fixed => true,
+            IA32_FIXED_CTR_CTRL | IA32_PERF_GLOBAL_CTRL | IA32_PERF_GLOBAL_STAUS | IA32_PERF_GLOBAL_OVF_CTRL => true,
+            _ => false,
+        }
+    }
+
+    fn fault(state:&mut SimState) -> u64 {
+        state.gp_faults += 1;
+        0
+    }
+}
+
+impl PmuBackend for SimulatedPmu {
+    fn rdmsr(&self, msr:u32) -> u64 {
+        let mut state = self.state.borrow_mut();
+        let general = self.general_count_of(&state) as u32;
+        let fixed = self.fixed_count_of(&state) as u32;
+        let version = self.version_of(&state);
+        match msr {
+            m if m >= IA32_PERFEVTSEL0 && m < IA32_PERFEVTSEL0 + general => state.perfevtsel[(m - IA32_PERFEVTSEL0) as usize],
+            m if m >= IA32_PMC0 && m < IA32_PMC0 + general => state.pmc[(m - IA32_PMC0) as usize],
+            m if m >= IA32_A_PMC0 && m < IA32_A_PMC0 + general && state.perf_capabilities & (1<<13) != 0 => state.pmc[(m - IA32_A_PMC0) as usize],
+            m if m >= IA32_FIXED_CTR0 && m < IA32_FIXED_CTR0 + fixed && version >= 2 => state.fixed_ctr[(m - IA32_FIXED_CTR0) as usize],
+            IA32_FIXED_CTR_CTRL if version >= 2 => state.fixed_ctr_ctrl,
+            IA32_PERF_GLOBAL_CTRL if version >= 2 => state.global_ctrl,
+            IA32_PERF_GLOBAL_STAUS if version >= 2 => state.global_status,
+            IA32_PERF_GLOBAL_OVF_CTRL if version >= 2 => state.global_ovf_ctrl,
+            IA32_PERF_CAPABILITIES if Self::leaf_of(&state, 0x01, 0).ecx & (1<<15) != 0 => state.perf_capabilities,
+            m if Self::is_unimplemented(m, general, fixed) => Self::fault(&mut state),
+            m => {
+                for i in 0..state.extra_msr_len {
+                    if state.extra_msr[i].0 == m {
+                        return state.extra_msr[i].1;
+                    }
+                }
+                0
+            }
+        }
+    }
+
+    fn wrmsr(&self, msr:u32, value:u64) {
+        let mut state = self.state.borrow_mut();
+        if state.msr_writes_len < MAX_MSR_WRITE_LOG {
+            let len = state.msr_writes_len;
+            state.msr_writes[len] = (msr, value);
+            state.msr_writes_len += 1;
+        }
+        let general = self.general_count_of(&state) as u32;
+        let fixed = self.fixed_count_of(&state) as u32;
+        let version = self.version_of(&state);
+        match msr {
+            m if m >= IA32_PERFEVTSEL0 && m < IA32_PERFEVTSEL0 + general => {
+                if value & EVTSEL_RESERVED != 0 {
+                    Self::fault(&mut state);
+                    return;
+                }
+                let i = (m - IA32_PERFEVTSEL0) as usize;
+                state.perfevtsel[i] = value;
+                state.last_condition[i] = false;
+            },
+            m if m >= IA32_PMC0 && m < IA32_PMC0 + general => {
+                //Legacy counter writes take bits 31:0 and sign extend them to the counter width.
+                let width = self.general_width_of(&state);
+                state.pmc[(m - IA32_PMC0) as usize] = (value as u32 as i32 as i64 as u64) & Self::width_mask(width);
+            },
+            m if m >= IA32_A_PMC0 && m < IA32_A_PMC0 + general && state.perf_capabilities & (1<<13) != 0 => {
+                let width = self.general_width_of(&state);
+                state.pmc[(m - IA32_A_PMC0) as usize] = value & Self::width_mask(width);
+            },
+            m if m >= IA32_FIXED_CTR0 && m < IA32_FIXED_CTR0 + fixed && version >= 2 => {
+                let width = self.fixed_width_of(&state);
+                state.fixed_ctr[(m - IA32_FIXED_CTR0) as usize] = value & Self::width_mask(width);
+            },
+            IA32_FIXED_CTR_CTRL if version >= 2 => state.fixed_ctr_ctrl = value,
+            IA32_PERF_GLOBAL_CTRL if version >= 2 => state.global_ctrl = value,
+            IA32_PERF_GLOBAL_OVF_CTRL if version >= 2 => {
+                state.global_ovf_ctrl = value;
+                state.global_status &= !value;
+            },
+            IA32_PERF_GLOBAL_STAUS | IA32_PERF_CAPABILITIES => {
+                Self::fault(&mut state);
+            },
+            m if Self::is_unimplemented(m, general, fixed) => {
+                Self::fault(&mut state);
+            },
+            m => {
+                for i in 0..state.extra_msr_len {
+                    if state.extra_msr[i].0 == m {
+                        state.extra_msr[i].1 = value;
+                        return;
+                    }
+                }
+                if state.extra_msr_len < MAX_EXTRA_MSR {
+                    let len = state.extra_msr_len;
+                    state.extra_msr[len] = (m, value);
+                    state.extra_msr_len += 1;
+                } else {
+                    Self::fault(&mut state);
+                }
+            }
+        }
+    }
+
+    fn cpuid(&self, leaf:u32, subleaf:u32) -> CpuIdResult {
+        Self::leaf_of(&self.state.borrow(), leaf, subleaf)
+    }
+
+    fn rdpmc(&self, counter:u32) -> u64 {
+        let hook = self.state.borrow_mut().before_rdpmc.take();
+        if let Some(f) = hook {
+            f(self);
+        }
+        let mut state = self.state.borrow_mut();
+        let index = (counter & 0x3FFF_FFFF) as usize;
+        if counter & (1<<30) != 0 {
+            if self.version_of(&state) < 2 || index >= self.fixed_count_of(&state) {
+                return Self::fault(&mut state);
+            }
+            state.fixed_ctr[index]
+        } else {
+            if index >= self.general_count_of(&state) {
+                return Self::fault(&mut state);
+            }
+            state.pmc[index]
+        }
+    }
+
+    fn rdtsc(&self) -> u64 {
+        self.state.borrow().tsc
+    }
+
+    fn read_lvt_perfmon(&self) -> u32 {
+        self.state.borrow().lvt_perfmon
+    }
+
+    fn write_lvt_perfmon(&self, value:u32) {
+        self.state.borrow_mut().lvt_perfmon = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86::perfcnt::intel::Counter;
+    use crate::AbstractPerfCounter;
+    use crate::x86_intel::{PerfCounter, globle_ctrl::PerfCounterControler};
+
+    const INST_RETIRED: (u8, u8) = (0xC0, 0x00);
+
+    fn evtsel(event:(u8, u8), extra:u64) -> u64 {
+        event.0 as u64 | (event.1 as u64) << 8 | EVTSEL_EN | extra
+    }
+
+    #[test]
+    fn counts_only_in_enabled_rings() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.wrmsr(IA32_PERFEVTSEL0, evtsel(INST_RETIRED, EVTSEL_USR));
+        pmu.wrmsr(IA32_PERFEVTSEL0 + 1, evtsel(INST_RETIRED, EVTSEL_OS));
+        pmu.count_event(0xC0, 0x00, SimRing::User, 5);
+        pmu.count_event(0xC0, 0x00, SimRing::Kernel, 3);
+        assert_eq!(pmu.rdpmc(0), 5);
+        assert_eq!(pmu.rdpmc(1), 3);
+    }
+
+    #[test]
+    fn enable_bits_gate_counting() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.wrmsr(IA32_PERFEVTSEL0, evtsel(INST_RETIRED, EVTSEL_USR | EVTSEL_OS) & !EVTSEL_EN);
+        pmu.wrmsr(IA32_PERFEVTSEL0 + 1, evtsel(INST_RETIRED, EVTSEL_USR | EVTSEL_OS));
+        pmu.wrmsr(IA32_PERF_GLOBAL_CTRL, 1);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 7);
+        assert_eq!(pmu.rdpmc(0), 0, "EN clear in IA32_PERFEVTSEL0");
+        assert_eq!(pmu.rdpmc(1), 0, "PMC1 disabled in IA32_PERF_GLOBAL_CTRL");
+        pmu.wrmsr(IA32_PERF_GLOBAL_CTRL, 2);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 7);
+        assert_eq!(pmu.rdpmc(1), 7);
+    }
+
+    #[test]
+    fn other_events_are_not_counted() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.wrmsr(IA32_PERFEVTSEL0, evtsel(INST_RETIRED, EVTSEL_USR));
+        pmu.unhalted_core_cycles(SimRing::User, 100);
+        assert_eq!(pmu.rdpmc(0), 0);
+        pmu.retire_instructions(SimRing::User, 2);
+        assert_eq!(pmu.rdpmc(0), 2);
+    }
+
+    #[test]
+    fn cmask_and_edge() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.wrmsr(IA32_PERFEVTSEL0, evtsel(INST_RETIRED, EVTSEL_USR | 2 << 24));
+        pmu.wrmsr(IA32_PERFEVTSEL0 + 1, evtsel(INST_RETIRED, EVTSEL_USR | EVTSEL_EDGE | 2 << 24));
+        for n in [1, 2, 3, 0, 4] {
+            pmu.count_event(0xC0, 0x00, SimRing::User, n);
+        }
+        //Cycles with at least 2 events, and how often that condition started.
+        assert_eq!(pmu.rdpmc(0), 3);
+        assert_eq!(pmu.rdpmc(1), 2);
+    }
+
+    #[test]
+    fn overflow_sets_global_status_and_raises_one_pmi() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.write_lvt_perfmon(0xF0);
+        pmu.wrmsr(IA32_PERFEVTSEL0, evtsel(INST_RETIRED, EVTSEL_USR | EVTSEL_INT));
+        pmu.wrmsr(IA32_A_PMC0, (1u64 << 48) - 2);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 1);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 0);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 3);
+        assert_eq!(pmu.rdpmc(0), 2);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1);
+        assert_eq!(pmu.pmi_count(), 1);
+        assert_ne!(pmu.read_lvt_perfmon() & LVT_MASKED, 0, "LVT masked on delivery");
+        pmu.wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 0);
+    }
+
+    #[test]
+    fn fixed_counter_overflow_uses_bit_32_and_up() {
+        let pmu = SimulatedPmu::default_v4();
+        pmu.write_lvt_perfmon(0xF0);
+        pmu.wrmsr(IA32_FIXED_CTR_CTRL, 0xA << 4);
+        pmu.wrmsr(IA32_PERF_GLOBAL_CTRL, 1 << 33);
+        pmu.wrmsr(IA32_FIXED_CTR0 + 1, (1u64 << 48) - 1);
+        pmu.unhalted_core_cycles(SimRing::User, 1);
+        assert_eq!(pmu.rdpmc(1 | 1 << 30), 0);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1 << 33);
+        assert_eq!(pmu.pmi_count(), 1);
+    }
+
+    #[test]
+    fn fixed_and_programmable_widths_differ() {
+        //40-bit general and 48-bit fixed counters, no full-width writes.
+        let pmu = SimulatedPmu::new(3 | 2 << 8 | 40 << 16 | 7 << 24, 0, 0, 3 | 48 << 5);
+        pmu.wrmsr(IA32_FIXED_CTR0, !0);
+        assert_eq!(pmu.fixed_counter(0), (1u64 << 48) - 1);
+        //Legacy writes sign extend bit 31 to the counter width.
+        pmu.wrmsr(IA32_PMC0, 0x8000_0000);
+        assert_eq!(pmu.general_counter(0), 0xFF_8000_0000);
+        pmu.wrmsr(IA32_PMC0, 0x7FFF_FFFF);
+        assert_eq!(pmu.general_counter(0), 0x7FFF_FFFF);
+        //IA32_A_PMCx is only there with full-width writes.
+        pmu.wrmsr(IA32_A_PMC0, 5);
+        assert_eq!(pmu.general_counter(0), 0x7FFF_FFFF);
+        assert_eq!(pmu.gp_faults(), 1);
+    }
+
+    #[test]
+    fn fixed_counters_need_version_2() {
+        let pmu = SimulatedPmu::new(1 | 2 << 8 | 40 << 16, 0, 0, 3 | 48 << 5);
+        pmu.count_fixed(0, SimRing::User, 1);
+        assert_eq!(pmu.rdpmc(1 << 30), 0);
+        assert_eq!(pmu.gp_faults(), 1);
+    }
+
+    #[test]
+    fn cpuid_table_full_is_an_error() {
+        let pmu = SimulatedPmu::default_v4();
+        for leaf in 0..(MAX_CPUID_LEAF as u32 - 2) {
+            assert!(pmu.set_cpuid(0x100 + leaf, 0, CpuIdResult{eax:leaf, ebx:0, ecx:0, edx:0}).is_ok());
+        }
+        assert!(matches!(pmu.set_cpuid(0x200, 0, CpuIdResult{eax:0, ebx:0, ecx:0, edx:0}), Err(ErrorMsg::BufferTooSmall)));
+        //Existing leaves can still be overridden.
+        assert!(pmu.set_cpuid(0x100, 0, CpuIdResult{eax:9, ebx:0, ecx:0, edx:0}).is_ok());
+        assert_eq!(pmu.cpuid(0x100, 0).eax, 9);
+    }
+
+    #[test]
+    fn global_msrs_need_version_2() {
+        let pmu = SimulatedPmu::new(1 | 2 << 8 | 40 << 16, 0, 0, 0);
+        pmu.wrmsr(IA32_PERF_GLOBAL_CTRL, 3);
+        pmu.wrmsr(IA32_PERF_GLOBAL_OVF_CTRL, 1);
+        pmu.wrmsr(IA32_FIXED_CTR_CTRL, 0xB);
+        assert_eq!(pmu.gp_faults(), 3);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL), 0);
+        assert_eq!(pmu.gp_faults(), 4);
+    }
+
+    #[test]
+    fn perfevtsel_keeps_in_tx_and_rejects_reserved_bits() {
+        let pmu = SimulatedPmu::default_v4();
+        let sel = evtsel(INST_RETIRED, EVTSEL_USR) | 1 << 32 | 1 << 33;
+        pmu.wrmsr(IA32_PERFEVTSEL0, sel);
+        assert_eq!(pmu.rdmsr(IA32_PERFEVTSEL0), sel);
+        pmu.wrmsr(IA32_PERFEVTSEL0, evtsel(INST_RETIRED, EVTSEL_OS) | 1 << 34);
+        assert_eq!(pmu.gp_faults(), 1);
+        assert_eq!(pmu.rdmsr(IA32_PERFEVTSEL0), sel, "a faulting write leaves the register alone");
+    }
+
+    fn controler(pmu:SimulatedPmu) -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        ctrler
+    }
+
+    #[test]
+    fn perf_counter_programs_and_reads_a_general_counter() {
+        let ctrler = controler(SimulatedPmu::default_v4());
+        let pmu = ctrler.backend();
+        let mut counter = PerfCounter::new(&ctrler);
+        counter.build_general_from_raw(0xC0, 0x00, true, false, 0, false, 1).unwrap();
+        counter.reset().unwrap();
+        counter.start().unwrap();
+        assert_eq!(pmu.rdmsr(IA32_PERFEVTSEL0 + 1), 0xC0 | EVTSEL_USR | EVTSEL_INT | EVTSEL_EN);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL), 0xF);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 5);
+        pmu.count_event(0xC0, 0x00, SimRing::Kernel, 3);
+        assert_eq!(counter.read().unwrap(), 5);
+        counter.stop().unwrap();
+        assert_eq!(pmu.rdmsr(IA32_PERFEVTSEL0 + 1), 0);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL), 0xD);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 5);
+        assert_eq!(counter.read().unwrap(), 5);
+        assert_eq!(pmu.gp_faults(), 0);
+    }
+
+    #[test]
+    fn perf_counter_programs_a_fixed_counter() {
+        let ctrler = controler(SimulatedPmu::default_v4());
+        let pmu = ctrler.backend();
+        let mut counter = PerfCounter::new(&ctrler);
+        counter.build_fixed(1, false).unwrap();
+        counter.exnclude_os();
+        counter.start().unwrap();
+        //USR and PMI in the field of fixed counter 1.
+        assert_eq!(pmu.rdmsr(IA32_FIXED_CTR_CTRL), 0xA << 4);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL), 0xF | 1 << 33);
+        pmu.unhalted_core_cycles(SimRing::User, 40);
+        pmu.unhalted_core_cycles(SimRing::Kernel, 2);
+        assert_eq!(counter.read().unwrap(), 40);
+        counter.stop().unwrap();
+        assert_eq!(pmu.rdmsr(IA32_FIXED_CTR_CTRL), 0);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_CTRL), 0xF);
+    }
+
+    #[test]
+    fn overflow_after_and_get_overflow_counter() {
+        let ctrler = controler(SimulatedPmu::default_v4());
+        let pmu = ctrler.backend();
+        ctrler.register_overflow_interrput(0xF0);
+        let mut general = PerfCounter::new(&ctrler);
+        general.build_general_from_raw(0xC0, 0x00, true, true, 0, false, 2).unwrap();
+        general.overflow_after(9);
+        assert_eq!(pmu.general_counter(2), (1u64 << 48) - 10);
+        let mut fixed = PerfCounter::new(&ctrler);
+        fixed.build_fixed(0, false).unwrap();
+        fixed.overflow_after(0);
+        assert_eq!(pmu.fixed_counter(0), (1u64 << 48) - 1);
+        general.start().unwrap();
+        fixed.start().unwrap();
+        assert_eq!(ctrler.get_overflow_counter(), None);
+        pmu.count_event(0xC0, 0x00, SimRing::User, 9);
+        assert!(!general.check_overflow());
+        pmu.retire_instructions(SimRing::User, 1);
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1 << 2 | 1 << 32);
+        assert!(general.check_overflow() && fixed.check_overflow());
+        assert_eq!(pmu.pmi_count(), 1);
+        assert_eq!(ctrler.get_overflow_counter(), Some(Counter::Programmable(2)));
+        ctrler.clear_overflow_bit(Counter::Programmable(2));
+        assert_eq!(pmu.rdmsr(IA32_PERF_GLOBAL_STAUS), 1 << 32);
+        assert_eq!(ctrler.get_overflow_counter(), Some(Counter::Fixed(0)));
+        ctrler.clear_overflow_bit(Counter::Fixed(0));
+        assert_eq!(ctrler.get_overflow_counter(), None);
+        assert_eq!(pmu.gp_faults(), 0);
+    }
+}
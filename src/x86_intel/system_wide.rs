@@ -0,0 +1,381 @@
+//! System-wide counting: the same events on every logical CPU, with per-CPU and summed results.
+//!
+//! The PMU registers of a CPU can only be accessed from that CPU, so every operation is sent to the
+//! CPUs through a CpuRunner provided by the kernel (an IPI, a per-CPU work queue, ...).
+//! configure() schedules and builds the events on every CPU's controler as a PerfCounterGroup,
+//! start() and stop() enable and disable the groups one CPU after the other, and read() collects them.
+//!
+//! Counters are released on their own CPU when the session is dropped.
+//!
+
+use x86::perfcnt::intel::{EventDescription, Counter};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, globle_ctrl::PerfCounterControler, group::{PerfCounterGroup, MAX_GROUP_MEMBERS}, PerfCounter};
+use super::allocator;
+
+///Hook running code on a given logical CPU.
+pub trait CpuRunner<'c, B: PmuBackend> {
+    ///Number of logical CPUs, numbered from 0.
+    fn cpu_count(&self) -> usize;
+
+    ///Run `f` on logical CPU `cpu` with the controler of that CPU, and wait until it returned.
+    ///
+    /// With NativePmu the controler is usually globle_ctrl::this_cpu_controler(), obtained on the target CPU.
+    fn run_on(&self, cpu:usize, f:&mut dyn FnMut(&'c PerfCounterControler<B>)) -> Result<(), ErrorMsg>;
+}
+
+///Why a CPU could not be configured.
+#[derive(Debug)]
+pub struct CpuError {
+    pub cpu: usize,
+    ///Position of the event that failed, if the failure is specific to one event.
+    pub index: Option<usize>,
+    pub reason: ErrorMsg,
+}
+
+///Counts of one read(), per CPU and summed over all CPUs.
+///
+/// Large with many CPUS (8 bytes per event and CPU), so callers keep one and let read() refill it.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemWideCounts<const CPUS: usize> {
+    pub per_cpu: [[u64; MAX_GROUP_MEMBERS]; CPUS],
+    pub total: [u64; MAX_GROUP_MEMBERS],
+    pub cpus: usize,
+    pub events: usize,
+}
+
+impl<const CPUS: usize> SystemWideCounts<CPUS> {
+    pub const fn new() -> SystemWideCounts<CPUS> {
+        SystemWideCounts{per_cpu: [[0; MAX_GROUP_MEMBERS]; CPUS], total: [0; MAX_GROUP_MEMBERS], cpus: 0, events: 0}
+    }
+
+    ///Count of event `index` on `cpu`.
+    pub fn cpu(&self, cpu:usize, index:usize) -> Option<u64> {
+        if cpu >= self.cpus || index >= self.events {
+            return None;
+        }
+        Some(self.per_cpu[cpu][index])
+    }
+
+    ///Count of event `index` summed over all CPUs.
+    pub fn total(&self, index:usize) -> Option<u64> {
+        if index >= self.events {
+            return None;
+        }
+        Some(self.total[index])
+    }
+}
+
+impl<const CPUS: usize> Default for SystemWideCounts<CPUS> {
+    fn default() -> SystemWideCounts<CPUS> {
+        SystemWideCounts::new()
+    }
+}
+
+///Identical counters on up to CPUS logical CPUs.
+pub struct SystemWideSession<'c, B: PmuBackend, R: CpuRunner<'c, B>, const CPUS: usize> {
+    runner: R,
+    cpus: usize,
+    events: usize,
+    groups: [Option<PerfCounterGroup<'c, B>>; CPUS],
+    running: bool,
+}
+
+impl<'c, B: PmuBackend, R: CpuRunner<'c, B>, const CPUS: usize> SystemWideSession<'c, B, R, CPUS> {
+    ///Session on the CPUs reported by `runner`, which must not be more than CPUS.
+    pub fn new(runner:R) -> Result<SystemWideSession<'c, B, R, CPUS>, ErrorMsg> {
+        let cpus = runner.cpu_count();
+        if cpus > CPUS {
+            return Err(ErrorMsg::CpuOutOfRange);
+        }
+        Ok(SystemWideSession{
+            runner: runner,
+            cpus: cpus,
+            events: 0,
+            groups: core::array::from_fn(|_| None),
+            running: false,
+        })
+    }
+
+    pub fn cpu_count(&self) -> usize {
+        self.cpus
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    ///Schedule and build `events` on every CPU, replacing the previous configuration.
+    ///
+    /// Each CPU gets its own assignment, so a counter already taken on one CPU does not prevent
+    /// the event from running elsewhere. If any CPU fails, no CPU stays configured.
+    pub fn configure(&mut self, events:&[&EventDescription]) -> Result<(), CpuError> {
+        if self.running {
+            return Err(CpuError{cpu:0, index:None, reason:ErrorMsg::CounterInUse});
+        }
+        if events.len() > MAX_GROUP_MEMBERS {
+            return Err(CpuError{cpu:0, index:None, reason:ErrorMsg::CounterOutOfRange});
+        }
+        self.release_all();
+        for cpu in 0..self.cpus {
+            let slot = &mut self.groups[cpu];
+            let mut failure = None;
+            let ran = self.runner.run_on(cpu, &mut |ctrler| {
+                match build_group(ctrler, events) {
+                    Ok(group) => *slot = Some(group),
+                    Err((index, reason)) => failure = Some((index, reason)),
+                }
+            });
+            if let Err(reason) = ran {
+                failure = Some((None, reason));
+            }
+            if let Some((index, reason)) = failure {
+                self.release_all();
+                return Err(CpuError{cpu:cpu, index:index, reason:reason});
+            }
+        }
+        self.events = events.len();
+        Ok(())
+    }
+
+    ///Reset and start the counters on every CPU.
+    ///
+    /// If a CPU fails, the CPUs started before it are stopped again.
+    pub fn start(&mut self) -> Result<(), ErrorMsg> {
+        if self.running {
+            return Ok(());
+        }
+        for cpu in 0..self.cpus {
+            let started = self.run_on_group(cpu, |group| {
+                group.reset()?;
+                group.start()
+            });
+            if let Err(e) = started {
+                for started_cpu in 0..cpu {
+                    let _ = self.run_on_group(started_cpu, |group| group.stop());
+                }
+                return Err(e);
+            }
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    ///Stop the counters on every CPU. They keep their counts until the next start().
+    ///
+    /// A CPU that fails does not prevent the others from being stopped. The session is not
+    /// running afterwards either way, and the first error is returned.
+    pub fn stop(&mut self) -> Result<(), ErrorMsg> {
+        if !self.running {
+            return Ok(());
+        }
+        let stopped = self.for_each_group(|group| group.stop());
+        self.running = false;
+        stopped
+    }
+
+    ///Read the counters of every CPU into `counts` and sum them.
+    pub fn read(&mut self, counts:&mut SystemWideCounts<CPUS>) -> Result<(), ErrorMsg> {
+        counts.cpus = self.cpus;
+        counts.events = self.events;
+        counts.total = [0; MAX_GROUP_MEMBERS];
+        for cpu in 0..self.cpus {
+            let values = &mut counts.per_cpu[cpu];
+            self.run_on_group(cpu, |group| group.read(values))?;
+        }
+        for values in counts.per_cpu[..self.cpus].iter() {
+            for (total, value) in counts.total.iter_mut().zip(values.iter()) {
+                *total = total.wrapping_add(*value);
+            }
+        }
+        Ok(())
+    }
+
+    ///Run `f` with the group of every CPU, even after one failed, and return the first error.
+    fn for_each_group(&mut self, mut f:impl FnMut(&mut PerfCounterGroup<'c, B>) -> Result<(), ErrorMsg>) -> Result<(), ErrorMsg> {
+        let mut result = Ok(());
+        for cpu in 0..self.cpus {
+            let ran = self.run_on_group(cpu, &mut f);
+            if result.is_ok() {
+                result = ran;
+            }
+        }
+        result
+    }
+
+    ///Run `f` with the group of `cpu` on that CPU.
+    fn run_on_group(&mut self, cpu:usize, mut f:impl FnMut(&mut PerfCounterGroup<'c, B>) -> Result<(), ErrorMsg>) -> Result<(), ErrorMsg> {
+        let group = match self.groups[cpu].as_mut() {
+            Some(group) => group,
+            None => return Err(ErrorMsg::UnsupportedEvent),
+        };
+        let mut result = Ok(());
+        self.runner.run_on(cpu, &mut |_| result = f(group))?;
+        result
+    }
+
+    ///Drop the groups of all CPUs on their own CPU, which stops and releases their counters.
+    ///
+    /// A group whose CPU cannot be reached only gives its reservations back, without touching MSRs.
+    fn release_all(&mut self) {
+        for cpu in 0..self.cpus {
+            if self.groups[cpu].is_none() {
+                continue;
+            }
+            let slot = &mut self.groups[cpu];
+            let _ = self.runner.run_on(cpu, &mut |_| { slot.take(); });
+            //Dropping it here would touch the MSRs of the wrong CPU.
+            if let Some(group) = slot.take() {
+                group.abandon();
+            }
+        }
+        self.events = 0;
+        self.running = false;
+    }
+}
+
+impl<'c, B: PmuBackend, R: CpuRunner<'c, B>, const CPUS: usize> Drop for SystemWideSession<'c, B, R, CPUS> {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+///Schedule `events` on `ctrler` and build them as one group.
+fn build_group<'c, B: PmuBackend>(ctrler:&'c PerfCounterControler<B>, events:&[&EventDescription]) -> Result<PerfCounterGroup<'c, B>, (Option<usize>, ErrorMsg)> {
+    let mut assignment = [Counter::Programmable(0); MAX_GROUP_MEMBERS];
+    if let Err(e) = allocator::schedule(ctrler, events, &mut assignment) {
+        return Err((Some(e.index), e.reason));
+    }
+    let mut group = PerfCounterGroup::new(ctrler);
+    for (i, event) in events.iter().enumerate() {
+        let mut counter = PerfCounter::new(ctrler);
+        allocator::build_assigned(&mut counter, event, assignment[i]).map_err(|e| (Some(i), e))?;
+        group.add(counter).map_err(|e| (Some(i), e))?;
+    }
+    Ok(group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use x86::msr::IA32_PERF_GLOBAL_CTRL;
+    use crate::x86_intel::lookup::{CpuModel, EventTable};
+    use crate::x86_intel::capabilities::CpuSignature;
+    use crate::x86_intel::simulator::{SimulatedPmu, SimRing};
+
+    ///CPUs simulated by one controler each. `unreachable` makes run_on fail for one CPU.
+    struct FakeCpus<'c> {
+        ctrlers: &'c [PerfCounterControler<SimulatedPmu>],
+        unreachable: Cell<Option<usize>>,
+    }
+
+    impl<'c> CpuRunner<'c, SimulatedPmu> for FakeCpus<'c> {
+        fn cpu_count(&self) -> usize {
+            self.ctrlers.len()
+        }
+
+        fn run_on(&self, cpu:usize, f:&mut dyn FnMut(&'c PerfCounterControler<SimulatedPmu>)) -> Result<(), ErrorMsg> {
+            if self.unreachable.get() == Some(cpu) {
+                return Err(ErrorMsg::ControlerNotReady);
+            }
+            f(&self.ctrlers[cpu]);
+            Ok(())
+        }
+    }
+
+    fn controlers() -> [PerfCounterControler<SimulatedPmu>; 3] {
+        core::array::from_fn(|_| {
+            let mut ctrler = PerfCounterControler::with_backend(SimulatedPmu::default_v4());
+            ctrler.init();
+            ctrler
+        })
+    }
+
+    fn session(ctrlers:&[PerfCounterControler<SimulatedPmu>]) -> SystemWideSession<'_, SimulatedPmu, FakeCpus<'_>, 4> {
+        let table = EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap();
+        let branches = table.lookup("BR_INST_RETIRED.ALL_BRANCHES").unwrap();
+        let instructions = table.lookup("INST_RETIRED.ANY").unwrap();
+        let mut session = SystemWideSession::new(FakeCpus{ctrlers: ctrlers, unreachable: Cell::new(None)}).unwrap();
+        session.configure(&[branches, instructions]).unwrap();
+        session
+    }
+
+    fn count(ctrlers:&[PerfCounterControler<SimulatedPmu>], scale:u64) {
+        for (cpu, ctrler) in ctrlers.iter().enumerate() {
+            ctrler.backend().count_event(0xC4, 0, SimRing::User, scale * (cpu as u64 + 1));
+            ctrler.backend().retire_instructions(SimRing::User, 100 * scale);
+        }
+    }
+
+    fn instructions_enabled(ctrler:&PerfCounterControler<SimulatedPmu>) -> bool {
+        ctrler.backend().rdmsr(IA32_PERF_GLOBAL_CTRL) & 1 << 32 != 0
+    }
+
+    #[test]
+    fn counts_on_every_cpu() {
+        let ctrlers = controlers();
+        let mut session = session(&ctrlers);
+        assert!(SystemWideSession::<_, _, 2>::new(FakeCpus{ctrlers: &ctrlers, unreachable: Cell::new(None)}).is_err());
+        session.start().unwrap();
+        assert!(session.is_running());
+        count(&ctrlers, 1);
+        session.stop().unwrap();
+        count(&ctrlers, 1000);
+        let mut counts = SystemWideCounts::<4>::new();
+        session.read(&mut counts).unwrap();
+        assert_eq!((counts.cpus, counts.events), (3, 2));
+        assert_eq!(counts.cpu(1, 0), Some(2));
+        assert_eq!(counts.cpu(2, 1), Some(100));
+        assert_eq!(counts.cpu(3, 0), None);
+        assert_eq!(counts.total(0), Some(1 + 2 + 3));
+        assert_eq!(counts.total(1), Some(300));
+        assert_eq!(counts.total(2), None);
+    }
+
+    #[test]
+    fn a_failed_start_stops_the_started_cpus() {
+        let ctrlers = controlers();
+        let mut session = session(&ctrlers);
+        session.runner.unreachable.set(Some(2));
+        assert!(matches!(session.start(), Err(ErrorMsg::ControlerNotReady)));
+        assert!(!session.is_running());
+        assert!(ctrlers.iter().all(|ctrler| !instructions_enabled(ctrler)));
+        count(&ctrlers, 1);
+        session.runner.unreachable.set(None);
+        let mut counts = SystemWideCounts::<4>::new();
+        session.read(&mut counts).unwrap();
+        assert_eq!(counts.total(0), Some(0));
+    }
+
+    #[test]
+    fn a_failed_stop_still_stops_the_other_cpus() {
+        let ctrlers = controlers();
+        let mut session = session(&ctrlers);
+        session.start().unwrap();
+        session.runner.unreachable.set(Some(1));
+        assert!(matches!(session.stop(), Err(ErrorMsg::ControlerNotReady)));
+        assert!(!session.is_running());
+        assert!(!instructions_enabled(&ctrlers[0]));
+        assert!(instructions_enabled(&ctrlers[1]));
+        assert!(!instructions_enabled(&ctrlers[2]));
+        //Once the CPU is back, the session can be started and stopped again.
+        session.runner.unreachable.set(None);
+        session.start().unwrap();
+        session.stop().unwrap();
+        assert!(ctrlers.iter().all(|ctrler| !instructions_enabled(ctrler)));
+    }
+
+    #[test]
+    fn a_failed_configure_releases_every_cpu() {
+        let ctrlers = controlers();
+        let mut session = session(&ctrlers);
+        let table = EventTable::for_model(CpuModel{vendor: *b"GenuineIntel", signature: CpuSignature{family: 6, model: 0x5E, stepping: 3}}).unwrap();
+        session.runner.unreachable.set(Some(1));
+        let err = session.configure(&[table.lookup("INST_RETIRED.ANY").unwrap()]).unwrap_err();
+        assert_eq!((err.cpu, err.index), (1, None));
+        //CPU 1 could not be reached, so its previous group only gave its reservations back.
+        assert!(ctrlers.iter().all(|ctrler| ctrler.get_reserved_counters() == 0));
+        assert!(!session.is_running());
+    }
+}
@@ -0,0 +1,508 @@
+//! Top-down microarchitecture analysis (TMA), levels 1 and 2.
+//!
+//! Level 1 splits the issue slots of the measured window into Frontend Bound, Bad Speculation,
+//! Backend Bound and Retiring; level 2 splits each of them in two. Every node is reported as a
+//! number of slots, so the breakdown sums up exactly and no floating point is needed; see
+//! basis_points() for ratios.
+//!
+//! Two methods are used, depending on the processor:
+//! - Ice Lake and later with IA32_PERF_CAPABILITIES.PERF_METRICS: fixed counter 3 (TOPDOWN.SLOTS)
+//!   counts the slots and IA32_PERF_METRICS holds the share of every level 1 node, plus the level 2
+//!   nodes on Sapphire Rapids, Alder Lake and later. Level 2 is not available on Ice Lake itself.
+//! - Sandy Bridge to Skylake: slots are pipeline width * CPU_CLK_UNHALTED.THREAD and the nodes are
+//!   computed from programmable events. Level 2 needs 8 PMCs, i.e. Hyper-Threading disabled, and
+//!   does not split Retiring.
+//!
+//! All events count in every ring and per logical processor, so with SMT enabled the figures
+//! describe the thread and not the core.
+//!
+
+use x86::perfcnt::intel::{EventDescription, Counter};
+use crate::ErrorMsg;
+use super::{backend::PmuBackend, capabilities::CpuSignature, globle_ctrl::PerfCounterControler, group::PerfCounterGroup, PerfCounter};
+use super::allocator;
+use super::lookup::{CpuModel, EventTable};
+
+pub const IA32_PERF_METRICS: u32 = 0x329;
+///IA32_PERF_GLOBAL_CTRL: update IA32_PERF_METRICS together with fixed counter 3.
+pub const GLOBAL_CTRL_EN_PERF_METRICS: u64 = 1<<48;
+
+///Programmable events used for level 1 by the event based method.
+const LEVEL1_EVENTS: usize = 4;
+///Programmable events used for level 1 and level 2 by the event based method.
+const LEVEL2_EVENTS: usize = 8;
+
+struct TopdownModel {
+    models: &'static [u8],
+    ///Model whose event table describes the events, for the models the x86 crate has no table for.
+    table: u8,
+    ///Issue slots per cycle.
+    width: u8,
+    events: [&'static str; LEVEL2_EVENTS],
+}
+
+//The events common to every model, then the cycles stalled on outstanding loads.
+const fn topdown_events(memory_stalls:&'static str) -> [&'static str; LEVEL2_EVENTS] {
+    ["IDQ_UOPS_NOT_DELIVERED.CORE", "UOPS_ISSUED.ANY", "UOPS_RETIRED.RETIRE_SLOTS", "INT_MISC.RECOVERY_CYCLES",
+     "IDQ_UOPS_NOT_DELIVERED.CYCLES_0_UOPS_DELIV.CORE", "BR_MISP_RETIRED.ALL_BRANCHES", "MACHINE_CLEARS.COUNT", memory_stalls]
+}
+
+//Family 6 models for the event based method. The events are resolved in the event table of the
+//processor, which gives their encoding and the counters they may use.
+const TOPDOWN_MODELS: [TopdownModel; 3] = [
+    //Sandy Bridge
+    TopdownModel{models: &[0x2A, 0x2D], table: 0x2A, width: 4, events: topdown_events("CYCLE_ACTIVITY.STALLS_L1D_PENDING")},
+    //Ivy Bridge, Haswell and Broadwell
+    TopdownModel{models: &[0x3A, 0x3E, 0x3C, 0x3F, 0x45, 0x46, 0x3D, 0x47, 0x4F, 0x56], table: 0x3C, width: 4,
+        events: topdown_events("CYCLE_ACTIVITY.STALLS_LDM_PENDING")},
+    //Skylake, Kaby Lake, Coffee Lake and Comet Lake
+    TopdownModel{models: &[0x4E, 0x5E, 0x55, 0x8E, 0x9E, 0xA5, 0xA6], table: 0x5E, width: 4,
+        events: topdown_events("CYCLE_ACTIVITY.STALLS_MEM_ANY")},
+];
+
+//Family 6 models whose IA32_PERF_METRICS holds the level 2 nodes in bits 63:32:
+//Sapphire Rapids, Emerald Rapids, Granite Rapids, Alder Lake, Raptor Lake and Meteor Lake.
+const PERF_METRICS_LEVEL2_MODELS: [u8; 10] = [0x8F, 0xCF, 0xAD, 0xAE, 0x97, 0x9A, 0xB7, 0xBA, 0xBF, 0xAA];
+
+fn signature<B: PmuBackend>(ctrler:&PerfCounterControler<B>) -> CpuSignature {
+    CpuSignature::decode(ctrler.backend().cpuid(0x01, 0).eax)
+}
+
+fn event_model(signature:CpuSignature) -> Option<&'static TopdownModel> {
+    if signature.family != 6 {
+        return None;
+    }
+    TOPDOWN_MODELS.iter().find(|m| m.models.contains(&signature.model))
+}
+
+///Level 1 nodes, in slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TopdownLevel1 {
+    ///Issue slots of the window; the four nodes add up to it.
+    pub slots: u64,
+    pub frontend_bound: u64,
+    pub bad_speculation: u64,
+    pub backend_bound: u64,
+    pub retiring: u64,
+}
+
+///Level 2 nodes, in slots. Each pair adds up to its level 1 parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TopdownLevel2 {
+    pub fetch_latency: u64,
+    pub fetch_bandwidth: u64,
+    pub branch_mispredicts: u64,
+    pub machine_clears: u64,
+    pub memory_bound: u64,
+    pub core_bound: u64,
+    ///Only reported by IA32_PERF_METRICS.
+    pub light_operations: Option<u64>,
+    ///Only reported by IA32_PERF_METRICS.
+    pub heavy_operations: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TopdownBreakdown {
+    pub level1: TopdownLevel1,
+    pub level2: Option<TopdownLevel2>,
+}
+
+///`part` / `whole` in hundredths of a percent, rounded down. 0 if `whole` is 0.
+pub fn basis_points(part:u64, whole:u64) -> u32 {
+    if whole == 0 {
+        0
+    } else {
+        ((part.min(whole) as u128 * 10_000) / whole as u128) as u32
+    }
+}
+
+//`slots` * `part` / `whole` without overflow.
+fn share(slots:u64, part:u64, whole:u64) -> u64 {
+    if whole == 0 {
+        0
+    } else {
+        ((slots as u128 * part as u128) / whole as u128) as u64
+    }
+}
+
+///Breakdown from TOPDOWN.SLOTS and IA32_PERF_METRICS.
+///
+/// Bytes 0 to 3 of `metrics` are the Retiring, Bad Speculation, Frontend Bound and Backend Bound
+/// shares, bytes 4 to 7 the Heavy Operations, Branch Mispredicts, Fetch Latency and Memory Bound
+/// shares. Shares are scaled by the sum of the level 1 bytes, which the processor keeps close to 255.
+pub fn decode_perf_metrics(slots:u64, metrics:u64, level2:bool) -> TopdownBreakdown {
+    let byte = |i:u32| (metrics >> (8 * i)) & 0xFF;
+    let sum = byte(0) + byte(1) + byte(2) + byte(3);
+    let retiring = share(slots, byte(0), sum);
+    let bad_speculation = share(slots, byte(1), sum);
+    let frontend_bound = share(slots, byte(2), sum);
+    //The remainder of the rounding goes to Backend Bound.
+    let backend_bound = if sum == 0 { 0 } else { slots - retiring - bad_speculation - frontend_bound };
+    let level1 = TopdownLevel1{
+        slots: slots,
+        frontend_bound: frontend_bound,
+        bad_speculation: bad_speculation,
+        backend_bound: backend_bound,
+        retiring: retiring,
+    };
+    let level2 = if level2 {
+        let heavy_operations = share(slots, byte(4), sum).min(retiring);
+        let branch_mispredicts = share(slots, byte(5), sum).min(bad_speculation);
+        let fetch_latency = share(slots, byte(6), sum).min(frontend_bound);
+        let memory_bound = share(slots, byte(7), sum).min(backend_bound);
+        Some(TopdownLevel2{
+            fetch_latency: fetch_latency,
+            fetch_bandwidth: frontend_bound - fetch_latency,
+            branch_mispredicts: branch_mispredicts,
+            machine_clears: bad_speculation - branch_mispredicts,
+            memory_bound: memory_bound,
+            core_bound: backend_bound - memory_bound,
+            light_operations: Some(retiring - heavy_operations),
+            heavy_operations: Some(heavy_operations),
+        })
+    } else {
+        None
+    };
+    TopdownBreakdown{level1:level1, level2:level2}
+}
+
+///Counts read by the event based method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TopdownEventCounts {
+    ///CPU_CLK_UNHALTED.THREAD.
+    pub cycles: u64,
+    ///IDQ_UOPS_NOT_DELIVERED.CORE.
+    pub uops_not_delivered: u64,
+    ///UOPS_ISSUED.ANY.
+    pub uops_issued: u64,
+    ///UOPS_RETIRED.RETIRE_SLOTS.
+    pub retire_slots: u64,
+    ///INT_MISC.RECOVERY_CYCLES.
+    pub recovery_cycles: u64,
+    ///IDQ_UOPS_NOT_DELIVERED.CYCLES_0_UOPS_DELIV.CORE, for level 2.
+    pub cycles_0_uops_delivered: u64,
+    ///BR_MISP_RETIRED.ALL_BRANCHES, for level 2.
+    pub branch_misses: u64,
+    ///MACHINE_CLEARS.COUNT, for level 2.
+    pub machine_clears: u64,
+    ///Cycles stalled with a load outstanding (CYCLE_ACTIVITY.STALLS_MEM_ANY or its predecessor), for level 2.
+    pub memory_stall_cycles: u64,
+}
+
+///Breakdown from the events of the Sandy Bridge to Skylake method, `width` slots per cycle:
+/// - Frontend Bound = IDQ_UOPS_NOT_DELIVERED.CORE
+/// - Bad Speculation = UOPS_ISSUED.ANY - UOPS_RETIRED.RETIRE_SLOTS + width * INT_MISC.RECOVERY_CYCLES
+/// - Retiring = UOPS_RETIRED.RETIRE_SLOTS
+/// - Backend Bound = the remaining slots
+/// - Fetch Latency = width * IDQ_UOPS_NOT_DELIVERED.CYCLES_0_UOPS_DELIV.CORE
+/// - Branch Mispredicts = Bad Speculation * BR_MISP_RETIRED.ALL_BRANCHES / (BR_MISP_RETIRED.ALL_BRANCHES + MACHINE_CLEARS.COUNT)
+/// - Memory Bound = width * memory stall cycles, i.e. every slot of a cycle stalled on a load
+///
+/// Each node is capped at the slots its parent leaves, in the order above.
+pub fn decode_events(width:u8, counts:&TopdownEventCounts, level2:bool) -> TopdownBreakdown {
+    let width = width as u64;
+    let slots = counts.cycles.saturating_mul(width);
+    let frontend_bound = counts.uops_not_delivered.min(slots);
+    let bad_speculation = counts.uops_issued.saturating_sub(counts.retire_slots)
+        .saturating_add(counts.recovery_cycles.saturating_mul(width))
+        .min(slots - frontend_bound);
+    let retiring = counts.retire_slots.min(slots - frontend_bound - bad_speculation);
+    let backend_bound = slots - frontend_bound - bad_speculation - retiring;
+    let level1 = TopdownLevel1{
+        slots: slots,
+        frontend_bound: frontend_bound,
+        bad_speculation: bad_speculation,
+        backend_bound: backend_bound,
+        retiring: retiring,
+    };
+    let level2 = if level2 {
+        let fetch_latency = counts.cycles_0_uops_delivered.saturating_mul(width).min(frontend_bound);
+        let branch_mispredicts = share(bad_speculation, counts.branch_misses, counts.branch_misses.saturating_add(counts.machine_clears));
+        let memory_bound = counts.memory_stall_cycles.saturating_mul(width).min(backend_bound);
+        Some(TopdownLevel2{
+            fetch_latency: fetch_latency,
+            fetch_bandwidth: frontend_bound - fetch_latency,
+            branch_mispredicts: branch_mispredicts,
+            machine_clears: bad_speculation - branch_mispredicts,
+            memory_bound: memory_bound,
+            core_bound: backend_bound - memory_bound,
+            light_operations: None,
+            heavy_operations: None,
+        })
+    } else {
+        None
+    };
+    TopdownBreakdown{level1:level1, level2:level2}
+}
+
+///How the processor measures the top-down nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopdownMethod {
+    ///Fixed counter 3 and IA32_PERF_METRICS. `level2`: bits 63:32 hold the level 2 nodes.
+    PerfMetrics{level2:bool},
+    ///Programmable events, `width` slots per cycle.
+    Events{width:u8},
+}
+
+impl TopdownMethod {
+    ///Pick the method of the processor by IA32_PERF_CAPABILITIES, family and model.
+    pub fn detect<B: PmuBackend>(ctrler:&PerfCounterControler<B>) -> Result<TopdownMethod, ErrorMsg> {
+        let signature = signature(ctrler);
+        let caps = ctrler.get_capabilities();
+        if signature.family == 6 && caps.perf_capabilities.is_some_and(|p| p.perf_metrics) && caps.is_fixed_counter_supported(3) {
+            let level2 = PERF_METRICS_LEVEL2_MODELS.contains(&signature.model);
+            return Ok(TopdownMethod::PerfMetrics{level2:level2});
+        }
+        match event_model(signature) {
+            Some(model) => Ok(TopdownMethod::Events{width:model.width}),
+            None => Err(ErrorMsg::UnsupportedFeature),
+        }
+    }
+}
+
+///Top-down measurement on one controler's processor.
+///
+/// All counters are one PerfCounterGroup, so every node covers the same window.
+pub struct Topdown<'a, B: PmuBackend> {
+    ctrler: &'a PerfCounterControler<B>,
+    group: PerfCounterGroup<'a, B>,
+    method: TopdownMethod,
+    level2: bool,
+}
+
+impl<'a, B: PmuBackend> Topdown<'a, B> {
+    ///Reserve the counters for level 1, and for level 2 if `level2` is set.
+    ///
+    /// Fails with UnsupportedFeature if the processor cannot report the requested level, with
+    /// CounterOutOfRange if it has too few PMCs for the event based method, and like
+    /// allocator::schedule() if its events do not fit on the free PMCs.
+    pub fn new(ctrler:&'a PerfCounterControler<B>, level2:bool) -> Result<Topdown<'a, B>, ErrorMsg> {
+        let method = TopdownMethod::detect(ctrler)?;
+        let mut group = PerfCounterGroup::new(ctrler);
+        match method {
+            TopdownMethod::PerfMetrics{level2:has_level2} => {
+                if level2 && !has_level2 {
+                    return Err(ErrorMsg::UnsupportedFeature);
+                }
+                let mut slots = PerfCounter::new(ctrler);
+                slots.build_fixed(3, false)?;
+                slots.disable_interrupt();
+                group.add(slots)?;
+            },
+            TopdownMethod::Events{..} => {
+                let model = match event_model(signature(ctrler)) {
+                    Some(model) => model,
+                    None => return Err(ErrorMsg::UnsupportedFeature),
+                };
+                let len = if level2 { LEVEL2_EVENTS } else { LEVEL1_EVENTS };
+                if ctrler.get_number_msr() < len as u8 {
+                    return Err(ErrorMsg::CounterOutOfRange);
+                }
+                let cpu = CpuModel::detect(ctrler.backend());
+                let table = match EventTable::for_model(cpu) {
+                    Ok(table) => table,
+                    Err(_) => EventTable::for_model(CpuModel{signature: CpuSignature{model: model.table, ..cpu.signature}, ..cpu})?,
+                };
+                let mut events: [&EventDescription; LEVEL2_EVENTS] = [table.lookup(model.events[0])?; LEVEL2_EVENTS];
+                for (event, name) in events.iter_mut().zip(model.events.iter()).take(len).skip(1) {
+                    *event = table.lookup(name)?;
+                }
+                let mut cycles = PerfCounter::new(ctrler);
+                cycles.build_fixed(1, false)?;
+                cycles.disable_interrupt();
+                group.add(cycles)?;
+                //Some events only count on some PMCs, e.g. CYCLE_ACTIVITY.STALLS_L1D_PENDING on PMC2.
+                let mut assignment = [Counter::Programmable(0); LEVEL2_EVENTS];
+                allocator::schedule(ctrler, &events[..len], &mut assignment).map_err(|e| e.reason)?;
+                for (event, assigned) in events.iter().zip(assignment.iter()).take(len) {
+                    let mut counter = PerfCounter::new(ctrler);
+                    allocator::build_assigned(&mut counter, event, *assigned)?;
+                    counter.disable_interrupt();
+                    group.add(counter)?;
+                }
+            },
+        }
+        Ok(Topdown{ctrler:ctrler, group:group, method:method, level2:level2})
+    }
+
+    pub fn method(&self) -> TopdownMethod {
+        self.method
+    }
+
+    ///Zero the counters, and IA32_PERF_METRICS with them.
+    pub fn reset(&self) -> Result<(), ErrorMsg> {
+        self.group.reset()?;
+        if let TopdownMethod::PerfMetrics{..} = self.method {
+            self.ctrler.backend().wrmsr(IA32_PERF_METRICS, 0);
+        }
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<(), ErrorMsg> {
+        self.group.start()?;
+        if let TopdownMethod::PerfMetrics{..} = self.method {
+            self.ctrler.set_globle_ctrl(self.ctrler.read_globle_ctrl_bits()? | GLOBAL_CTRL_EN_PERF_METRICS);
+        }
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), ErrorMsg> {
+        if let TopdownMethod::PerfMetrics{..} = self.method {
+            self.ctrler.set_globle_ctrl(self.ctrler.read_globle_ctrl_bits()? & !GLOBAL_CTRL_EN_PERF_METRICS);
+        }
+        self.group.stop()
+    }
+
+    ///Read the counters and compute the breakdown of the window since reset().
+    pub fn read(&self) -> Result<TopdownBreakdown, ErrorMsg> {
+        let mut values = [0u64; 1 + LEVEL2_EVENTS];
+        match self.method {
+            TopdownMethod::PerfMetrics{..} => {
+                //Freeze the slots and the metrics together.
+                let saved = self.ctrler.read_globle_ctrl_bits()?;
+                self.ctrler.set_globle_ctrl(saved & !(self.group.enable_mask() | GLOBAL_CTRL_EN_PERF_METRICS));
+                self.group.read(&mut values)?;
+                let metrics = self.ctrler.backend().rdmsr(IA32_PERF_METRICS);
+                self.ctrler.set_globle_ctrl(saved);
+                Ok(decode_perf_metrics(values[0], metrics, self.level2))
+            },
+            TopdownMethod::Events{width} => {
+                self.group.read(&mut values)?;
+                let counts = TopdownEventCounts{
+                    cycles: values[0],
+                    uops_not_delivered: values[1],
+                    uops_issued: values[2],
+                    retire_slots: values[3],
+                    recovery_cycles: values[4],
+                    cycles_0_uops_delivered: values[5],
+                    branch_misses: values[6],
+                    machine_clears: values[7],
+                    memory_stall_cycles: values[8],
+                };
+                Ok(decode_events(width, &counts, self.level2))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_intel::simulator::SimulatedPmu;
+    use x86::cpuid::CpuIdResult;
+
+    fn assert_level1_sums(breakdown:&TopdownBreakdown) {
+        let l1 = breakdown.level1;
+        assert_eq!(l1.frontend_bound + l1.bad_speculation + l1.backend_bound + l1.retiring, l1.slots);
+    }
+
+    fn assert_level2_within_parents(breakdown:&TopdownBreakdown) {
+        let (l1, l2) = (breakdown.level1, breakdown.level2.unwrap());
+        assert_eq!(l2.fetch_latency + l2.fetch_bandwidth, l1.frontend_bound);
+        assert_eq!(l2.branch_mispredicts + l2.machine_clears, l1.bad_speculation);
+        assert_eq!(l2.memory_bound + l2.core_bound, l1.backend_bound);
+        if let (Some(light), Some(heavy)) = (l2.light_operations, l2.heavy_operations) {
+            assert_eq!(light + heavy, l1.retiring);
+        }
+    }
+
+    #[test]
+    fn perf_metrics_breakdown() {
+        //Retiring 64, Bad Speculation 32, Frontend Bound 48, Backend Bound 111; level 2 bytes above.
+        let metrics = 0x50_20_18_10_6F_30_20_40;
+        let breakdown = decode_perf_metrics(1000, metrics, true);
+        assert_eq!(breakdown.level1, TopdownLevel1{slots: 1000, frontend_bound: 188, bad_speculation: 125, backend_bound: 437, retiring: 250});
+        assert_level1_sums(&breakdown);
+        assert_level2_within_parents(&breakdown);
+        let l2 = breakdown.level2.unwrap();
+        assert_eq!((l2.heavy_operations, l2.branch_mispredicts, l2.fetch_latency, l2.memory_bound), (Some(62), 94, 125, 313));
+        assert!(decode_perf_metrics(1000, metrics, false).level2.is_none());
+    }
+
+    #[test]
+    fn perf_metrics_level2_is_capped_by_its_parent() {
+        //Every level 2 byte is larger than its parent.
+        let breakdown = decode_perf_metrics(12345, 0xFF_FF_FF_FF_3F_3F_3F_40, true);
+        assert_level1_sums(&breakdown);
+        assert_level2_within_parents(&breakdown);
+        let l2 = breakdown.level2.unwrap();
+        assert_eq!((l2.fetch_bandwidth, l2.machine_clears, l2.core_bound, l2.light_operations), (0, 0, 0, Some(0)));
+    }
+
+    #[test]
+    fn perf_metrics_without_shares() {
+        let breakdown = decode_perf_metrics(1000, 0, true);
+        assert_eq!(breakdown.level1, TopdownLevel1{slots: 1000, ..TopdownLevel1::default()});
+        assert_level2_within_parents(&breakdown);
+        assert_eq!(basis_points(0, 0), 0);
+    }
+
+    #[test]
+    fn event_breakdown() {
+        let counts = TopdownEventCounts{
+            cycles: 1000,
+            uops_not_delivered: 800,
+            uops_issued: 2200,
+            retire_slots: 2000,
+            recovery_cycles: 50,
+            cycles_0_uops_delivered: 100,
+            branch_misses: 30,
+            machine_clears: 10,
+            memory_stall_cycles: 150,
+        };
+        let breakdown = decode_events(4, &counts, true);
+        assert_eq!(breakdown.level1, TopdownLevel1{slots: 4000, frontend_bound: 800, bad_speculation: 400, backend_bound: 800, retiring: 2000});
+        assert_eq!(breakdown.level2, Some(TopdownLevel2{
+            fetch_latency: 400,
+            fetch_bandwidth: 400,
+            branch_mispredicts: 300,
+            machine_clears: 100,
+            memory_bound: 600,
+            core_bound: 200,
+            light_operations: None,
+            heavy_operations: None,
+        }));
+        assert_eq!(basis_points(breakdown.level1.retiring, breakdown.level1.slots), 5000);
+        //Counts past the slots of the window, as left by multiplexing or skew, are capped.
+        let skewed = decode_events(4, &TopdownEventCounts{uops_not_delivered: 5000, memory_stall_cycles: 5000, ..counts}, true);
+        assert_eq!(skewed.level1, TopdownLevel1{slots: 4000, frontend_bound: 4000, ..TopdownLevel1::default()});
+        assert_level2_within_parents(&skewed);
+        let idle = decode_events(4, &TopdownEventCounts::default(), true);
+        assert_eq!(idle.level1, TopdownLevel1::default());
+        assert_level2_within_parents(&idle);
+    }
+
+    //Version 3 PMU with 8 general counters on a family 6 model `model` Intel processor.
+    fn intel_controler(model:u8) -> PerfCounterControler<SimulatedPmu> {
+        let pmu = SimulatedPmu::new(3 | 8<<8 | 48<<16 | 7<<24, 0, 0, 3 | 48<<5);
+        pmu.set_cpuid(0x00, 0, CpuIdResult{eax: 0x0D, ebx: 0x756E_6547, ecx: 0x6C65_746E, edx: 0x4965_6E69}).unwrap();
+        let signature = ((model as u32 >> 4) << 16) | 6 << 8 | ((model as u32 & 0xF) << 4);
+        pmu.set_cpuid(0x01, 0, CpuIdResult{eax: signature, ebx: 0, ecx: 0, edx: 0}).unwrap();
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        ctrler
+    }
+
+    #[test]
+    fn events_are_scheduled_on_their_counters() {
+        let ctrler = intel_controler(0x2A);
+        //Take PMC2, the only counter of CYCLE_ACTIVITY.STALLS_L1D_PENDING on Sandy Bridge.
+        let mut other = PerfCounter::new(&ctrler);
+        other.build_general_from_raw(0xC0, 0x00, true, true, 0, false, 2).unwrap();
+        assert!(matches!(Topdown::new(&ctrler, true), Err(ErrorMsg::CounterOutOfRange)));
+        //Level 1 does not need it.
+        assert!(Topdown::new(&ctrler, false).is_ok());
+        drop(other);
+        let topdown = Topdown::new(&ctrler, true).unwrap();
+        assert_eq!(topdown.method(), TopdownMethod::Events{width: 4});
+        assert_eq!(topdown.group.member(0).unwrap().get_counter_type(), Counter::Fixed(1));
+        assert_eq!(topdown.group.member(8).unwrap().get_counter_type(), Counter::Programmable(2));
+    }
+
+    #[test]
+    fn models_without_a_table_use_their_family_table() {
+        //Comet Lake shares the Skylake events.
+        let ctrler = intel_controler(0xA5);
+        assert!(Topdown::new(&ctrler, true).is_ok());
+    }
+}
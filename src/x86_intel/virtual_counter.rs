@@ -0,0 +1,207 @@
+//! 64-bit counters on top of the 32 to 48 bit hardware PMCs.
+//!
+//! A VirtualCounter counts the wraparounds (epochs) of one PerfCounter and returns
+//! epochs * 2^width + hardware value. Wraparounds are accounted by account_overflow(), called either
+//! from the PMI handler or periodically, at least once every 2^width events.
+//!
+//! On version 2+ PMUs a wraparound is detected through IA32_PERF_GLOBAL_STATUS. Version 1 has no
+//! overflow status and a wraparound is detected as the hardware value going backwards.
+//!
+
+use core::cell::Cell;
+use x86::perfcnt::intel::Counter;
+use crate::{AbstractPerfCounter, ErrorMsg};
+use super::{backend::{PmuBackend, NativePmu}, PerfCounter};
+
+pub struct VirtualCounter<'a, B: PmuBackend = NativePmu> {
+    counter: PerfCounter<'a, B>,
+    width: u8,
+    epochs: Cell<u64>,
+    //Last hardware value seen, used to detect wraparounds without IA32_PERF_GLOBAL_STATUS.
+    last_raw: Cell<u64>,
+}
+
+impl<'a, B: PmuBackend> VirtualCounter<'a, B> {
+    ///Wrap a built counter.
+    pub fn new(counter:PerfCounter<'a, B>) -> VirtualCounter<'a, B> {
+        let ctrler = counter.global_ctrler;
+        let width = match counter.get_counter_type() {
+            Counter::Programmable(_) => ctrler.get_bit_width(),
+            Counter::Fixed(_) => ctrler.get_bit_width_fixed_counter(),
+        };
+        VirtualCounter{
+            counter: counter,
+            width: width.min(63),
+            epochs: Cell::new(0),
+            last_raw: Cell::new(0),
+        }
+    }
+
+    pub fn counter(&self) -> &PerfCounter<'a, B> {
+        &self.counter
+    }
+
+    pub fn into_inner(self) -> PerfCounter<'a, B> {
+        self.counter
+    }
+
+    ///Number of hardware wraparounds accounted so far.
+    pub fn epochs(&self) -> u64 {
+        self.epochs.get()
+    }
+
+    fn has_overflow_status(&self) -> bool {
+        self.counter.global_ctrler.get_version_identifier() >= 2
+    }
+
+    fn overflow_pending(&self) -> bool {
+        self.has_overflow_status() && self.counter.check_overflow()
+    }
+
+    ///Account a wraparound of the hardware counter, if one happened.
+    ///
+    /// Call it from the PMI handler (before clearing the overflow status of other counters)
+    /// or periodically. Returns true if a wraparound was accounted.
+    pub fn account_overflow(&self) -> bool {
+        if self.has_overflow_status() {
+            let wrapped = self.counter.check_overflow();
+            if wrapped {
+                self.epochs.set(self.epochs.get() + 1);
+                self.counter.global_ctrler.clear_overflow_bit(self.counter.get_counter_type());
+            }
+            wrapped
+        } else {
+            let raw = self.counter.read_ctr();
+            let wrapped = raw < self.last_raw.get();
+            if wrapped {
+                self.epochs.set(self.epochs.get() + 1);
+            }
+            self.last_raw.set(raw);
+            wrapped
+        }
+    }
+
+    ///Current 64-bit value.
+    ///
+    /// An overflow that is pending in IA32_PERF_GLOBAL_STATUS but not yet accounted is included.
+    /// The value is read again if the counter overflows, or a PMI accounts an epoch, during the read.
+    pub fn value(&self) -> u64 {
+        if !self.has_overflow_status() {
+            self.account_overflow();
+            return self.compose(self.epochs.get(), self.last_raw.get());
+        }
+        loop {
+            let epochs = self.epochs.get();
+            let pending = self.overflow_pending();
+            let raw = self.counter.read_ctr();
+            if pending != self.overflow_pending() || epochs != self.epochs.get() {
+                continue;
+            }
+            return self.compose(epochs + pending as u64, raw);
+        }
+    }
+
+    fn compose(&self, epochs:u64, raw:u64) -> u64 {
+        epochs.wrapping_shl(self.width as u32).wrapping_add(raw)
+    }
+}
+
+impl<'a, B: PmuBackend> AbstractPerfCounter for VirtualCounter<'a, B> {
+    fn reset(&self) -> Result<(), ErrorMsg> {
+        self.counter.reset()?;
+        if self.has_overflow_status() {
+            self.counter.global_ctrler.clear_overflow_bit(self.counter.get_counter_type());
+        }
+        self.epochs.set(0);
+        self.last_raw.set(0);
+        Ok(())
+    }
+
+    fn start(&self) -> Result<(), ErrorMsg> {
+        self.counter.start()
+    }
+
+    fn stop(&self) -> Result<(), ErrorMsg> {
+        self.counter.stop()
+    }
+
+    fn read(&mut self) -> Result<u64, ErrorMsg> {
+        Ok(self.value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::globle_ctrl::PerfCounterControler;
+    use crate::x86_intel::simulator::{SimulatedPmu, SimRing};
+
+    fn controler(pmu:SimulatedPmu) -> PerfCounterControler<SimulatedPmu> {
+        let mut ctrler = PerfCounterControler::with_backend(pmu);
+        ctrler.init();
+        ctrler
+    }
+
+    ///Branch counter on PMC0 starting `before_wrap` events before the wraparound.
+    fn branches(ctrler:&PerfCounterControler<SimulatedPmu>, before_wrap:u64) -> VirtualCounter<'_, SimulatedPmu> {
+        let mut counter = PerfCounter::new(ctrler);
+        counter.build_general_from_raw(0xC4, 0, true, true, 0, false, 0).unwrap();
+        counter.disable_interrupt();
+        let counter = VirtualCounter::new(counter);
+        counter.reset().unwrap();
+        counter.counter().set_general_pmc_ctr(0, (1 << counter.width) - before_wrap);
+        counter.start().unwrap();
+        counter
+    }
+
+    fn count_branches(pmu:&SimulatedPmu) {
+        pmu.count_event(0xC4, 0, SimRing::User, 10);
+    }
+
+    #[test]
+    fn wraps_through_the_overflow_status() {
+        let ctrler = controler(SimulatedPmu::default_v4());
+        let pmu = ctrler.backend();
+        let counter = branches(&ctrler, 5);
+        assert_eq!(counter.value(), (1 << 48) - 5);
+        assert!(!counter.account_overflow());
+        count_branches(pmu);
+        //Pending in IA32_PERF_GLOBAL_STATUS but not accounted yet.
+        assert_eq!(counter.epochs(), 0);
+        assert_eq!(counter.value(), (1 << 48) + 5);
+        assert!(counter.account_overflow());
+        assert_eq!(counter.epochs(), 1);
+        assert!(!counter.counter().check_overflow());
+        assert_eq!(counter.value(), (1 << 48) + 5);
+        assert!(!counter.account_overflow());
+        counter.reset().unwrap();
+        assert_eq!((counter.epochs(), counter.value()), (0, 0));
+    }
+
+    #[test]
+    fn overflow_during_the_read_is_seen() {
+        let ctrler = controler(SimulatedPmu::default_v4());
+        let pmu = ctrler.backend();
+        let counter = branches(&ctrler, 5);
+        //The counter wraps after the overflow status was read, before RDPMC.
+        pmu.before_next_rdpmc(count_branches);
+        assert_eq!(counter.value(), (1 << 48) + 5);
+        assert_eq!(counter.epochs(), 0);
+    }
+
+    #[test]
+    fn wraps_detected_by_polling_without_overflow_status() {
+        let ctrler = controler(SimulatedPmu::new(1 | 2 << 8 | 40 << 16, 0, 0, 0));
+        let pmu = ctrler.backend();
+        let counter = branches(&ctrler, 5);
+        assert_eq!(counter.width, 40);
+        assert_eq!(counter.value(), (1 << 40) - 5);
+        count_branches(pmu);
+        assert!(counter.account_overflow());
+        assert!(!counter.account_overflow());
+        assert_eq!(counter.value(), (1 << 40) + 5);
+        count_branches(pmu);
+        assert_eq!(counter.value(), (1 << 40) + 15);
+        assert_eq!(counter.epochs(), 1);
+    }
+}